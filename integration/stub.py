@@ -0,0 +1,114 @@
+#!/usr/bin/env python3
+"""Stub origin and stub redirection.io agent used by the integration tests.
+
+The origin listens on port 9090 and always answers with a small HTML page.
+The agent listens on port 9091 and answers to the `action` endpoint with an
+action depending on the path of the request, and accepts every log.
+"""
+
+import json
+import threading
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+ORIGIN_PORT = 9090
+AGENT_PORT = 9091
+TOKEN = "integration-token"
+
+
+def header_filter(action, header, value, rule_id):
+    return {
+        "filter": {"action": action, "header": header, "value": value},
+        "on_response_status_codes": [],
+        "exclude_response_status_codes": False,
+        "rule_id": rule_id,
+    }
+
+
+def status_code_update(status_code, rule_id):
+    return {
+        "status_code": status_code,
+        "on_response_status_codes": [],
+        "exclude_response_status_codes": False,
+        "fallback_status_code": 0,
+        "rule_id": rule_id,
+    }
+
+
+ACTIONS = {
+    "/redirect": {
+        "status_code_update": status_code_update(301, "redirect-rule"),
+        "header_filters": [header_filter("override", "Location", "/target", "redirect-rule")],
+        "body_filters": [],
+        "rule_ids": ["redirect-rule"],
+    },
+    "/override": {
+        "status_code_update": None,
+        "header_filters": [header_filter("override", "X-Stub", "overridden", "override-rule")],
+        "body_filters": [],
+        "rule_ids": ["override-rule"],
+    },
+    "/body": {
+        "status_code_update": None,
+        "header_filters": [],
+        "body_filters": [
+            {
+                "filter": {"action": "append_text", "content": "<!-- filtered -->"},
+                "on_response_status_codes": [],
+                "exclude_response_status_codes": False,
+                "rule_id": "body-rule",
+            }
+        ],
+        "rule_ids": ["body-rule"],
+    },
+}
+
+EMPTY_ACTION = {
+    "status_code_update": None,
+    "header_filters": [],
+    "body_filters": [],
+    "rule_ids": [],
+}
+
+
+class OriginHandler(BaseHTTPRequestHandler):
+    def do_GET(self):
+        body = b"<html><body><p>origin</p></body></html>"
+
+        self.send_response(200)
+        self.send_header("Content-Type", "text/html; charset=UTF-8")
+        self.send_header("X-Stub", "origin")
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+
+class AgentHandler(BaseHTTPRequestHandler):
+    def do_POST(self):
+        length = int(self.headers.get("Content-Length", 0))
+        request = json.loads(self.rfile.read(length) or b"{}")
+
+        if self.path == "/%s/log" % TOKEN:
+            self.respond(200, {})
+        elif self.path == "/%s/action" % TOKEN:
+            path = (request.get("path_and_query_v2") or "/").split("?")[0]
+            self.respond(200, ACTIONS.get(path, EMPTY_ACTION))
+        else:
+            self.respond(404, {"error": "unknown endpoint %s" % self.path})
+
+    def respond(self, status, payload):
+        body = json.dumps(payload).encode()
+
+        self.send_response(status)
+        self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+
+def serve(port, handler):
+    ThreadingHTTPServer(("127.0.0.1", port), handler).serve_forever()
+
+
+if __name__ == "__main__":
+    threading.Thread(target=serve, args=(ORIGIN_PORT, OriginHandler), daemon=True).start()
+    serve(AGENT_PORT, AgentHandler)
@@ -0,0 +1,56 @@
+#!/usr/bin/env bash
+#
+# Run the worker with Viceroy against the stub origin and the stub agent, and
+# check the end-to-end behavior of redirects, header overrides and body filters.
+
+set -euo pipefail
+
+cd "$(dirname "$0")/.."
+
+WORKER_URL="http://127.0.0.1:7676"
+WASM="target/wasm32-wasi/release/redirectionio-fastly-worker.wasm"
+
+cargo build --release --features integration-test
+
+python3 integration/stub.py &
+STUB_PID=$!
+viceroy -C integration/fastly.toml "$WASM" &
+VICEROY_PID=$!
+trap 'kill $STUB_PID $VICEROY_PID' EXIT
+
+for _ in $(seq 1 50); do
+    curl -s -o /dev/null "$WORKER_URL/" && break
+    sleep 0.2
+done
+
+FAILURES=0
+
+assert_contains() {
+    local name="$1" output="$2" expected="$3"
+
+    if grep -qi -- "$expected" <<< "$output"; then
+        echo "ok - $name"
+    else
+        echo "not ok - $name: \"$expected\" not found in:"
+        echo "$output"
+        FAILURES=$((FAILURES + 1))
+    fi
+}
+
+output=$(curl -s -D - -o /dev/null "$WORKER_URL/redirect")
+assert_contains "redirect status" "$output" "HTTP/1.1 301"
+assert_contains "redirect location" "$output" "location: /target"
+assert_contains "redirect rule ids" "$output" "x-redirectionio-ruleids: redirect-rule"
+
+output=$(curl -s -D - -o /dev/null "$WORKER_URL/override")
+assert_contains "override status" "$output" "HTTP/1.1 200"
+assert_contains "override header" "$output" "x-stub: overridden"
+
+output=$(curl -s "$WORKER_URL/body")
+assert_contains "body filter" "$output" "<p>origin</p></body></html><!-- filtered -->"
+
+output=$(curl -s -D - "$WORKER_URL/pass")
+assert_contains "pass through header" "$output" "x-stub: origin"
+assert_contains "pass through action type" "$output" "x-redirectionio-action-type: proxy"
+
+exit $FAILURES
@@ -3,88 +3,572 @@ extern crate quick_error;
 
 mod rio;
 
-use crate::rio::application::Application;
-use crate::rio::configuration::{Configuration, ConfigurationError};
-use crate::rio::logging::{Context, FastlyLogger};
+use crate::rio::access_log::{self, AccessLog};
+use crate::rio::admin::{self, AdminRouter};
+use crate::rio::affinity::WeightedRequestSender;
+use crate::rio::allowed_hosts::is_allowed_request;
+use crate::rio::api::{ApiClient, FastlyApiClient, LogBuffer};
+use crate::rio::application::{is_body_filter_disabled, Application};
+use crate::rio::backend_health::HealthAwareRequestSender;
+use crate::rio::background::BackgroundTasks;
+use crate::rio::beacon::{self, BeaconCollector};
+use crate::rio::bucket::Bucket;
+use crate::rio::bypass::is_bypass_request;
+use crate::rio::cache_busting::is_cache_busting_request;
+use crate::rio::caching::CachingRequestSender;
+use crate::rio::clock::{Clock, SystemClock};
+use crate::rio::configuration::{self, with_config_json, ApiErrorPolicy, ChainRole};
+use crate::rio::debug::DebugRequest;
+use crate::rio::encoding::normalize_accept_encoding;
+use crate::rio::error::{Phase, WorkerError};
+use crate::rio::explain::Explain;
+use crate::rio::flags::{is_sampled, FeatureFlags};
+use crate::rio::geo_policy;
+use crate::rio::hooks::{NoHooks, WorkerHooks};
+use crate::rio::kv_store::{FastlyKvStore, KvStore};
+use crate::rio::logging::{Context, FastlyLogSink, FastlyLogger};
+use crate::rio::methods;
+use crate::rio::migration::{MigrationApiClient, ShadowDifferences};
+use crate::rio::mirroring::MirroringRequestSender;
+use crate::rio::mtls::MtlsRequestSender;
+use crate::rio::panic_hook;
+use crate::rio::prerender::{is_verified_crawler, PrerenderRequestSender};
+use crate::rio::purge::PurgeHandler;
+use crate::rio::recording::RecordingApiClient;
 use crate::rio::request_sender::{DirectRequestSender, RequestSender};
+use crate::rio::secrets::get_secret;
+use crate::rio::shield::{Shield, ShieldRequestSender};
+use crate::rio::static_files;
+use crate::rio::synthetic::{self, DEFAULT_SYNTHETIC_CACHE_CONTROL};
+use fastly::experimental::RequestUpgradeWebsocket;
+use fastly::geo::geo_lookup;
+use fastly::http::header;
 use fastly::{ConfigStore, Error, Request, Response};
+use redirectionio::action::Action;
+use std::collections::HashMap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 
-#[fastly::main]
-fn main(req: Request) -> Result<Response, Error> {
-    let start_time = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .ok()
-        .map(|time| time.as_millis())
-        .unwrap_or(0);
+fn main() -> Result<(), Error> {
+    // Started first, to measure the initialization of the worker
+    let clock = SystemClock::new();
+    let kv_store = FastlyKvStore;
+    let start_time = clock.now();
+    let mut req = Request::from_client();
+    let debug = DebugRequest::from_request(&mut req);
+    let url = req.get_url_str().to_string();
+    let context = Context::new(&req);
     let config_store = ConfigStore::open("redirectionio");
-    let req_sender = DirectRequestSender;
+    let get_config = with_config_json(|key| config_store.get(key));
+    // The log endpoint is only opened by the first log
     let fastly_logger = FastlyLogger::new(
-        config_store.get("log_endpoint"),
-        config_store.get("log_level"),
-        Context::new(req.clone_without_body()),
+        get_config("log_endpoint"),
+        debug.log_level(get_config("log_level")),
+        get_config("log_format"),
+        context.clone(),
+        Box::new(SystemClock::new()),
+        Box::new(FastlyKvStore),
+        Box::new(FastlyLogSink),
     );
+    let log_buffer = LogBuffer::default();
+    let background_tasks = BackgroundTasks::default();
+    let access_log = AccessLog::default();
+    let explain = Explain::default();
+    let shadow_differences = ShadowDifferences::default();
+    let request_id = req.get_client_request_id().map(String::from);
+
+    panic_hook::install(fastly_logger.settings(), request_id.clone());
+
+    if let Some(fanout_backend) = fanout_backend(&req, &get_config) {
+        // Fanout keeps the connection open: no response is sent by the worker
+        fastly_logger.log_info(
+            format!(
+                "Hand off the request to Fanout with the \"{}\" backend.",
+                fanout_backend
+            ),
+            None,
+        );
+        panic_hook::mark_response_sent();
+        req.handoff_fanout(&fanout_backend)?;
+
+        return Ok(());
+    }
+
+    let response = catch_unwind(AssertUnwindSafe(|| {
+        handle_request(
+            req,
+            &debug,
+            &get_config,
+            &fastly_logger,
+            &log_buffer,
+            &background_tasks,
+            &access_log,
+            &explain,
+            &shadow_differences,
+            &clock,
+            &kv_store,
+        )
+    }));
+    let response = match response {
+        Ok(response) => response?,
+        // The panic has been logged by the hook
+        Err(_) => panic_hook::error_response(request_id.as_deref()),
+    };
+
+    if let Some(access_log_endpoint) = get_config("access_log_endpoint") {
+        let entry = access_log.entry(&context, &response, start_time, clock.elapsed().as_millis());
+
+        if let Err(error) = access_log::send(&access_log_endpoint, &entry) {
+            fastly_logger.log_error(
+                format!("Can not write to the access log endpoint: {}.", error),
+                None,
+            );
+        }
+    }
+
+    if let Some(mut explain_context) = explain.context() {
+        explain_context.insert("status", response.get_status().as_u16().to_string());
+        fastly_logger.log_record("Explain request.".to_string(), explain_context);
+    }
+
+    panic_hook::mark_response_sent();
+    response.send_to_client();
+
+    // Background tasks and logs only run once the client got its response
+    background_tasks.run();
+
+    for error in log_buffer.flush(&clock) {
+        let error = WorkerError::new(error, Phase::Log, &url);
+
+        fastly_logger.log_error(
+            format!("Can not send \"log\" request to redirection.io: {}.", error),
+            Some(error.context()),
+        );
+    }
+
+    for difference in shadow_differences.drain() {
+        fastly_logger.log_info(
+            format!(
+                "The secondary project returned a different action for \"{}\".",
+                difference.request
+            ),
+            Some(HashMap::from([
+                ("primary_action", difference.primary_action),
+                ("secondary_action", difference.secondary_action),
+            ])),
+        );
+    }
+
+    Ok(())
+}
+
+/// The Fanout backend of a streaming request, when the `fanout_backend` entry is set.
+fn fanout_backend(req: &Request, get_config: &dyn Fn(&str) -> Option<String>) -> Option<String> {
+    // The whole configuration is only read for services using Fanout
+    get_config("fanout_backend")?;
+
+    let config = configuration::load(get_config)
+        .ok()?
+        .with_project_for(req.get_path());
+    let fanout_backend = config.fanout_backend.clone()?;
+
+    match config.streaming_markers().matches(req) {
+        true => Some(fanout_backend),
+        false => None,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_request(
+    mut req: Request,
+    debug: &DebugRequest,
+    get_config: &dyn Fn(&str) -> Option<String>,
+    fastly_logger: &FastlyLogger,
+    log_buffer: &LogBuffer,
+    background_tasks: &BackgroundTasks,
+    access_log: &AccessLog,
+    explain: &Explain,
+    shadow_differences: &ShadowDifferences,
+    clock: &dyn Clock,
+    kv_store: &dyn KvStore,
+) -> Result<Response, Error> {
+    let start_time = clock.now();
+    let init_duration = clock.elapsed();
+    let req_sender = DirectRequestSender;
 
-    let config = match Configuration::new(
-        config_store.get("backend_name"),
-        config_store.get("token"),
-        config_store.get("instance_name"),
-        config_store.get("add_rule_ids_header"),
-    ) {
+    let config = configuration::load(get_config).map(|config| {
+        config
+            .with_project_for(req.get_path())
+            .with_instance_name_for(req.get_path(), debug.instance_name.clone())
+            .with_rule_ids_header(debug.rule_ids)
+            .with_server_timing(debug.enabled)
+    });
+
+    let shield = Shield::new(get_secret("shield_secret"), clock);
+    let flags = FeatureFlags::new(kv_store);
+
+    // Admin endpoints also answer when the worker can not be configured, to report why
+    if let Some(route) = admin::Route::from_path(req.get_path()) {
+        let admin_router = AdminRouter::new(
+            get_secret("admin_secret"),
+            &shield,
+            fastly_logger,
+            clock,
+            kv_store,
+        );
+
+        return Ok(admin_router.handle(route, &mut req, config.as_ref(), &flags));
+    }
+
+    let config = match config {
         Ok(config) => config,
         Err(error) => {
-            return match error {
-                ConfigurationError::MissingBackendName => {
-                    let message = format!("Fastly worker configuration error: {}.\n", error);
-                    fastly_logger.log_error(message.clone(), None);
+            let backend_name = error.backend_name();
+            let error = WorkerError::new(error, Phase::Configuration, req.get_url_str());
+            let message = format!("Fastly worker configuration error: {}.\n", error.kind);
+            // Every request hits the error until the configuration is fixed
+            fastly_logger.log_error_once(message.clone(), Some(error.context()));
 
-                    Ok(generate_synthetic_response(message, 500))
-                }
-                ConfigurationError::MissingToken(ref backend_name)
-                | ConfigurationError::MissingInstanceName(ref backend_name)
-                | ConfigurationError::MissingAddRuleIdsHeader(ref backend_name) => {
-                    // The worked can not be configured: log an error and transparently forward the
-                    // request to the backend with no changes
-                    let message = format!("Fastly worker configuration error: {}.\n", error);
-                    fastly_logger.log_error(message.clone(), None);
-
-                    Ok(req_sender.send(req, backend_name.clone())?)
+            return match backend_name {
+                // The worked can not be configured: transparently forward the request to the
+                // backend with no changes
+                Some(backend_name) => {
+                    access_log.set_backend(backend_name.clone());
+
+                    Ok(req_sender.send(req, backend_name)?)
                 }
+                None => Ok(synthetic::text_response(
+                    message,
+                    error.status_code(),
+                    DEFAULT_SYNTHETIC_CACHE_CONTROL,
+                )),
             };
         }
     };
 
-    let application = Application::new(&config, &fastly_logger, &req_sender);
+    access_log.set_backend(config.backend_name.clone());
+
+    if config.explain || debug.enabled {
+        explain.enable();
+    }
+
+    let refresh_action_cache = is_cache_busting_request(&mut req);
+    let mtls_sender = MtlsRequestSender::new(&config.mtls_backends, fastly_logger, &req_sender);
+    let health_sender = HealthAwareRequestSender::new(
+        config.failover_backend.clone(),
+        config.retry_non_idempotent,
+        fastly_logger,
+        clock,
+        kv_store,
+        &mtls_sender,
+    );
+    let chain = Shield::chain(get_secret("chain_secret"), clock);
+
+    // Bypassing the worker comes before any other policy, the request goes straight to the
+    // backend, signed so the shield node and chained services let it through as well
+    if is_bypass_request(&mut req) {
+        fastly_logger.log_info("Bypass worker".to_string(), None);
+        explain.record("excluded", "bypass");
+
+        let shield_sender = ShieldRequestSender::new(&shield, &health_sender);
+        let bypass_sender = ShieldRequestSender::new(&chain, &shield_sender);
+
+        return Ok(bypass_sender.send(req, config.backend_name.clone())?);
+    }
+
+    if !is_allowed_request(&config.allowed_hosts, &req) {
+        explain.record("excluded", "unknown_host");
+
+        return Ok(synthetic::text_response(
+            "Unknown host.\n".to_string(),
+            config.unknown_host_status,
+            &config.synthetic_cache_control,
+        ));
+    }
+
+    if req.get_path() == beacon::BEACON_PATH && config.collects_beacons() {
+        explain.record("excluded", "beacon");
+
+        let api_client = FastlyApiClient::new(
+            config.token.clone(),
+            config.instance_name.clone(),
+            &config.api_endpoints,
+            log_buffer,
+            background_tasks,
+            clock,
+        );
+        let beacon_collector = BeaconCollector::new(
+            &config.beacon_origins,
+            config.beacon_log_endpoint.clone(),
+            config.beacon_api.then_some(&api_client),
+            background_tasks,
+            fastly_logger,
+            clock,
+        );
+
+        return Ok(beacon_collector.handle(&mut req, &config.synthetic_cache_control));
+    }
+
+    if shield.verify(&mut req) {
+        // The request has already been processed by the edge node: forward it transparently
+        explain.record("excluded", "shield");
+
+        return Ok(health_sender.send(req, config.backend_name.clone())?);
+    }
+
+    if chain.verify(&mut req) {
+        // An upstream service running this worker already applied the rules
+        explain.record("excluded", "chain");
+
+        return Ok(health_sender.send(req, config.backend_name.clone())?);
+    }
+
+    if config.chain_role == ChainRole::Delegate {
+        // The rules are applied by a downstream service running this worker
+        explain.record("excluded", "chain_delegate");
+
+        return Ok(health_sender.send(req, config.backend_name.clone())?);
+    }
+
+    if PurgeHandler::is_purge_request(&req) {
+        if let Some(purge_handler) = PurgeHandler::new(fastly_logger) {
+            explain.record("excluded", "purge");
+
+            return Ok(purge_handler.handle(&req));
+        }
+    }
+
+    // TRACE, TRACK or unknown methods are not forwarded to the origins
+    if !methods::is_allowed_request(&config.allowed_methods, &req) {
+        explain.record("excluded", "method_not_allowed");
+
+        let mut response = synthetic::text_response(
+            "Method not allowed.\n".to_string(),
+            405,
+            &config.synthetic_cache_control,
+        );
+        response.set_header(header::ALLOW, config.allowed_methods.join(", "));
+
+        return Ok(response);
+    }
+
+    if flags.maintenance() {
+        explain.record("excluded", "maintenance");
+
+        return Ok(synthetic::text_response(
+            "Service under maintenance.\n".to_string(),
+            503,
+            &config.synthetic_cache_control,
+        ));
+    }
+
+    // Compliance policies must not depend on the availability of the API
+    if let Some(response) = geo_policy::handle(&config.geo_policies, &req) {
+        explain.record("excluded", "geo_policy");
+
+        return Ok(response);
+    }
+
+    if let Some(response) = static_files::handle(&req, get_config) {
+        explain.record("excluded", "static_file");
+
+        return Ok(response);
+    }
+
+    let caching_sender =
+        CachingRequestSender::new(config.cache_key_builder(), debug.enabled, &health_sender);
+    let backend_sender: &dyn RequestSender = if config.readthrough_cache {
+        &caching_sender
+    } else {
+        &health_sender
+    };
+    let weighted_sender = WeightedRequestSender::new(
+        config.backend_name.clone(),
+        &config.backend_weights,
+        match config.affinity_cookie {
+            true => get_secret("affinity_secret"),
+            false => None,
+        },
+        backend_sender,
+    );
+    let mirroring_sender = MirroringRequestSender::new(
+        config.backend_name.clone(),
+        config.shadow_backend.clone(),
+        config.shadow_sample_rate,
+        background_tasks,
+        &weighted_sender,
+    );
+    let shield_sender = ShieldRequestSender::new(&shield, &mirroring_sender);
+    let verified_crawler = config.prerender
+        && is_verified_crawler(
+            req.get_header_str(header::USER_AGENT).unwrap_or_default(),
+            req.get_client_ip_addr()
+                .and_then(geo_lookup)
+                .map(|geo| geo.as_number()),
+        );
+    let chain_sender = ShieldRequestSender::new(&chain, &shield_sender);
+    let req_sender = PrerenderRequestSender::new(verified_crawler, &chain_sender);
+
+    if config.streaming_markers().matches(&req) {
+        // Long-polling and event streams are relayed as they come, without rules
+        explain.record("excluded", "streaming");
+        req.set_pass(true);
+
+        return Ok(req_sender.send(req, config.backend_name.clone())?);
+    }
+
+    if config.normalize_accept_encoding {
+        normalize_accept_encoding(&mut req);
+    }
+
+    // Percentage-based rules get the same answer for all the requests of a visitor
+    let bucket = match config.percentage_bucket {
+        true => Some(Bucket::assign(&mut req)),
+        false => None,
+    };
+
+    let fastly_api_client = FastlyApiClient::new(
+        config.token.clone(),
+        config.instance_name.clone(),
+        &config.api_endpoints,
+        log_buffer,
+        background_tasks,
+        clock,
+    );
+    let secondary_api_client = config.secondary_token.clone().map(|secondary_token| {
+        FastlyApiClient::new(
+            secondary_token,
+            config.instance_name.clone(),
+            &config.api_endpoints,
+            log_buffer,
+            background_tasks,
+            clock,
+        )
+    });
+    let migration_api_client = secondary_api_client.as_ref().map(|secondary_api_client| {
+        MigrationApiClient::new(
+            config.migration_mode,
+            &fastly_api_client,
+            secondary_api_client,
+            shadow_differences,
+        )
+    });
+    let project_api_client: &dyn ApiClient = match migration_api_client {
+        Some(ref migration_api_client) => migration_api_client,
+        None => &fastly_api_client,
+    };
+    let api_client = RecordingApiClient::new(config.api_recording, project_api_client);
+    let hooks = NoHooks;
+    let application = Application::new(
+        &config,
+        fastly_logger,
+        &req_sender,
+        &hooks,
+        &api_client,
+        clock,
+        kv_store,
+    )
+    .with_body_filtering(
+        flags.body_filtering() && !is_body_filter_disabled(&config.disable_body_filter, &req),
+    )
+    .with_action_cache_refresh(refresh_action_cache)
+    .with_explain(explain);
+    application.record_timing("init", init_duration);
     fastly_logger.log_info("Start worker".to_string(), None);
 
-    let rio_request = match application.create_rio_request(&req) {
-        Some(rio_request) => rio_request,
-        None => return Ok(req_sender.send(req, config.backend_name.clone())?),
+    let mut rio_request = match application.create_rio_request(&req) {
+        Ok(rio_request) => rio_request,
+        Err(error) => {
+            fastly_logger.log_info(error.to_string(), Some(error.context()));
+            explain.record("excluded", "invalid_request");
+
+            return Ok(req_sender.send(req, config.backend_name.clone())?);
+        }
     };
 
+    if let Some(response) = hooks.before_match(&mut req, &mut rio_request) {
+        return Ok(response);
+    }
+
     let mut rio_action = match application.get_action(&rio_request) {
-        Some(rio_action) => rio_action,
-        None => return Ok(req_sender.send(req, config.backend_name.clone())?),
+        Ok(rio_action) => rio_action,
+        Err(error) if config.on_api_error == ApiErrorPolicy::FailClosed => {
+            explain.record("degradation", "fail_closed");
+
+            return Ok(synthetic::text_response(
+                "Service temporarily unavailable.\n".to_string(),
+                error.status_code(),
+                &config.synthetic_cache_control,
+            ));
+        }
+        Err(_) => {
+            explain.record("degradation", "fail_open");
+
+            return Ok(req_sender.send(req, config.backend_name.clone())?);
+        }
     };
 
-    match application.proxy(req, &mut rio_action) {
-        Ok((response, backend_status_code)) => {
-            application.log(
-                &response,
-                backend_status_code,
-                &rio_request,
-                &mut rio_action,
-                start_time,
+    hooks.after_match(&req, &mut rio_action);
+
+    let request_id = req.get_client_request_id().map(String::from);
+    let log = |response: &Response, backend_status_code: u16, rio_action: &mut Action| {
+        if !is_sampled(request_id.as_deref(), flags.log_sampling()) {
+            return;
+        }
+
+        if let Err(error) = application.log(
+            response,
+            backend_status_code,
+            &rio_request,
+            rio_action,
+            start_time,
+        ) {
+            fastly_logger.log_error(
+                format!("Can not send \"log\" request to redirection.io: {}.", error),
+                Some(error.context()),
             );
-            Ok(response)
         }
-        Err(error) => Err(error),
+    };
+
+    if flags.dry_run() {
+        // Log what the rules would have done, without applying them
+        explain.record("excluded", "dry_run");
+        let response = req_sender.send(req, config.backend_name.clone())?;
+        log(&response, response.get_status().as_u16(), &mut rio_action);
+
+        return Ok(response);
     }
-}
 
-fn generate_synthetic_response(error_message: String, status_code: u16) -> Response {
-    let mut response = Response::new();
-    response.set_body(error_message);
-    response.set_status(status_code);
+    match application.proxy(req, &mut rio_action) {
+        Ok((mut response, backend_status_code)) => {
+            hooks.before_respond(&mut response);
+
+            if let Some(bucket) = bucket {
+                bucket.persist(&mut response);
+            }
+
+            access_log.set_rule_ids(rio_action.get_applied_rule_ids().iter().cloned().collect());
+            log(&response, backend_status_code, &mut rio_action);
 
-    return response;
+            if let Err(error) = application.report_rule_metrics(&rio_action) {
+                fastly_logger.log_error(
+                    format!(
+                        "Can not send \"metrics\" request to redirection.io: {}.",
+                        error
+                    ),
+                    None,
+                );
+            }
+
+            Ok(response)
+        }
+        Err(error) => {
+            fastly_logger.log_error(error.to_string(), Some(error.context()));
+
+            Ok(synthetic::text_response(
+                format!("{}.\n", error.kind),
+                error.status_code(),
+                &config.synthetic_cache_control,
+            ))
+        }
+    }
 }
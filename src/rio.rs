@@ -1,5 +1,66 @@
+pub mod access_log;
+pub mod action_cache;
+pub mod admin;
+pub mod affinity;
+pub mod allowed_hosts;
+pub mod api;
 pub mod application;
+pub mod backend_health;
+pub mod background;
+pub mod backoff;
+pub mod beacon;
+pub mod bucket;
+pub mod bypass;
+pub mod cache_busting;
+pub mod cache_key;
+pub mod cache_policy;
+pub mod cache_state;
+pub mod caching;
+pub mod client_hints;
+pub mod clock;
 pub mod configuration;
+pub mod cookies;
+pub mod debug;
+pub mod encoding;
 pub mod error;
+pub mod esi;
+pub mod explain;
+pub mod flags;
+pub mod geo_policy;
+pub mod header_value;
+pub mod health;
+pub mod hooks;
+pub mod html_injection;
+pub mod image_optimizer;
+pub mod json_rewrite;
+pub mod kv_store;
+pub mod link_headers;
+pub mod log_budget;
 pub mod logging;
+pub mod methods;
+pub mod migration;
+pub mod mirroring;
+// Outside tests, the mocks are only exposed to the integration harness, the worker does not use them
+#[cfg(any(test, feature = "test-utils"))]
+#[cfg_attr(not(test), allow(dead_code))]
+pub mod mock;
+pub mod mtls;
+pub mod origin_stamp;
+pub mod outage;
+pub mod panic_hook;
+pub mod prerender;
+pub mod purge;
+pub mod range;
+pub mod recording;
 pub mod request_sender;
+pub mod response_headers;
+pub mod rule_metrics;
+pub mod secrets;
+pub mod server_timing;
+pub mod shield;
+pub mod soft_404;
+pub mod static_files;
+pub mod streaming;
+pub mod synthetic;
+pub mod url_normalization;
+pub mod vary;
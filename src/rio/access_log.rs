@@ -0,0 +1,92 @@
+use super::logging::{format_date, Context};
+use fastly::log::Endpoint;
+use fastly::Response;
+use serde::Serialize;
+use serde_json::to_string as json_encode;
+use std::cell::RefCell;
+use std::io::Write;
+
+#[derive(Debug, Serialize)]
+pub struct AccessLogEntry {
+    pub date: String,
+    pub method: String,
+    pub url: String,
+    pub status: u16,
+    pub duration: u128,
+    pub rule_ids: Vec<String>,
+    pub backend: Option<String>,
+    pub cache_state: Option<String>,
+}
+
+/// Edge access log: one line per request, sent to a Fastly log endpoint, whether or not the
+/// request is logged to redirection.io.
+///
+/// Request handling fills in what it learns (backend, matched rules) as it goes, and the entry is
+/// written once the response is ready.
+#[derive(Default)]
+pub struct AccessLog {
+    backend: RefCell<Option<String>>,
+    rule_ids: RefCell<Vec<String>>,
+}
+
+impl AccessLog {
+    pub fn set_backend(&self, backend: String) {
+        self.backend.replace(Some(backend));
+    }
+
+    pub fn set_rule_ids(&self, rule_ids: Vec<String>) {
+        self.rule_ids.replace(rule_ids);
+    }
+
+    pub fn entry(
+        &self,
+        context: &Context,
+        response: &Response,
+        date: u128,
+        duration: u128,
+    ) -> AccessLogEntry {
+        AccessLogEntry {
+            date: format_date(date),
+            method: context.method.clone(),
+            url: context.url.clone(),
+            status: response.get_status().as_u16(),
+            duration,
+            rule_ids: self.rule_ids.borrow().clone(),
+            backend: self.backend.borrow().clone(),
+            cache_state: response.get_header_str("x-cache").map(String::from),
+        }
+    }
+}
+
+/// Write an entry to the access log endpoint, as a JSON line.
+pub fn send(endpoint: &str, entry: &AccessLogEntry) -> Result<(), String> {
+    let mut endpoint = Endpoint::try_from_name(endpoint).map_err(|error| error.to_string())?;
+    let json = json_encode(entry).map_err(|error| error.to_string())?;
+
+    writeln!(endpoint, "{}", json).map_err(|error| error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fastly::Request;
+
+    #[test]
+    fn test_entry() {
+        let access_log = AccessLog::default();
+        access_log.set_backend("backend_host".to_string());
+        access_log.set_rule_ids(vec!["rule".to_string()]);
+
+        let context = Context::new(&Request::get("https://example.org/foo"));
+        let response = Response::from_status(301).with_header("X-Cache", "MISS");
+        let entry = access_log.entry(&context, &response, 1445412480250, 12);
+
+        assert_eq!("GET", entry.method);
+        assert_eq!("https://example.org/foo", entry.url);
+        assert_eq!(301, entry.status);
+        assert_eq!(12, entry.duration);
+        assert_eq!(vec!["rule".to_string()], entry.rule_ids);
+        assert_eq!(Some("backend_host".to_string()), entry.backend);
+        assert_eq!(Some("MISS".to_string()), entry.cache_state);
+    }
+}
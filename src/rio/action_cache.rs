@@ -0,0 +1,80 @@
+use fastly::cache::core::{insert, lookup, CacheKey};
+use redirectionio::action::Action;
+use serde_json::from_str as json_decode;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::time::Duration;
+
+pub const SURROGATE_KEY: &str = "redirectionio-action";
+
+/// Stores the actions returned by the redirection.io API in the Fastly cache of the current POP.
+///
+/// Actions are keyed by the namespace, the token and the serialized redirection.io request, so two
+/// requests only share an action when they would be sent identically to the API.
+#[derive(Clone)]
+pub struct ActionCache {
+    namespace: &'static str,
+    token: String,
+    ttl: Duration,
+    stale_while_revalidate: Duration,
+}
+
+impl ActionCache {
+    pub(crate) fn new(
+        namespace: &'static str,
+        token: String,
+        ttl: Duration,
+        stale_while_revalidate: Duration,
+    ) -> ActionCache {
+        ActionCache {
+            namespace,
+            token,
+            ttl,
+            stale_while_revalidate,
+        }
+    }
+
+    pub fn key(&self, rio_request_json: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.token.as_bytes());
+        hasher.update(rio_request_json.as_bytes());
+
+        format!(
+            "{}-{}:{}",
+            SURROGATE_KEY,
+            self.namespace,
+            hex::encode(hasher.finalize())
+        )
+    }
+
+    pub fn get(&self, key: &str) -> Option<Action> {
+        self.lookup(key).map(|(action, _)| action)
+    }
+
+    /// Get a cached action, and whether it is stale and should be refreshed.
+    pub fn lookup(&self, key: &str) -> Option<(Action, bool)> {
+        let found = lookup(CacheKey::from(key.to_string())).execute().ok()??;
+        let body = found.to_stream().ok()?.into_string();
+
+        json_decode(&body)
+            .ok()
+            .map(|action| (action, found.is_stale()))
+    }
+
+    pub fn set(&self, key: &str, action_json: &str) -> bool {
+        let mut body = match insert(CacheKey::from(key.to_string()), self.ttl)
+            .stale_while_revalidate(self.stale_while_revalidate)
+            .surrogate_keys([SURROGATE_KEY])
+            .execute()
+        {
+            Ok(body) => body,
+            Err(_) => return false,
+        };
+
+        if body.write_all(action_json.as_bytes()).is_err() {
+            return false;
+        }
+
+        body.finish().is_ok()
+    }
+}
@@ -0,0 +1,275 @@
+use super::api::AGENT_VERSION;
+use super::backend_health::{BackendHealthState, BackendHealthTracker};
+use super::clock::Clock;
+use super::configuration::{Configuration, ConfigurationError};
+use super::flags::FeatureFlags;
+use super::health;
+use super::kv_store::KvStore;
+use super::logging::FastlyLogger;
+use super::outage::{OutageState, OutageTracker};
+use super::purge::PurgeHandler;
+use super::secrets::secure_compare;
+use super::shield::Shield;
+use fastly::http::{Method, StatusCode, Url};
+use fastly::{Request, Response};
+use serde::Serialize;
+use std::collections::HashMap;
+
+pub const ADMIN_PATH_PREFIX: &str = "/__redirectionio/";
+pub const ADMIN_SECRET_HEADER: &str = "x-redirectionio-admin-secret";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    Health,
+    ConfigCheck,
+    Metrics,
+    Purge,
+    Flags,
+}
+
+impl Route {
+    pub fn from_path(path: &str) -> Option<Route> {
+        match path.strip_prefix(ADMIN_PATH_PREFIX)? {
+            "health" => Some(Route::Health),
+            "config-check" => Some(Route::ConfigCheck),
+            "metrics" => Some(Route::Metrics),
+            "purge" => Some(Route::Purge),
+            "flags" => Some(Route::Flags),
+            _ => None,
+        }
+    }
+
+    /// The health endpoint is polled by load balancers and monitoring: it is the only public one.
+    pub fn is_public(&self) -> bool {
+        *self == Route::Health
+    }
+}
+
+#[derive(Debug, Serialize, Default)]
+struct AdminResult {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backend_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Metrics {
+    agent_version: &'static str,
+    pop: Option<String>,
+    service_version: Option<String>,
+    api_outage: Option<OutageState>,
+    backends: HashMap<String, Option<BackendHealthState>>,
+}
+
+/// Router of the admin endpoints, under `/__redirectionio/`:
+///
+/// * `health`: the state of the redirection.io API and of the backends, public;
+/// * `config-check`: whether the worker can be configured;
+/// * `metrics`: the version of the worker, the POP, and the health states;
+/// * `purge`: purge the `Surrogate-Key` header keys, or the `url` query parameter, with a `POST`;
+/// * `flags`: the feature flags of the KV Store.
+///
+/// The other endpoints are authenticated with the `admin_secret` Secret Store entry, sent in the
+/// `x-redirectionio-admin-secret` header, or with the signature of another node of the service.
+pub struct AdminRouter<'a> {
+    secret: Option<String>,
+    shield: &'a Shield<'a>,
+    fastly_logger: &'a FastlyLogger,
+    clock: &'a dyn Clock,
+    kv_store: &'a dyn KvStore,
+}
+
+impl<'a> AdminRouter<'a> {
+    pub(crate) fn new(
+        secret: Option<String>,
+        shield: &'a Shield<'a>,
+        fastly_logger: &'a FastlyLogger,
+        clock: &'a dyn Clock,
+        kv_store: &'a dyn KvStore,
+    ) -> AdminRouter<'a> {
+        AdminRouter {
+            secret,
+            shield,
+            fastly_logger,
+            clock,
+            kv_store,
+        }
+    }
+
+    pub fn handle(
+        &self,
+        route: Route,
+        req: &mut Request,
+        configuration: Result<&Configuration, &ConfigurationError>,
+        flags: &FeatureFlags,
+    ) -> Response {
+        if !route.is_public() && !is_authenticated(req, self.secret.as_deref(), self.shield) {
+            return error_response(StatusCode::UNAUTHORIZED, "invalid admin secret");
+        }
+
+        let backends = match configuration {
+            Ok(configuration) => {
+                let mut backends = vec![configuration.backend_name.clone()];
+                backends.extend(configuration.failover_backend.clone());
+
+                backends
+            }
+            Err(error) => error.backend_name().into_iter().collect(),
+        };
+
+        match route {
+            Route::Health => health::handle(
+                &OutageTracker,
+                &BackendHealthTracker,
+                &backends,
+                self.clock,
+                self.kv_store,
+            ),
+            Route::ConfigCheck => config_check(configuration),
+            Route::Metrics => json_response(
+                StatusCode::OK,
+                &Metrics {
+                    agent_version: AGENT_VERSION,
+                    pop: std::env::var("FASTLY_POP").ok(),
+                    service_version: std::env::var("FASTLY_SERVICE_VERSION").ok(),
+                    api_outage: OutageTracker.state(self.kv_store),
+                    backends: backends
+                        .iter()
+                        .map(|backend| {
+                            (
+                                backend.clone(),
+                                BackendHealthTracker.state(backend, self.kv_store),
+                            )
+                        })
+                        .collect(),
+                },
+            ),
+            Route::Purge => self.purge(req),
+            Route::Flags => json_response(StatusCode::OK, flags.all()),
+        }
+    }
+
+    fn purge(&self, req: &Request) -> Response {
+        if req.get_method() != Method::POST {
+            return error_response(StatusCode::METHOD_NOT_ALLOWED, "purge with a POST request");
+        }
+
+        let url = req
+            .get_query_parameter("url")
+            .and_then(|url| Url::parse(url).ok());
+        let has_surrogate_keys = req.get_header("surrogate-key").is_some();
+
+        match url {
+            Some(url) => PurgeHandler::authenticated(self.fastly_logger).purge(req, &url),
+            None if has_surrogate_keys => {
+                PurgeHandler::authenticated(self.fastly_logger).purge(req, req.get_url())
+            }
+            None => error_response(
+                StatusCode::BAD_REQUEST,
+                "missing \"Surrogate-Key\" header or \"url\" query parameter",
+            ),
+        }
+    }
+}
+
+/// Whether the request carries the admin secret, or the signature of another node of the service.
+pub fn is_authenticated(req: &mut Request, secret: Option<&str>, shield: &Shield) -> bool {
+    let has_secret = match (req.get_header_str(ADMIN_SECRET_HEADER), secret) {
+        (Some(value), Some(secret)) => secure_compare(value, secret),
+        _ => false,
+    };
+
+    has_secret || shield.verify(req)
+}
+
+fn config_check(configuration: Result<&Configuration, &ConfigurationError>) -> Response {
+    match configuration {
+        Ok(configuration) => json_response(
+            StatusCode::OK,
+            &AdminResult {
+                status: "ok",
+                backend_name: Some(configuration.backend_name.clone()),
+                instance_name: Some(configuration.instance_name.clone()),
+                ..Default::default()
+            },
+        ),
+        Err(error) => json_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &AdminResult {
+                status: "error",
+                backend_name: error.backend_name(),
+                message: Some(error.to_string()),
+                ..Default::default()
+            },
+        ),
+    }
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    json_response(
+        status,
+        &AdminResult {
+            status: "error",
+            message: Some(message.to_string()),
+            ..Default::default()
+        },
+    )
+}
+
+fn json_response(status: StatusCode, body: &impl Serialize) -> Response {
+    let mut response = Response::from_status(status);
+    response.set_header("Cache-Control", "no-store");
+
+    if response.set_body_json(body).is_err() {
+        response.set_body_text_plain("error");
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rio::mock::MockClock;
+
+    #[test]
+    fn test_route_from_path() {
+        assert_eq!(
+            Some(Route::Health),
+            Route::from_path("/__redirectionio/health")
+        );
+        assert_eq!(
+            Some(Route::ConfigCheck),
+            Route::from_path("/__redirectionio/config-check")
+        );
+        assert_eq!(
+            Some(Route::Flags),
+            Route::from_path("/__redirectionio/flags")
+        );
+        assert_eq!(None, Route::from_path("/__redirectionio/unknown"));
+        assert_eq!(None, Route::from_path("/health"));
+        assert!(Route::Health.is_public());
+        assert!(!Route::Purge.is_public());
+    }
+
+    #[test]
+    fn test_is_authenticated() {
+        let clock = MockClock::new(1_000_000, 0);
+        let shield = Shield::new(None, &clock);
+        let mut req = Request::get("https://example.org/__redirectionio/flags")
+            .with_header(ADMIN_SECRET_HEADER, "secret");
+
+        assert!(is_authenticated(&mut req, Some("secret"), &shield));
+        assert!(!is_authenticated(&mut req, Some("other"), &shield));
+        assert!(!is_authenticated(&mut req, None, &shield));
+        assert!(!is_authenticated(
+            &mut Request::get("https://example.org/__redirectionio/flags"),
+            Some("secret"),
+            &shield
+        ));
+    }
+}
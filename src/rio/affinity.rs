@@ -0,0 +1,184 @@
+use super::cookies::cookie_value;
+use super::request_sender::RequestSender;
+use fastly::http::header;
+use fastly::http::request::SendError;
+use fastly::{Request, Response};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+pub const AFFINITY_COOKIE: &str = "redirectionio_backend";
+
+// Lifetime, in seconds, of the affinity cookie
+const AFFINITY_MAX_AGE: u64 = 86400;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Request sender spreading the requests of the main backend over several origins, according to
+/// the `backend_weights` entry.
+///
+/// With an affinity secret, the chosen origin is stored in a signed cookie, so the next requests
+/// of the same client reach the same origin.
+pub struct WeightedRequestSender<'a> {
+    backend_name: String,
+    backend_weights: Vec<(String, u32)>,
+    affinity_secret: Option<String>,
+    inner: &'a dyn RequestSender,
+}
+
+impl<'a> WeightedRequestSender<'a> {
+    pub(crate) fn new(
+        backend_name: String,
+        backend_weights: &HashMap<String, u32>,
+        affinity_secret: Option<String>,
+        inner: &'a dyn RequestSender,
+    ) -> WeightedRequestSender<'a> {
+        let mut backend_weights: Vec<(String, u32)> = backend_weights
+            .iter()
+            .filter(|(_, weight)| **weight > 0)
+            .map(|(backend, weight)| (backend.clone(), *weight))
+            .collect();
+        // Sort the backends so a draw always picks the same one
+        backend_weights.sort();
+
+        WeightedRequestSender {
+            backend_name,
+            backend_weights,
+            affinity_secret,
+            inner,
+        }
+    }
+
+    fn affinity_backend(&self, req: &Request) -> Option<String> {
+        let secret = self.affinity_secret.as_ref()?;
+        let value = req
+            .get_header_all_str(header::COOKIE)
+            .into_iter()
+            .find_map(|cookies| cookie_value(cookies, AFFINITY_COOKIE))?;
+        let backend = verify(secret, value)?;
+
+        self.backend_weights
+            .iter()
+            .find(|(name, _)| *name == backend)
+            .map(|(name, _)| name.clone())
+    }
+}
+
+impl<'a> RequestSender for WeightedRequestSender<'a> {
+    fn send(&self, req: Request, backend: String) -> Result<Response, SendError> {
+        if backend != self.backend_name || self.backend_weights.is_empty() {
+            return self.inner.send(req, backend);
+        }
+
+        if let Some(backend) = self.affinity_backend(&req) {
+            return self.inner.send(req, backend);
+        }
+
+        let draw = draw(req.get_client_request_id().unwrap_or_default());
+        let backend = pick_backend(&self.backend_weights, draw).to_string();
+        let mut response = self.inner.send(req, backend.clone())?;
+
+        if let Some(ref secret) = self.affinity_secret {
+            response.append_header(
+                header::SET_COOKIE,
+                format!(
+                    "{}={}; Path=/; Max-Age={}; Secure; HttpOnly; SameSite=Lax",
+                    AFFINITY_COOKIE,
+                    sign(secret, &backend),
+                    AFFINITY_MAX_AGE
+                ),
+            );
+        }
+
+        Ok(response)
+    }
+}
+
+/// Pick a backend for a draw between 0 and 1, each backend having a share of its weight.
+fn pick_backend(backend_weights: &[(String, u32)], draw: f64) -> &str {
+    let total: u64 = backend_weights
+        .iter()
+        .map(|(_, weight)| *weight as u64)
+        .sum();
+    let mut target = (draw * total as f64) as u64;
+
+    for (backend, weight) in backend_weights {
+        if target < *weight as u64 {
+            return backend;
+        }
+
+        target -= *weight as u64;
+    }
+
+    backend_weights
+        .last()
+        .map(|(backend, _)| backend.as_str())
+        .unwrap_or_default()
+}
+
+/// Draw a number between 0 and 1 from the unique identifier of the request.
+fn draw(request_id: &str) -> f64 {
+    let hash = Sha256::digest(request_id.as_bytes());
+
+    u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) as f64 / (u32::MAX as f64 + 1.0)
+}
+
+fn sign(secret: &str, backend: &str) -> String {
+    match create_mac(secret, backend) {
+        Some(mac) => format!("{}.{}", backend, hex::encode(mac.finalize().into_bytes())),
+        None => backend.to_string(),
+    }
+}
+
+/// Check the signature of an affinity cookie value, and return the backend it holds.
+fn verify<'v>(secret: &str, value: &'v str) -> Option<&'v str> {
+    let (backend, signature) = value.rsplit_once('.')?;
+    let signature = hex::decode(signature).ok()?;
+
+    create_mac(secret, backend)?
+        .verify_slice(&signature)
+        .ok()
+        .map(|_| backend)
+}
+
+fn create_mac(secret: &str, backend: &str) -> Option<HmacSha256> {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(backend.as_bytes());
+
+    Some(mac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend_weights() -> Vec<(String, u32)> {
+        vec![("legacy".to_string(), 90), ("next".to_string(), 10)]
+    }
+
+    #[test]
+    fn test_pick_backend() {
+        assert_eq!("legacy", pick_backend(&backend_weights(), 0.0));
+        assert_eq!("legacy", pick_backend(&backend_weights(), 0.89));
+        assert_eq!("next", pick_backend(&backend_weights(), 0.9));
+        assert_eq!("next", pick_backend(&backend_weights(), 0.999));
+    }
+
+    #[test]
+    fn test_draw() {
+        let draw = draw("request-id");
+
+        assert!((0.0..1.0).contains(&draw));
+        assert_eq!(draw, super::draw("request-id"));
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let value = sign("secret", "next");
+
+        assert_eq!(Some("next"), verify("secret", &value));
+        assert_eq!(None, verify("other secret", &value));
+        assert_eq!(None, verify("secret", &value.replace("next", "legacy")));
+        assert_eq!(None, verify("secret", "next"));
+    }
+}
@@ -0,0 +1,55 @@
+use fastly::Request;
+
+pub const DEFAULT_UNKNOWN_HOST_STATUS: u16 = 421;
+
+/// Check whether the host of the request is in the `allowed_hosts` entry.
+///
+/// All hosts are allowed when the list is empty. An item starting with `*.` allows all the
+/// subdomains of a domain.
+pub fn is_allowed_request(allowed_hosts: &[String], req: &Request) -> bool {
+    allowed_hosts.is_empty() || is_allowed_host(allowed_hosts, req.get_url().host_str())
+}
+
+pub(crate) fn is_allowed_host(allowed_hosts: &[String], host: Option<&str>) -> bool {
+    let host = match host {
+        Some(host) => host.trim_end_matches('.').to_lowercase(),
+        None => return false,
+    };
+
+    allowed_hosts.iter().any(|allowed_host| {
+        let allowed_host = allowed_host.to_lowercase();
+
+        match allowed_host.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|subdomain| subdomain.ends_with('.')),
+            None => host == allowed_host,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed_hosts() -> Vec<String> {
+        vec!["example.com".to_string(), "*.example.org".to_string()]
+    }
+
+    #[test]
+    fn test_exact_host() {
+        assert!(is_allowed_host(&allowed_hosts(), Some("example.com")));
+        assert!(is_allowed_host(&allowed_hosts(), Some("Example.COM.")));
+        assert!(!is_allowed_host(&allowed_hosts(), Some("www.example.com")));
+        assert!(!is_allowed_host(&allowed_hosts(), Some("151.101.1.1")));
+        assert!(!is_allowed_host(&allowed_hosts(), None));
+    }
+
+    #[test]
+    fn test_wildcard_host() {
+        assert!(is_allowed_host(&allowed_hosts(), Some("www.example.org")));
+        assert!(is_allowed_host(&allowed_hosts(), Some("a.b.example.org")));
+        assert!(!is_allowed_host(&allowed_hosts(), Some("example.org")));
+        assert!(!is_allowed_host(&allowed_hosts(), Some("badexample.org")));
+    }
+}
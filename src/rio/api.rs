@@ -0,0 +1,399 @@
+use super::background::BackgroundTasks;
+use super::backoff::parse_retry_after;
+use super::clock::Clock;
+use super::configuration::ApiEndpoint;
+use super::error::ApiError;
+use fastly::http::request::PendingRequest;
+use fastly::http::{header, StatusCode, Version};
+use fastly::Request;
+use flate2::read::GzDecoder;
+use std::cell::RefCell;
+use std::io::Read;
+
+// Internal stuff
+pub const AGENT_VERSION: &str = "dev";
+#[cfg(not(feature = "integration-test"))]
+const API_ENDPOINT: &str = "https://agent.redirection.io";
+#[cfg(feature = "integration-test")]
+const API_ENDPOINT: &str = "http://127.0.0.1:9091";
+const API_BACKEND: &str = "redirectionio";
+// Actions matching many rules are large, they are requested compressed
+const ACTION_ACCEPT_ENCODING: &str = "gzip, br";
+
+/// This trait abstracts the calls to the redirection.io API, so the application can be tested
+/// without reaching the network.
+pub trait ApiClient {
+    /// Send a serialized redirection.io request to the `action` endpoint, and return the body of
+    /// the response.
+    fn action(&self, rio_request_json: String) -> Result<String, ApiError>;
+
+    /// Send a serialized redirection.io request to the `action` endpoint without blocking the
+    /// request processing: `on_action` is called with the body of a successful response, at the
+    /// latest once the response has been sent to the client.
+    fn action_in_background(&self, rio_request_json: String, on_action: Box<dyn FnOnce(String)>) {
+        if let Ok(action_json) = self.action(rio_request_json) {
+            on_action(action_json);
+        }
+    }
+
+    /// Send a serialized log to the `log` endpoint.
+    fn log(&self, log_json: String) -> Result<(), ApiError>;
+
+    /// Send the serialized metrics of the rules to the `metrics` endpoint.
+    fn rule_metrics(&self, metrics_json: String) -> Result<(), ApiError>;
+}
+
+/// Default implementation sending requests to the redirection.io API.
+///
+/// Every endpoint goes through `request` and `wait`, so the headers and the error mapping are
+/// shared by all the calls.
+///
+/// The API endpoints are tried in order: when one of them can not be reached or answers with a
+/// server error, the `action` request is sent to the next one. Logs are not sent right away: they
+/// are pushed to the log buffer, which is flushed once the response has been sent to the client.
+pub struct FastlyApiClient<'a> {
+    token: String,
+    instance_name: String,
+    user_agent: String,
+    api_endpoints: Vec<ApiEndpoint>,
+    log_buffer: &'a LogBuffer,
+    background_tasks: &'a BackgroundTasks,
+    clock: &'a dyn Clock,
+}
+
+impl<'a> FastlyApiClient<'a> {
+    pub(crate) fn new(
+        token: String,
+        instance_name: String,
+        api_endpoints: &[ApiEndpoint],
+        log_buffer: &'a LogBuffer,
+        background_tasks: &'a BackgroundTasks,
+        clock: &'a dyn Clock,
+    ) -> FastlyApiClient<'a> {
+        let region = std::env::var("FASTLY_REGION").ok();
+
+        FastlyApiClient {
+            token,
+            instance_name,
+            user_agent: format!("fastly-worker/{}", AGENT_VERSION),
+            api_endpoints: sort_endpoints(api_endpoints, region.as_deref()),
+            log_buffer,
+            background_tasks,
+            clock,
+        }
+    }
+
+    /// Build a request to an endpoint of the API.
+    pub fn request(&self, api_endpoint: &ApiEndpoint, endpoint: &str, body: String) -> Request {
+        Request::post(format!("{}/{}/{}", api_endpoint.url, self.token, endpoint))
+            .with_header("User-Agent", self.user_agent.as_str())
+            .with_header("x-redirectionio-instance-name", self.instance_name.as_str())
+            .with_body(body)
+            .with_version(Version::HTTP_11)
+    }
+
+    /// Send a first-party analytics beacon to the `beacon` endpoint, with the logs.
+    pub fn beacon(&self, beacon_json: String) {
+        if let Some(api_endpoint) = self.api_endpoints.first() {
+            self.log_buffer.push(
+                self.request(api_endpoint, "beacon", beacon_json),
+                api_endpoint.backend.clone(),
+            );
+        }
+    }
+
+    /// Build a request to the `action` endpoint, accepting a compressed response.
+    fn action_request(&self, api_endpoint: &ApiEndpoint, rio_request_json: String) -> Request {
+        self.request(api_endpoint, "action", rio_request_json)
+            .with_header(header::ACCEPT_ENCODING, ACTION_ACCEPT_ENCODING)
+    }
+}
+
+impl<'a> ApiClient for FastlyApiClient<'a> {
+    fn action(&self, rio_request_json: String) -> Result<String, ApiError> {
+        let mut result = Err(ApiError::Send("no API endpoint".to_string()));
+
+        for api_endpoint in &self.api_endpoints {
+            result = self
+                .action_request(api_endpoint, rio_request_json.clone())
+                .send_async(api_endpoint.backend.as_str())
+                .map_err(|error| ApiError::Send(error.to_string()))
+                .and_then(|pending_request| wait(pending_request, self.clock.now_secs()));
+
+            match result {
+                Err(ApiError::Send(_)) => continue,
+                Err(ApiError::Status(status, _)) if status >= 500 => continue,
+                _ => return result,
+            }
+        }
+
+        result
+    }
+
+    fn action_in_background(&self, rio_request_json: String, on_action: Box<dyn FnOnce(String)>) {
+        let api_endpoint = match self.api_endpoints.first() {
+            Some(api_endpoint) => api_endpoint,
+            None => return,
+        };
+
+        let pending_request = match self
+            .action_request(api_endpoint, rio_request_json)
+            .send_async(api_endpoint.backend.as_str())
+        {
+            Ok(pending_request) => pending_request,
+            Err(_) => return,
+        };
+
+        let now = self.clock.now_secs();
+
+        self.background_tasks.push(Box::new(move || {
+            if let Ok(action_json) = wait(pending_request, now) {
+                on_action(action_json);
+            }
+        }));
+    }
+
+    fn log(&self, log_json: String) -> Result<(), ApiError> {
+        if let Some(api_endpoint) = self.api_endpoints.first() {
+            self.log_buffer.push(
+                self.request(api_endpoint, "log", log_json),
+                api_endpoint.backend.clone(),
+            );
+        }
+
+        Ok(())
+    }
+
+    fn rule_metrics(&self, metrics_json: String) -> Result<(), ApiError> {
+        if let Some(api_endpoint) = self.api_endpoints.first() {
+            self.log_buffer.push(
+                self.request(api_endpoint, "metrics", metrics_json),
+                api_endpoint.backend.clone(),
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Order the API endpoints by preference: the ones serving the region of the POP first, then the
+/// others in their configuration order. The default endpoint is used when none is configured.
+pub fn sort_endpoints(api_endpoints: &[ApiEndpoint], region: Option<&str>) -> Vec<ApiEndpoint> {
+    if api_endpoints.is_empty() {
+        return vec![ApiEndpoint {
+            url: API_ENDPOINT.to_string(),
+            backend: API_BACKEND.to_string(),
+            regions: Vec::new(),
+        }];
+    }
+
+    let serves_region = |api_endpoint: &ApiEndpoint| match region {
+        Some(region) => api_endpoint
+            .regions
+            .iter()
+            .any(|prefix| region.to_lowercase().starts_with(&prefix.to_lowercase())),
+        None => false,
+    };
+
+    let (mut sorted, others): (Vec<ApiEndpoint>, Vec<ApiEndpoint>) =
+        api_endpoints.iter().cloned().partition(serves_region);
+    sorted.extend(others);
+
+    sorted
+}
+
+/// Requests to the `log` endpoint waiting for the end of the request lifecycle.
+///
+/// They are not merged into a single request: the `log`, `metrics` and `beacon` endpoints take
+/// one document per request. The version of the Fastly SDK in use has no
+/// `send_async_without_waiting`, so the buffered requests are all sent before waiting for any of
+/// them, which only holds the instance once the client got its response.
+#[derive(Default)]
+pub struct LogBuffer {
+    requests: RefCell<Vec<(Request, String)>>,
+}
+
+impl LogBuffer {
+    pub fn push(&self, request: Request, backend: String) {
+        self.requests.borrow_mut().push((request, backend));
+    }
+
+    /// Send all the buffered logs at once, and return the errors of the failed ones.
+    pub fn flush(&self, clock: &dyn Clock) -> Vec<ApiError> {
+        let mut errors = Vec::new();
+        let mut pending_requests = Vec::new();
+
+        let now = clock.now_secs();
+
+        for (request, backend) in self.requests.borrow_mut().drain(..) {
+            match request.send_async(backend) {
+                Ok(pending_request) => pending_requests.push(pending_request),
+                Err(error) => errors.push(ApiError::Send(error.to_string())),
+            }
+        }
+
+        for pending_request in pending_requests {
+            if let Err(error) = wait(pending_request, now) {
+                errors.push(error);
+            }
+        }
+
+        errors
+    }
+}
+
+/// Wait for a pending API request, and return the body of a successful response. A date in the
+/// `Retry-After` header is compared to `now`, the time the request was sent.
+fn wait(pending_request: PendingRequest, now: u64) -> Result<String, ApiError> {
+    let mut response = pending_request
+        .wait()
+        .map_err(|error| ApiError::Send(error.to_string()))?;
+    let status = response.get_status();
+    let retry_after = response
+        .get_header_str(header::RETRY_AFTER)
+        .and_then(|retry_after| parse_retry_after(retry_after, now));
+
+    if is_rate_limited(status, retry_after.is_some()) {
+        return Err(ApiError::RateLimited(retry_after));
+    }
+
+    let content_encoding = response
+        .get_header_str(header::CONTENT_ENCODING)
+        .map(str::to_string);
+    let body = decode_body(content_encoding.as_deref(), response.take_body_bytes())?;
+
+    if !status.is_success() {
+        return Err(ApiError::Status(status.as_u16(), body));
+    }
+
+    Ok(body)
+}
+
+/// Whether the API asks the worker to slow down: a `429` status code, or a `503` with a
+/// `Retry-After` header. The header is ignored on other responses.
+pub fn is_rate_limited(status: StatusCode, has_retry_after: bool) -> bool {
+    match status {
+        StatusCode::TOO_MANY_REQUESTS => true,
+        StatusCode::SERVICE_UNAVAILABLE => has_retry_after,
+        _ => false,
+    }
+}
+
+/// Decompress the body of an API response according to its `Content-Encoding` header.
+pub fn decode_body(content_encoding: Option<&str>, bytes: Vec<u8>) -> Result<String, ApiError> {
+    let mut body = String::new();
+
+    let result = match content_encoding.map(|encoding| encoding.trim().to_lowercase()) {
+        Some(encoding) if encoding == "gzip" => GzDecoder::new(bytes.as_slice())
+            .read_to_string(&mut body)
+            .map(|_| ()),
+        Some(encoding) if encoding == "br" => brotli::Decompressor::new(bytes.as_slice(), 4096)
+            .read_to_string(&mut body)
+            .map(|_| ()),
+        Some(encoding) if encoding != "identity" => {
+            return Err(ApiError::Decompression(format!(
+                "unsupported encoding \"{}\"",
+                encoding
+            )))
+        }
+        _ => return Ok(String::from_utf8_lossy(&bytes).into_owned()),
+    };
+
+    result.map_err(|error| ApiError::Decompression(error.to_string()))?;
+
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_endpoint(backend: &str, regions: &[&str]) -> ApiEndpoint {
+        ApiEndpoint {
+            url: format!("https://{}.example.org", backend),
+            backend: backend.to_string(),
+            regions: regions.iter().map(|region| region.to_string()).collect(),
+        }
+    }
+
+    fn backends(api_endpoints: Vec<ApiEndpoint>) -> Vec<String> {
+        api_endpoints
+            .into_iter()
+            .map(|api_endpoint| api_endpoint.backend)
+            .collect()
+    }
+
+    #[test]
+    fn test_default_endpoint() {
+        let api_endpoints = sort_endpoints(&[], Some("EU-West"));
+
+        assert_eq!(1, api_endpoints.len());
+        assert_eq!(API_ENDPOINT, api_endpoints[0].url);
+        assert_eq!(API_BACKEND, api_endpoints[0].backend);
+    }
+
+    #[test]
+    fn test_sort_endpoints_by_region() {
+        let api_endpoints = [
+            api_endpoint("rio_us", &["US", "SouthAmerica"]),
+            api_endpoint("rio_eu", &["EU"]),
+        ];
+
+        assert_eq!(
+            vec!["rio_eu", "rio_us"],
+            backends(sort_endpoints(&api_endpoints, Some("EU-West")))
+        );
+        assert_eq!(
+            vec!["rio_us", "rio_eu"],
+            backends(sort_endpoints(&api_endpoints, Some("US-East")))
+        );
+        assert_eq!(
+            vec!["rio_us", "rio_eu"],
+            backends(sort_endpoints(&api_endpoints, Some("Asia")))
+        );
+        assert_eq!(
+            vec!["rio_us", "rio_eu"],
+            backends(sort_endpoints(&api_endpoints, None))
+        );
+    }
+
+    #[test]
+    fn test_is_rate_limited() {
+        assert!(is_rate_limited(StatusCode::TOO_MANY_REQUESTS, false));
+        assert!(is_rate_limited(StatusCode::SERVICE_UNAVAILABLE, true));
+        assert!(!is_rate_limited(StatusCode::SERVICE_UNAVAILABLE, false));
+        assert!(!is_rate_limited(StatusCode::OK, true));
+        assert!(!is_rate_limited(StatusCode::MOVED_PERMANENTLY, true));
+    }
+
+    #[test]
+    fn test_decode_body() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let action = r#"{"status_code_update":null}"#;
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(action.as_bytes()).unwrap();
+        let gzip = gzip.finish().unwrap();
+
+        let mut br = Vec::new();
+        {
+            let mut compressor = brotli::CompressorWriter::new(&mut br, 4096, 5, 22);
+            compressor.write_all(action.as_bytes()).unwrap();
+        }
+
+        assert_eq!(action, decode_body(Some("gzip"), gzip).unwrap());
+        assert_eq!(action, decode_body(Some("br"), br).unwrap());
+        assert_eq!(action, decode_body(None, action.into()).unwrap());
+        assert!(matches!(
+            decode_body(Some("gzip"), action.into()),
+            Err(ApiError::Decompression(_))
+        ));
+        assert!(matches!(
+            decode_body(Some("zstd"), action.into()),
+            Err(ApiError::Decompression(_))
+        ));
+    }
+}
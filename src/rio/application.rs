@@ -1,32 +1,130 @@
-use super::configuration::Configuration;
+use super::action_cache::ActionCache;
+use super::allowed_hosts::is_allowed_host;
+use super::api::{ApiClient, AGENT_VERSION};
+use super::backoff::Backoff;
+use super::cache_key::CacheKeyBuilder;
+use super::cache_policy::CachePolicy;
+use super::cache_state::CacheState;
+use super::client_hints::add_accept_ch;
+use super::clock::Clock;
+use super::configuration::{ApiErrorPolicy, Configuration};
+use super::cookies::cookie_headers;
+use super::error::{ApiError, ErrorKind, Phase, WorkerError};
+use super::esi::{forwarded_headers, is_esi_response, strip_esi_directive, EsiProcessor};
+use super::explain::Explain;
+use super::header_value;
+use super::hooks::WorkerHooks;
+use super::html_injection::{HtmlInjection, HtmlInjector};
+use super::image_optimizer::ImageOptimizer;
+use super::json_rewrite::{is_json_response, JsonRewriter};
+use super::kv_store::KvStore;
+use super::link_headers::{add_link_headers, LinkHeader};
 use super::logging::FastlyLogger;
+use super::origin_stamp::OriginStamp;
+use super::outage::OutageTracker;
+use super::range::{is_partial_response, strip_range};
 use super::request_sender::RequestSender;
+use super::response_headers::{keep_backend_headers, ResponseHeaderPolicy};
+use super::rule_metrics::RuleMetrics;
+use super::server_timing::ServerTiming;
+use super::soft_404::Soft404Detector;
+use super::synthetic;
+use super::url_normalization::UrlNormalization;
+use super::vary::add_vary_headers;
 
 use fastly::http::header;
 use fastly::http::Method;
-use fastly::http::Version;
-use fastly::{Error, Request, Response};
+use fastly::{Body, Request, Response};
 use redirectionio::action::Action;
 use redirectionio::api::Log;
-use redirectionio::http::{Header, Request as RedirectionioRequest};
+use redirectionio::filter::FilterBodyAction;
+use redirectionio::http::{Header, PathAndQueryWithSkipped, Request as RedirectionioRequest};
 use serde_json::from_str as json_decode;
 use serde_json::to_string as json_encode;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::str::FromStr;
+use std::time::Duration;
 
-// Internal stuff
-const AGENT_VERSION: &str = "dev";
-const API_ENDPOINT: &str = "https://agent.redirection.io";
+// How long an action is kept to be served when the API is unavailable
+const CACHED_ACTION_TTL: Duration = Duration::from_secs(86400);
+
+// Age after which a kept action is replaced by the next one received from the API
+const CACHED_ACTION_REFRESH: Duration = Duration::from_secs(300);
+
+// Body given to the filters of a rule to find out whether they replace the whole body
+const SYNTHETIC_BODY_MARKER: &[u8] = b"\0redirectionio-synthetic-body\0";
+
+const ACTION_TYPE_HEADER_NAME: &str = "X-RedirectionIo-Action-Type";
+const TARGET_HEADER_NAME: &str = "X-RedirectionIo-Target";
+const API_LATENCY_HEADER_NAME: &str = "X-RedirectionIo-Api-Latency";
+
+// Size of the chunks read from the backend response when filtering its body
+const BODY_CHUNK_SIZE: usize = 16 * 1024;
+
+// Headers describing the body of the backend response, sent back as is to HEAD requests
+const LENGTH_HEADERS: [&str; 3] = ["content-length", "content-range", "transfer-encoding"];
+
+// Requests sent to the API are limited in size: pathological header sets, like hundreds of headers
+// or huge cookies, are cut down
+const MAX_API_HEADERS: usize = 100;
+const MAX_API_HEADER_SIZE: usize = 4096;
+const TRUNCATED_MARKER: &str = "[truncated]";
 
 pub struct Application<'a> {
     backend_name: String,
-    token: String,
-    instance_name: String,
     add_rule_ids_header: bool,
+    rule_ids_header_name: String,
+    add_action_metadata_headers: bool,
+    api_latency: Cell<Option<u128>>,
+    server_timing: ServerTiming,
+    on_api_error: ApiErrorPolicy,
+    action_cache: ActionCache,
+    swr_action_cache: Option<ActionCache>,
+    refresh_action_cache: bool,
+    cache_key_builder: CacheKeyBuilder,
+    outage_tracker: OutageTracker,
+    backoff: Backoff,
+    image_optimizer: ImageOptimizer,
+    origin_stamp: OriginStamp,
+    vary_headers: Vec<String>,
+    max_vary_headers: usize,
+    cache_policies: HashMap<String, CachePolicy>,
+    link_headers: Vec<LinkHeader>,
+    client_hints: Vec<String>,
+    range_stripped_paths: Vec<String>,
+    response_header_policy: ResponseHeaderPolicy,
+    synthetic_cache_control: String,
+    api_request_headers: Vec<String>,
+    api_excluded_request_headers: Vec<String>,
+    match_cookies: bool,
+    minimal_match_payload: bool,
+    match_payload_headers: Vec<String>,
+    filterable_content_types: Vec<String>,
+    html_injections: Vec<HtmlInjection>,
+    esi_processor: Option<EsiProcessor>,
+    json_rewriter: Option<JsonRewriter>,
+    preserve_header_case: bool,
+    log_status_classes: Vec<u16>,
+    url_normalization: UrlNormalization,
+    soft_404_detector: Option<Soft404Detector>,
+    soft_404_status: bool,
+    is_soft_404: Cell<bool>,
+    cache_state: Cell<Option<CacheState>>,
+    rule_metrics: bool,
+    filter_duration: Cell<Option<Duration>>,
+    raw_path_and_query: RefCell<Option<String>>,
+    body_filtering: bool,
     agent_version: &'static str,
-    api_endpoint: &'static str,
+    explain: Option<&'a Explain>,
     fastly_logger: &'a FastlyLogger,
     request_manager: &'a dyn RequestSender,
+    hooks: &'a dyn WorkerHooks,
+    api_client: &'a dyn ApiClient,
+    clock: &'a dyn Clock,
+    kv_store: &'a dyn KvStore,
 }
 
 impl<'a> Application<'a> {
@@ -34,154 +132,529 @@ impl<'a> Application<'a> {
         configuration: &Configuration,
         fastly_logger: &'a FastlyLogger,
         request_sender: &'a dyn RequestSender,
+        hooks: &'a dyn WorkerHooks,
+        api_client: &'a dyn ApiClient,
+        clock: &'a dyn Clock,
+        kv_store: &'a dyn KvStore,
     ) -> Application<'a> {
         let backend_name = configuration.backend_name.clone();
-        let token = configuration.token.clone();
-        let instance_name = configuration.instance_name.clone();
         let add_rule_ids_header = configuration.add_rule_ids_header;
+        let rule_ids_header_name = configuration.rule_ids_header_name.clone();
+        let add_action_metadata_headers = configuration.add_action_metadata_headers;
+        let on_api_error = configuration.on_api_error;
+        // Actions become stale once they should be refreshed, and are still served until the end
+        // of their TTL
+        let action_cache = ActionCache::new(
+            "fallback",
+            configuration.token.clone(),
+            CACHED_ACTION_REFRESH,
+            CACHED_ACTION_TTL.saturating_sub(CACHED_ACTION_REFRESH),
+        );
+        let swr_action_cache = match configuration.action_cache_ttl {
+            0 => None,
+            ttl => Some(ActionCache::new(
+                "swr",
+                configuration.token.clone(),
+                Duration::from_secs(ttl),
+                Duration::from_secs(configuration.action_cache_stale_while_revalidate),
+            )),
+        };
+        let esi_processor = match configuration.esi {
+            true => Some(EsiProcessor::new(
+                backend_name.clone(),
+                configuration.esi_backends.clone(),
+            )),
+            false => None,
+        };
+        let image_optimizer = ImageOptimizer::new(configuration.image_optimizer_paths.clone());
+        let json_rewriter = match configuration.json_rewrites.is_empty() {
+            true => None,
+            false => Some(JsonRewriter::new(&configuration.json_rewrites)),
+        };
 
         return Application {
             backend_name,
-            token,
-            instance_name,
             add_rule_ids_header,
+            rule_ids_header_name,
+            add_action_metadata_headers,
+            api_latency: Cell::new(None),
+            server_timing: ServerTiming::new(configuration.server_timing),
+            on_api_error,
+            action_cache,
+            swr_action_cache,
+            refresh_action_cache: false,
+            cache_key_builder: configuration.cache_key_builder(),
+            outage_tracker: OutageTracker,
+            backoff: Backoff,
+            image_optimizer,
+            origin_stamp: OriginStamp::new(
+                configuration.origin_served_by,
+                &configuration.origin_headers,
+                configuration.origin_user_agent_suffix.clone(),
+            ),
+            vary_headers: configuration.vary_headers.clone(),
+            max_vary_headers: configuration.max_vary_headers,
+            cache_policies: configuration.cache_policies.clone(),
+            link_headers: configuration.link_headers.clone(),
+            client_hints: configuration.client_hints.clone(),
+            range_stripped_paths: configuration.range_stripped_paths.clone(),
+            response_header_policy: ResponseHeaderPolicy::new(
+                &configuration.stripped_response_headers,
+                &configuration.protected_response_headers,
+            ),
+            synthetic_cache_control: configuration.synthetic_cache_control.clone(),
+            api_request_headers: configuration.api_request_headers.clone(),
+            api_excluded_request_headers: configuration.api_excluded_request_headers.clone(),
+            match_cookies: configuration.match_cookies,
+            minimal_match_payload: configuration.minimal_match_payload,
+            match_payload_headers: configuration.match_payload_headers.clone(),
+            filterable_content_types: configuration.filterable_content_types.clone(),
+            html_injections: configuration.html_injections.clone(),
+            esi_processor,
+            json_rewriter,
+            preserve_header_case: configuration.preserve_header_case,
+            log_status_classes: configuration.log_status_classes.clone(),
+            url_normalization: configuration.url_normalization,
+            soft_404_detector: Soft404Detector::new(&configuration.soft_404_markers),
+            soft_404_status: configuration.soft_404_status,
+            is_soft_404: Cell::new(false),
+            cache_state: Cell::new(None),
+            rule_metrics: configuration.rule_metrics,
+            filter_duration: Cell::new(None),
+            raw_path_and_query: RefCell::new(None),
+            body_filtering: true,
+            explain: None,
             fastly_logger,
             request_manager: request_sender,
+            hooks,
+            api_client,
+            clock,
+            kv_store,
             agent_version: AGENT_VERSION,
-            api_endpoint: API_ENDPOINT,
         };
     }
 
-    pub fn create_rio_request(&self, req: &Request) -> Option<RedirectionioRequest> {
-        let mut rio_request = match RedirectionioRequest::from_str(req.get_url().as_str()) {
+    /// Enable or disable the body filters of the rules.
+    pub fn with_body_filtering(mut self, body_filtering: bool) -> Self {
+        self.body_filtering = body_filtering;
+        self
+    }
+
+    /// Skip the action cache lookup, so the action is fetched from the API and replaces the
+    /// cached one.
+    pub fn with_action_cache_refresh(mut self, refresh_action_cache: bool) -> Self {
+        self.refresh_action_cache = refresh_action_cache;
+        self
+    }
+
+    pub fn create_rio_request(&self, req: &Request) -> Result<RedirectionioRequest, WorkerError> {
+        let mut url = req.get_url().clone();
+        let path = self.url_normalization.normalize_path(url.path());
+
+        // Only the url used for matching is normalized, the raw one is kept for the logs
+        if path != url.path() {
+            *self.raw_path_and_query.borrow_mut() = Some(match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => url.path().to_string(),
+            });
+            url.set_path(&path);
+        }
+
+        let mut rio_request = match RedirectionioRequest::from_str(url.as_str()) {
             Ok(rio_request) => rio_request,
-            Err(_) => return None,
+            Err(_) => {
+                return Err(WorkerError::new(
+                    ErrorKind::InvalidUrl(req.get_url_str().to_string()),
+                    Phase::Request,
+                    req.get_url_str(),
+                ))
+            }
         };
 
         rio_request.method = Some(req.get_method().to_string());
         rio_request.remote_addr = req.get_client_ip_addr();
+        self.add_request_headers(&mut rio_request, req);
+
+        Ok(rio_request)
+    }
+
+    /// Add the request headers sent to the API, within its limits.
+    ///
+    /// When the `match_cookies` entry is `true`, each cookie is also exposed to the rules in its
+    /// own header, unless the `Cookie` header is not sent to the API.
+    fn add_request_headers(&self, rio_request: &mut RedirectionioRequest, req: &Request) {
+        let mut headers = Vec::new();
 
         for (name, value) in req.get_headers() {
             let header_name = name.to_string();
 
-            if header_name.starts_with(':') {
+            if header_name.starts_with(':')
+                || !is_sent_to_api(
+                    &header_name,
+                    &self.api_request_headers,
+                    &self.api_excluded_request_headers,
+                )
+            {
+                continue;
+            }
+
+            let value = header_value::decode(value);
+
+            if self.match_cookies && name == header::COOKIE {
+                headers.extend(cookie_headers(&value));
+            }
+
+            headers.push((header_name, value));
+        }
+
+        let mut header_count = 0;
+        let mut dropped_headers = 0;
+        let mut truncated_headers = 0;
+
+        for (header_name, mut value) in headers {
+            if header_count == MAX_API_HEADERS {
+                dropped_headers += 1;
                 continue;
             }
 
-            if let Ok(s) = value.to_str() {
-                rio_request.add_header(header_name, s.to_string(), true);
-            } else {
-                continue; // Invalid UTF-8
+            if truncate_header_value(&mut value) {
+                truncated_headers += 1;
             }
+
+            header_count += 1;
+            rio_request.add_header(header_name, value, true);
+        }
+
+        if dropped_headers > 0 || truncated_headers > 0 {
+            self.fastly_logger.log_warn(
+                "Request headers exceed the API limits, some are not sent.".to_string(),
+                Some(HashMap::from([
+                    ("dropped_headers", dropped_headers.to_string()),
+                    ("truncated_headers", truncated_headers.to_string()),
+                ])),
+            );
+        }
+    }
+
+    /// Record the decisions of the application in the `explain` record of the request.
+    pub fn with_explain(mut self, explain: &'a Explain) -> Self {
+        self.explain = Some(explain);
+        self
+    }
+
+    fn explain(&self, stage: &'static str, decision: impl Into<String>) {
+        if let Some(explain) = self.explain {
+            explain.record(stage, decision);
         }
+    }
 
-        Some(rio_request)
+    /// Record the duration of a step run before the application, for the `Server-Timing` header.
+    pub fn record_timing(&self, name: &'static str, duration: Duration) {
+        self.server_timing.record(name, duration);
     }
 
-    pub fn get_action(&self, rio_request: &RedirectionioRequest) -> Option<Action> {
-        // FIXME: add some cache // => not now
+    pub fn get_action(&self, rio_request: &RedirectionioRequest) -> Result<Action, WorkerError> {
+        self.server_timing
+            .measure("rio-match", self.clock, || self.match_action(rio_request))
+    }
+
+    fn match_action(&self, rio_request: &RedirectionioRequest) -> Result<Action, WorkerError> {
+        let url = rio_request_url(rio_request);
+        let rio_request = match self.minimal_match_payload {
+            true => Cow::Owned(minimal_rio_request(
+                rio_request,
+                &self.match_payload_headers,
+            )),
+            false => Cow::Borrowed(rio_request),
+        };
+        let rio_request = rio_request.as_ref();
         let json = match json_encode(&rio_request) {
             Ok(json) => json,
-            Err(error) => {
-                self.fastly_logger.log_error(
-                    format!(
-                        "Cannot get action from API. Cannot serialize redirection_io request: {}.",
-                        error,
-                    ),
-                    None,
-                );
-
-                return None;
+            Err(error) => return Err(WorkerError::new(error, Phase::Action, url)),
+        };
+        let cache_json = match self.cache_key_builder.filters_rio_requests() {
+            true => match json_encode(&self.cache_key_builder.rio_request(rio_request)) {
+                Ok(cache_json) => cache_json,
+                Err(error) => return Err(WorkerError::new(error, Phase::Action, url)),
+            },
+            false => json.clone(),
+        };
+
+        let swr_cache_key = match self.swr_action_cache {
+            Some(ref swr_action_cache) => {
+                let swr_cache_key = swr_action_cache.key(&cache_json);
+                let cached = match self.refresh_action_cache {
+                    true => None,
+                    false => swr_action_cache.lookup(&swr_cache_key),
+                };
+
+                if let Some((action, is_stale)) = cached {
+                    self.explain("action_cache", if is_stale { "stale" } else { "hit" });
+
+                    if is_stale {
+                        // Serve the stale action right away, and refresh it for the next requests
+                        let swr_action_cache = swr_action_cache.clone();
+                        let key = swr_cache_key.clone();
+
+                        self.api_client.action_in_background(
+                            json,
+                            Box::new(move |action_json| {
+                                swr_action_cache.set(&key, &action_json);
+                            }),
+                        );
+                    }
+
+                    return Ok(action);
+                }
+
+                match self.refresh_action_cache {
+                    true => self.explain("action_cache", "refresh"),
+                    false => self.explain("action_cache", "miss"),
+                }
+
+                Some(swr_cache_key)
             }
+            None => None,
         };
 
-        let response = Request::post(format!("{}/{}/action", self.api_endpoint, self.token))
-            .with_header(
-                "User-Agent",
-                format!("fastly-worker/{}", self.agent_version),
-            )
-            .with_header("x-redirectionio-instance-name", self.instance_name.clone())
-            .with_body(json)
-            .with_version(Version::HTTP_11)
-            .send("redirectionio");
+        let mut result = match self.backoff.deadline(self.clock) {
+            // The API asked to slow down: do not call it until the deadline
+            Some(deadline) => Err(ApiError::Backoff(deadline)),
+            None => self.fetch_action(&json),
+        };
 
-        let mut response = match response {
-            Ok(response) => response,
-            Err(error) => {
-                self.fastly_logger.log_error(
-                    format!(
-                        "Cannot get action from API. Cannot send redirection_io request: {}.",
-                        error,
-                    ),
-                    None,
-                );
-
-                return None;
+        let should_retry = match result {
+            Err(ref error) => {
+                error.is_transient() && self.on_api_error == ApiErrorPolicy::RetryOnce
             }
+            Ok(_) => false,
         };
 
-        if response.get_status() != 200 {
+        if should_retry {
+            self.explain("degradation", "retry");
+            result = self.fetch_action(&json);
+        }
+
+        if let Err(ApiError::Backoff(_)) = result {
+            self.explain("degradation", "api_backoff");
+        }
+
+        if let Err(ApiError::RateLimited(retry_after)) = result {
+            let deadline = self.backoff.record(retry_after, self.clock);
+
             self.fastly_logger.log_error(
-                format!(
-                    "Cannot get action from API. Returned status {}.",
-                    response.get_status(),
-                ),
-                Some(HashMap::from([
-                    ("status", response.get_status().to_string()),
-                    ("body", response.take_body_str()),
-                ])),
+                "redirection.io API rate limit reached, suspend API calls.".to_string(),
+                Some(HashMap::from([("backoff_until", deadline.to_string())])),
             );
+        }
+
+        match result {
+            Err(ApiError::Backoff(_)) => (),
+            Ok(_) => {
+                if let Some(outage) = self
+                    .outage_tracker
+                    .record_success(self.clock, self.kv_store)
+                {
+                    self.fastly_logger.log_info(
+                        "redirection.io API is available again.".to_string(),
+                        Some(HashMap::from([
+                            ("outage_since", outage.first_failure.to_string()),
+                            ("failures", outage.failures.to_string()),
+                        ])),
+                    );
+                }
+            }
+            Err(ref error) => {
+                if let Some(outage) = self
+                    .outage_tracker
+                    .record_failure(self.clock, self.kv_store)
+                {
+                    let mut context = HashMap::from([
+                        ("outage_since", outage.first_failure.to_string()),
+                        ("failures", outage.failures.to_string()),
+                    ]);
+
+                    match error {
+                        ApiError::Status(status, body) => {
+                            context.insert("status", status.to_string());
+                            context.insert("body", body.clone());
+                        }
+                        ApiError::Deserialization(_, body) => {
+                            context.insert("body", body.clone());
+                        }
+                        _ => (),
+                    }
+
+                    self.fastly_logger.log_error(
+                        format!("Cannot get action from API. {}.", error),
+                        Some(context),
+                    );
+                }
+            }
+        }
 
-            return None;
+        if let (Some(swr_action_cache), Some(swr_cache_key), Ok((_, body))) =
+            (&self.swr_action_cache, &swr_cache_key, &result)
+        {
+            swr_action_cache.set(swr_cache_key, body);
         }
 
-        match json_decode(&response.take_body().into_string()) {
-            Ok(action) => Some(action),
-            Err(error) => {
-                self.fastly_logger.log_error(
-                    format!("Cannot get action from API. Cannot deserialize redirection_io API response: {}.", error),
-                    Some(HashMap::from([
-                        ("status", response.get_status().to_string()),
-                        ("body", response.take_body_str()),
-                    ])),
-                );
-                None
+        if self.on_api_error != ApiErrorPolicy::ServeCachedAction {
+            return result
+                .map(|(action, _)| action)
+                .map_err(|error| WorkerError::new(error, Phase::Action, url));
+        }
+
+        let cache_key = self.action_cache.key(&cache_json);
+
+        match result {
+            Ok((action, body)) => {
+                // The cache is only written when its action is missing or stale, not on every
+                // request
+                let is_fresh = matches!(self.action_cache.lookup(&cache_key), Some((_, false)));
+
+                if !is_fresh {
+                    self.action_cache.set(&cache_key, &body);
+                }
+
+                Ok(action)
             }
+            Err(error) => match self.action_cache.get(&cache_key) {
+                Some(action) => {
+                    self.explain("degradation", "cached_action");
+                    self.fastly_logger
+                        .log_info("Serve cached action after API error.".to_string(), None);
+
+                    Ok(action)
+                }
+                None => Err(WorkerError::new(error, Phase::Action, url)),
+            },
+        }
+    }
+
+    fn fetch_action(&self, json: &str) -> Result<(Action, String), ApiError> {
+        let api_start_time = self.clock.elapsed();
+        let result = self.api_client.action(json.to_string());
+
+        self.api_latency.set(Some(
+            self.clock
+                .elapsed()
+                .saturating_sub(api_start_time)
+                .as_millis(),
+        ));
+
+        let body = result?;
+
+        match json_decode(&body) {
+            Ok(action) => Ok((action, body)),
+            Err(error) => Err(ApiError::Deserialization(error.to_string(), body)),
         }
     }
 
-    pub fn proxy(&self, req: Request, action: &mut Action) -> Result<(Response, u16), Error> {
+    pub fn proxy(
+        &self,
+        mut req: Request,
+        action: &mut Action,
+    ) -> Result<(Response, u16), WorkerError> {
         let status_code_before_response = action.get_status_code(0, None);
 
         let request_method = req.get_method().clone();
+        let is_head = request_method == Method::HEAD;
+        let path = req.get_path().to_string();
+        let page_url = req.get_url().clone();
+        let page_headers = match self.esi_processor {
+            Some(_) => forwarded_headers(&req),
+            None => Vec::new(),
+        };
+        let is_image_optimized = self.image_optimizer.matches(&req);
+
+        if is_image_optimized {
+            self.image_optimizer.enable(&mut req);
+        }
+
+        strip_range(&mut req, &self.range_stripped_paths);
 
         let mut response = if status_code_before_response == 0 {
-            self.request_manager.send(req, self.backend_name.clone())?
+            let url = req.get_url_str().to_string();
+
+            self.origin_stamp.apply(&mut req, self.agent_version);
+            self.hooks.before_backend(&mut req);
+
+            #[allow(clippy::result_large_err)]
+            let result = self.server_timing.measure("origin", self.clock, || {
+                self.request_manager.send(req, self.backend_name.clone())
+            });
+
+            match result {
+                Ok(mut response) => {
+                    // Read before the header filters, which may remove the cache headers
+                    self.cache_state.set(CacheState::from_response(&response));
+
+                    if let Some(backend) = response.get_backend_name() {
+                        self.explain("backend", backend);
+                    }
+
+                    if let Some(cache_state) = self.cache_state.get() {
+                        self.explain("cache_state", cache_state.as_str());
+                    }
+
+                    self.hooks.after_backend(&mut response);
+
+                    response
+                }
+                Err(error) => {
+                    // The backend can not be reached: answer with an error page, which the rules
+                    // still apply to, so the request is logged to redirection.io
+                    let error = WorkerError::new(error, Phase::Backend, url);
+                    self.explain("degradation", "backend_error_page");
+                    self.fastly_logger
+                        .log_error(error.to_string(), Some(error.context()));
+
+                    synthetic::response(
+                        error.status_code(),
+                        None,
+                        synthetic::error_page(error.status_code(), None),
+                        !is_head,
+                        &self.synthetic_cache_control,
+                    )
+                }
+            }
+        } else if let Some((body, content_type)) =
+            synthetic_body(action, status_code_before_response)
+        {
+            // The rule defines its own body, like a custom 410 page or a text answer
+            synthetic::response(
+                status_code_before_response,
+                content_type,
+                body,
+                !is_head,
+                &self.synthetic_cache_control,
+            )
         } else {
-            let mut r = Response::new();
-            r.set_status(status_code_before_response);
-            r.append_header(header::CONTENT_TYPE, "text/html; charset=UTF-8");
-            r.set_body(format!(
-                "
-<html>
-<head><title>{}</title></head>
-<body bgcolor=\"white\">
-<center><h1>{}</h1></center>
-</body>
-</html>
-<!-- a padding to disable MSIE and Chrome friendly error page -->
-<!-- a padding to disable MSIE and Chrome friendly error page -->
-<!-- a padding to disable MSIE and Chrome friendly error page -->
-<!-- a padding to disable MSIE and Chrome friendly error page -->
-<!-- a padding to disable MSIE and Chrome friendly error page -->
-<!-- a padding to disable MSIE and Chrome friendly error page -->
-<!-- a padding to disable MSIE and Chrome friendly error page -->
-",
-                &status_code_before_response, &status_code_before_response
-            ));
-            r
+            synthetic::response(
+                status_code_before_response,
+                None,
+                synthetic::error_page(status_code_before_response, None),
+                !is_head,
+                &self.synthetic_cache_control,
+            )
         };
 
+        if status_code_before_response == 0 && !is_head {
+            if let Some(ref soft_404_detector) = self.soft_404_detector {
+                if soft_404_detector.detect(&mut response) {
+                    self.is_soft_404.set(true);
+
+                    // Rules handling 404s apply to the page as well
+                    if self.soft_404_status {
+                        response.set_status(404);
+                    }
+                }
+            }
+        }
+
+        // Byte ranges of the backend response are sent back as is
+        let is_partial = status_code_before_response == 0 && is_partial_response(&response);
         let backend_status_code = response.get_status().as_u16();
         let status_code_after_response = action.get_status_code(backend_status_code, None);
 
@@ -190,63 +663,261 @@ impl<'a> Application<'a> {
         }
 
         let mut headers: Vec<Header> = vec![];
+        // Headers decoded from latin-1, to be sent back unchanged when no rule modified them
+        let mut latin1_headers: Vec<Header> = vec![];
 
         for name in response.get_header_names() {
-            match response.get_header(name) {
-                Some(value) => {
-                    if let Ok(s) = value.to_str() {
-                        headers.push(Header {
-                            name: name.to_string(),
-                            value: s.to_string(),
-                        });
-                    } else {
-                        continue; // Invalid UTF-8
-                    }
+            for value in response.get_header_all(name) {
+                let header = Header {
+                    name: name.to_string(),
+                    value: header_value::decode(value),
+                };
+
+                if header_value::is_latin1(value) {
+                    latin1_headers.push(header.clone());
                 }
-                None => continue,
+
+                headers.push(header);
             }
         }
 
-        let headers =
-            action.filter_headers(headers, backend_status_code, self.add_rule_ids_header, None);
+        let backend_headers = headers.clone();
+        let mut headers = self.filter_headers(
+            action,
+            headers,
+            backend_status_code,
+            status_code_before_response,
+            status_code_after_response,
+        );
+
+        let action_type = action_type(
+            backend_status_code,
+            status_code_before_response,
+            status_code_after_response,
+        );
+        self.explain("action_type", action_type);
 
-        for header in &headers {
-            response.set_header(header.name.clone(), header.value.clone());
+        if !action.get_applied_rule_ids().is_empty() {
+            let rule_ids: Vec<String> = action.get_applied_rule_ids().iter().cloned().collect();
+            self.explain("rule_ids", rule_ids.join(";"));
         }
 
-        match response.get_header(header::CONTENT_TYPE) {
-            Some(content_type_value)
-                if content_type_value
-                    .to_str()
-                    .unwrap()
-                    .to_lowercase()
-                    .contains("utf-8") =>
-            {
-                ()
-            }
-            _ => return Ok((response, backend_status_code)),
+        if let Some(cache_policy) = self.cache_policies.get(action_type) {
+            cache_policy.apply(&backend_headers, &mut headers);
         }
 
-        if request_method != &Method::HEAD {
-            match action.create_filter_body(backend_status_code, &headers) {
-                Some(mut body_filter) => {
-                    let mut new_response = response.clone_without_body();
-                    let body = response.into_body().into_bytes();
-                    let mut new_body = Vec::new();
+        add_link_headers(&mut headers, &self.link_headers, &path);
+        add_accept_ch(&mut headers, &self.client_hints);
 
-                    new_body.extend(body_filter.filter(body, None));
-                    new_body.extend(body_filter.end(None));
-                    new_response.set_body(new_body);
+        self.response_header_policy
+            .apply(&backend_headers, &mut headers);
 
-                    response = new_response;
-                }
-                None => (),
+        // There is no body to match the length headers of a HEAD response, and partial contents
+        // are not filtered
+        if is_head || is_partial {
+            keep_backend_headers(&backend_headers, &mut headers, &LENGTH_HEADERS);
+        }
+
+        apply_headers(
+            &mut response,
+            &backend_headers,
+            &headers,
+            &latin1_headers,
+            self.preserve_header_case,
+        );
+
+        let content_type = response
+            .get_header(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        let is_filterable =
+            is_filterable_content_type(content_type, &self.filterable_content_types);
+        // JSON responses are rewritten whatever their charset, as they are parsed
+        let json_rewriter = self
+            .json_rewriter
+            .as_ref()
+            .filter(|_| self.body_filtering && is_json_response(&headers));
+
+        if !is_filterable && json_rewriter.is_none() {
+            self.server_timing.add_header(&mut response);
+
+            return Ok((response, backend_status_code));
+        }
+
+        // The body of a HEAD response is never read, nor filtered as partial contents
+        if !is_head && !is_partial && !is_image_optimized {
+            let mut body_filter = match self.body_filtering && is_filterable {
+                true => action.create_filter_body(backend_status_code, &headers),
+                false => None,
             };
+            let mut html_injector = match is_filterable {
+                true => HtmlInjector::new(&self.html_injections, &headers),
+                false => None,
+            };
+            let esi_processor = self
+                .esi_processor
+                .as_ref()
+                .filter(|_| is_filterable && is_esi_response(&headers));
+            let is_buffered = esi_processor.is_some() || json_rewriter.is_some();
+
+            let filters = [
+                ("body_filter", body_filter.is_some()),
+                ("html_injection", html_injector.is_some()),
+                ("esi", esi_processor.is_some()),
+                ("json_rewrite", json_rewriter.is_some()),
+            ];
+
+            for (filter, _) in filters.iter().filter(|(_, is_applied)| *is_applied) {
+                self.explain("filters", *filter);
+            }
+
+            if body_filter.is_some() || html_injector.is_some() || is_buffered {
+                let filter_start = self.clock.elapsed();
+
+                let result = self.server_timing.measure("body-filter", self.clock, || {
+                    let mut body = response.take_body();
+                    let mut filtered_body = Body::new();
+                    let mut buffered_body = Vec::new();
+                    let mut read_error = None;
+                    let chunks = body
+                        .read_chunks(BODY_CHUNK_SIZE)
+                        .map_while(|chunk| chunk.map_err(|error| read_error = Some(error)).ok());
+
+                    // ESI and JSON rewriting need the whole body, other filters stream it
+                    let mut write_body = |chunk: &[u8]| match is_buffered {
+                        true => buffered_body.extend_from_slice(chunk),
+                        false => {
+                            filtered_body.write_bytes(chunk);
+                        }
+                    };
+
+                    // Snippets are injected in the body already filtered by the rules
+                    let mut write = |chunk: &[u8]| match html_injector {
+                        Some(ref mut html_injector) => write_body(&html_injector.filter(chunk)),
+                        None => write_body(chunk),
+                    };
+
+                    match body_filter {
+                        Some(ref mut body_filter) => filter_body(body_filter, chunks, &mut write),
+                        None => chunks.for_each(|chunk| write(&chunk)),
+                    }
+
+                    // A partial body would be sent as a complete page
+                    if let Some(error) = read_error {
+                        return Err(error);
+                    }
+
+                    if let Some(html_injector) = html_injector {
+                        write_body(&html_injector.end());
+                    }
+
+                    if let Some(esi_processor) = esi_processor {
+                        let page = esi_processor.process(
+                            &page_url,
+                            &page_headers,
+                            &String::from_utf8_lossy(&buffered_body),
+                            self.fastly_logger,
+                        );
+
+                        filtered_body.write_str(&page);
+                        strip_esi_header(&mut response);
+                    } else if let Some(json_rewriter) = json_rewriter {
+                        let rewritten = std::str::from_utf8(&buffered_body)
+                            .ok()
+                            .and_then(|body| json_rewriter.rewrite(body));
+
+                        match rewritten {
+                            Some(rewritten) => filtered_body.write_str(&rewritten),
+                            None => filtered_body.write_bytes(&buffered_body),
+                        };
+                    }
+
+                    response.set_body(filtered_body);
+
+                    Ok(())
+                });
+
+                self.filter_duration
+                    .set(Some(self.clock.elapsed().saturating_sub(filter_start)));
+
+                if let Err(error) = result {
+                    return Err(WorkerError::new(error, Phase::Proxy, page_url));
+                }
+            }
         }
 
+        self.server_timing.add_header(&mut response);
+
         Ok((response, backend_status_code))
     }
 
+    /// Apply the header filters of the action to the response headers, and add the worker
+    /// headers (vary, rule ids and action metadata) when enabled.
+    pub fn filter_headers(
+        &self,
+        action: &mut Action,
+        headers: Vec<Header>,
+        backend_status_code: u16,
+        status_code_before_response: u16,
+        status_code_after_response: u16,
+    ) -> Vec<Header> {
+        let mut headers = action.filter_headers(headers, backend_status_code, false, None);
+
+        add_vary_headers(&mut headers, &self.vary_headers, self.max_vary_headers);
+
+        if self.add_rule_ids_header {
+            headers.push(Header {
+                name: self.rule_ids_header_name.clone(),
+                value: action
+                    .get_applied_rule_ids()
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<String>>()
+                    .join(";"),
+            });
+        }
+
+        if self.add_action_metadata_headers {
+            self.add_action_metadata(
+                &mut headers,
+                action_type(
+                    backend_status_code,
+                    status_code_before_response,
+                    status_code_after_response,
+                ),
+            );
+        }
+
+        headers
+    }
+
+    fn add_action_metadata(&self, headers: &mut Vec<Header>, action_type: &str) {
+        headers.push(Header {
+            name: ACTION_TYPE_HEADER_NAME.to_string(),
+            value: action_type.to_string(),
+        });
+
+        if action_type == "redirect" {
+            let target = headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case("location"))
+                .map(|header| header.value.clone());
+
+            if let Some(target) = target {
+                headers.push(Header {
+                    name: TARGET_HEADER_NAME.to_string(),
+                    value: target,
+                });
+            }
+        }
+
+        if let Some(api_latency) = self.api_latency.get() {
+            headers.push(Header {
+                name: API_LATENCY_HEADER_NAME.to_string(),
+                value: api_latency.to_string(),
+            });
+        }
+    }
+
     pub fn log(
         &self,
         response: &Response,
@@ -254,28 +925,27 @@ impl<'a> Application<'a> {
         rio_request: &RedirectionioRequest,
         action: &mut Action,
         start_time: u128,
-    ) {
+    ) -> Result<(), WorkerError> {
         if !action.should_log_request(true, backend_status_code, None) {
-            return;
+            return Ok(());
         }
 
-        let mut response_headers: Vec<Header> = vec![];
-        for name in response.get_header_names() {
-            match response.get_header(name) {
-                Some(value) => {
-                    if let Ok(s) = value.to_str() {
-                        response_headers.push(Header {
-                            name: name.to_string(),
-                            value: s.to_string(),
-                        });
-                    } else {
-                        continue; // Invalid UTF-8
-                    }
-                }
-                None => continue,
-            }
+        let is_matched = !action.get_applied_rule_ids().is_empty();
+
+        if !should_log_status(&self.log_status_classes, backend_status_code, is_matched) {
+            return Ok(());
         }
 
+        let response_headers = log_headers(response);
+        let raw_request;
+        let rio_request = match self.raw_path_and_query.borrow().as_ref() {
+            Some(raw_path_and_query) => {
+                raw_request = with_path_and_query(rio_request, raw_path_and_query);
+                &raw_request
+            }
+            None => rio_request,
+        };
+
         let log = Log::from_proxy(
             rio_request,
             response.get_status().as_u16(),
@@ -291,29 +961,868 @@ impl<'a> Application<'a> {
             None,
         );
 
+        let mut log = match serde_json::to_value(&log) {
+            Err(error) => {
+                return Err(WorkerError::new(
+                    error,
+                    Phase::Log,
+                    rio_request_url(rio_request),
+                ))
+            }
+            Ok(log) => log,
+        };
+
+        if self.is_soft_404.get() {
+            log["soft_404"] = Value::Bool(true);
+        }
+
+        // Rule impact analysis tells cached responses apart from the ones served by the backend
+        if let Some(cache_state) = self.cache_state.get() {
+            log["cache_state"] = Value::String(cache_state.as_str().to_string());
+        }
+
         let json = match json_encode(&log) {
-            Err(_) => return,
+            Err(error) => {
+                return Err(WorkerError::new(
+                    error,
+                    Phase::Log,
+                    rio_request_url(rio_request),
+                ))
+            }
             Ok(s) => s,
         };
 
-        let result = Request::post(format!("{}/{}/log", self.api_endpoint, self.token))
-            .with_header(
-                "User-Agent",
-                format!("fastly-worker/{}", self.agent_version),
+        match self.api_client.log(json) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(WorkerError::new(
+                error,
+                Phase::Log,
+                rio_request_url(rio_request),
+            )),
+        }
+    }
+
+    /// Add the rules applied to the request to the metrics of the current window, and send them
+    /// to the API once the window is over, when the `rule_metrics` entry is `true`.
+    pub fn report_rule_metrics(&self, action: &Action) -> Result<(), ApiError> {
+        if !self.rule_metrics || action.get_applied_rule_ids().is_empty() {
+            return Ok(());
+        }
+
+        let rule_ids: Vec<String> = action.get_applied_rule_ids().iter().cloned().collect();
+        let report = RuleMetrics.record(
+            &rule_ids,
+            self.filter_duration.get(),
+            self.agent_version,
+            self.clock,
+            self.kv_store,
+        );
+
+        let report = match report {
+            Some(report) => report,
+            None => return Ok(()),
+        };
+
+        match json_encode(&report) {
+            Ok(json) => self.api_client.rule_metrics(json),
+            Err(error) => Err(ApiError::Send(error.to_string())),
+        }
+    }
+}
+
+/// Run the chunks of a body through a body filter, passing each filtered chunk to `write` as soon
+/// as it is available.
+pub fn filter_body<I, W>(body_filter: &mut FilterBodyAction, chunks: I, mut write: W)
+where
+    I: IntoIterator<Item = Vec<u8>>,
+    W: FnMut(&[u8]),
+{
+    for chunk in chunks {
+        let filtered_chunk = body_filter.filter(chunk, None);
+
+        if !filtered_chunk.is_empty() {
+            write(&filtered_chunk);
+        }
+    }
+
+    let last_chunk = body_filter.end(None);
+
+    if !last_chunk.is_empty() {
+        write(&last_chunk);
+    }
+}
+
+/// Replace the response headers by the filtered ones.
+///
+/// Headers are applied per name: all the values of a name are removed, then each filtered value
+/// is appended, so multiple values (`Set-Cookie`, `Vary`) survive. Backend headers removed by the
+/// rules are removed from the response.
+pub fn apply_headers(
+    response: &mut Response,
+    backend_headers: &[Header],
+    headers: &[Header],
+    latin1_headers: &[Header],
+    preserve_header_case: bool,
+) {
+    for backend_header in backend_headers {
+        let is_removed = !headers
+            .iter()
+            .any(|header| header.name.eq_ignore_ascii_case(&backend_header.name));
+
+        if is_removed {
+            response.remove_header(backend_header.name.as_str());
+        }
+    }
+
+    let mut names: Vec<&str> = vec![];
+
+    for header in headers {
+        if !names
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(&header.name))
+        {
+            names.push(&header.name);
+        }
+    }
+
+    for name in names {
+        let values = |headers: &[Header]| {
+            headers
+                .iter()
+                .filter(|header| header.name.eq_ignore_ascii_case(name))
+                .map(|header| header.value.clone())
+                .collect::<Vec<String>>()
+        };
+        let filtered_values = values(headers);
+
+        // Untouched backend headers are left as received, with their original name casing
+        if preserve_header_case && filtered_values == values(backend_headers) {
+            continue;
+        }
+
+        response.remove_header(name);
+
+        for value in filtered_values {
+            let is_unchanged_latin1 = latin1_headers.iter().any(|latin1_header| {
+                latin1_header.name.eq_ignore_ascii_case(name) && latin1_header.value == value
+            });
+
+            match header_value::latin1_encode(&value) {
+                Some(latin1_value) if is_unchanged_latin1 => {
+                    response.append_header(name, latin1_value)
+                }
+                _ => response.append_header(name, value),
+            }
+        }
+    }
+}
+
+/// Collect the response headers sent in logs, with one entry per value, so headers like
+/// `Set-Cookie` or `Vary` are logged in full.
+pub fn log_headers(response: &Response) -> Vec<Header> {
+    let mut headers = vec![];
+
+    for name in response.get_header_names() {
+        for value in response.get_header_all(name) {
+            headers.push(Header {
+                name: name.to_string(),
+                value: header_value::decode(value),
+            });
+        }
+    }
+
+    headers
+}
+
+/// Copy a rio request with another path and query, like the raw one of a normalized request.
+fn with_path_and_query(
+    rio_request: &RedirectionioRequest,
+    path_and_query: &str,
+) -> RedirectionioRequest {
+    let mut rio_request = rio_request.clone();
+    rio_request.path_and_query_skipped = PathAndQueryWithSkipped::from_static(path_and_query);
+    rio_request.path_and_query = Some(path_and_query.to_string());
+
+    rio_request
+}
+
+fn strip_esi_header(response: &mut Response) {
+    let surrogate_control = match response.get_header_str("Surrogate-Control") {
+        Some(surrogate_control) => strip_esi_directive(surrogate_control),
+        None => return,
+    };
+
+    match surrogate_control.is_empty() {
+        true => {
+            response.remove_header("Surrogate-Control");
+        }
+        false => response.set_header("Surrogate-Control", surrogate_control),
+    }
+}
+
+/// Whether a request is logged, given the status classes to log: requests matching a rule are
+/// always logged, and all requests are logged when no class is configured.
+fn should_log_status(
+    log_status_classes: &[u16],
+    backend_status_code: u16,
+    is_matched: bool,
+) -> bool {
+    is_matched
+        || log_status_classes.is_empty()
+        || log_status_classes.contains(&(backend_status_code / 100))
+}
+
+/// Body and content type defined by the action for a synthetic response: the body of the text
+/// filters replacing the whole body, and the content type set by the header filters.
+fn synthetic_body(action: &Action, status_code: u16) -> Option<(String, Option<String>)> {
+    // Applying the filters records the rules they belong to, the response applies them itself
+    let mut action = action.clone();
+    let headers = action.filter_headers(Vec::new(), status_code, false, None);
+    let content_type = headers
+        .iter()
+        .rev()
+        .find(|header| header.name.eq_ignore_ascii_case("content-type"))
+        .map(|header| header.value.clone());
+
+    let mut body_filter = action.create_filter_body(status_code, &headers)?;
+    let mut body = body_filter.filter(SYNTHETIC_BODY_MARKER.to_vec(), None);
+    body.extend(body_filter.end(None));
+
+    // Appended or prepended texts keep the body they are given: only a replaced body drops it
+    if body
+        .windows(SYNTHETIC_BODY_MARKER.len())
+        .any(|window| window == SYNTHETIC_BODY_MARKER)
+    {
+        return None;
+    }
+
+    String::from_utf8(body)
+        .ok()
+        .map(|body| (body, content_type))
+}
+
+/// Whether the body filters are disabled for the request by the `disable_body_filter` entry, which
+/// lists hosts (with `*.` wildcards), path prefixes starting with `/`, or both, like
+/// `example.com/templates/`.
+pub fn is_body_filter_disabled(disable_body_filter: &[String], req: &Request) -> bool {
+    disable_body_filter
+        .iter()
+        .any(|item| matches_host_and_path(item, req.get_url().host_str(), req.get_path()))
+}
+
+fn matches_host_and_path(item: &str, host: Option<&str>, path: &str) -> bool {
+    let (item_host, item_path) = match item.find('/') {
+        Some(index) => item.split_at(index),
+        None => (item, ""),
+    };
+
+    (item_host.is_empty() || is_allowed_host(&[item_host.to_string()], host))
+        && path.starts_with(item_path)
+}
+
+/// Whether the body of a response can be filtered: its media type must be one of the
+/// `filterable_content_types` entry when set, with no charset other than UTF-8, otherwise the
+/// content type must be declared as UTF-8.
+fn is_filterable_content_type(content_type: Option<&str>, filterable: &[String]) -> bool {
+    let content_type = match content_type {
+        Some(content_type) => content_type.to_lowercase(),
+        None => return false,
+    };
+
+    if filterable.is_empty() {
+        return content_type.contains("utf-8");
+    }
+
+    let mut parameters = content_type.split(';');
+    let media_type = parameters.next().unwrap_or_default().trim();
+    let is_utf8 = parameters
+        .filter_map(|parameter| parameter.trim().strip_prefix("charset="))
+        .all(|charset| matches!(charset.trim_matches('"'), "utf-8" | "us-ascii"));
+
+    is_utf8
+        && filterable
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(media_type))
+}
+
+/// Cut a header value longer than the API limit, ending it with a marker. Returns whether the value
+/// was truncated.
+fn truncate_header_value(value: &mut String) -> bool {
+    if value.len() <= MAX_API_HEADER_SIZE {
+        return false;
+    }
+
+    let mut end = MAX_API_HEADER_SIZE - TRUNCATED_MARKER.len();
+
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    value.truncate(end);
+    value.push_str(TRUNCATED_MARKER);
+
+    true
+}
+
+/// Whether a request header is serialized in the requests to the API: only the headers of the
+/// `api_request_headers` entry when it is set, and never the ones of the
+/// `api_excluded_request_headers` entry.
+fn is_sent_to_api(name: &str, allowed: &[String], excluded: &[String]) -> bool {
+    let is_listed = |names: &[String]| names.iter().any(|n| n.eq_ignore_ascii_case(name));
+
+    (allowed.is_empty() || is_listed(allowed)) && !is_listed(excluded)
+}
+
+/// Reduce a redirection.io request to the fields rules need: the method, the url, and the headers of
+/// the `match_payload_headers` entry.
+fn minimal_rio_request(
+    rio_request: &RedirectionioRequest,
+    match_payload_headers: &[String],
+) -> RedirectionioRequest {
+    let mut minimal = rio_request.clone();
+    minimal.headers.retain(|header| {
+        match_payload_headers
+            .iter()
+            .any(|name| header.name.eq_ignore_ascii_case(name))
+    });
+    minimal.remote_addr = None;
+    minimal.created_at = None;
+
+    minimal
+}
+
+/// Describe what the action did to the response: `proxy`, `redirect`, `synthetic` or
+/// `status_override`.
+fn action_type(
+    backend_status_code: u16,
+    status_code_before_response: u16,
+    status_code_after_response: u16,
+) -> &'static str {
+    let final_status_code = if status_code_after_response != 0 {
+        status_code_after_response
+    } else {
+        backend_status_code
+    };
+
+    if status_code_before_response == 0 && status_code_after_response == 0 {
+        "proxy"
+    } else if (300..400).contains(&final_status_code) {
+        "redirect"
+    } else if status_code_before_response != 0 {
+        "synthetic"
+    } else {
+        "status_override"
+    }
+}
+
+fn rio_request_url(rio_request: &RedirectionioRequest) -> String {
+    format!(
+        "{}://{}{}",
+        rio_request.scheme().unwrap_or("http"),
+        rio_request.host().unwrap_or_default(),
+        rio_request.path_and_query()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rio::hooks::NoHooks;
+    use crate::rio::logging::Context;
+    use crate::rio::mock::{MockApiClient, MockClock, MockKvStore, MockLogSink, MockRequestSender};
+
+    const REDIRECT_ACTION: &str = r#"{
+        "status_code_update": {
+            "status_code": 301,
+            "on_response_status_codes": [],
+            "exclude_response_status_codes": false,
+            "fallback_status_code": 0,
+            "rule_id": "rule-1"
+        },
+        "header_filters": [{
+            "filter": {"action": "override", "header": "Location", "value": "/target"},
+            "on_response_status_codes": [],
+            "exclude_response_status_codes": false,
+            "rule_id": "rule-1"
+        }],
+        "body_filters": [],
+        "rule_ids": ["rule-1"]
+    }"#;
+
+    const BODY_ACTION: &str = r#"{
+        "status_code_update": null,
+        "header_filters": [],
+        "body_filters": [{
+            "filter": {"action": "append_text", "content": " world"},
+            "on_response_status_codes": [],
+            "exclude_response_status_codes": false,
+            "rule_id": "rule-2"
+        }],
+        "rule_ids": ["rule-2"]
+    }"#;
+
+    const GONE_ACTION: &str = r#"{
+        "status_code_update": {
+            "status_code": 410,
+            "on_response_status_codes": [],
+            "exclude_response_status_codes": false,
+            "fallback_status_code": 0,
+            "rule_id": "rule-3"
+        },
+        "header_filters": [{
+            "filter": {"action": "override", "header": "Content-Type", "value": "text/plain"},
+            "on_response_status_codes": [],
+            "exclude_response_status_codes": false,
+            "rule_id": "rule-3"
+        }],
+        "body_filters": [{
+            "filter": {"action": "replace_text", "content": "Gone"},
+            "on_response_status_codes": [410],
+            "exclude_response_status_codes": false,
+            "rule_id": "rule-3"
+        }],
+        "rule_ids": ["rule-3"]
+    }"#;
+
+    fn create_configuration(values: &[(&str, &str)]) -> Configuration {
+        let mut all_values = HashMap::from([
+            ("backend_name", "origin"),
+            ("token", "my-token"),
+            ("instance_name", "my-instance"),
+            ("add_rule_ids_header", "false"),
+        ]);
+        all_values.extend(values.iter().cloned());
+
+        Configuration::new(|key| all_values.get(key).map(|value| value.to_string())).unwrap()
+    }
+
+    fn create_logger() -> FastlyLogger {
+        FastlyLogger::new(
+            None,
+            None,
+            None,
+            Context::new(&Request::get("https://example.org/")),
+            Box::new(MockClock::new(0, 0)),
+            Box::new(MockKvStore::default()),
+            Box::new(MockLogSink::default()),
+        )
+    }
+
+    /// Mock boundaries of an application, answering the action requests with the given responses
+    /// after 25ms.
+    struct Boundaries {
+        logger: FastlyLogger,
+        sender: MockRequestSender,
+        api_client: MockApiClient,
+        clock: MockClock,
+        kv_store: MockKvStore,
+    }
+
+    impl Boundaries {
+        fn new(action_responses: Vec<Result<String, ApiError>>) -> Boundaries {
+            Boundaries {
+                logger: create_logger(),
+                sender: MockRequestSender::new(200),
+                api_client: MockApiClient::new(action_responses),
+                clock: MockClock::new(1000, 25),
+                kv_store: MockKvStore::default(),
+            }
+        }
+
+        fn application<'a>(&'a self, configuration: &'a Configuration) -> Application<'a> {
+            Application::new(
+                configuration,
+                &self.logger,
+                &self.sender,
+                &NoHooks,
+                &self.api_client,
+                &self.clock,
+                &self.kv_store,
             )
-            .with_header("x-redirectionio-instance-name", self.instance_name.clone())
-            .with_body(json)
-            .with_version(Version::HTTP_11)
-            .send("redirectionio");
+        }
+    }
 
-        if result.is_err() {
-            self.fastly_logger.log_error(
-                format!(
-                    "Can not send \"log\" request to redirection.io: {}.",
-                    result.err().unwrap()
-                ),
-                None,
+    #[test]
+    fn test_fetch_action() {
+        let configuration = create_configuration(&[]);
+        let boundaries = Boundaries::new(vec![Ok(REDIRECT_ACTION.to_string())]);
+        let application = boundaries.application(&configuration);
+
+        let (mut action, body) = application.fetch_action("{}").unwrap();
+
+        assert_eq!(REDIRECT_ACTION, body);
+        assert_eq!(301, action.get_status_code(0, None));
+        assert_eq!(
+            vec!["{}".to_string()],
+            *boundaries.api_client.action_requests.borrow()
+        );
+        assert_eq!(Some(25), application.api_latency.get());
+    }
+
+    #[test]
+    fn test_fetch_action_status_error() {
+        let configuration = create_configuration(&[]);
+        let boundaries = Boundaries::new(vec![Err(ApiError::Status(500, "oops".to_string()))]);
+        let application = boundaries.application(&configuration);
+
+        match application.fetch_action("{}") {
+            Err(ApiError::Status(status, body)) => {
+                assert_eq!(500, status);
+                assert_eq!("oops", body);
+            }
+            _ => panic!("expected a status error"),
+        }
+
+        assert_eq!(Some(25), application.api_latency.get());
+    }
+
+    #[test]
+    fn test_fetch_action_deserialization_error() {
+        let configuration = create_configuration(&[]);
+        let boundaries = Boundaries::new(vec![Ok("not json".to_string())]);
+        let application = boundaries.application(&configuration);
+
+        match application.fetch_action("{}") {
+            Err(ApiError::Deserialization(_, body)) => assert_eq!("not json", body),
+            _ => panic!("expected a deserialization error"),
+        }
+    }
+
+    #[test]
+    fn test_filter_headers_with_rule_ids_header() {
+        let configuration = create_configuration(&[
+            ("add_rule_ids_header", "true"),
+            ("rule_ids_header_name", "X-Rules"),
+        ]);
+        let boundaries = Boundaries::new(vec![]);
+        let application = boundaries.application(&configuration);
+
+        let mut action: Action = json_decode(REDIRECT_ACTION).unwrap();
+        let headers = application.filter_headers(&mut action, vec![], 0, 301, 301);
+
+        assert!(headers
+            .iter()
+            .any(|header| header.name == "Location" && header.value == "/target"));
+        assert!(headers
+            .iter()
+            .any(|header| header.name == "X-Rules" && header.value == "rule-1"));
+        assert!(!headers
+            .iter()
+            .any(|header| header.name == ACTION_TYPE_HEADER_NAME));
+    }
+
+    #[test]
+    fn test_filter_headers_with_action_metadata() {
+        let configuration = create_configuration(&[("add_action_metadata_headers", "true")]);
+        let boundaries = Boundaries::new(vec![Ok(REDIRECT_ACTION.to_string())]);
+        let application = boundaries.application(&configuration);
+
+        let (mut action, _) = application.fetch_action("{}").unwrap();
+        let headers = application.filter_headers(&mut action, vec![], 0, 301, 301);
+
+        let get = |name: &str| {
+            headers
+                .iter()
+                .find(|header| header.name == name)
+                .map(|header| header.value.clone())
+        };
+
+        assert_eq!(Some("redirect".to_string()), get(ACTION_TYPE_HEADER_NAME));
+        assert_eq!(Some("/target".to_string()), get(TARGET_HEADER_NAME));
+        assert_eq!(Some("25".to_string()), get(API_LATENCY_HEADER_NAME));
+    }
+
+    #[test]
+    fn test_filter_body() {
+        let mut action: Action = json_decode(BODY_ACTION).unwrap();
+        let headers = vec![Header {
+            name: "Content-Type".to_string(),
+            value: "text/plain; charset=utf-8".to_string(),
+        }];
+        let mut body_filter = action.create_filter_body(200, &headers).unwrap();
+        let mut filtered_body = Vec::new();
+
+        filter_body(
+            &mut body_filter,
+            vec![b"hel".to_vec(), b"lo".to_vec()],
+            |chunk| filtered_body.extend_from_slice(chunk),
+        );
+
+        assert_eq!(b"hello world".to_vec(), filtered_body);
+    }
+
+    #[test]
+    fn test_log_headers_keep_all_values() {
+        let mut response = Response::from_status(200)
+            .with_header("Set-Cookie", "a=1")
+            .with_header("Content-Type", "text/html");
+        response.append_header("Set-Cookie", "b=2");
+
+        let headers = log_headers(&response);
+        let cookies: Vec<&str> = headers
+            .iter()
+            .filter(|header| header.name == "set-cookie")
+            .map(|header| header.value.as_str())
+            .collect();
+
+        assert_eq!(vec!["a=1", "b=2"], cookies);
+        assert_eq!(3, headers.len());
+    }
+
+    fn header(name: &str, value: &str) -> Header {
+        Header {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn values(response: &Response, name: &str) -> Vec<String> {
+        response
+            .get_header_all_str(name)
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_apply_headers_keeps_multiple_values() {
+        let mut response = Response::from_status(200);
+        response.append_header("Set-Cookie", "a=1");
+        response.append_header("Set-Cookie", "b=2");
+        response.append_header("Vary", "Accept-Encoding");
+        response.append_header("Vary", "Cookie");
+
+        let backend_headers = vec![
+            header("set-cookie", "a=1"),
+            header("set-cookie", "b=2"),
+            header("vary", "Accept-Encoding"),
+            header("vary", "Cookie"),
+        ];
+        let mut headers = backend_headers.clone();
+        // Headers added by rules
+        headers.push(header("Set-Cookie", "c=3"));
+        headers.push(header("Vary", "Accept-Language"));
+
+        apply_headers(&mut response, &backend_headers, &headers, &[], false);
+
+        assert_eq!(vec!["a=1", "b=2", "c=3"], values(&response, "Set-Cookie"));
+        assert_eq!(
+            vec!["Accept-Encoding", "Cookie", "Accept-Language"],
+            values(&response, "Vary")
+        );
+    }
+
+    #[test]
+    fn test_apply_headers_removes_headers() {
+        let mut response = Response::from_status(200)
+            .with_header("Set-Cookie", "a=1")
+            .with_header("X-Powered-By", "PHP");
+        let backend_headers = vec![header("set-cookie", "a=1"), header("x-powered-by", "PHP")];
+        let headers = vec![header("set-cookie", "a=1")];
+
+        apply_headers(&mut response, &backend_headers, &headers, &[], false);
+
+        assert_eq!(vec!["a=1"], values(&response, "Set-Cookie"));
+        assert!(values(&response, "X-Powered-By").is_empty());
+    }
+
+    #[test]
+    fn test_apply_headers_preserves_untouched_headers() {
+        let mut response = Response::from_status(200)
+            .with_header("X-Custom", "value")
+            .with_header("X-Changed", "before");
+        let backend_headers = vec![header("x-custom", "value"), header("x-changed", "before")];
+        let headers = vec![header("x-custom", "value"), header("x-changed", "after")];
+
+        apply_headers(&mut response, &backend_headers, &headers, &[], true);
+
+        assert_eq!(vec!["value"], values(&response, "X-Custom"));
+        assert_eq!(vec!["after"], values(&response, "X-Changed"));
+    }
+
+    #[test]
+    fn test_should_log_status() {
+        assert!(should_log_status(&[], 200, false));
+        assert!(!should_log_status(&[3, 4, 5], 200, false));
+        assert!(should_log_status(&[3, 4, 5], 200, true));
+        assert!(should_log_status(&[3, 4, 5], 404, false));
+    }
+
+    #[test]
+    fn test_synthetic_body() {
+        let action: Action = json_decode(GONE_ACTION).unwrap();
+
+        assert_eq!(
+            Some(("Gone".to_string(), Some("text/plain".to_string()))),
+            synthetic_body(&action, 410)
+        );
+        assert_eq!(None, synthetic_body(&action, 404));
+
+        let action: Action = json_decode(BODY_ACTION).unwrap();
+
+        assert_eq!(None, synthetic_body(&action, 410));
+    }
+
+    #[test]
+    fn test_is_filterable_content_type() {
+        assert!(is_filterable_content_type(
+            Some("text/html; charset=UTF-8"),
+            &[]
+        ));
+        assert!(!is_filterable_content_type(Some("application/json"), &[]));
+        assert!(!is_filterable_content_type(None, &[]));
+
+        let filterable = vec!["text/html".to_string(), "application/json".to_string()];
+
+        assert!(is_filterable_content_type(
+            Some("application/json"),
+            &filterable
+        ));
+        assert!(is_filterable_content_type(
+            Some("Text/HTML; charset=\"utf-8\""),
+            &filterable
+        ));
+        assert!(!is_filterable_content_type(
+            Some("text/html; charset=iso-8859-1"),
+            &filterable
+        ));
+        assert!(!is_filterable_content_type(
+            Some("text/css; charset=utf-8"),
+            &filterable
+        ));
+        assert!(!is_filterable_content_type(None, &filterable));
+    }
+
+    #[test]
+    fn test_add_request_headers_with_many_headers() {
+        let configuration = create_configuration(&[]);
+        let boundaries = Boundaries::new(vec![]);
+        let application = boundaries.application(&configuration);
+        let mut req = Request::get("https://example.org/");
+
+        for index in 0..300 {
+            req.set_header(format!("x-header-{}", index), "value");
+        }
+
+        let mut rio_request = RedirectionioRequest::from_str("https://example.org/").unwrap();
+        application.add_request_headers(&mut rio_request, &req);
+
+        assert_eq!(MAX_API_HEADERS, rio_request.headers.len());
+    }
+
+    #[test]
+    fn test_add_request_headers_with_cookies() {
+        let boundaries = Boundaries::new(vec![]);
+        let req = Request::get("https://example.org/").with_header("cookie", "lang=fr; ab=b");
+
+        for (excluded, expected) in [("Authorization", Some("fr")), ("Cookie", None)] {
+            let configuration = create_configuration(&[
+                ("match_cookies", "true"),
+                ("api_excluded_request_headers", excluded),
+            ]);
+            let application = boundaries.application(&configuration);
+            let mut rio_request = RedirectionioRequest::from_str("https://example.org/").unwrap();
+            application.add_request_headers(&mut rio_request, &req);
+
+            assert_eq!(
+                expected,
+                rio_request
+                    .header_values("x-redirectionio-cookie-lang")
+                    .first()
+                    .copied()
             );
         }
     }
+
+    #[test]
+    fn test_add_request_headers_with_large_header() {
+        let configuration = create_configuration(&[]);
+        let boundaries = Boundaries::new(vec![]);
+        let application = boundaries.application(&configuration);
+        let req = Request::get("https://example.org/")
+            .with_header("Cookie", format!("session={}", "a".repeat(20_000)))
+            .with_header("Accept-Language", "fr");
+
+        let mut rio_request = RedirectionioRequest::from_str("https://example.org/").unwrap();
+        application.add_request_headers(&mut rio_request, &req);
+        let cookie = rio_request
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case("cookie"))
+            .unwrap();
+
+        assert_eq!(MAX_API_HEADER_SIZE, cookie.value.len());
+        assert!(cookie.value.ends_with(TRUNCATED_MARKER));
+        assert_eq!(2, rio_request.headers.len());
+    }
+
+    #[test]
+    fn test_truncate_header_value() {
+        let mut value = "é".repeat(MAX_API_HEADER_SIZE);
+
+        assert!(truncate_header_value(&mut value));
+        assert!(value.len() <= MAX_API_HEADER_SIZE);
+        assert!(value.ends_with(TRUNCATED_MARKER));
+
+        let mut value = "short".to_string();
+
+        assert!(!truncate_header_value(&mut value));
+        assert_eq!("short", value);
+    }
+
+    #[test]
+    fn test_is_sent_to_api() {
+        let excluded = vec!["Authorization".to_string(), "Cookie".to_string()];
+
+        assert!(is_sent_to_api("user-agent", &[], &excluded));
+        assert!(!is_sent_to_api("authorization", &[], &excluded));
+
+        let allowed = vec!["User-Agent".to_string(), "Cookie".to_string()];
+
+        assert!(is_sent_to_api("user-agent", &allowed, &excluded));
+        assert!(!is_sent_to_api("accept-language", &allowed, &excluded));
+        assert!(!is_sent_to_api("cookie", &allowed, &excluded));
+    }
+
+    #[test]
+    fn test_minimal_rio_request() {
+        let mut rio_request =
+            RedirectionioRequest::from_str("https://example.org/foo?bar=1").unwrap();
+        rio_request.method = Some("GET".to_string());
+        rio_request.remote_addr = Some("192.0.2.1".parse().unwrap());
+        rio_request.add_header("User-Agent".to_string(), "curl".to_string(), true);
+        rio_request.add_header("Accept-Language".to_string(), "fr".to_string(), true);
+
+        let minimal = minimal_rio_request(&rio_request, &["accept-language".to_string()]);
+
+        assert_eq!(Some("GET".to_string()), minimal.method);
+        assert_eq!(rio_request_url(&rio_request), rio_request_url(&minimal));
+        assert_eq!(None, minimal.remote_addr);
+        assert_eq!(1, minimal.headers.len());
+        assert_eq!("fr", minimal.headers[0].value);
+    }
+
+    #[test]
+    fn test_matches_host_and_path() {
+        let host = Some("shop.example.org");
+
+        assert!(matches_host_and_path("shop.example.org", host, "/"));
+        assert!(matches_host_and_path("*.example.org", host, "/cart"));
+        assert!(matches_host_and_path(
+            "/templates/",
+            host,
+            "/templates/home"
+        ));
+        assert!(matches_host_and_path(
+            "shop.example.org/templates/",
+            host,
+            "/templates/home"
+        ));
+        assert!(!matches_host_and_path(
+            "shop.example.org/templates/",
+            host,
+            "/blog/"
+        ));
+        assert!(!matches_host_and_path("example.com", host, "/"));
+    }
 }
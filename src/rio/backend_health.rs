@@ -0,0 +1,402 @@
+use super::clock::Clock;
+use super::error::send_error_class;
+use super::kv_store::{self, KvStore};
+use super::logging::FastlyLogger;
+use super::request_sender::RequestSender;
+use fastly::http::request::SendError;
+use fastly::http::Method;
+use fastly::{Request, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+const KEY_PREFIX: &str = "backend_health";
+
+// Number of failures within the failure window making a backend unhealthy
+const FAILURE_THRESHOLD: u64 = 5;
+
+// Duration, in seconds, of the window failures are counted in
+const FAILURE_WINDOW: u64 = 60;
+
+// Duration, in seconds, during which an unhealthy backend is avoided
+const COOL_DOWN: u64 = 30;
+
+// Delay, in seconds, after which an instance reads the state of a backend from the KV Store again
+const REFRESH_INTERVAL: u64 = 5;
+
+// Health of the backends seen by the instance, by backend name
+static INSTANCE_HEALTH: OnceLock<Mutex<HashMap<String, InstanceBackendHealth>>> = OnceLock::new();
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BackendHealthState {
+    pub window_start: u64,
+    pub failures: u64,
+    pub unhealthy_until: u64,
+    // Failures of the window by cause, see `error::send_error_class`, or `status_5xx`
+    #[serde(default)]
+    pub causes: HashMap<String, u64>,
+}
+
+impl BackendHealthState {
+    pub fn is_unhealthy(&self, now: u64) -> bool {
+        self.unhealthy_until > now
+    }
+
+    /// Count a failure, starting a new window when the current one is over, and mark the backend
+    /// unhealthy when the threshold is reached.
+    pub fn record_failure(&mut self, now: u64, cause: &str) {
+        if now.saturating_sub(self.window_start) >= FAILURE_WINDOW {
+            self.window_start = now;
+            self.failures = 0;
+            self.causes.clear();
+        }
+
+        self.failures += 1;
+        *self.causes.entry(cause.to_string()).or_insert(0) += 1;
+
+        if self.failures >= FAILURE_THRESHOLD {
+            self.unhealthy_until = now + COOL_DOWN;
+            self.window_start = now;
+            self.failures = 0;
+        }
+    }
+}
+
+/// Health of a backend seen by an instance.
+///
+/// Failures are counted in memory. The KV Store is only written when the instance marks the
+/// backend unhealthy, and when the cool-down it started ends; the state of the other instances is
+/// read from it at most once per refresh interval.
+#[derive(Debug, Default)]
+pub struct InstanceBackendHealth {
+    state: BackendHealthState,
+    // State of the KV Store, and when it was read
+    shared: Option<BackendHealthState>,
+    refreshed_at: Option<u64>,
+}
+
+impl InstanceBackendHealth {
+    pub fn is_unhealthy(&mut self, backend: &str, clock: &dyn Clock, store: &dyn KvStore) -> bool {
+        let now = clock.now_secs();
+        self.end_cool_down(backend, now, store);
+
+        if self.state.is_unhealthy(now) {
+            return true;
+        }
+
+        let is_fresh = matches!(self.refreshed_at, Some(refreshed_at) if now.saturating_sub(refreshed_at) < REFRESH_INTERVAL);
+
+        if !is_fresh {
+            self.shared = kv_store::get_json(store, &key(backend));
+            self.refreshed_at = Some(now);
+        }
+
+        match self.shared {
+            Some(ref shared) => shared.is_unhealthy(now),
+            None => false,
+        }
+    }
+
+    pub fn record_failure(
+        &mut self,
+        backend: &str,
+        cause: &str,
+        clock: &dyn Clock,
+        store: &dyn KvStore,
+    ) {
+        let now = clock.now_secs();
+        self.end_cool_down(backend, now, store);
+
+        let was_unhealthy = self.state.is_unhealthy(now);
+        self.state.record_failure(now, cause);
+
+        if !was_unhealthy && self.state.is_unhealthy(now) {
+            self.share(backend, now, store);
+        }
+    }
+
+    // Only the instance which started the cool-down marks the backend healthy again
+    fn end_cool_down(&mut self, backend: &str, now: u64, store: &dyn KvStore) {
+        if self.state.unhealthy_until > 0 && !self.state.is_unhealthy(now) {
+            self.state.unhealthy_until = 0;
+            self.share(backend, now, store);
+        }
+    }
+
+    fn share(&mut self, backend: &str, now: u64, store: &dyn KvStore) {
+        kv_store::set_json(store, &key(backend), &self.state);
+        self.shared = Some(self.state.clone());
+        self.refreshed_at = Some(now);
+    }
+}
+
+/// Keeps track of backend failures, per backend name, in memory and in the KV Store.
+pub struct BackendHealthTracker;
+
+impl BackendHealthTracker {
+    pub fn state(&self, backend: &str, store: &dyn KvStore) -> Option<BackendHealthState> {
+        kv_store::get_json(store, &key(backend))
+    }
+
+    pub fn is_unhealthy(&self, backend: &str, clock: &dyn Clock, store: &dyn KvStore) -> bool {
+        with_instance_health(backend, |health| health.is_unhealthy(backend, clock, store))
+    }
+
+    pub fn record_failure(
+        &self,
+        backend: &str,
+        cause: &str,
+        clock: &dyn Clock,
+        store: &dyn KvStore,
+    ) {
+        with_instance_health(backend, |health| {
+            health.record_failure(backend, cause, clock, store)
+        })
+    }
+}
+
+fn with_instance_health<T>(
+    backend: &str,
+    record: impl FnOnce(&mut InstanceBackendHealth) -> T,
+) -> T {
+    let mut backends = match INSTANCE_HEALTH.get_or_init(Default::default).lock() {
+        Ok(backends) => backends,
+        // A panic while recording must not stop the next records
+        Err(poisoned) => poisoned.into_inner(),
+    };
+
+    record(backends.entry(backend.to_string()).or_default())
+}
+
+/// Request sender avoiding unhealthy backends.
+///
+/// Send errors and `5xx` responses count as failures. While a backend is unhealthy, its requests
+/// are sent to the failover backend, when there is one.
+///
+/// Requests which can not be sent to a backend are retried once on the failover backend, unless
+/// their method is not idempotent: POST or PATCH requests are only retried with an
+/// `Idempotency-Key` header, or when the `retry_non_idempotent` entry is `true`.
+pub struct HealthAwareRequestSender<'a> {
+    tracker: BackendHealthTracker,
+    failover_backend: Option<String>,
+    retry_non_idempotent: bool,
+    fastly_logger: &'a FastlyLogger,
+    clock: &'a dyn Clock,
+    kv_store: &'a dyn KvStore,
+    inner: &'a dyn RequestSender,
+}
+
+impl<'a> HealthAwareRequestSender<'a> {
+    pub(crate) fn new(
+        failover_backend: Option<String>,
+        retry_non_idempotent: bool,
+        fastly_logger: &'a FastlyLogger,
+        clock: &'a dyn Clock,
+        kv_store: &'a dyn KvStore,
+        inner: &'a dyn RequestSender,
+    ) -> HealthAwareRequestSender<'a> {
+        HealthAwareRequestSender {
+            tracker: BackendHealthTracker,
+            failover_backend,
+            retry_non_idempotent,
+            fastly_logger,
+            clock,
+            kv_store,
+            inner,
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn send_and_track(&self, req: Request, backend: String) -> Result<Response, SendError> {
+        let result = self.inner.send(req, backend.clone());
+
+        match result {
+            Ok(ref response) if !response.get_status().is_server_error() => (),
+            Ok(_) => self
+                .tracker
+                .record_failure(&backend, "status_5xx", self.clock, self.kv_store),
+            Err(ref error) => self.tracker.record_failure(
+                &backend,
+                send_error_class(error.root_cause()),
+                self.clock,
+                self.kv_store,
+            ),
+        }
+
+        result
+    }
+}
+
+impl<'a> RequestSender for HealthAwareRequestSender<'a> {
+    fn send(&self, mut req: Request, backend: String) -> Result<Response, SendError> {
+        let backend = match self.failover_backend {
+            Some(ref failover_backend)
+                if *failover_backend != backend
+                    && self
+                        .tracker
+                        .is_unhealthy(&backend, self.clock, self.kv_store) =>
+            {
+                failover_backend.clone()
+            }
+            _ => backend,
+        };
+
+        let failover_backend = self
+            .failover_backend
+            .clone()
+            .filter(|failover_backend| *failover_backend != backend)
+            .filter(|_| {
+                is_retryable(
+                    req.get_method(),
+                    req.contains_header(IDEMPOTENCY_KEY_HEADER),
+                    self.retry_non_idempotent,
+                )
+            });
+
+        let failover_backend = match failover_backend {
+            Some(failover_backend) => failover_backend,
+            None => return self.send_and_track(req, backend),
+        };
+
+        // The body is kept in memory to be sent again
+        let retry = req.clone_with_body();
+
+        match self.send_and_track(req, backend.clone()) {
+            Err(error) => {
+                self.fastly_logger.log_info(
+                    format!(
+                        "Retry the request on the \"{}\" failover backend after: {}.",
+                        failover_backend, error
+                    ),
+                    Some(HashMap::from([
+                        ("backend", backend),
+                        ("failover_backend", failover_backend.clone()),
+                        ("retry_count", "1".to_string()),
+                    ])),
+                );
+
+                self.send_and_track(retry, failover_backend)
+            }
+            result => result,
+        }
+    }
+}
+
+/// Whether a request failing to reach its backend can be sent again: idempotent methods always
+/// are, POST and PATCH requests need an idempotency key or the `retry_non_idempotent` entry.
+pub fn is_retryable(
+    method: &Method,
+    has_idempotency_key: bool,
+    retry_non_idempotent: bool,
+) -> bool {
+    match *method {
+        Method::POST | Method::PATCH => has_idempotency_key || retry_non_idempotent,
+        _ => true,
+    }
+}
+
+fn key(backend: &str) -> String {
+    format!("{}:{}", KEY_PREFIX, backend)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rio::mock::{MockClock, MockKvStore};
+
+    fn at(now: u64) -> MockClock {
+        MockClock::new(u128::from(now) * 1000, 0)
+    }
+
+    #[test]
+    fn test_backend_becomes_unhealthy() {
+        let mut state = BackendHealthState::default();
+
+        for _ in 1..FAILURE_THRESHOLD {
+            state.record_failure(1000, "connect");
+        }
+
+        assert!(!state.is_unhealthy(1000));
+
+        state.record_failure(1000, "connect");
+
+        assert!(state.is_unhealthy(1000));
+        assert!(state.is_unhealthy(1000 + COOL_DOWN - 1));
+        assert!(!state.is_unhealthy(1000 + COOL_DOWN));
+    }
+
+    #[test]
+    fn test_failures_expire_with_the_window() {
+        let mut state = BackendHealthState::default();
+
+        for _ in 1..FAILURE_THRESHOLD {
+            state.record_failure(1000, "connect");
+        }
+
+        state.record_failure(1000 + FAILURE_WINDOW, "status_5xx");
+
+        assert!(!state.is_unhealthy(1000 + FAILURE_WINDOW));
+        assert_eq!(1, state.failures);
+        assert_eq!(HashMap::from([("status_5xx".to_string(), 1)]), state.causes);
+    }
+
+    #[test]
+    fn test_unhealthy_state_is_shared() {
+        let store = MockKvStore::default();
+        let mut failing = InstanceBackendHealth::default();
+        let mut other = InstanceBackendHealth::default();
+
+        assert!(!other.is_unhealthy("origin", &at(1000), &store));
+
+        for _ in 0..FAILURE_THRESHOLD * 2 {
+            failing.record_failure("origin", "connect", &at(1000), &store);
+        }
+
+        // Written once, when the backend becomes unhealthy
+        assert_eq!(1, store.writes.borrow().len());
+        assert!(failing.is_unhealthy("origin", &at(1000), &store));
+
+        // The other instance reads the KV Store again after the refresh interval
+        assert!(!other.is_unhealthy("origin", &at(1000 + REFRESH_INTERVAL - 1), &store));
+        assert!(other.is_unhealthy("origin", &at(1000 + REFRESH_INTERVAL), &store));
+    }
+
+    #[test]
+    fn test_cool_down_end_is_shared() {
+        let store = MockKvStore::default();
+        let mut failing = InstanceBackendHealth::default();
+        let mut other = InstanceBackendHealth::default();
+
+        for _ in 0..FAILURE_THRESHOLD {
+            failing.record_failure("origin", "connect", &at(1000), &store);
+        }
+
+        assert!(other.is_unhealthy("origin", &at(1000), &store));
+        assert!(!other.is_unhealthy("origin", &at(1000 + COOL_DOWN), &store));
+        assert_eq!(1, store.writes.borrow().len());
+
+        // The instance which started the cool-down writes its end, once
+        assert!(!failing.is_unhealthy("origin", &at(1000 + COOL_DOWN), &store));
+        assert!(!failing.is_unhealthy("origin", &at(1000 + COOL_DOWN + 1), &store));
+        assert_eq!(2, store.writes.borrow().len());
+        assert_eq!(
+            Some(0),
+            BackendHealthTracker
+                .state("origin", &store)
+                .map(|state| state.unhealthy_until)
+        );
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(is_retryable(&Method::GET, false, false));
+        assert!(is_retryable(&Method::PUT, false, false));
+        assert!(is_retryable(&Method::DELETE, false, false));
+        assert!(!is_retryable(&Method::POST, false, false));
+        assert!(!is_retryable(&Method::PATCH, false, false));
+        assert!(is_retryable(&Method::POST, true, false));
+        assert!(is_retryable(&Method::PATCH, false, true));
+    }
+}
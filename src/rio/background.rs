@@ -0,0 +1,21 @@
+use std::cell::RefCell;
+
+/// Work to run once the response has been sent to the client, like refreshing a cached action.
+#[derive(Default)]
+pub struct BackgroundTasks {
+    tasks: RefCell<Vec<Box<dyn FnOnce()>>>,
+}
+
+impl BackgroundTasks {
+    pub fn push(&self, task: Box<dyn FnOnce()>) {
+        self.tasks.borrow_mut().push(task);
+    }
+
+    pub fn run(&self) {
+        let tasks: Vec<Box<dyn FnOnce()>> = self.tasks.borrow_mut().drain(..).collect();
+
+        for task in tasks {
+            task();
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use super::clock::Clock;
+use super::kv_store::{self, FastlyKvStore};
+use chrono::DateTime;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+const BACKOFF_KEY: &str = "api_backoff";
+
+// Backoff, in seconds, when the API rate limits the worker without a `Retry-After` header
+const DEFAULT_BACKOFF: u64 = 60;
+
+// Longest backoff, in seconds, accepted from the API
+const MAX_BACKOFF: u64 = 3600;
+
+// Deadline of the instance, read from the KV Store once, 0 when there is none
+static DEADLINE: OnceLock<AtomicU64> = OnceLock::new();
+
+/// Keeps in the KV Store the deadline until which the redirection.io API must not be called,
+/// after it answered with a `429`, or a `503` with a `Retry-After` header.
+///
+/// The deadline is read from the KV Store once per instance, then kept in memory for the next
+/// API calls, like the configuration.
+pub struct Backoff;
+
+impl Backoff {
+    /// Return the deadline of the current backoff, if any.
+    pub fn deadline(&self, clock: &dyn Clock) -> Option<u64> {
+        let deadline = cached_deadline().load(Ordering::Relaxed);
+
+        if deadline > clock.now_secs() {
+            Some(deadline)
+        } else {
+            None
+        }
+    }
+
+    /// Start a backoff, and return its deadline.
+    pub fn record(&self, retry_after: Option<u64>, clock: &dyn Clock) -> u64 {
+        let deadline = clock.now_secs() + backoff_duration(retry_after);
+        cached_deadline().store(deadline, Ordering::Relaxed);
+
+        // Other instances of the POP read it from the KV Store
+        kv_store::set_json(&FastlyKvStore, BACKOFF_KEY, &deadline);
+
+        deadline
+    }
+}
+
+fn cached_deadline() -> &'static AtomicU64 {
+    DEADLINE.get_or_init(|| {
+        let deadline = kv_store::get_json(&FastlyKvStore, BACKOFF_KEY).unwrap_or(0);
+
+        AtomicU64::new(deadline)
+    })
+}
+
+/// Parse a `Retry-After` header value, either a number of seconds or an HTTP date, into a number
+/// of seconds from `now`.
+pub fn parse_retry_after(value: &str, now: u64) -> Option<u64> {
+    let value = value.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(seconds);
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+
+    Some((date.timestamp().max(0) as u64).saturating_sub(now))
+}
+
+pub fn backoff_duration(retry_after: Option<u64>) -> u64 {
+    retry_after.unwrap_or(DEFAULT_BACKOFF).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_retry_after_seconds() {
+        assert_eq!(Some(120), parse_retry_after("120", 0));
+        assert_eq!(Some(5), parse_retry_after(" 5 ", 0));
+        assert_eq!(None, parse_retry_after("soon", 0));
+    }
+
+    #[test]
+    fn test_parse_retry_after_date() {
+        // Wed, 21 Oct 2015 07:28:00 GMT
+        let date = 1445412480;
+
+        assert_eq!(
+            Some(30),
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", date - 30)
+        );
+        assert_eq!(
+            Some(0),
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", date + 30)
+        );
+    }
+
+    #[test]
+    fn test_backoff_duration() {
+        assert_eq!(DEFAULT_BACKOFF, backoff_duration(None));
+        assert_eq!(10, backoff_duration(Some(10)));
+        assert_eq!(MAX_BACKOFF, backoff_duration(Some(86400)));
+    }
+}
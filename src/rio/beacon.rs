@@ -0,0 +1,243 @@
+use super::api::FastlyApiClient;
+use super::background::BackgroundTasks;
+use super::clock::Clock;
+use super::logging::{format_date, FastlyLogger};
+use super::synthetic;
+use fastly::http::{header, Method, StatusCode};
+use fastly::log::Endpoint;
+use fastly::{Request, Response};
+use serde::Serialize;
+use serde_json::Value;
+use std::io::Write;
+
+pub const BEACON_PATH: &str = "/__rio/beacon";
+
+// Beacons are page views or Web Vitals measures, anything larger is not one
+const MAX_BEACON_SIZE: usize = 16 * 1024;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum BeaconError {
+        Method {
+            display("beacons must be sent with a POST request")
+        }
+        Origin (origin: Option<String>) {
+            display("beacon origin {:?} is not allowed", origin)
+        }
+        TooLarge (size: usize) {
+            display("beacon of {} bytes is too large", size)
+        }
+        InvalidJson (error: String) {
+            display("invalid beacon: {}", error)
+        }
+    }
+}
+
+impl BeaconError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            BeaconError::Method => 405,
+            BeaconError::Origin(_) => 403,
+            BeaconError::TooLarge(_) => 413,
+            BeaconError::InvalidJson(_) => 400,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BeaconEntry {
+    pub date: String,
+    pub host: String,
+    pub user_agent: Option<String>,
+    pub beacon: Value,
+}
+
+/// Collects the beacons sent to `/__rio/beacon` by frontend snippets, like page views or Core Web
+/// Vitals, for first-party analytics.
+///
+/// Beacons are JSON documents sent with a `POST` request from the host itself, or from one of the
+/// `beacon_origins` entry. They are forwarded to the `beacon_log_endpoint` entry, and to the
+/// redirection.io API when the `beacon_api` entry is `true`, once the response has been sent.
+pub struct BeaconCollector<'a> {
+    allowed_origins: &'a [String],
+    log_endpoint: Option<String>,
+    api_client: Option<&'a FastlyApiClient<'a>>,
+    background_tasks: &'a BackgroundTasks,
+    fastly_logger: &'a FastlyLogger,
+    clock: &'a dyn Clock,
+}
+
+impl<'a> BeaconCollector<'a> {
+    pub(crate) fn new(
+        allowed_origins: &'a [String],
+        log_endpoint: Option<String>,
+        api_client: Option<&'a FastlyApiClient<'a>>,
+        background_tasks: &'a BackgroundTasks,
+        fastly_logger: &'a FastlyLogger,
+        clock: &'a dyn Clock,
+    ) -> BeaconCollector<'a> {
+        BeaconCollector {
+            allowed_origins,
+            log_endpoint,
+            api_client,
+            background_tasks,
+            fastly_logger,
+            clock,
+        }
+    }
+
+    pub fn handle(&self, req: &mut Request, cache_control: &str) -> Response {
+        let entry = match self.collect(req) {
+            Ok(entry) => entry,
+            Err(error) => {
+                return synthetic::text_response(
+                    format!("{}.\n", error),
+                    error.status_code(),
+                    cache_control,
+                )
+            }
+        };
+
+        let json = match serde_json::to_string(&entry) {
+            Ok(json) => json,
+            Err(error) => {
+                self.fastly_logger
+                    .log_error(format!("Cannot serialize beacon: {}.", error), None);
+
+                return no_content(cache_control);
+            }
+        };
+
+        if let Some(api_client) = self.api_client {
+            api_client.beacon(json.clone());
+        }
+
+        if let Some(log_endpoint) = self.log_endpoint.clone() {
+            let fastly_logger = self.fastly_logger.settings();
+
+            self.background_tasks.push(Box::new(move || {
+                if let Err(error) = send(&log_endpoint, &json) {
+                    fastly_logger.logger().log_error(
+                        format!("Cannot send beacon to \"{}\": {}.", log_endpoint, error),
+                        None,
+                    );
+                }
+            }));
+        }
+
+        no_content(cache_control)
+    }
+
+    fn collect(&self, req: &mut Request) -> Result<BeaconEntry, BeaconError> {
+        if req.get_method() != Method::POST {
+            return Err(BeaconError::Method);
+        }
+
+        let host = req.get_url().host_str().unwrap_or_default().to_string();
+        let origin = req.get_header_str(header::ORIGIN);
+
+        if !is_allowed_origin(origin, &host, self.allowed_origins) {
+            return Err(BeaconError::Origin(origin.map(String::from)));
+        }
+
+        if let Some(size) = req.get_content_length() {
+            if size > MAX_BEACON_SIZE {
+                return Err(BeaconError::TooLarge(size));
+            }
+        }
+
+        let body = req.take_body_bytes();
+
+        if body.len() > MAX_BEACON_SIZE {
+            return Err(BeaconError::TooLarge(body.len()));
+        }
+
+        Ok(BeaconEntry {
+            date: format_date(self.clock.now()),
+            host,
+            user_agent: req.get_header_str(header::USER_AGENT).map(String::from),
+            beacon: parse(&body)?,
+        })
+    }
+}
+
+/// Whether a beacon comes from the host it is sent to, or from one of the allowed origins, like
+/// `https://www.example.com`.
+pub fn is_allowed_origin(origin: Option<&str>, host: &str, allowed_origins: &[String]) -> bool {
+    let origin = match origin {
+        Some(origin) => origin.trim_end_matches('/'),
+        None => return false,
+    };
+
+    let origin_host = origin
+        .split_once("://")
+        .map(|(_, host)| host)
+        .unwrap_or(origin);
+
+    origin_host.eq_ignore_ascii_case(host)
+        || allowed_origins
+            .iter()
+            .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+}
+
+/// Beacons are JSON objects, or arrays of them when the snippet batches its measures.
+fn parse(body: &[u8]) -> Result<Value, BeaconError> {
+    match serde_json::from_slice(body) {
+        Ok(value @ (Value::Object(_) | Value::Array(_))) => Ok(value),
+        Ok(_) => Err(BeaconError::InvalidJson("not an object".to_string())),
+        Err(error) => Err(BeaconError::InvalidJson(error.to_string())),
+    }
+}
+
+fn no_content(cache_control: &str) -> Response {
+    let mut response = Response::from_status(StatusCode::NO_CONTENT);
+    response.set_header(header::CACHE_CONTROL, cache_control);
+
+    response
+}
+
+fn send(endpoint: &str, json: &str) -> Result<(), String> {
+    let mut endpoint = Endpoint::try_from_name(endpoint).map_err(|error| error.to_string())?;
+
+    writeln!(endpoint, "{}", json).map_err(|error| error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_allowed_origin() {
+        let allowed_origins = vec!["https://www.example.com/".to_string()];
+
+        assert!(is_allowed_origin(
+            Some("https://example.org"),
+            "example.org",
+            &[]
+        ));
+        assert!(is_allowed_origin(
+            Some("https://www.example.com"),
+            "example.org",
+            &allowed_origins
+        ));
+        assert!(!is_allowed_origin(
+            Some("http://www.example.com"),
+            "example.org",
+            &allowed_origins
+        ));
+        assert!(!is_allowed_origin(
+            Some("https://attacker.example"),
+            "example.org",
+            &allowed_origins
+        ));
+        assert!(!is_allowed_origin(None, "example.org", &allowed_origins));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(parse(br#"{"type": "page_view", "lcp": 1250}"#).is_ok());
+        assert!(parse(br#"[{"type": "cls", "value": 0.02}]"#).is_ok());
+        assert!(matches!(parse(b"42"), Err(BeaconError::InvalidJson(_))));
+        assert!(matches!(parse(b"{"), Err(BeaconError::InvalidJson(_))));
+    }
+}
@@ -0,0 +1,116 @@
+use super::cookies::cookie_value;
+use fastly::http::header;
+use fastly::{Request, Response};
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+
+pub const BUCKET_COOKIE: &str = "redirectionio_bucket";
+pub const BUCKET_HEADER: &str = "x-redirectionio-bucket";
+
+// Lifetime, in seconds, of the bucket cookie
+const BUCKET_MAX_AGE: u64 = 30 * 86400;
+
+/// Stable bucket of a visitor, from 1 to 100, for percentage-based rules.
+///
+/// The bucket is computed from the client IP and user agent, then kept in a cookie, so a visitor
+/// stays in the same traffic split. It is sent to redirection.io and to the backend in the
+/// `x-redirectionio-bucket` request header, which rules can match on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    pub value: u8,
+    is_new: bool,
+}
+
+impl Bucket {
+    /// Read the bucket of the request cookie, or compute it, and set the bucket request header.
+    pub fn assign(req: &mut Request) -> Bucket {
+        let bucket = match from_cookie(req) {
+            Some(value) => Bucket {
+                value,
+                is_new: false,
+            },
+            None => Bucket {
+                value: compute(
+                    req.get_client_ip_addr(),
+                    req.get_header_str(header::USER_AGENT),
+                ),
+                is_new: true,
+            },
+        };
+
+        req.set_header(BUCKET_HEADER, bucket.value.to_string());
+
+        bucket
+    }
+
+    /// Store a new bucket in a cookie, for the next requests of the visitor.
+    pub fn persist(&self, response: &mut Response) {
+        if !self.is_new {
+            return;
+        }
+
+        response.append_header(
+            header::SET_COOKIE,
+            format!(
+                "{}={}; Path=/; Max-Age={}; Secure; HttpOnly; SameSite=Lax",
+                BUCKET_COOKIE, self.value, BUCKET_MAX_AGE
+            ),
+        );
+    }
+}
+
+fn from_cookie(req: &Request) -> Option<u8> {
+    req.get_header_all_str(header::COOKIE)
+        .into_iter()
+        .find_map(|cookies| cookie_value(cookies, BUCKET_COOKIE))?
+        .parse()
+        .ok()
+        .filter(|value| (1..=100).contains(value))
+}
+
+fn compute(client_ip: Option<IpAddr>, user_agent: Option<&str>) -> u8 {
+    let mut hasher = Sha256::new();
+
+    if let Some(client_ip) = client_ip {
+        hasher.update(client_ip.to_string());
+    }
+
+    hasher.update("\n");
+    hasher.update(user_agent.unwrap_or_default());
+
+    let hash = hasher.finalize();
+
+    (u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) % 100 + 1) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute() {
+        let ip = Some("192.0.2.1".parse().unwrap());
+
+        let bucket = compute(ip, Some("Mozilla/5.0"));
+        assert!((1..=100).contains(&bucket));
+        assert_eq!(bucket, compute(ip, Some("Mozilla/5.0")));
+
+        let buckets: Vec<u8> = (0..50)
+            .map(|i| compute(ip, Some(format!("agent {}", i).as_str())))
+            .collect();
+        assert!(buckets.iter().any(|other| *other != buckets[0]));
+    }
+
+    #[test]
+    fn test_from_cookie() {
+        let req = Request::get("https://example.org/")
+            .with_header("Cookie", "session=abc; redirectionio_bucket=42");
+        assert_eq!(Some(42), from_cookie(&req));
+
+        let req =
+            Request::get("https://example.org/").with_header("Cookie", "redirectionio_bucket=0");
+        assert_eq!(None, from_cookie(&req));
+
+        assert_eq!(None, from_cookie(&Request::get("https://example.org/")));
+    }
+}
@@ -0,0 +1,21 @@
+use super::secrets::{get_secret, secure_compare};
+use fastly::Request;
+
+pub const BYPASS_HEADER: &str = "x-redirectionio-bypass";
+
+/// Check whether the request asks to skip the worker, and remove the bypass header.
+///
+/// A request carrying the `bypass_secret` Secret Store entry in the `x-redirectionio-bypass`
+/// header is forwarded to the backend without action lookup, filtering nor logging. This helps
+/// to find out whether an issue comes from a rule or from the origin.
+pub fn is_bypass_request(req: &mut Request) -> bool {
+    let value = match req.remove_header_str(BYPASS_HEADER) {
+        Some(value) => value,
+        None => return false,
+    };
+
+    match get_secret("bypass_secret") {
+        Some(secret) => secure_compare(&value, &secret),
+        None => false,
+    }
+}
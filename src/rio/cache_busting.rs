@@ -0,0 +1,84 @@
+use super::secrets::{get_secret, secure_compare};
+use fastly::Request;
+
+pub const CACHE_BUSTING_HEADER: &str = "x-redirectionio-nocache";
+pub const CACHE_BUSTING_PARAMETER: &str = "__rio_nocache";
+
+/// Check whether the request asks for a fresh action, and remove the cache busting header and
+/// query parameter.
+///
+/// A request carrying the `cache_busting_secret` Secret Store entry in the `__rio_nocache` query
+/// parameter, or the `x-redirectionio-nocache` header, skips the action cache: the action is
+/// fetched from the API and replaces the cached one. This lets editors check a rule as soon as
+/// it is published.
+///
+/// They are removed from every request, so they are never part of the action sent to the API,
+/// nor forwarded to the backend.
+pub fn is_cache_busting_request(req: &mut Request) -> bool {
+    let header = req.remove_header_str(CACHE_BUSTING_HEADER);
+    let parameter = match req.get_query_str() {
+        Some(query) => {
+            let (value, query) = remove_query_parameter(query, CACHE_BUSTING_PARAMETER);
+
+            if value.is_some() {
+                match query.is_empty() {
+                    true => req.remove_query(),
+                    false => req.set_query_str(query),
+                }
+            }
+
+            value
+        }
+        None => None,
+    };
+
+    let value = match header.or(parameter) {
+        Some(value) => value,
+        None => return false,
+    };
+
+    match get_secret("cache_busting_secret") {
+        Some(secret) => secure_compare(&value, &secret),
+        None => false,
+    }
+}
+
+/// Remove a parameter from a query string, returning its first value and the other parameters
+/// as they were sent.
+pub fn remove_query_parameter(query: &str, name: &str) -> (Option<String>, String) {
+    let mut value = None;
+    let mut kept = Vec::new();
+
+    for pair in query.split('&') {
+        let (pair_name, pair_value) = pair.split_once('=').unwrap_or((pair, ""));
+
+        if pair_name != name {
+            kept.push(pair);
+        } else if value.is_none() {
+            value = Some(pair_value.to_string());
+        }
+    }
+
+    (value, kept.join("&"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_query_parameter() {
+        assert_eq!(
+            (Some("secret".to_string()), "a=1&b=%20".to_string()),
+            remove_query_parameter("a=1&__rio_nocache=secret&b=%20", CACHE_BUSTING_PARAMETER)
+        );
+        assert_eq!(
+            (Some("".to_string()), "".to_string()),
+            remove_query_parameter("__rio_nocache", CACHE_BUSTING_PARAMETER)
+        );
+        assert_eq!(
+            (None, "a=1&__rio_nocache_other=2".to_string()),
+            remove_query_parameter("a=1&__rio_nocache_other=2", CACHE_BUSTING_PARAMETER)
+        );
+    }
+}
@@ -0,0 +1,273 @@
+use super::cookies::{self, cookie_header_name, cookie_value};
+use fastly::http::header;
+use fastly::Request;
+use redirectionio::http::{PathAndQueryWithSkipped, Request as RedirectionioRequest};
+
+/// Builds the keys of the readthrough cache and of the action cache.
+///
+/// By default, a readthrough cache key holds the method and the normalized url of the request, and
+/// an action cache key the whole redirection.io request. The key can include request headers and
+/// cookies the response varies on, and leave out query parameters the response does not depend
+/// on, like tracking parameters.
+#[derive(Debug, Clone, Default)]
+pub struct CacheKeyBuilder {
+    headers: Vec<String>,
+    cookies: Vec<String>,
+    query_params: Vec<String>,
+    excluded_query_params: Vec<String>,
+}
+
+impl CacheKeyBuilder {
+    pub(crate) fn new() -> CacheKeyBuilder {
+        CacheKeyBuilder::default()
+    }
+
+    /// Include the value of these request headers in readthrough cache keys.
+    pub fn with_headers(mut self, headers: Vec<String>) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Include the value of these cookies in readthrough cache keys, and only keep these cookies
+    /// in action cache keys.
+    pub fn with_cookies(mut self, cookies: Vec<String>) -> Self {
+        self.cookies = cookies;
+        self
+    }
+
+    /// Only keep these query parameters in cache keys.
+    pub fn with_query_params(mut self, query_params: Vec<String>) -> Self {
+        self.query_params = query_params;
+        self
+    }
+
+    /// Leave these query parameters out of cache keys.
+    pub fn without_query_params(mut self, excluded_query_params: Vec<String>) -> Self {
+        self.excluded_query_params = excluded_query_params;
+        self
+    }
+
+    /// Build the readthrough cache key of a request: its method, its url with a lowercase host and
+    /// sorted query parameters, and the value of each of the headers and cookies.
+    pub fn build(&self, req: &Request) -> String {
+        let url = req.get_url();
+        let mut query: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(name, _)| self.keeps_query_param(name))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        query.sort();
+
+        let mut cache_key = format!(
+            "{} {}://{}{}",
+            req.get_method_str(),
+            url.scheme(),
+            url.host_str().unwrap_or_default().to_lowercase(),
+            url.path()
+        );
+
+        for (index, (name, value)) in query.iter().enumerate() {
+            cache_key.push(if index == 0 { '?' } else { '&' });
+            cache_key.push_str(&format!("{}={}", name, value));
+        }
+
+        for name in &self.headers {
+            cache_key.push_str(&format!(
+                "\n{}: {}",
+                name.to_lowercase(),
+                req.get_header_str(name.as_str()).unwrap_or_default()
+            ));
+        }
+
+        let cookies = req.get_header_all_str(header::COOKIE).join("; ");
+
+        for name in &self.cookies {
+            cache_key.push_str(&format!(
+                "\ncookie {}: {}",
+                name,
+                cookie_value(&cookies, name).unwrap_or_default()
+            ));
+        }
+
+        cache_key
+    }
+
+    /// The redirection.io request to build an action cache key from, without the query parameters
+    /// and cookies left out of cache keys.
+    pub fn rio_request(&self, rio_request: &RedirectionioRequest) -> RedirectionioRequest {
+        let mut rio_request = rio_request.clone();
+
+        if !self.query_params.is_empty() || !self.excluded_query_params.is_empty() {
+            let path_and_query =
+                self.filter_query(rio_request.path_and_query_skipped.original.as_str());
+            rio_request.path_and_query_skipped =
+                PathAndQueryWithSkipped::from_static(&path_and_query);
+            rio_request.path_and_query = Some(path_and_query);
+        }
+
+        if !self.cookies.is_empty() {
+            for header in rio_request.headers.iter_mut() {
+                if header.name.eq_ignore_ascii_case("Cookie") {
+                    header.value = cookies::parse(&header.value)
+                        .filter(|(name, _)| self.keeps_cookie(name))
+                        .map(|(name, value)| format!("{}={}", name, value))
+                        .collect::<Vec<String>>()
+                        .join("; ");
+                }
+            }
+
+            // The cookies exposed to the rules are left out as well
+            rio_request.headers.retain(|header| {
+                cookie_header_name(&header.name).is_none_or(|name| self.keeps_cookie(name))
+            });
+        }
+
+        rio_request
+    }
+
+    /// Whether the action cache keys differ from the whole redirection.io request.
+    pub fn filters_rio_requests(&self) -> bool {
+        !self.cookies.is_empty()
+            || !self.query_params.is_empty()
+            || !self.excluded_query_params.is_empty()
+    }
+
+    fn keeps_cookie(&self, name: &str) -> bool {
+        self.cookies.iter().any(|cookie| cookie == name)
+    }
+
+    fn keeps_query_param(&self, name: &str) -> bool {
+        (self.query_params.is_empty() || self.query_params.iter().any(|param| param == name))
+            && !self.excluded_query_params.iter().any(|param| param == name)
+    }
+
+    fn filter_query(&self, path_and_query: &str) -> String {
+        let (path, query) = match path_and_query.split_once('?') {
+            Some((path, query)) => (path, query),
+            None => return path_and_query.to_string(),
+        };
+
+        let params: Vec<&str> = query
+            .split('&')
+            .filter(|param| {
+                let name = param.split_once('=').map_or(*param, |(name, _)| name);
+
+                self.keeps_query_param(name)
+            })
+            .collect();
+
+        match params.is_empty() {
+            true => path.to_string(),
+            false => format!("{}?{}", path, params.join("&")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_cache_key_normalizes_the_url() {
+        let builder = CacheKeyBuilder::new();
+
+        assert_eq!(
+            builder.build(&Request::get("https://Example.org/foo?b=2&a=1")),
+            builder.build(&Request::get("https://example.org/foo?a=1&b=2"))
+        );
+        assert_eq!(
+            "GET https://example.org/foo?a=1&b=2",
+            builder.build(&Request::get("https://example.org/foo?b=2&a=1"))
+        );
+        assert_ne!(
+            builder.build(&Request::get("https://example.org/foo")),
+            builder.build(&Request::head("https://example.org/foo"))
+        );
+    }
+
+    #[test]
+    fn test_cache_key_includes_headers() {
+        let builder = CacheKeyBuilder::new()
+            .with_headers(vec!["X-AB-Bucket".to_string(), "X-Device".to_string()]);
+        let req = Request::get("https://example.org/").with_header("X-AB-Bucket", "b");
+
+        assert_eq!(
+            "GET https://example.org/\nx-ab-bucket: b\nx-device: ",
+            builder.build(&req)
+        );
+        assert_ne!(
+            builder.build(&req),
+            builder.build(&Request::get("https://example.org/").with_header("X-AB-Bucket", "a"))
+        );
+    }
+
+    #[test]
+    fn test_cache_key_includes_cookies() {
+        let builder = CacheKeyBuilder::new().with_cookies(vec!["currency".to_string()]);
+        let req =
+            Request::get("https://example.org/").with_header("Cookie", "session=abc; currency=EUR");
+
+        assert_eq!(
+            "GET https://example.org/\ncookie currency: EUR",
+            builder.build(&req)
+        );
+    }
+
+    #[test]
+    fn test_cache_key_filters_query_params() {
+        let builder = CacheKeyBuilder::new()
+            .without_query_params(vec!["utm_source".to_string(), "gclid".to_string()]);
+
+        assert_eq!(
+            "GET https://example.org/?page=2",
+            builder.build(&Request::get(
+                "https://example.org/?utm_source=mail&page=2&gclid=1"
+            ))
+        );
+
+        let builder = CacheKeyBuilder::new().with_query_params(vec!["page".to_string()]);
+
+        assert_eq!(
+            "GET https://example.org/?page=2",
+            builder.build(&Request::get("https://example.org/?page=2&sort=asc"))
+        );
+    }
+
+    #[test]
+    fn test_rio_request() {
+        let builder = CacheKeyBuilder::new()
+            .with_cookies(vec!["currency".to_string()])
+            .without_query_params(vec!["utm_source".to_string()]);
+        let mut rio_request =
+            RedirectionioRequest::from_str("https://example.org/foo?utm_source=mail&page=2")
+                .unwrap();
+        rio_request.add_header(
+            "Cookie".to_string(),
+            "session=abc; currency=EUR".to_string(),
+            false,
+        );
+        rio_request.add_header(
+            "x-redirectionio-cookie-session".to_string(),
+            "abc".to_string(),
+            false,
+        );
+        rio_request.add_header(
+            "x-redirectionio-cookie-currency".to_string(),
+            "EUR".to_string(),
+            false,
+        );
+
+        let rio_request = builder.rio_request(&rio_request);
+
+        assert_eq!(Some("/foo?page=2".to_string()), rio_request.path_and_query);
+        assert_eq!("currency=EUR", rio_request.headers[0].value);
+        assert_eq!(2, rio_request.headers.len());
+        assert_eq!(
+            "x-redirectionio-cookie-currency",
+            rio_request.headers[1].name
+        );
+        assert!(builder.filters_rio_requests());
+        assert!(!CacheKeyBuilder::new().filters_rio_requests());
+    }
+}
@@ -0,0 +1,88 @@
+use redirectionio::http::Header;
+use serde::Deserialize;
+
+/// Edge and browser caching directives for the responses of an action type, from the
+/// `cache_policies` entry.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CachePolicy {
+    pub surrogate_control: Option<String>,
+    pub cache_control: Option<String>,
+}
+
+impl CachePolicy {
+    /// Set the `Surrogate-Control` and `Cache-Control` headers of the policy.
+    ///
+    /// Rules win over the policy: a header changed by the header filters of the action is kept
+    /// as is.
+    pub fn apply(&self, backend_headers: &[Header], headers: &mut Vec<Header>) {
+        for (name, value) in [
+            ("Surrogate-Control", &self.surrogate_control),
+            ("Cache-Control", &self.cache_control),
+        ] {
+            let value = match value {
+                Some(value) => value,
+                None => continue,
+            };
+
+            if get(headers, name) != get(backend_headers, name) {
+                continue;
+            }
+
+            headers.retain(|header| !header.name.eq_ignore_ascii_case(name));
+            headers.push(Header {
+                name: name.to_string(),
+                value: value.clone(),
+            });
+        }
+    }
+}
+
+fn get<'a>(headers: &'a [Header], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| header.value.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, value: &str) -> Header {
+        Header {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_apply_policy() {
+        let policy = CachePolicy {
+            surrogate_control: Some("max-age=86400".to_string()),
+            cache_control: Some("max-age=60".to_string()),
+        };
+        let backend_headers = vec![header("cache-control", "no-cache")];
+        let mut headers = backend_headers.clone();
+
+        policy.apply(&backend_headers, &mut headers);
+
+        assert_eq!(Some("max-age=86400"), get(&headers, "Surrogate-Control"));
+        assert_eq!(Some("max-age=60"), get(&headers, "Cache-Control"));
+        assert_eq!(2, headers.len());
+    }
+
+    #[test]
+    fn test_rules_win_over_policy() {
+        let policy = CachePolicy {
+            surrogate_control: None,
+            cache_control: Some("max-age=60".to_string()),
+        };
+        let backend_headers = vec![header("Cache-Control", "no-cache")];
+        let mut headers = vec![header("Cache-Control", "max-age=3600")];
+
+        policy.apply(&backend_headers, &mut headers);
+
+        assert_eq!(Some("max-age=3600"), get(&headers, "Cache-Control"));
+        assert_eq!(None, get(&headers, "Surrogate-Control"));
+    }
+}
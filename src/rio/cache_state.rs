@@ -0,0 +1,79 @@
+use fastly::Response;
+
+/// Whether a backend response was served by the Fastly cache, from its `x-cache` header, or its
+/// `Age` header when there is none.
+///
+/// With shielding, `x-cache` lists the state of each POP, the last one being the edge.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheState {
+    Hit,
+    Miss,
+    Pass,
+}
+
+impl CacheState {
+    pub fn from_response(response: &Response) -> Option<CacheState> {
+        match response.get_header_str("x-cache") {
+            Some(x_cache) => parse(x_cache),
+            None => response
+                .get_header_str("age")
+                .and_then(|age| age.trim().parse::<u64>().ok())
+                .map(|age| match age {
+                    0 => CacheState::Miss,
+                    _ => CacheState::Hit,
+                }),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheState::Hit => "hit",
+            CacheState::Miss => "miss",
+            CacheState::Pass => "pass",
+        }
+    }
+}
+
+fn parse(x_cache: &str) -> Option<CacheState> {
+    let state = x_cache.rsplit(',').next()?.trim().to_uppercase();
+
+    match state.as_str() {
+        "HIT" | "HIT-STALE" | "HIT-SYNTH" => Some(CacheState::Hit),
+        "MISS" => Some(CacheState::Miss),
+        "PASS" => Some(CacheState::Pass),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Some(CacheState::Hit), parse("HIT"));
+        assert_eq!(Some(CacheState::Hit), parse("MISS, HIT"));
+        assert_eq!(Some(CacheState::Miss), parse("HIT, miss"));
+        assert_eq!(Some(CacheState::Pass), parse("PASS"));
+        assert_eq!(None, parse("unknown"));
+    }
+
+    #[test]
+    fn test_from_response() {
+        let response = |name: &str, value: &str| Response::new().with_header(name, value);
+
+        assert_eq!(
+            Some(CacheState::Hit),
+            CacheState::from_response(&response("x-cache", "MISS, HIT"))
+        );
+        assert_eq!(
+            Some(CacheState::Hit),
+            CacheState::from_response(&response("age", "120"))
+        );
+        assert_eq!(
+            Some(CacheState::Miss),
+            CacheState::from_response(&response("age", "0"))
+        );
+        assert_eq!(None, CacheState::from_response(&Response::new()));
+    }
+}
@@ -0,0 +1,56 @@
+use super::cache_key::CacheKeyBuilder;
+use super::request_sender::RequestSender;
+use fastly::experimental::RequestCacheKey;
+use fastly::http::request::SendError;
+use fastly::http::Method;
+use fastly::{Request, Response};
+
+pub const CACHE_KEY_HEADER: &str = "x-redirectionio-cache-key";
+
+/// Request sender going through the Fastly readthrough cache with a cache key built from the
+/// normalized url and the request headers the response varies on (A/B bucket, device class...).
+///
+/// As the cache sits below the application, cached responses are still filtered by the action.
+/// For debug requests, the cache key is sent back in the `x-redirectionio-cache-key` header.
+pub struct CachingRequestSender<'a> {
+    cache_key_builder: CacheKeyBuilder,
+    debug: bool,
+    inner: &'a dyn RequestSender,
+}
+
+impl<'a> CachingRequestSender<'a> {
+    pub(crate) fn new(
+        cache_key_builder: CacheKeyBuilder,
+        debug: bool,
+        inner: &'a dyn RequestSender,
+    ) -> CachingRequestSender<'a> {
+        CachingRequestSender {
+            cache_key_builder,
+            debug,
+            inner,
+        }
+    }
+}
+
+impl<'a> RequestSender for CachingRequestSender<'a> {
+    fn send(&self, mut req: Request, backend: String) -> Result<Response, SendError> {
+        // The cache would fetch the whole response with a GET, HEAD requests go to the backend
+        if req.get_method() == Method::HEAD {
+            req.set_pass(true);
+
+            return self.inner.send(req, backend);
+        }
+
+        let cache_key = self.cache_key_builder.build(&req);
+        req.set_cache_key_str(&cache_key);
+
+        let mut response = self.inner.send(req, backend)?;
+
+        if self.debug {
+            // Header values can not hold the line breaks separating the parts of the key
+            response.set_header(CACHE_KEY_HEADER, cache_key.replace('\n', "; "));
+        }
+
+        Ok(response)
+    }
+}
@@ -0,0 +1,104 @@
+use redirectionio::http::Header;
+
+/// Advertise the client hints of the `client_hints` entry, like `Sec-CH-UA-Mobile`, in the
+/// `Accept-CH` header of an HTML response.
+///
+/// Browsers then send these hints on the next requests, which forward them to the backend and to
+/// redirection.io for rule matching. Hints already advertised by the backend or the rules are
+/// kept.
+pub fn add_accept_ch(headers: &mut Vec<Header>, client_hints: &[String]) {
+    let is_html = headers.iter().any(|header| {
+        header.name.eq_ignore_ascii_case("Content-Type")
+            && header.value.to_lowercase().contains("text/html")
+    });
+
+    if client_hints.is_empty() || !is_html {
+        return;
+    }
+
+    let mut hints: Vec<String> = headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("Accept-CH"))
+        .flat_map(|header| header.value.split(','))
+        .map(|hint| hint.trim().to_string())
+        .filter(|hint| !hint.is_empty())
+        .collect();
+
+    for client_hint in client_hints {
+        if !hints
+            .iter()
+            .any(|hint| hint.eq_ignore_ascii_case(client_hint))
+        {
+            hints.push(client_hint.clone());
+        }
+    }
+
+    headers.retain(|header| !header.name.eq_ignore_ascii_case("Accept-CH"));
+    headers.push(Header {
+        name: "Accept-CH".to_string(),
+        value: hints.join(", "),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, value: &str) -> Header {
+        Header {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn accept_ch(headers: &[Header]) -> Vec<&str> {
+        headers
+            .iter()
+            .filter(|header| header.name == "Accept-CH")
+            .map(|header| header.value.as_str())
+            .collect()
+    }
+
+    fn client_hints() -> Vec<String> {
+        vec![
+            "Sec-CH-UA-Mobile".to_string(),
+            "Sec-CH-Viewport-Width".to_string(),
+        ]
+    }
+
+    #[test]
+    fn test_add_accept_ch() {
+        let mut headers = vec![header("Content-Type", "text/html; charset=utf-8")];
+
+        add_accept_ch(&mut headers, &client_hints());
+
+        assert_eq!(
+            vec!["Sec-CH-UA-Mobile, Sec-CH-Viewport-Width"],
+            accept_ch(&headers)
+        );
+    }
+
+    #[test]
+    fn test_merge_with_backend_hints() {
+        let mut headers = vec![
+            header("Content-Type", "text/html"),
+            header("accept-ch", "DPR, sec-ch-ua-mobile"),
+        ];
+
+        add_accept_ch(&mut headers, &client_hints());
+
+        assert_eq!(
+            vec!["DPR, sec-ch-ua-mobile, Sec-CH-Viewport-Width"],
+            accept_ch(&headers)
+        );
+    }
+
+    #[test]
+    fn test_skip_other_responses() {
+        let mut headers = vec![header("Content-Type", "image/png")];
+
+        add_accept_ch(&mut headers, &client_hints());
+
+        assert!(accept_ch(&headers).is_empty());
+    }
+}
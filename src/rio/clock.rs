@@ -0,0 +1,43 @@
+use std::time::{Duration, Instant};
+
+/// This trait provides the current time, so timings can be made deterministic in tests.
+pub trait Clock {
+    /// Number of milliseconds elapsed since the Unix epoch.
+    fn now(&self) -> u128;
+
+    /// Number of seconds elapsed since the Unix epoch, the precision of the states shared through
+    /// the KV Store.
+    fn now_secs(&self) -> u64 {
+        (self.now() / 1000) as u64
+    }
+
+    /// Monotonic time elapsed since the clock was created, for measuring durations: unlike `now`,
+    /// it never goes backward when the system clock is adjusted.
+    fn elapsed(&self) -> Duration;
+}
+
+/// Default implementation based on the system clock.
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub(crate) fn new() -> SystemClock {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> u128 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|time| time.as_millis())
+            .unwrap_or(0)
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
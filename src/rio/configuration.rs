@@ -1,49 +1,1151 @@
+use super::allowed_hosts::DEFAULT_UNKNOWN_HOST_STATUS;
+use super::cache_key::CacheKeyBuilder;
+use super::cache_policy::CachePolicy;
+use super::geo_policy::GeoPolicy;
+use super::html_injection::HtmlInjection;
+use super::link_headers::LinkHeader;
+use super::methods::DEFAULT_ALLOWED_METHODS;
+use super::streaming::StreamingMarkers;
+use super::synthetic::DEFAULT_SYNTHETIC_CACHE_CONTROL;
+use super::url_normalization::UrlNormalization;
+use super::vary::DEFAULT_MAX_VARY_HEADERS;
+use serde::Deserialize;
+use serde_json::from_str as json_decode;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+// Options which can be set in the `config_json` entry
+const OPTIONS: &[&str] = &[
+    "access_log_endpoint",
+    "action_cache_stale_while_revalidate",
+    "action_cache_ttl",
+    "add_action_metadata_headers",
+    "add_rule_ids_header",
+    "affinity_cookie",
+    "allowed_hosts",
+    "allowed_methods",
+    "api_endpoints",
+    "api_excluded_request_headers",
+    "api_recording",
+    "api_request_headers",
+    "backend_name",
+    "backend_weights",
+    "beacon_api",
+    "beacon_log_endpoint",
+    "beacon_origins",
+    "cache_key_cookies",
+    "cache_key_excluded_query_params",
+    "cache_key_headers",
+    "cache_key_query_params",
+    "cache_policies",
+    "chain_role",
+    "client_hints",
+    "disable_body_filter",
+    "esi",
+    "esi_backends",
+    "explain",
+    "failover_backend",
+    "fanout_backend",
+    "filterable_content_types",
+    "geo_policies",
+    "html_injections",
+    "image_optimizer_paths",
+    "instance_name",
+    "instance_name_paths",
+    "json_rewrites",
+    "link_headers",
+    "log_endpoint",
+    "log_format",
+    "log_level",
+    "log_status_classes",
+    "match_cookies",
+    "match_payload_headers",
+    "max_vary_headers",
+    "migration_mode",
+    "minimal_match_payload",
+    "mtls_backends",
+    "normalize_accept_encoding",
+    "on_api_error",
+    "origin_headers",
+    "origin_served_by",
+    "origin_user_agent_suffix",
+    "percentage_bucket",
+    "prerender",
+    "preserve_header_case",
+    "projects",
+    "protected_response_headers",
+    "range_stripped_paths",
+    "readthrough_cache",
+    "retry_non_idempotent",
+    "robots_txt",
+    "secondary_token",
+    "server_timing",
+    "shadow_backend",
+    "shadow_sample_rate",
+    "rule_ids_header_name",
+    "rule_metrics",
+    "soft_404_markers",
+    "soft_404_status",
+    "streaming_headers",
+    "streaming_paths",
+    "stripped_response_headers",
+    "synthetic_cache_control",
+    "token",
+    "unknown_host_status",
+    "url_normalization",
+    "vary_headers",
+];
+
+// Options of `config_json` holding JSON, like the flat entries
+const JSON_OPTIONS: &[&str] = &[
+    "api_endpoints",
+    "backend_weights",
+    "cache_policies",
+    "esi_backends",
+    "geo_policies",
+    "html_injections",
+    "instance_name_paths",
+    "json_rewrites",
+    "link_headers",
+    "mtls_backends",
+    "origin_headers",
+    "projects",
+];
+
+// Options of `config_json` holding a list, joined with commas like the flat entries
+const LIST_OPTIONS: &[&str] = &[
+    "allowed_hosts",
+    "allowed_methods",
+    "api_excluded_request_headers",
+    "api_request_headers",
+    "beacon_origins",
+    "cache_key_cookies",
+    "cache_key_excluded_query_params",
+    "cache_key_headers",
+    "cache_key_query_params",
+    "client_hints",
+    "disable_body_filter",
+    "filterable_content_types",
+    "image_optimizer_paths",
+    "log_status_classes",
+    "match_payload_headers",
+    "protected_response_headers",
+    "range_stripped_paths",
+    "soft_404_markers",
+    "streaming_headers",
+    "streaming_paths",
+    "stripped_response_headers",
+    "url_normalization",
+    "vary_headers",
+];
+
+const DEFAULT_RULE_IDS_HEADER_NAME: &str = "X-RedirectionIo-RuleIds";
+// Credentials are never sent to the redirection.io API, unless configured otherwise
+const DEFAULT_API_EXCLUDED_REQUEST_HEADERS: &str = "Authorization,Proxy-Authorization";
+
+// Configuration of the instance, built by its first request
+static CONFIGURATION: OnceLock<Result<Configuration, ConfigurationError>> = OnceLock::new();
+
+// Options of the `config_json` entry, parsed by the first lookup of the instance needing them
+static CONFIG_JSON: OnceLock<Result<HashMap<String, String>, String>> = OnceLock::new();
+
 #[readonly::make]
+#[derive(Clone)]
 pub struct Configuration {
     pub backend_name: String,
     pub token: String,
     pub instance_name: String,
     pub add_rule_ids_header: bool,
+    pub rule_ids_header_name: String,
+    pub add_action_metadata_headers: bool,
+    pub mtls_backends: HashMap<String, MtlsBackend>,
+    pub on_api_error: ApiErrorPolicy,
+    pub api_recording: ApiRecording,
+    pub api_endpoints: Vec<ApiEndpoint>,
+    pub failover_backend: Option<String>,
+    pub image_optimizer_paths: Vec<String>,
+    pub vary_headers: Vec<String>,
+    pub max_vary_headers: usize,
+    pub normalize_accept_encoding: bool,
+    pub readthrough_cache: bool,
+    pub cache_key_headers: Vec<String>,
+    pub cache_key_cookies: Vec<String>,
+    pub cache_key_query_params: Vec<String>,
+    pub cache_key_excluded_query_params: Vec<String>,
+    pub action_cache_ttl: u64,
+    pub action_cache_stale_while_revalidate: u64,
+    pub projects: HashMap<String, Project>,
+    pub cache_policies: HashMap<String, CachePolicy>,
+    pub prerender: bool,
+    pub link_headers: Vec<LinkHeader>,
+    pub html_injections: Vec<HtmlInjection>,
+    pub esi: bool,
+    pub esi_backends: HashMap<String, String>,
+    pub preserve_header_case: bool,
+    pub log_status_classes: Vec<u16>,
+    pub url_normalization: UrlNormalization,
+    pub allowed_hosts: Vec<String>,
+    pub unknown_host_status: u16,
+    pub allowed_methods: Vec<String>,
+    pub soft_404_markers: Vec<String>,
+    pub soft_404_status: bool,
+    pub geo_policies: HashMap<String, GeoPolicy>,
+    pub client_hints: Vec<String>,
+    pub backend_weights: HashMap<String, u32>,
+    pub affinity_cookie: bool,
+    pub secondary_token: Option<String>,
+    pub migration_mode: MigrationMode,
+    pub shadow_backend: Option<String>,
+    pub shadow_sample_rate: f64,
+    pub instance_name_paths: HashMap<String, String>,
+    pub range_stripped_paths: Vec<String>,
+    pub stripped_response_headers: Vec<String>,
+    pub protected_response_headers: Vec<String>,
+    pub api_request_headers: Vec<String>,
+    pub api_excluded_request_headers: Vec<String>,
+    pub percentage_bucket: bool,
+    pub synthetic_cache_control: String,
+    pub retry_non_idempotent: bool,
+    pub minimal_match_payload: bool,
+    pub match_payload_headers: Vec<String>,
+    pub server_timing: bool,
+    pub streaming_paths: Vec<String>,
+    pub streaming_headers: Vec<String>,
+    pub fanout_backend: Option<String>,
+    pub disable_body_filter: Vec<String>,
+    pub origin_served_by: bool,
+    pub origin_headers: HashMap<String, String>,
+    pub origin_user_agent_suffix: Option<String>,
+    pub filterable_content_types: Vec<String>,
+    pub json_rewrites: HashMap<String, HashMap<String, String>>,
+    pub beacon_log_endpoint: Option<String>,
+    pub beacon_api: bool,
+    pub beacon_origins: Vec<String>,
+    pub explain: bool,
+    pub rule_metrics: bool,
+    pub chain_role: ChainRole,
+    pub match_cookies: bool,
+}
+
+/// Mutual TLS settings of a backend, keyed by backend name in the `mtls_backends` entry.
+///
+/// The certificate and the key are the names of Secret Store entries holding them in PEM format.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MtlsBackend {
+    pub target: String,
+    pub host: Option<String>,
+    pub certificate: String,
+    pub key: String,
+}
+
+/// A redirection.io project, keyed by path prefix in the `projects` entry.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Project {
+    pub token: String,
+    pub instance_name: Option<String>,
+}
+
+/// A redirection.io API endpoint, from the `api_endpoints` entry.
+///
+/// `regions` are prefixes of the Fastly region names (`EU`, `US`, `Asia`...) the endpoint should
+/// be preferred for.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiEndpoint {
+    pub url: String,
+    pub backend: String,
+    #[serde(default)]
+    pub regions: Vec<String>,
+}
+
+/// What to do when the redirection.io API can not provide an action.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApiErrorPolicy {
+    /// Forward the request to the backend with no changes
+    Pass,
+    /// Use the last action received for the same request, if any
+    ServeCachedAction,
+    /// Answer with a 503 synthetic response
+    FailClosed,
+    /// Call the API a second time before forwarding the request
+    RetryOnce,
+}
+
+/// Whether the `action` responses are recorded to, or replayed from, the KV Store.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApiRecording {
+    /// Always call the API
+    Off,
+    /// Call the API and store its responses
+    Record,
+    /// Only serve stored responses, the API is never called
+    Replay,
+}
+
+/// How the secondary project of the `secondary_token` entry is used during a migration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MigrationMode {
+    /// Send the logs to both projects
+    DualWrite,
+    /// Fetch the actions of the secondary project too, and report the differences
+    Shadow,
+}
+
+/// Which of two chained services running this worker applies the rules.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChainRole {
+    /// Apply the rules, and sign the requests forwarded to the backend so a downstream service
+    /// running this worker lets them through
+    Authoritative,
+    /// Forward the requests to the backend, the rules are applied by a downstream service
+    Delegate,
 }
 
 impl Configuration {
-    pub(crate) fn new(
-        backend_name: Option<String>,
-        token: Option<String>,
-        instance_name: Option<String>,
-        add_rule_ids_header: Option<String>,
-    ) -> Result<Self, ConfigurationError> {
-        let backend_name = match backend_name {
+    /// Build the configuration from a lookup function, falling back to the options of its
+    /// `config_json` entry. The worker goes through `load`, which parses `config_json` once per
+    /// instance.
+    #[cfg(test)]
+    pub(crate) fn new<F>(get: F) -> Result<Self, ConfigurationError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let options = match get("config_json") {
+            Some(config_json) => parse_config_json(&config_json),
+            None => Ok(HashMap::new()),
+        };
+
+        Self::with_options(get, &options)
+    }
+
+    /// Build the configuration from a lookup function, usually backed by the Config Store, and the
+    /// options of its `config_json` entry.
+    fn with_options<F>(
+        get: F,
+        options: &Result<HashMap<String, String>, String>,
+    ) -> Result<Self, ConfigurationError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let options = match options {
+            Ok(options) => options,
+            Err(error) => {
+                return Err(ConfigurationError::InvalidConfigJson(
+                    get("backend_name"),
+                    error.clone(),
+                ))
+            }
+        };
+        let get = |key: &str| get(key).or_else(|| options.get(key).cloned());
+
+        let backend_name = match get("backend_name") {
             Some(backend_name) => backend_name,
             None => return Err(ConfigurationError::MissingBackendName),
         };
 
-        let token = match token {
+        let token = match get("token") {
             Some(token) => token,
             None => return Err(ConfigurationError::MissingToken(backend_name)),
         };
 
-        let instance_name = match instance_name {
+        let instance_name = match get("instance_name") {
             Some(instance_name) => instance_name,
             None => return Err(ConfigurationError::MissingInstanceName(backend_name)),
         };
 
-        let add_rule_ids_header = match add_rule_ids_header {
+        let add_rule_ids_header = match get("add_rule_ids_header") {
             Some(add_rule_ids_header) => add_rule_ids_header == "true",
             None => false,
         };
 
+        let rule_ids_header_name = match get("rule_ids_header_name") {
+            Some(rule_ids_header_name) if !rule_ids_header_name.is_empty() => rule_ids_header_name,
+            _ => DEFAULT_RULE_IDS_HEADER_NAME.to_string(),
+        };
+
+        let add_action_metadata_headers = match get("add_action_metadata_headers") {
+            Some(add_action_metadata_headers) => add_action_metadata_headers == "true",
+            None => false,
+        };
+
+        let mtls_backends = match get("mtls_backends") {
+            Some(mtls_backends) => match json_decode(&mtls_backends) {
+                Ok(mtls_backends) => mtls_backends,
+                Err(error) => {
+                    return Err(ConfigurationError::InvalidMtlsBackends(
+                        backend_name,
+                        error.to_string(),
+                    ))
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        let on_api_error = match get("on_api_error").as_deref() {
+            None | Some("") | Some("pass") => ApiErrorPolicy::Pass,
+            Some("serve_cached_action") => ApiErrorPolicy::ServeCachedAction,
+            Some("fail_closed_503") => ApiErrorPolicy::FailClosed,
+            Some("retry_once") => ApiErrorPolicy::RetryOnce,
+            Some(on_api_error) => {
+                return Err(ConfigurationError::InvalidApiErrorPolicy(
+                    backend_name,
+                    on_api_error.to_string(),
+                ))
+            }
+        };
+
+        let api_recording = match get("api_recording").as_deref() {
+            None | Some("") | Some("off") => ApiRecording::Off,
+            Some("record") => ApiRecording::Record,
+            Some("replay") => ApiRecording::Replay,
+            Some(api_recording) => {
+                return Err(ConfigurationError::InvalidApiRecording(
+                    backend_name,
+                    api_recording.to_string(),
+                ))
+            }
+        };
+
+        let api_endpoints = match get("api_endpoints") {
+            Some(api_endpoints) => match json_decode(&api_endpoints) {
+                Ok(api_endpoints) => api_endpoints,
+                Err(error) => {
+                    return Err(ConfigurationError::InvalidApiEndpoints(
+                        backend_name,
+                        error.to_string(),
+                    ))
+                }
+            },
+            None => Vec::new(),
+        };
+
+        let failover_backend = get("failover_backend").filter(|backend| !backend.is_empty());
+
+        let image_optimizer_paths = match get("image_optimizer_paths") {
+            Some(image_optimizer_paths) => split_list(&image_optimizer_paths),
+            None => Vec::new(),
+        };
+
+        let vary_headers = match get("vary_headers") {
+            Some(vary_headers) => split_list(&vary_headers),
+            None => Vec::new(),
+        };
+
+        let max_vary_headers = match get("max_vary_headers") {
+            Some(max_vary_headers) => match max_vary_headers.parse() {
+                Ok(max_vary_headers) => max_vary_headers,
+                Err(_) => {
+                    return Err(ConfigurationError::InvalidMaxVaryHeaders(
+                        backend_name,
+                        max_vary_headers,
+                    ))
+                }
+            },
+            None => DEFAULT_MAX_VARY_HEADERS,
+        };
+
+        let normalize_accept_encoding = match get("normalize_accept_encoding") {
+            Some(normalize_accept_encoding) => normalize_accept_encoding == "true",
+            None => false,
+        };
+
+        let readthrough_cache = match get("readthrough_cache") {
+            Some(readthrough_cache) => readthrough_cache == "true",
+            None => false,
+        };
+
+        let prerender = match get("prerender") {
+            Some(prerender) => prerender == "true",
+            None => false,
+        };
+
+        let cache_key_headers = match get("cache_key_headers") {
+            Some(cache_key_headers) => split_list(&cache_key_headers),
+            None => Vec::new(),
+        };
+
+        let cache_key_cookies = match get("cache_key_cookies") {
+            Some(cache_key_cookies) => split_list(&cache_key_cookies),
+            None => Vec::new(),
+        };
+
+        let cache_key_query_params = match get("cache_key_query_params") {
+            Some(cache_key_query_params) => split_list(&cache_key_query_params),
+            None => Vec::new(),
+        };
+
+        let cache_key_excluded_query_params = match get("cache_key_excluded_query_params") {
+            Some(cache_key_excluded_query_params) => split_list(&cache_key_excluded_query_params),
+            None => Vec::new(),
+        };
+
+        let action_cache_ttl = match get("action_cache_ttl") {
+            Some(value) => match value.parse() {
+                Ok(action_cache_ttl) => action_cache_ttl,
+                Err(_) => {
+                    return Err(ConfigurationError::InvalidDuration(
+                        backend_name,
+                        "action_cache_ttl",
+                        value,
+                    ))
+                }
+            },
+            None => 0,
+        };
+
+        let action_cache_stale_while_revalidate = match get("action_cache_stale_while_revalidate") {
+            Some(value) => match value.parse() {
+                Ok(action_cache_stale_while_revalidate) => action_cache_stale_while_revalidate,
+                Err(_) => {
+                    return Err(ConfigurationError::InvalidDuration(
+                        backend_name,
+                        "action_cache_stale_while_revalidate",
+                        value,
+                    ))
+                }
+            },
+            None => 0,
+        };
+
+        let projects = match get("projects") {
+            Some(projects) => match json_decode(&projects) {
+                Ok(projects) => projects,
+                Err(error) => {
+                    return Err(ConfigurationError::InvalidProjects(
+                        backend_name,
+                        error.to_string(),
+                    ))
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        let cache_policies = match get("cache_policies") {
+            Some(cache_policies) => match json_decode(&cache_policies) {
+                Ok(cache_policies) => cache_policies,
+                Err(error) => {
+                    return Err(ConfigurationError::InvalidCachePolicies(
+                        backend_name,
+                        error.to_string(),
+                    ))
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        let link_headers = match get("link_headers") {
+            Some(link_headers) => match json_decode(&link_headers) {
+                Ok(link_headers) => link_headers,
+                Err(error) => {
+                    return Err(ConfigurationError::InvalidLinkHeaders(
+                        backend_name,
+                        error.to_string(),
+                    ))
+                }
+            },
+            None => Vec::new(),
+        };
+
+        let html_injections = match get("html_injections") {
+            Some(html_injections) => match json_decode(&html_injections) {
+                Ok(html_injections) => html_injections,
+                Err(error) => {
+                    return Err(ConfigurationError::InvalidHtmlInjections(
+                        backend_name,
+                        error.to_string(),
+                    ))
+                }
+            },
+            None => Vec::new(),
+        };
+
+        let esi = match get("esi") {
+            Some(esi) => esi == "true",
+            None => false,
+        };
+
+        let esi_backends = match get("esi_backends") {
+            Some(esi_backends) => match json_decode(&esi_backends) {
+                Ok(esi_backends) => esi_backends,
+                Err(error) => {
+                    return Err(ConfigurationError::InvalidEsiBackends(
+                        backend_name,
+                        error.to_string(),
+                    ))
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        let preserve_header_case = match get("preserve_header_case") {
+            Some(preserve_header_case) => preserve_header_case == "true",
+            None => false,
+        };
+
+        let log_status_classes = match get("log_status_classes") {
+            Some(log_status_classes) => match parse_status_classes(&log_status_classes) {
+                Some(log_status_classes) => log_status_classes,
+                None => {
+                    return Err(ConfigurationError::InvalidLogStatusClasses(
+                        backend_name,
+                        log_status_classes,
+                    ))
+                }
+            },
+            None => Vec::new(),
+        };
+
+        let url_normalization = match get("url_normalization") {
+            Some(url_normalization) => {
+                match UrlNormalization::parse(&split_list(&url_normalization)) {
+                    Ok(url_normalization) => url_normalization,
+                    Err(_) => {
+                        return Err(ConfigurationError::InvalidUrlNormalization(
+                            backend_name,
+                            url_normalization,
+                        ))
+                    }
+                }
+            }
+            None => UrlNormalization::default(),
+        };
+
+        let allowed_hosts = match get("allowed_hosts") {
+            Some(allowed_hosts) => split_list(&allowed_hosts),
+            None => Vec::new(),
+        };
+
+        let allowed_methods = split_list(
+            &get("allowed_methods").unwrap_or_else(|| DEFAULT_ALLOWED_METHODS.to_string()),
+        );
+
+        let unknown_host_status = match get("unknown_host_status") {
+            Some(unknown_host_status) => match unknown_host_status.as_str() {
+                "404" => 404,
+                "421" => 421,
+                _ => {
+                    return Err(ConfigurationError::InvalidUnknownHostStatus(
+                        backend_name,
+                        unknown_host_status,
+                    ))
+                }
+            },
+            None => DEFAULT_UNKNOWN_HOST_STATUS,
+        };
+
+        let soft_404_markers = match get("soft_404_markers") {
+            Some(soft_404_markers) => split_list(&soft_404_markers),
+            None => Vec::new(),
+        };
+
+        let soft_404_status = match get("soft_404_status") {
+            Some(soft_404_status) => soft_404_status == "true",
+            None => false,
+        };
+
+        let geo_policies = match get("geo_policies") {
+            Some(geo_policies) => match json_decode(&geo_policies) {
+                Ok(geo_policies) => geo_policies,
+                Err(error) => {
+                    return Err(ConfigurationError::InvalidGeoPolicies(
+                        backend_name,
+                        error.to_string(),
+                    ))
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        let client_hints = match get("client_hints") {
+            Some(client_hints) => split_list(&client_hints),
+            None => Vec::new(),
+        };
+
+        let backend_weights = match get("backend_weights") {
+            Some(backend_weights) => match json_decode(&backend_weights) {
+                Ok(backend_weights) => backend_weights,
+                Err(error) => {
+                    return Err(ConfigurationError::InvalidBackendWeights(
+                        backend_name,
+                        error.to_string(),
+                    ))
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        let affinity_cookie = match get("affinity_cookie") {
+            Some(affinity_cookie) => affinity_cookie == "true",
+            None => false,
+        };
+
+        let secondary_token = get("secondary_token").filter(|token| !token.is_empty());
+
+        let migration_mode = match get("migration_mode").as_deref() {
+            None | Some("") | Some("dual_write") => MigrationMode::DualWrite,
+            Some("shadow") => MigrationMode::Shadow,
+            Some(migration_mode) => {
+                return Err(ConfigurationError::InvalidMigrationMode(
+                    backend_name,
+                    migration_mode.to_string(),
+                ))
+            }
+        };
+
+        let shadow_backend = get("shadow_backend").filter(|backend| !backend.is_empty());
+
+        let shadow_sample_rate = match get("shadow_sample_rate") {
+            Some(shadow_sample_rate) => match shadow_sample_rate.parse::<f64>() {
+                Ok(rate) if (0.0..=1.0).contains(&rate) => rate,
+                _ => {
+                    return Err(ConfigurationError::InvalidShadowSampleRate(
+                        backend_name,
+                        shadow_sample_rate,
+                    ))
+                }
+            },
+            None => 1.0,
+        };
+
+        let range_stripped_paths = match get("range_stripped_paths") {
+            Some(range_stripped_paths) => split_list(&range_stripped_paths),
+            None => Vec::new(),
+        };
+
+        let stripped_response_headers = match get("stripped_response_headers") {
+            Some(stripped_response_headers) => split_list(&stripped_response_headers),
+            None => Vec::new(),
+        };
+
+        let protected_response_headers = match get("protected_response_headers") {
+            Some(protected_response_headers) => split_list(&protected_response_headers),
+            None => Vec::new(),
+        };
+
+        let api_request_headers = match get("api_request_headers") {
+            Some(api_request_headers) => split_list(&api_request_headers),
+            None => Vec::new(),
+        };
+
+        let api_excluded_request_headers = split_list(
+            &get("api_excluded_request_headers")
+                .unwrap_or_else(|| DEFAULT_API_EXCLUDED_REQUEST_HEADERS.to_string()),
+        );
+
+        let percentage_bucket = match get("percentage_bucket") {
+            Some(percentage_bucket) => percentage_bucket == "true",
+            None => false,
+        };
+
+        let synthetic_cache_control = get("synthetic_cache_control")
+            .unwrap_or_else(|| DEFAULT_SYNTHETIC_CACHE_CONTROL.to_string());
+
+        let retry_non_idempotent = match get("retry_non_idempotent") {
+            Some(retry_non_idempotent) => retry_non_idempotent == "true",
+            None => false,
+        };
+
+        let minimal_match_payload = match get("minimal_match_payload") {
+            Some(minimal_match_payload) => minimal_match_payload == "true",
+            None => false,
+        };
+
+        let match_payload_headers = match get("match_payload_headers") {
+            Some(match_payload_headers) => split_list(&match_payload_headers),
+            None => Vec::new(),
+        };
+
+        let server_timing = match get("server_timing") {
+            Some(server_timing) => server_timing == "true",
+            None => false,
+        };
+
+        let streaming_paths = match get("streaming_paths") {
+            Some(streaming_paths) => split_list(&streaming_paths),
+            None => Vec::new(),
+        };
+
+        let streaming_headers = match get("streaming_headers") {
+            Some(streaming_headers) => split_list(&streaming_headers),
+            None => Vec::new(),
+        };
+
+        let fanout_backend = get("fanout_backend").filter(|backend| !backend.is_empty());
+
+        let disable_body_filter = match get("disable_body_filter") {
+            Some(disable_body_filter) => split_list(&disable_body_filter),
+            None => Vec::new(),
+        };
+
+        let origin_served_by = get("origin_served_by").unwrap_or_default() == "true";
+
+        let origin_headers = match get("origin_headers") {
+            Some(origin_headers) => match json_decode(&origin_headers) {
+                Ok(origin_headers) => origin_headers,
+                Err(error) => {
+                    return Err(ConfigurationError::InvalidOriginHeaders(
+                        backend_name,
+                        error.to_string(),
+                    ))
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        let origin_user_agent_suffix =
+            get("origin_user_agent_suffix").filter(|suffix| !suffix.is_empty());
+
+        let filterable_content_types = match get("filterable_content_types") {
+            Some(filterable_content_types) => split_list(&filterable_content_types),
+            None => Vec::new(),
+        };
+
+        let json_rewrites = match get("json_rewrites") {
+            Some(json_rewrites) => match json_decode(&json_rewrites) {
+                Ok(json_rewrites) => json_rewrites,
+                Err(error) => {
+                    return Err(ConfigurationError::InvalidJsonRewrites(
+                        backend_name,
+                        error.to_string(),
+                    ))
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        let beacon_log_endpoint =
+            get("beacon_log_endpoint").filter(|endpoint| !endpoint.is_empty());
+
+        let beacon_api = get("beacon_api").unwrap_or_default() == "true";
+
+        let beacon_origins = match get("beacon_origins") {
+            Some(beacon_origins) => split_list(&beacon_origins),
+            None => Vec::new(),
+        };
+
+        let explain = get("explain").unwrap_or_default() == "true";
+        let rule_metrics = get("rule_metrics").unwrap_or_default() == "true";
+        let match_cookies = get("match_cookies").unwrap_or_default() == "true";
+
+        let chain_role = match get("chain_role").as_deref() {
+            None | Some("") | Some("authoritative") => ChainRole::Authoritative,
+            Some("delegate") => ChainRole::Delegate,
+            Some(chain_role) => {
+                return Err(ConfigurationError::InvalidChainRole(
+                    backend_name,
+                    chain_role.to_string(),
+                ))
+            }
+        };
+
+        let instance_name_paths = match get("instance_name_paths") {
+            Some(instance_name_paths) => match json_decode(&instance_name_paths) {
+                Ok(instance_name_paths) => instance_name_paths,
+                Err(error) => {
+                    return Err(ConfigurationError::InvalidInstanceNamePaths(
+                        backend_name,
+                        error.to_string(),
+                    ))
+                }
+            },
+            None => HashMap::new(),
+        };
+
         Ok(Configuration {
             backend_name,
             token,
             instance_name,
             add_rule_ids_header,
+            rule_ids_header_name,
+            add_action_metadata_headers,
+            mtls_backends,
+            on_api_error,
+            api_recording,
+            api_endpoints,
+            failover_backend,
+            image_optimizer_paths,
+            vary_headers,
+            max_vary_headers,
+            normalize_accept_encoding,
+            readthrough_cache,
+            cache_key_headers,
+            cache_key_cookies,
+            cache_key_query_params,
+            cache_key_excluded_query_params,
+            action_cache_ttl,
+            action_cache_stale_while_revalidate,
+            projects,
+            cache_policies,
+            prerender,
+            link_headers,
+            html_injections,
+            esi,
+            esi_backends,
+            preserve_header_case,
+            log_status_classes,
+            url_normalization,
+            allowed_hosts,
+            unknown_host_status,
+            allowed_methods,
+            soft_404_markers,
+            soft_404_status,
+            geo_policies,
+            client_hints,
+            backend_weights,
+            affinity_cookie,
+            secondary_token,
+            migration_mode,
+            shadow_backend,
+            shadow_sample_rate,
+            instance_name_paths,
+            range_stripped_paths,
+            stripped_response_headers,
+            protected_response_headers,
+            api_request_headers,
+            api_excluded_request_headers,
+            percentage_bucket,
+            synthetic_cache_control,
+            retry_non_idempotent,
+            minimal_match_payload,
+            match_payload_headers,
+            server_timing,
+            streaming_paths,
+            streaming_headers,
+            fanout_backend,
+            disable_body_filter,
+            origin_served_by,
+            origin_headers,
+            origin_user_agent_suffix,
+            filterable_content_types,
+            json_rewrites,
+            beacon_log_endpoint,
+            beacon_api,
+            beacon_origins,
+            explain,
+            rule_metrics,
+            chain_role,
+            match_cookies,
+        })
+    }
+
+    /// Whether the beacons of the frontend snippets are collected, to a log endpoint or the API.
+    pub fn collects_beacons(&self) -> bool {
+        self.beacon_log_endpoint.is_some() || self.beacon_api
+    }
+
+    /// Builder of the readthrough and action cache keys, from the `cache_key_*` entries.
+    pub fn cache_key_builder(&self) -> CacheKeyBuilder {
+        CacheKeyBuilder::new()
+            .with_headers(self.cache_key_headers.clone())
+            .with_cookies(self.cache_key_cookies.clone())
+            .with_query_params(self.cache_key_query_params.clone())
+            .without_query_params(self.cache_key_excluded_query_params.clone())
+    }
+
+    /// Use the token and the instance name of the project matching the longest prefix of the
+    /// path, if any.
+    pub fn with_project_for(mut self, path: &str) -> Self {
+        let project = self
+            .projects
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, project)| project.clone());
+
+        if let Some(project) = project {
+            self.token = project.token;
+
+            if let Some(instance_name) = project.instance_name {
+                self.instance_name = instance_name;
+            }
+        }
+
+        self
+    }
+
+    /// Markers of the requests relayed without buffering, from the `streaming_*` entries.
+    pub fn streaming_markers(&self) -> StreamingMarkers {
+        StreamingMarkers::new(&self.streaming_paths, &self.streaming_headers)
+    }
+
+    /// Add the rule ids header to the response of a debug request asking for it.
+    pub fn with_rule_ids_header(mut self, requested: bool) -> Self {
+        self.add_rule_ids_header |= requested;
+
+        self
+    }
+
+    /// Send the `Server-Timing` header to debug requests.
+    pub fn with_server_timing(mut self, requested: bool) -> Self {
+        self.server_timing |= requested;
+
+        self
+    }
+
+    /// Use the instance name requested by a trusted header, if any, or the one of the longest
+    /// prefix of the path in the `instance_name_paths` entry, to tag canary traffic.
+    pub fn with_instance_name_for(mut self, path: &str, requested: Option<String>) -> Self {
+        let instance_name = requested.or_else(|| {
+            self.instance_name_paths
+                .iter()
+                .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+                .max_by_key(|(prefix, _)| prefix.len())
+                .map(|(_, instance_name)| instance_name.clone())
+        });
+
+        if let Some(instance_name) = instance_name {
+            self.instance_name = instance_name;
+        }
+
+        self
+    }
+}
+
+/// Split a comma separated configuration value, ignoring empty items.
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Parse a list of status classes, like `3xx,4xx,5xx`, into their first digits.
+fn parse_status_classes(value: &str) -> Option<Vec<u16>> {
+    split_list(value)
+        .iter()
+        .map(|class| match class.to_lowercase().as_bytes() {
+            [digit @ b'1'..=b'5', b'x', b'x'] => Some((digit - b'0') as u16),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Build the configuration once per instance: when an instance serves several requests, they get
+/// the same configuration, or the same error, without parsing the Config Store entries again.
+pub fn load<F>(get: F) -> Result<Configuration, ConfigurationError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    CONFIGURATION
+        .get_or_init(|| Configuration::with_options(&get, config_json_options(&get)))
+        .clone()
+}
+
+/// Read the configuration from the flat Config Store entries, falling back to the options of the
+/// `config_json` entry, a JSON object holding the whole configuration.
+///
+/// The `config_json` entry is only parsed once per instance, by the first lookup it answers.
+/// Invalid `config_json` entries are ignored here, and reported by `load`.
+pub fn with_config_json<F>(get: F) -> impl Fn(&str) -> Option<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    move |key| {
+        get(key).or_else(|| {
+            let options = config_json_options(&get).as_ref().ok()?;
+
+            options.get(key).cloned()
         })
     }
 }
 
+fn config_json_options<F>(get: &F) -> &'static Result<HashMap<String, String>, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    CONFIG_JSON.get_or_init(|| match get("config_json") {
+        Some(config_json) => parse_config_json(&config_json),
+        None => Ok(HashMap::new()),
+    })
+}
+
+/// Parse the `config_json` entry into flat options, in the format of the flat entries: strings,
+/// booleans and numbers are kept as text, lists are joined with commas, and the objects and lists
+/// of the options holding JSON are serialized back to JSON.
+pub fn parse_config_json(config_json: &str) -> Result<HashMap<String, String>, String> {
+    let options = match serde_json::from_str(config_json) {
+        Ok(Value::Object(options)) => options,
+        Ok(_) => return Err("expected an object".to_string()),
+        Err(error) => return Err(error.to_string()),
+    };
+
+    let mut flat_options = HashMap::new();
+
+    for (name, value) in options {
+        if !OPTIONS.contains(&name.as_str()) {
+            return Err(format!("unknown option \"{}\"", name));
+        }
+
+        let value = match value {
+            Value::Null => continue,
+            Value::String(value) => value,
+            value if JSON_OPTIONS.contains(&name.as_str()) => match value {
+                Value::Object(_) | Value::Array(_) => value.to_string(),
+                _ => return Err(format!("option \"{}\" expects a JSON object or list", name)),
+            },
+            Value::Array(items) if LIST_OPTIONS.contains(&name.as_str()) => {
+                match items.iter().map(list_item).collect::<Option<Vec<String>>>() {
+                    Some(items) => items.join(","),
+                    None => return Err(format!("option \"{}\" expects a list of strings", name)),
+                }
+            }
+            Value::Bool(_) | Value::Number(_) => value.to_string(),
+            _ => return Err(format!("option \"{}\" expects a string", name)),
+        };
+
+        flat_options.insert(name, value);
+    }
+
+    Ok(flat_options)
+}
+
+fn list_item(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+impl ConfigurationError {
+    /// Name of the backend to forward requests to, when the configuration is too broken to run
+    /// the worker but still allows to reach the backend.
+    pub fn backend_name(&self) -> Option<String> {
+        match self {
+            ConfigurationError::MissingBackendName => None,
+            ConfigurationError::InvalidConfigJson(backend_name, _) => backend_name.clone(),
+            ConfigurationError::MissingToken(backend_name)
+            | ConfigurationError::MissingInstanceName(backend_name)
+            | ConfigurationError::MissingAddRuleIdsHeader(backend_name)
+            | ConfigurationError::InvalidMtlsBackends(backend_name, _)
+            | ConfigurationError::InvalidApiErrorPolicy(backend_name, _)
+            | ConfigurationError::InvalidApiRecording(backend_name, _)
+            | ConfigurationError::InvalidChainRole(backend_name, _)
+            | ConfigurationError::InvalidApiEndpoints(backend_name, _)
+            | ConfigurationError::InvalidMaxVaryHeaders(backend_name, _)
+            | ConfigurationError::InvalidInstanceNamePaths(backend_name, _)
+            | ConfigurationError::InvalidOriginHeaders(backend_name, _)
+            | ConfigurationError::InvalidJsonRewrites(backend_name, _)
+            | ConfigurationError::InvalidShadowSampleRate(backend_name, _)
+            | ConfigurationError::InvalidMigrationMode(backend_name, _)
+            | ConfigurationError::InvalidBackendWeights(backend_name, _)
+            | ConfigurationError::InvalidGeoPolicies(backend_name, _)
+            | ConfigurationError::InvalidUnknownHostStatus(backend_name, _)
+            | ConfigurationError::InvalidUrlNormalization(backend_name, _)
+            | ConfigurationError::InvalidLogStatusClasses(backend_name, _)
+            | ConfigurationError::InvalidDuration(backend_name, _, _)
+            | ConfigurationError::InvalidProjects(backend_name, _)
+            | ConfigurationError::InvalidCachePolicies(backend_name, _)
+            | ConfigurationError::InvalidLinkHeaders(backend_name, _)
+            | ConfigurationError::InvalidHtmlInjections(backend_name, _)
+            | ConfigurationError::InvalidEsiBackends(backend_name, _) => Some(backend_name.clone()),
+        }
+    }
+}
+
 quick_error! {
-    #[derive(Debug)]
+    #[derive(Debug, Clone)]
     pub enum ConfigurationError {
         MissingBackendName {
             display("missing \"backend_name\"")
@@ -57,5 +1159,917 @@ quick_error! {
         MissingAddRuleIdsHeader (backend_name: String) {
             display("missing \"add_rule_ids_header\"")
         }
+        InvalidMtlsBackends (backend_name: String, error: String) {
+            display("invalid \"mtls_backends\": {}", error)
+        }
+        InvalidApiErrorPolicy (backend_name: String, value: String) {
+            display("invalid \"on_api_error\" value \"{}\"", value)
+        }
+        InvalidApiRecording (backend_name: String, value: String) {
+            display("invalid \"api_recording\" value \"{}\"", value)
+        }
+        InvalidChainRole (backend_name: String, value: String) {
+            display("invalid \"chain_role\" value \"{}\"", value)
+        }
+        InvalidApiEndpoints (backend_name: String, error: String) {
+            display("invalid \"api_endpoints\": {}", error)
+        }
+        InvalidMaxVaryHeaders (backend_name: String, value: String) {
+            display("invalid \"max_vary_headers\" value \"{}\"", value)
+        }
+        InvalidLogStatusClasses (backend_name: String, value: String) {
+            display("invalid \"log_status_classes\" value \"{}\", expected a list like \"3xx,4xx,5xx\"", value)
+        }
+        InvalidUrlNormalization (backend_name: String, value: String) {
+            display("invalid \"url_normalization\" value \"{}\", expected a list of \"percent_encoding\" and \"case_folding\"", value)
+        }
+        InvalidUnknownHostStatus (backend_name: String, value: String) {
+            display("invalid \"unknown_host_status\" value \"{}\", expected \"421\" or \"404\"", value)
+        }
+        InvalidGeoPolicies (backend_name: String, error: String) {
+            display("invalid \"geo_policies\": {}", error)
+        }
+        InvalidBackendWeights (backend_name: String, error: String) {
+            display("invalid \"backend_weights\": {}", error)
+        }
+        InvalidMigrationMode (backend_name: String, value: String) {
+            display("invalid \"migration_mode\" value \"{}\"", value)
+        }
+        InvalidShadowSampleRate (backend_name: String, value: String) {
+            display("invalid \"shadow_sample_rate\" value \"{}\", expected a number between 0 and 1", value)
+        }
+        InvalidInstanceNamePaths (backend_name: String, error: String) {
+            display("invalid \"instance_name_paths\": {}", error)
+        }
+        InvalidOriginHeaders (backend_name: String, error: String) {
+            display("invalid \"origin_headers\": {}", error)
+        }
+        InvalidJsonRewrites (backend_name: String, error: String) {
+            display("invalid \"json_rewrites\": {}", error)
+        }
+        InvalidConfigJson (backend_name: Option<String>, error: String) {
+            display("invalid \"config_json\": {}", error)
+        }
+        InvalidProjects (backend_name: String, error: String) {
+            display("invalid \"projects\": {}", error)
+        }
+        InvalidCachePolicies (backend_name: String, error: String) {
+            display("invalid \"cache_policies\": {}", error)
+        }
+        InvalidLinkHeaders (backend_name: String, error: String) {
+            display("invalid \"link_headers\": {}", error)
+        }
+        InvalidHtmlInjections (backend_name: String, error: String) {
+            display("invalid \"html_injections\": {}", error)
+        }
+        InvalidEsiBackends (backend_name: String, error: String) {
+            display("invalid \"esi_backends\": {}", error)
+        }
+        InvalidDuration (backend_name: String, name: &'static str, value: String) {
+            display("invalid \"{}\" value \"{}\", expected a number of seconds", name, value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_configuration(values: &[(&str, &str)]) -> Result<Configuration, ConfigurationError> {
+        let values: HashMap<String, String> = values
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        Configuration::new(|key| values.get(key).cloned())
+    }
+
+    #[test]
+    fn test_minimal_configuration() {
+        let configuration = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+        ])
+        .unwrap();
+
+        assert_eq!("backend_host", configuration.backend_name);
+        assert_eq!("token", configuration.token);
+        assert_eq!("instance", configuration.instance_name);
+        assert!(!configuration.add_rule_ids_header);
+        assert_eq!(
+            DEFAULT_RULE_IDS_HEADER_NAME,
+            configuration.rule_ids_header_name
+        );
+        assert!(!configuration.add_action_metadata_headers);
+        assert!(configuration.mtls_backends.is_empty());
+        assert_eq!(ApiErrorPolicy::Pass, configuration.on_api_error);
+        assert_eq!(ApiRecording::Off, configuration.api_recording);
+        assert!(configuration.api_endpoints.is_empty());
+        assert_eq!(None, configuration.failover_backend);
+        assert!(configuration.image_optimizer_paths.is_empty());
+        assert!(configuration.vary_headers.is_empty());
+        assert_eq!(DEFAULT_MAX_VARY_HEADERS, configuration.max_vary_headers);
+        assert!(!configuration.normalize_accept_encoding);
+        assert!(!configuration.readthrough_cache);
+        assert!(configuration.cache_key_headers.is_empty());
+        assert!(configuration.cache_key_cookies.is_empty());
+        assert!(configuration.cache_key_query_params.is_empty());
+        assert!(configuration.cache_key_excluded_query_params.is_empty());
+        assert_eq!(0, configuration.action_cache_ttl);
+        assert_eq!(0, configuration.action_cache_stale_while_revalidate);
+        assert!(configuration.projects.is_empty());
+        assert!(configuration.cache_policies.is_empty());
+        assert!(!configuration.prerender);
+        assert!(configuration.link_headers.is_empty());
+        assert!(configuration.html_injections.is_empty());
+        assert!(!configuration.esi);
+        assert!(configuration.esi_backends.is_empty());
+        assert!(!configuration.preserve_header_case);
+        assert!(configuration.log_status_classes.is_empty());
+        assert!(!configuration.url_normalization.is_enabled());
+        assert!(configuration.allowed_hosts.is_empty());
+        assert_eq!(
+            vec!["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"],
+            configuration.allowed_methods
+        );
+        assert_eq!(
+            DEFAULT_UNKNOWN_HOST_STATUS,
+            configuration.unknown_host_status
+        );
+        assert!(configuration.soft_404_markers.is_empty());
+        assert!(!configuration.soft_404_status);
+        assert!(configuration.geo_policies.is_empty());
+        assert!(configuration.client_hints.is_empty());
+        assert!(configuration.backend_weights.is_empty());
+        assert!(!configuration.affinity_cookie);
+        assert_eq!(None, configuration.secondary_token);
+        assert_eq!(MigrationMode::DualWrite, configuration.migration_mode);
+        assert_eq!(None, configuration.shadow_backend);
+        assert_eq!(1.0, configuration.shadow_sample_rate);
+        assert!(configuration.instance_name_paths.is_empty());
+        assert!(configuration.range_stripped_paths.is_empty());
+        assert!(configuration.stripped_response_headers.is_empty());
+        assert!(configuration.protected_response_headers.is_empty());
+        assert!(configuration.api_request_headers.is_empty());
+        assert!(!configuration.percentage_bucket);
+        assert_eq!("no-store", configuration.synthetic_cache_control);
+        assert!(!configuration.retry_non_idempotent);
+        assert!(!configuration.minimal_match_payload);
+        assert!(configuration.match_payload_headers.is_empty());
+        assert!(!configuration.server_timing);
+        assert!(configuration.streaming_paths.is_empty());
+        assert!(configuration.streaming_headers.is_empty());
+        assert_eq!(None, configuration.fanout_backend);
+        assert!(configuration.disable_body_filter.is_empty());
+        assert!(!configuration.origin_served_by);
+        assert!(configuration.origin_headers.is_empty());
+        assert_eq!(None, configuration.origin_user_agent_suffix);
+        assert!(configuration.filterable_content_types.is_empty());
+        assert!(configuration.json_rewrites.is_empty());
+        assert_eq!(None, configuration.beacon_log_endpoint);
+        assert!(!configuration.beacon_api);
+        assert!(configuration.beacon_origins.is_empty());
+        assert!(!configuration.explain);
+        assert!(!configuration.rule_metrics);
+        assert_eq!(ChainRole::Authoritative, configuration.chain_role);
+        assert!(!configuration.match_cookies);
+        assert_eq!(
+            vec!["Authorization", "Proxy-Authorization"],
+            configuration.api_excluded_request_headers
+        );
+    }
+
+    #[test]
+    fn test_missing_backend_name() {
+        let error = create_configuration(&[("token", "token")]).err().unwrap();
+
+        assert!(matches!(error, ConfigurationError::MissingBackendName));
+        assert_eq!(None, error.backend_name());
+    }
+
+    #[test]
+    fn test_missing_token_forwards_to_backend() {
+        let error = create_configuration(&[("backend_name", "backend_host")])
+            .err()
+            .unwrap();
+
+        assert!(matches!(error, ConfigurationError::MissingToken(_)));
+        assert_eq!(Some("backend_host".to_string()), error.backend_name());
+    }
+
+    #[test]
+    fn test_rule_ids_header() {
+        let configuration = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("add_rule_ids_header", "true"),
+            ("rule_ids_header_name", "X-Rules"),
+        ])
+        .unwrap();
+
+        assert!(configuration.add_rule_ids_header);
+        assert_eq!("X-Rules", configuration.rule_ids_header_name);
+    }
+
+    #[test]
+    fn test_mtls_backends() {
+        let configuration = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            (
+                "mtls_backends",
+                r#"{"backend_host": {"target": "origin.example.com:443", "certificate": "cert", "key": "key"}}"#,
+            ),
+        ])
+        .unwrap();
+
+        let mtls_backend = configuration.mtls_backends.get("backend_host").unwrap();
+
+        assert_eq!("origin.example.com:443", mtls_backend.target);
+        assert_eq!(None, mtls_backend.host);
+        assert_eq!("cert", mtls_backend.certificate);
+        assert_eq!("key", mtls_backend.key);
+    }
+
+    #[test]
+    fn test_invalid_mtls_backends() {
+        let error = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("mtls_backends", "not json"),
+        ])
+        .err()
+        .unwrap();
+
+        assert!(matches!(
+            error,
+            ConfigurationError::InvalidMtlsBackends(_, _)
+        ));
+    }
+
+    #[test]
+    fn test_api_error_policy() {
+        for (value, expected) in [
+            ("pass", ApiErrorPolicy::Pass),
+            ("serve_cached_action", ApiErrorPolicy::ServeCachedAction),
+            ("fail_closed_503", ApiErrorPolicy::FailClosed),
+            ("retry_once", ApiErrorPolicy::RetryOnce),
+        ] {
+            let configuration = create_configuration(&[
+                ("backend_name", "backend_host"),
+                ("token", "token"),
+                ("instance_name", "instance"),
+                ("on_api_error", value),
+            ])
+            .unwrap();
+
+            assert_eq!(expected, configuration.on_api_error);
+        }
+
+        let error = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("on_api_error", "ignore"),
+        ])
+        .err()
+        .unwrap();
+
+        assert!(matches!(
+            error,
+            ConfigurationError::InvalidApiErrorPolicy(_, _)
+        ));
+    }
+
+    #[test]
+    fn test_api_recording() {
+        for (value, expected) in [
+            ("off", ApiRecording::Off),
+            ("record", ApiRecording::Record),
+            ("replay", ApiRecording::Replay),
+        ] {
+            let configuration = create_configuration(&[
+                ("backend_name", "backend_host"),
+                ("token", "token"),
+                ("instance_name", "instance"),
+                ("api_recording", value),
+            ])
+            .unwrap();
+
+            assert_eq!(expected, configuration.api_recording);
+        }
+
+        let error = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("api_recording", "always"),
+        ])
+        .err()
+        .unwrap();
+
+        assert!(matches!(
+            error,
+            ConfigurationError::InvalidApiRecording(_, _)
+        ));
+    }
+
+    #[test]
+    fn test_chain_role() {
+        let configuration = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("chain_role", "delegate"),
+        ])
+        .unwrap();
+
+        assert_eq!(ChainRole::Delegate, configuration.chain_role);
+
+        let error = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("chain_role", "upstream"),
+        ])
+        .err()
+        .unwrap();
+
+        assert!(matches!(error, ConfigurationError::InvalidChainRole(_, _)));
+    }
+
+    #[test]
+    fn test_api_endpoints() {
+        let configuration = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            (
+                "api_endpoints",
+                r#"[{"url": "https://eu.example.org", "backend": "rio_eu", "regions": ["EU"]}, {"url": "https://us.example.org", "backend": "rio_us"}]"#,
+            ),
+        ])
+        .unwrap();
+
+        assert_eq!(2, configuration.api_endpoints.len());
+        assert_eq!("rio_eu", configuration.api_endpoints[0].backend);
+        assert_eq!(
+            vec!["EU".to_string()],
+            configuration.api_endpoints[0].regions
+        );
+        assert!(configuration.api_endpoints[1].regions.is_empty());
+
+        let error = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("api_endpoints", "{}"),
+        ])
+        .err()
+        .unwrap();
+
+        assert!(matches!(
+            error,
+            ConfigurationError::InvalidApiEndpoints(_, _)
+        ));
+    }
+
+    #[test]
+    fn test_image_optimizer_paths() {
+        let configuration = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("image_optimizer_paths", "/images/, /media/,"),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            vec!["/images/".to_string(), "/media/".to_string()],
+            configuration.image_optimizer_paths
+        );
+    }
+
+    #[test]
+    fn test_vary_headers() {
+        let configuration = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("vary_headers", "Accept-Language,Cookie"),
+            ("max_vary_headers", "2"),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            vec!["Accept-Language".to_string(), "Cookie".to_string()],
+            configuration.vary_headers
+        );
+        assert_eq!(2, configuration.max_vary_headers);
+
+        let error = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("max_vary_headers", "many"),
+        ])
+        .err()
+        .unwrap();
+
+        assert!(matches!(
+            error,
+            ConfigurationError::InvalidMaxVaryHeaders(_, _)
+        ));
+    }
+
+    #[test]
+    fn test_action_cache() {
+        let configuration = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("action_cache_ttl", "60"),
+            ("action_cache_stale_while_revalidate", "300"),
+        ])
+        .unwrap();
+
+        assert_eq!(60, configuration.action_cache_ttl);
+        assert_eq!(300, configuration.action_cache_stale_while_revalidate);
+
+        let error = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("action_cache_ttl", "1m"),
+        ])
+        .err()
+        .unwrap();
+
+        assert!(matches!(
+            error,
+            ConfigurationError::InvalidDuration(_, "action_cache_ttl", _)
+        ));
+    }
+
+    #[test]
+    fn test_project_for_path() {
+        let create = || {
+            create_configuration(&[
+                ("backend_name", "backend_host"),
+                ("token", "token"),
+                ("instance_name", "instance"),
+                (
+                    "projects",
+                    r#"{"/blog/": {"token": "blog-token", "instance_name": "blog"}, "/blog/en/": {"token": "blog-en-token"}}"#,
+                ),
+            ])
+            .unwrap()
+        };
+
+        let configuration = create().with_project_for("/blog/post");
+        assert_eq!("blog-token", configuration.token);
+        assert_eq!("blog", configuration.instance_name);
+
+        let configuration = create().with_project_for("/blog/en/post");
+        assert_eq!("blog-en-token", configuration.token);
+        assert_eq!("instance", configuration.instance_name);
+
+        let configuration = create().with_project_for("/shop/");
+        assert_eq!("token", configuration.token);
+        assert_eq!("instance", configuration.instance_name);
+    }
+
+    #[test]
+    fn test_invalid_projects() {
+        let error = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("projects", r#"{"/blog/": {}}"#),
+        ])
+        .err()
+        .unwrap();
+
+        assert!(matches!(error, ConfigurationError::InvalidProjects(_, _)));
+    }
+
+    #[test]
+    fn test_cache_policies() {
+        let configuration = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            (
+                "cache_policies",
+                r#"{"redirect": {"surrogate_control": "max-age=86400", "cache_control": "max-age=3600"}}"#,
+            ),
+        ])
+        .unwrap();
+
+        let cache_policy = configuration.cache_policies.get("redirect").unwrap();
+
+        assert_eq!(
+            Some("max-age=86400".to_string()),
+            cache_policy.surrogate_control
+        );
+        assert_eq!(Some("max-age=3600".to_string()), cache_policy.cache_control);
+    }
+
+    #[test]
+    fn test_link_headers() {
+        let configuration = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            (
+                "link_headers",
+                r#"[{"value": "</app.css>; rel=preload; as=style", "paths": ["/blog/"]}]"#,
+            ),
+        ])
+        .unwrap();
+
+        assert_eq!(1, configuration.link_headers.len());
+        assert_eq!(vec!["/blog/"], configuration.link_headers[0].paths);
+    }
+
+    #[test]
+    fn test_invalid_html_injections() {
+        let error = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            (
+                "html_injections",
+                r#"[{"position": "footer", "snippet": "<script></script>"}]"#,
+            ),
+        ])
+        .err()
+        .unwrap();
+
+        assert!(matches!(
+            error,
+            ConfigurationError::InvalidHtmlInjections(_, _)
+        ));
+    }
+
+    #[test]
+    fn test_esi_backends() {
+        let configuration = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("esi", "true"),
+            ("esi_backends", r#"{"cdn.example.org": "cdn"}"#),
+        ])
+        .unwrap();
+
+        assert!(configuration.esi);
+        assert_eq!(
+            Some(&"cdn".to_string()),
+            configuration.esi_backends.get("cdn.example.org")
+        );
+    }
+
+    #[test]
+    fn test_log_status_classes() {
+        let configuration = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("log_status_classes", "3xx, 4XX,5xx"),
+        ])
+        .unwrap();
+
+        assert_eq!(vec![3, 4, 5], configuration.log_status_classes);
+
+        let error = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("log_status_classes", "404"),
+        ])
+        .err()
+        .unwrap();
+
+        assert!(matches!(
+            error,
+            ConfigurationError::InvalidLogStatusClasses(_, _)
+        ));
+    }
+
+    #[test]
+    fn test_url_normalization() {
+        let configuration = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("url_normalization", "percent_encoding, case_folding"),
+        ])
+        .unwrap();
+
+        assert!(configuration.url_normalization.percent_encoding);
+        assert!(configuration.url_normalization.case_folding);
+
+        let error = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("url_normalization", "lowercase"),
+        ])
+        .err()
+        .unwrap();
+
+        assert!(matches!(
+            error,
+            ConfigurationError::InvalidUrlNormalization(_, _)
+        ));
+    }
+
+    #[test]
+    fn test_allowed_hosts() {
+        let configuration = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("allowed_hosts", "example.com, *.example.org"),
+            ("unknown_host_status", "404"),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            vec!["example.com", "*.example.org"],
+            configuration.allowed_hosts
+        );
+        assert_eq!(404, configuration.unknown_host_status);
+
+        let error = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("unknown_host_status", "403"),
+        ])
+        .err()
+        .unwrap();
+
+        assert!(matches!(
+            error,
+            ConfigurationError::InvalidUnknownHostStatus(_, _)
+        ));
+    }
+
+    #[test]
+    fn test_geo_policies() {
+        let error = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("geo_policies", r#"{"XX": {"action": "hide"}}"#),
+        ])
+        .err()
+        .unwrap();
+
+        assert!(matches!(
+            error,
+            ConfigurationError::InvalidGeoPolicies(_, _)
+        ));
+    }
+
+    #[test]
+    fn test_shadow_sample_rate() {
+        let configuration = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("shadow_backend", "new_origin"),
+            ("shadow_sample_rate", "0.05"),
+        ])
+        .unwrap();
+
+        assert_eq!(Some("new_origin".to_string()), configuration.shadow_backend);
+        assert_eq!(0.05, configuration.shadow_sample_rate);
+
+        let error = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("shadow_sample_rate", "5%"),
+        ])
+        .err()
+        .unwrap();
+
+        assert!(matches!(
+            error,
+            ConfigurationError::InvalidShadowSampleRate(_, _)
+        ));
+    }
+
+    #[test]
+    fn test_origin_headers() {
+        let configuration = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("origin_headers", r#"{"x-origin-tag": "migration"}"#),
+            ("origin_user_agent_suffix", "rio-worker"),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            Some(&"migration".to_string()),
+            configuration.origin_headers.get("x-origin-tag")
+        );
+        assert_eq!(
+            Some("rio-worker".to_string()),
+            configuration.origin_user_agent_suffix
+        );
+
+        let error = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("origin_headers", "x-origin-tag"),
+        ])
+        .err()
+        .unwrap();
+
+        assert!(matches!(
+            error,
+            ConfigurationError::InvalidOriginHeaders(_, _)
+        ));
+    }
+
+    #[test]
+    fn test_json_rewrites() {
+        let configuration = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            (
+                "json_rewrites",
+                r#"{"/data": {"https://old.example.com/": "https://new.example.com/"}}"#,
+            ),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            Some(&"https://new.example.com/".to_string()),
+            configuration.json_rewrites["/data"].get("https://old.example.com/")
+        );
+
+        let error = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("token", "token"),
+            ("instance_name", "instance"),
+            ("json_rewrites", r#"{"/data": "https://new.example.com/"}"#),
+        ])
+        .err()
+        .unwrap();
+
+        assert!(matches!(
+            error,
+            ConfigurationError::InvalidJsonRewrites(_, _)
+        ));
+    }
+
+    #[test]
+    fn test_instance_name_for() {
+        let create = || {
+            create_configuration(&[
+                ("backend_name", "backend_host"),
+                ("token", "token"),
+                ("instance_name", "prod"),
+                (
+                    "instance_name_paths",
+                    r#"{"/canary/": "prod-canary", "/canary/v2/": "prod-canary-v2"}"#,
+                ),
+            ])
+            .unwrap()
+        };
+
+        assert_eq!(
+            "prod-canary-v2",
+            create()
+                .with_instance_name_for("/canary/v2/", None)
+                .instance_name
+        );
+        assert_eq!(
+            "prod-canary",
+            create()
+                .with_instance_name_for("/canary/", None)
+                .instance_name
+        );
+        assert_eq!(
+            "prod",
+            create().with_instance_name_for("/blog", None).instance_name
+        );
+        assert_eq!(
+            "prod-test",
+            create()
+                .with_instance_name_for("/canary/", Some("prod-test".to_string()))
+                .instance_name
+        );
+    }
+
+    #[test]
+    fn test_config_json() {
+        let configuration = create_configuration(&[
+            (
+                "config_json",
+                r#"{
+                    "backend_name": "backend_host",
+                    "token": "json_token",
+                    "instance_name": "instance",
+                    "max_vary_headers": 2,
+                    "esi": true,
+                    "esi_backends": {"cdn.example.org": "cdn"}
+                }"#,
+            ),
+            ("token", "flat_token"),
+        ])
+        .unwrap();
+
+        assert_eq!("backend_host", configuration.backend_name);
+        // Flat entries win over the ones of config_json
+        assert_eq!("flat_token", configuration.token);
+        assert_eq!(2, configuration.max_vary_headers);
+        assert!(configuration.esi);
+        assert_eq!(
+            Some(&"cdn".to_string()),
+            configuration.esi_backends.get("cdn.example.org")
+        );
+    }
+
+    #[test]
+    fn test_config_json_lists_and_maps() {
+        let configuration = create_configuration(&[(
+            "config_json",
+            r#"{
+                "backend_name": "backend_host",
+                "token": "token",
+                "instance_name": "instance",
+                "allowed_hosts": ["example.org", "www.example.org"],
+                "log_status_classes": ["4xx", "5xx"],
+                "cache_key_headers": [],
+                "backend_weights": {"backend_host": 90, "canary": 10}
+            }"#,
+        )])
+        .unwrap();
+
+        assert_eq!(
+            vec!["example.org".to_string(), "www.example.org".to_string()],
+            configuration.allowed_hosts
+        );
+        assert_eq!(vec![4, 5], configuration.log_status_classes);
+        assert!(configuration.cache_key_headers.is_empty());
+        assert_eq!(Some(&10), configuration.backend_weights.get("canary"));
+    }
+
+    #[test]
+    fn test_config_json_wrong_types() {
+        for (config_json, expected) in [
+            (
+                r#"{"allowed_hosts": [["example.org"]]}"#,
+                "option \"allowed_hosts\" expects a list of strings",
+            ),
+            (
+                r#"{"esi_backends": true}"#,
+                "option \"esi_backends\" expects a JSON object or list",
+            ),
+            (
+                r#"{"token": ["token"]}"#,
+                "option \"token\" expects a string",
+            ),
+        ] {
+            let error = parse_config_json(config_json).err();
+
+            assert!(
+                error
+                    .as_deref()
+                    .is_some_and(|error| error.contains(expected)),
+                "{}: {:?}",
+                config_json,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_config_json() {
+        let error = create_configuration(&[
+            ("backend_name", "backend_host"),
+            ("config_json", r#"{"tokn": "token"}"#),
+        ])
+        .err()
+        .unwrap();
+
+        assert_eq!(
+            "invalid \"config_json\": unknown option \"tokn\"",
+            error.to_string()
+        );
+        assert_eq!(Some("backend_host".to_string()), error.backend_name());
     }
 }
@@ -0,0 +1,80 @@
+pub const COOKIE_HEADER_PREFIX: &str = "x-redirectionio-cookie-";
+
+/// Iterate over the names and values of the cookies of a `Cookie` header.
+pub fn parse(cookies: &str) -> impl Iterator<Item = (&str, &str)> {
+    cookies
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .filter(|(name, _)| !name.is_empty())
+}
+
+pub fn cookie_value<'c>(cookies: &'c str, name: &str) -> Option<&'c str> {
+    parse(cookies).find_map(|(cookie_name, value)| (cookie_name == name).then_some(value))
+}
+
+/// Headers exposing each cookie of a `Cookie` header to the rules, like
+/// `x-redirectionio-cookie-lang: fr` for the `lang=fr` cookie.
+pub fn cookie_headers(cookies: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    parse(cookies).map(|(name, value)| {
+        (
+            format!("{}{}", COOKIE_HEADER_PREFIX, name),
+            value.to_string(),
+        )
+    })
+}
+
+/// The cookie exposed by a header added by `cookie_headers`, if it is one.
+pub fn cookie_header_name(header_name: &str) -> Option<&str> {
+    let prefix = header_name.get(..COOKIE_HEADER_PREFIX.len())?;
+
+    match prefix.eq_ignore_ascii_case(COOKIE_HEADER_PREFIX) {
+        true => Some(&header_name[COOKIE_HEADER_PREFIX.len()..]),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            vec![("session", "1"), ("lang", "fr"), ("empty", "")],
+            parse("session=1; lang=fr;empty=; invalid; =value").collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_cookie_value() {
+        assert_eq!(
+            Some("next.abc"),
+            cookie_value(
+                "session=1; redirectionio_backend=next.abc",
+                "redirectionio_backend"
+            )
+        );
+        assert_eq!(None, cookie_value("session=1", "redirectionio_backend"));
+    }
+
+    #[test]
+    fn test_cookie_headers() {
+        let headers: Vec<(String, String)> = cookie_headers("lang=fr; ab_test=b").collect();
+
+        assert_eq!(
+            vec![
+                ("x-redirectionio-cookie-lang".to_string(), "fr".to_string()),
+                (
+                    "x-redirectionio-cookie-ab_test".to_string(),
+                    "b".to_string()
+                ),
+            ],
+            headers
+        );
+        assert_eq!(
+            Some("ab_test"),
+            cookie_header_name("X-RedirectionIo-Cookie-ab_test")
+        );
+        assert_eq!(None, cookie_header_name("cookie"));
+    }
+}
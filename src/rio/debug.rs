@@ -0,0 +1,185 @@
+use super::secrets::{get_secret, secure_compare};
+use fastly::Request;
+use std::str::FromStr;
+
+pub const DEBUG_HEADER: &str = "x-redirectionio-debug";
+pub const LOG_LEVEL_HEADER: &str = "x-redirectionio-log-level";
+pub const INSTANCE_NAME_HEADER: &str = "x-redirectionio-instance-name";
+pub const RULE_IDS_HEADER: &str = "x-redirectionio-rule-ids";
+
+/// Debugging options of a request, only honored when the request carries the `debug_secret`
+/// secret in the `x-redirectionio-debug` header.
+#[derive(Debug, Default)]
+pub struct DebugRequest {
+    pub enabled: bool,
+    pub log_level: Option<String>,
+    pub instance_name: Option<String>,
+    pub rule_ids: bool,
+}
+
+impl DebugRequest {
+    /// Read the debugging headers of a debug request, and remove them so they are never
+    /// forwarded to the backend.
+    ///
+    /// Other requests only lose the debug header: the headers they share a name with are the
+    /// client's, and are forwarded untouched.
+    pub fn from_request(req: &mut Request) -> DebugRequest {
+        let value = match req.remove_header_str(DEBUG_HEADER) {
+            Some(value) => value,
+            None => return DebugRequest::default(),
+        };
+
+        // The secret is only fetched for requests asking for debugging
+        let secret = match get_secret("debug_secret") {
+            Some(secret) => secret,
+            None => return DebugRequest::default(),
+        };
+
+        let debug = DebugRequest::new(&value, &secret, None);
+
+        if !debug.enabled {
+            return debug;
+        }
+
+        DebugRequest {
+            log_level: req.remove_header_str(LOG_LEVEL_HEADER),
+            ..debug
+        }
+        .with_instance_name(req.remove_header_str(INSTANCE_NAME_HEADER))
+        .with_rule_ids(req.remove_header_str(RULE_IDS_HEADER).as_deref() == Some("true"))
+    }
+
+    pub fn new(value: &str, secret: &str, log_level: Option<String>) -> DebugRequest {
+        if !secure_compare(value, secret) {
+            return DebugRequest::default();
+        }
+
+        DebugRequest {
+            enabled: true,
+            log_level,
+            instance_name: None,
+            rule_ids: false,
+        }
+    }
+
+    /// Tag the request with another instance name, like `prod-canary`, so it appears as a
+    /// separate instance in the redirection.io dashboard.
+    pub fn with_instance_name(mut self, instance_name: Option<String>) -> Self {
+        if self.enabled {
+            self.instance_name = instance_name.filter(|instance_name| !instance_name.is_empty());
+        }
+
+        self
+    }
+
+    /// Add the rule ids header to the response of this request only, whatever the
+    /// `add_rule_ids_header` entry.
+    pub fn with_rule_ids(mut self, rule_ids: bool) -> Self {
+        self.rule_ids = self.enabled && rule_ids;
+
+        self
+    }
+
+    /// The log level of the request: the requested one when it is more verbose than the
+    /// configured one.
+    pub fn log_level(&self, configured: Option<String>) -> Option<String> {
+        let requested = match self.log_level {
+            Some(ref requested) => requested,
+            None => return configured,
+        };
+
+        let requested_filter = match log::LevelFilter::from_str(requested) {
+            Ok(filter) => filter,
+            Err(_) => return configured,
+        };
+
+        let is_more_verbose = match configured {
+            Some(ref configured) => match log::LevelFilter::from_str(configured) {
+                Ok(configured_filter) => requested_filter > configured_filter,
+                Err(_) => true,
+            },
+            None => true,
+        };
+
+        match is_more_verbose {
+            true => Some(requested.clone()),
+            false => configured,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_request_needs_the_secret() {
+        assert_eq!(
+            Some("trace".to_string()),
+            DebugRequest::new("secret", "secret", Some("trace".to_string())).log_level
+        );
+        assert!(DebugRequest::new("secret", "secret", None).enabled);
+        assert!(!DebugRequest::new("guess", "secret", None).enabled);
+        assert_eq!(
+            None,
+            DebugRequest::new("guess", "secret", Some("trace".to_string())).log_level
+        );
+    }
+
+    #[test]
+    fn test_log_level_is_only_elevated() {
+        let debug = DebugRequest::new("secret", "secret", Some("trace".to_string()));
+
+        assert_eq!(Some("trace".to_string()), debug.log_level(None));
+        assert_eq!(
+            Some("trace".to_string()),
+            debug.log_level(Some("warn".to_string()))
+        );
+
+        let debug = DebugRequest::new("secret", "secret", Some("error".to_string()));
+
+        assert_eq!(
+            Some("info".to_string()),
+            debug.log_level(Some("info".to_string()))
+        );
+
+        let debug = DebugRequest::new("secret", "secret", Some("loud".to_string()));
+
+        assert_eq!(
+            Some("info".to_string()),
+            debug.log_level(Some("info".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_instance_name_needs_the_secret() {
+        let canary = Some("prod-canary".to_string());
+
+        assert_eq!(
+            canary,
+            DebugRequest::new("secret", "secret", None)
+                .with_instance_name(canary.clone())
+                .instance_name
+        );
+        assert_eq!(
+            None,
+            DebugRequest::new("guess", "secret", None)
+                .with_instance_name(canary)
+                .instance_name
+        );
+    }
+
+    #[test]
+    fn test_rule_ids_needs_the_secret() {
+        assert!(
+            DebugRequest::new("secret", "secret", None)
+                .with_rule_ids(true)
+                .rule_ids
+        );
+        assert!(
+            !DebugRequest::new("guess", "secret", None)
+                .with_rule_ids(true)
+                .rule_ids
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use fastly::http::header;
+use fastly::Request;
+
+/// Reduce the `Accept-Encoding` header of a request to one of three variants: `br`, `gzip`, or
+/// no header at all for identity, so the backend and the cache see at most three variants.
+pub fn normalize_accept_encoding(req: &mut Request) {
+    let normalized = req
+        .get_header_str(header::ACCEPT_ENCODING)
+        .and_then(preferred_encoding);
+
+    match normalized {
+        Some(encoding) => req.set_header(header::ACCEPT_ENCODING, encoding),
+        None => {
+            req.remove_header(header::ACCEPT_ENCODING);
+        }
+    }
+}
+
+/// Pick the best encoding accepted by the client, `br` being preferred over `gzip`.
+pub fn preferred_encoding(accept_encoding: &str) -> Option<&'static str> {
+    let mut accepts_br = false;
+    let mut accepts_gzip = false;
+
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let encoding = parts.next().unwrap_or_default().trim().to_lowercase();
+        let refused = parts.any(|parameter| {
+            let parameter = parameter.trim().replace(' ', "");
+
+            match parameter.strip_prefix("q=") {
+                Some(quality) => quality.parse::<f32>().map(|q| q <= 0.0).unwrap_or(false),
+                None => false,
+            }
+        });
+
+        if refused {
+            continue;
+        }
+
+        match encoding.as_str() {
+            "br" => accepts_br = true,
+            "gzip" | "x-gzip" => accepts_gzip = true,
+            _ => (),
+        }
+    }
+
+    if accepts_br {
+        Some("br")
+    } else if accepts_gzip {
+        Some("gzip")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preferred_encoding() {
+        assert_eq!(Some("br"), preferred_encoding("gzip, deflate, br"));
+        assert_eq!(Some("gzip"), preferred_encoding("gzip, deflate"));
+        assert_eq!(Some("gzip"), preferred_encoding("x-gzip"));
+        assert_eq!(Some("gzip"), preferred_encoding("br;q=0, GZIP;q=0.5"));
+        assert_eq!(None, preferred_encoding("deflate, identity"));
+        assert_eq!(None, preferred_encoding("gzip;q=0"));
+        assert_eq!(None, preferred_encoding(""));
+    }
+
+    #[test]
+    fn test_normalize_accept_encoding() {
+        let mut req = Request::get("https://example.org/")
+            .with_header(header::ACCEPT_ENCODING, "gzip, deflate, br;q=0.9");
+
+        normalize_accept_encoding(&mut req);
+
+        assert_eq!(Some("br"), req.get_header_str(header::ACCEPT_ENCODING));
+
+        let mut req =
+            Request::get("https://example.org/").with_header(header::ACCEPT_ENCODING, "deflate");
+
+        normalize_accept_encoding(&mut req);
+
+        assert_eq!(None, req.get_header_str(header::ACCEPT_ENCODING));
+    }
+}
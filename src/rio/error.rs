@@ -1,3 +1,7 @@
+use super::configuration::ConfigurationError;
+use fastly::http::request::{SendError, SendErrorCause};
+use std::collections::HashMap;
+use std::fmt;
 use std::string::FromUtf8Error;
 
 quick_error! {
@@ -23,3 +27,252 @@ impl From<FromUtf8Error> for InternalError {
         InternalError::DecodingFailed(e.to_string())
     }
 }
+
+quick_error! {
+    #[derive(Debug, Clone)]
+    pub enum ApiError {
+        Send (e: String) {
+            display("cannot send redirection_io request: {}", e)
+        }
+        Status (status: u16, body: String) {
+            display("returned status {}", status)
+        }
+        Deserialization (e: String, body: String) {
+            display("cannot deserialize redirection_io API response: {}", e)
+        }
+        Decompression (e: String) {
+            display("cannot decompress redirection_io API response: {}", e)
+        }
+        RateLimited (retry_after: Option<u64>) {
+            display("rate limited by redirection_io API")
+        }
+        Backoff (deadline: u64) {
+            display("redirection_io API calls are suspended until {}", deadline)
+        }
+        NotRecorded (signature: String) {
+            display("no recorded action for \"{}\"", signature)
+        }
+    }
+}
+
+impl ApiError {
+    /// Whether the error may not happen again on the next call: the API could not be reached, or
+    /// answered with a server error.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ApiError::Send(_) => true,
+            ApiError::Status(status, _) => *status >= 500,
+            _ => false,
+        }
+    }
+}
+
+/// Step of the request processing during which an error occurred.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Configuration,
+    Request,
+    Action,
+    Backend,
+    Proxy,
+    Log,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let phase = match self {
+            Phase::Configuration => "configuration",
+            Phase::Request => "request",
+            Phase::Action => "action",
+            Phase::Backend => "backend",
+            Phase::Proxy => "proxy",
+            Phase::Log => "log",
+        };
+
+        write!(f, "{}", phase)
+    }
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ErrorKind {
+        Configuration (e: ConfigurationError) {
+            display("configuration error: {}", e)
+            from()
+        }
+        InvalidUrl (url: String) {
+            display("cannot create redirection_io request from url \"{}\"", url)
+        }
+        Api (e: ApiError) {
+            display("{}", e)
+            from()
+        }
+        Send (e: Box<SendError>) {
+            display("cannot send request to backend: {}", e)
+        }
+        Serialization (e: serde_json::Error) {
+            display("cannot serialize: {}", e)
+            from()
+        }
+        BodyRead (e: std::io::Error) {
+            display("cannot read backend response body: {}", e)
+            from()
+        }
+    }
+}
+
+impl From<SendError> for ErrorKind {
+    fn from(e: SendError) -> Self {
+        ErrorKind::Send(Box::new(e))
+    }
+}
+
+/// Error raised while processing a request, along with the URL and the phase it occurred in.
+#[derive(Debug)]
+pub struct WorkerError {
+    pub kind: ErrorKind,
+    pub phase: Phase,
+    pub url: String,
+}
+
+impl WorkerError {
+    pub fn new(kind: impl Into<ErrorKind>, phase: Phase, url: impl ToString) -> WorkerError {
+        WorkerError {
+            kind: kind.into(),
+            phase,
+            url: url.to_string(),
+        }
+    }
+
+    /// Status code of the synthetic response to send to the client for this error.
+    pub fn status_code(&self) -> u16 {
+        match self.kind {
+            ErrorKind::Configuration(_) | ErrorKind::Serialization(_) => 500,
+            ErrorKind::InvalidUrl(_) => 400,
+            ErrorKind::Api(_) => 503,
+            ErrorKind::BodyRead(_) => 502,
+            ErrorKind::Send(ref error) => match error.root_cause() {
+                SendErrorCause::DnsTimeout
+                | SendErrorCause::ConnectionTimeout
+                | SendErrorCause::HttpResponseTimeout => 504,
+                _ => 502,
+            },
+        }
+    }
+
+    pub fn context(&self) -> HashMap<&'static str, String> {
+        let mut context = HashMap::from([
+            ("phase", self.phase.to_string()),
+            ("error_url", self.url.clone()),
+        ]);
+
+        if let ErrorKind::Send(ref error) = self.kind {
+            context.insert(
+                "backend_error",
+                send_error_class(error.root_cause()).to_string(),
+            );
+        }
+
+        context
+    }
+}
+
+/// Classify the cause of a backend send failure, to triage origin issues: `dns`, `tls`,
+/// `connect_timeout`, `connect`, `read_timeout`, `protocol` or `internal`.
+#[allow(deprecated)]
+pub fn send_error_class(cause: &SendErrorCause) -> &'static str {
+    match cause {
+        SendErrorCause::DnsTimeout | SendErrorCause::DnsError { .. } => "dns",
+        SendErrorCause::TlsProtocolError
+        | SendErrorCause::TlsCertificateError
+        | SendErrorCause::TlsAlertReceived { .. }
+        | SendErrorCause::TlsConfigurationError => "tls",
+        SendErrorCause::ConnectionTimeout => "connect_timeout",
+        SendErrorCause::DestinationNotFound
+        | SendErrorCause::DestinationUnavailable
+        | SendErrorCause::DestinationIpUnroutable
+        | SendErrorCause::ConnectionRefused
+        | SendErrorCause::ConnectionTerminated
+        | SendErrorCause::ConnectionLimitReached => "connect",
+        SendErrorCause::HttpResponseTimeout => "read_timeout",
+        SendErrorCause::Invalid
+        | SendErrorCause::Incomplete
+        | SendErrorCause::InvalidStatus
+        | SendErrorCause::HeadTooLarge
+        | SendErrorCause::HttpIncompleteResponse
+        | SendErrorCause::HttpResponseHeaderSectionTooLarge
+        | SendErrorCause::HttpResponseBodyTooLarge
+        | SendErrorCause::HttpResponseStatusInvalid
+        | SendErrorCause::HttpUpgradeFailed
+        | SendErrorCause::HttpProtocolError => "protocol",
+        _ => "internal",
+    }
+}
+
+impl fmt::Display for WorkerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} error on \"{}\": {}", self.phase, self.url, self.kind)
+    }
+}
+
+impl std::error::Error for WorkerError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_error_class() {
+        assert_eq!("dns", send_error_class(&SendErrorCause::DnsTimeout));
+        assert_eq!(
+            "tls",
+            send_error_class(&SendErrorCause::TlsAlertReceived { alert_id: Some(42) })
+        );
+        assert_eq!(
+            "connect_timeout",
+            send_error_class(&SendErrorCause::ConnectionTimeout)
+        );
+        assert_eq!(
+            "connect",
+            send_error_class(&SendErrorCause::ConnectionRefused)
+        );
+        assert_eq!(
+            "read_timeout",
+            send_error_class(&SendErrorCause::HttpResponseTimeout)
+        );
+        assert_eq!(
+            "protocol",
+            send_error_class(&SendErrorCause::HttpProtocolError)
+        );
+        assert_eq!(
+            "internal",
+            send_error_class(&SendErrorCause::InternalError(None))
+        );
+    }
+
+    #[test]
+    fn test_body_read_error() {
+        let error = WorkerError::new(
+            std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset"),
+            Phase::Proxy,
+            "https://example.org/",
+        );
+
+        assert_eq!(502, error.status_code());
+        assert_eq!("proxy", error.context()["phase"]);
+        assert_eq!(
+            "proxy error on \"https://example.org/\": cannot read backend response body: reset",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(ApiError::Send("timeout".to_string()).is_transient());
+        assert!(ApiError::Status(502, String::new()).is_transient());
+        assert!(!ApiError::Status(403, String::new()).is_transient());
+        assert!(!ApiError::Deserialization("eof".to_string(), String::new()).is_transient());
+        assert!(!ApiError::RateLimited(None).is_transient());
+        assert!(!ApiError::Backoff(0).is_transient());
+    }
+}
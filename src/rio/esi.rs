@@ -0,0 +1,348 @@
+use super::logging::FastlyLogger;
+use fastly::http::header::{ACCEPT_LANGUAGE, COOKIE};
+use fastly::http::request::PendingRequest;
+use fastly::http::{HeaderName, HeaderValue, Url};
+use fastly::Request;
+use redirectionio::http::Header;
+use std::collections::HashMap;
+
+// Maximum number of fragments fetched for a page, the includes after it are replaced by nothing
+const MAX_INCLUDES: usize = 32;
+
+// Headers of the page request sent with its fragment requests, `Cookie` only to the host of the page
+const FORWARDED_HEADERS: [HeaderName; 2] = [COOKIE, ACCEPT_LANGUAGE];
+
+#[derive(Debug, PartialEq)]
+pub enum Segment<'a> {
+    Text(&'a str),
+    Include(String),
+}
+
+/// Edge Side Includes processor, for responses carrying `Surrogate-Control: content="ESI/1.0"`.
+///
+/// Supports `<esi:include src="..."/>`, `<esi:remove>` and `<esi:comment/>`. Fragments are fetched
+/// in parallel: relative ones, and the ones on the host of the page, from the backend of the page,
+/// the others from the backend configured for their host in `esi_backends`. A fragment which can
+/// not be fetched is replaced by nothing and logged, as are the includes over `MAX_INCLUDES`.
+pub struct EsiProcessor {
+    backend_name: String,
+    esi_backends: HashMap<String, String>,
+}
+
+impl EsiProcessor {
+    pub(crate) fn new(backend_name: String, esi_backends: HashMap<String, String>) -> EsiProcessor {
+        EsiProcessor {
+            backend_name,
+            esi_backends,
+        }
+    }
+
+    pub fn process(
+        &self,
+        page_url: &Url,
+        page_headers: &[(HeaderName, HeaderValue)],
+        body: &str,
+        fastly_logger: &FastlyLogger,
+    ) -> String {
+        let segments = parse(body);
+        let skipped = skipped_includes(&segments);
+
+        if skipped > 0 {
+            fastly_logger.log_warn(
+                "Too many ESI includes, some fragments are not fetched.".to_string(),
+                Some(HashMap::from([
+                    ("url", page_url.to_string()),
+                    ("skipped_includes", skipped.to_string()),
+                ])),
+            );
+        }
+
+        // Send all the fragment requests before waiting for any of them
+        let pending_requests: Vec<Result<PendingRequest, String>> = segments
+            .iter()
+            .filter_map(|segment| match segment {
+                Segment::Include(src) => Some(src),
+                Segment::Text(_) => None,
+            })
+            .take(MAX_INCLUDES)
+            .map(|src| self.send(page_url, page_headers, src))
+            .collect();
+
+        let mut pending_requests = pending_requests.into_iter();
+        let mut output = String::with_capacity(body.len());
+
+        for segment in segments.iter() {
+            let src = match segment {
+                Segment::Text(text) => {
+                    output.push_str(text);
+                    continue;
+                }
+                Segment::Include(src) => src,
+            };
+
+            // Includes over the limit have no request
+            let pending_request = match pending_requests.next() {
+                Some(pending_request) => pending_request,
+                None => continue,
+            };
+
+            let result = pending_request.and_then(|pending_request| {
+                let mut response = pending_request.wait().map_err(|error| error.to_string())?;
+
+                match response.get_status().is_success() {
+                    true => Ok(response.take_body_str_lossy()),
+                    false => Err(format!("status {}", response.get_status().as_u16())),
+                }
+            });
+
+            match result {
+                Ok(fragment) => output.push_str(&fragment),
+                Err(error) => fastly_logger.log_warn(
+                    "An ESI fragment can not be fetched.".to_string(),
+                    Some(HashMap::from([
+                        ("url", page_url.to_string()),
+                        ("src", src.clone()),
+                        ("error", error),
+                    ])),
+                ),
+            }
+        }
+
+        output
+    }
+
+    fn send(
+        &self,
+        page_url: &Url,
+        page_headers: &[(HeaderName, HeaderValue)],
+        src: &str,
+    ) -> Result<PendingRequest, String> {
+        let (url, backend) = self
+            .resolve(page_url, src)
+            .ok_or_else(|| "no backend for the fragment".to_string())?;
+        let is_page_host = url.host_str() == page_url.host_str();
+        let mut request = Request::get(url);
+
+        for (name, value) in page_headers {
+            if *name != COOKIE || is_page_host {
+                request.append_header(name, value);
+            }
+        }
+
+        request
+            .send_async(backend)
+            .map_err(|error| error.to_string())
+    }
+
+    /// Resolve the url of a fragment against the url of the page, and find its backend.
+    pub fn resolve(&self, page_url: &Url, src: &str) -> Option<(Url, String)> {
+        let url = page_url.join(src).ok()?;
+        let host = url.host_str()?.to_lowercase();
+
+        if Some(host.as_str()) == page_url.host_str().map(str::to_lowercase).as_deref() {
+            return Some((url, self.backend_name.clone()));
+        }
+
+        let backend = self.esi_backends.get(&host)?.clone();
+
+        Some((url, backend))
+    }
+}
+
+/// Headers of the page request to send with its fragment requests, read before the page request
+/// is sent to the backend.
+pub fn forwarded_headers(req: &Request) -> Vec<(HeaderName, HeaderValue)> {
+    FORWARDED_HEADERS
+        .iter()
+        .flat_map(|name| {
+            req.get_header_all(name)
+                .map(move |value| (name.clone(), value.clone()))
+        })
+        .collect()
+}
+
+/// Number of includes over `MAX_INCLUDES`, which are not fetched.
+fn skipped_includes(segments: &[Segment]) -> usize {
+    segments
+        .iter()
+        .filter(|segment| matches!(segment, Segment::Include(_)))
+        .count()
+        .saturating_sub(MAX_INCLUDES)
+}
+
+pub fn is_esi_response(headers: &[Header]) -> bool {
+    headers.iter().any(|header| {
+        header.name.eq_ignore_ascii_case("Surrogate-Control")
+            && header.value.to_uppercase().contains("CONTENT=\"ESI/1.0\"")
+    })
+}
+
+/// Split a body into text and includes, dropping the other ESI tags.
+pub fn parse(body: &str) -> Vec<Segment<'_>> {
+    let mut segments = Vec::new();
+    let mut rest = body;
+
+    while let Some(start) = rest.find("<esi:") {
+        if start > 0 {
+            segments.push(Segment::Text(&rest[..start]));
+        }
+
+        let tag = &rest[start..];
+        let tag_end = match tag.find('>') {
+            Some(tag_end) => tag_end + 1,
+            None => {
+                // Unterminated tag: keep it as text
+                segments.push(Segment::Text(tag));
+                rest = "";
+                break;
+            }
+        };
+        let tag_content = &tag[..tag_end];
+
+        rest = if tag.starts_with("<esi:remove") {
+            match tag.find("</esi:remove>") {
+                Some(end) => &tag[end + "</esi:remove>".len()..],
+                None => "",
+            }
+        } else if tag.starts_with("<esi:include") {
+            if let Some(src) = attribute(tag_content, "src") {
+                segments.push(Segment::Include(src));
+            }
+
+            let after = &tag[tag_end..];
+
+            after.strip_prefix("</esi:include>").unwrap_or(after)
+        } else {
+            &tag[tag_end..]
+        };
+    }
+
+    if !rest.is_empty() {
+        segments.push(Segment::Text(rest));
+    }
+
+    segments
+}
+
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let start = tag.find(&format!(" {}=", name))? + name.len() + 2;
+    let value = &tag[start..];
+    let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let value = &value[1..];
+    let end = value.find(quote)?;
+
+    Some(value[..end].replace("&amp;", "&"))
+}
+
+/// Remove the ESI content directive from a `Surrogate-Control` header, once the page is assembled.
+pub fn strip_esi_directive(value: &str) -> String {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.to_uppercase().starts_with("CONTENT="))
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let segments = parse(
+            "<p>Hello</p><esi:include src=\"/header?a=1&amp;b=2\"/><esi:remove><a href=\"/header\">Header</a></esi:remove><esi:comment text=\"footer\"/><esi:include src='https://cdn.example.org/footer'></esi:include>!",
+        );
+
+        assert_eq!(
+            vec![
+                Segment::Text("<p>Hello</p>"),
+                Segment::Include("/header?a=1&b=2".to_string()),
+                Segment::Include("https://cdn.example.org/footer".to_string()),
+                Segment::Text("!"),
+            ],
+            segments
+        );
+    }
+
+    #[test]
+    fn test_parse_without_tags() {
+        assert_eq!(vec![Segment::Text("<p>Hello</p>")], parse("<p>Hello</p>"));
+        assert_eq!(
+            vec![Segment::Text("<p>"), Segment::Text("<esi:include src=")],
+            parse("<p><esi:include src=")
+        );
+    }
+
+    #[test]
+    fn test_skipped_includes() {
+        let body = "<esi:include src=\"/fragment\"/>".repeat(MAX_INCLUDES);
+
+        assert_eq!(0, skipped_includes(&parse(&body)));
+        assert_eq!(
+            2,
+            skipped_includes(&parse(&format!("{}<p>{}</p>", body, &body[..60])))
+        );
+    }
+
+    #[test]
+    fn test_forwarded_headers() {
+        let req = Request::get("https://example.org/")
+            .with_header(COOKIE, "session=1")
+            .with_header("Accept-Language", "fr")
+            .with_header("Authorization", "Bearer token");
+
+        assert_eq!(
+            vec![
+                (COOKIE, HeaderValue::from_static("session=1")),
+                (ACCEPT_LANGUAGE, HeaderValue::from_static("fr")),
+            ],
+            forwarded_headers(&req)
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let processor = EsiProcessor::new(
+            "origin".to_string(),
+            HashMap::from([("cdn.example.org".to_string(), "cdn".to_string())]),
+        );
+        let page_url = Url::parse("https://example.org/blog/post").unwrap();
+
+        assert_eq!(
+            Some((
+                Url::parse("https://example.org/blog/header").unwrap(),
+                "origin".to_string()
+            )),
+            processor.resolve(&page_url, "header")
+        );
+        assert_eq!(
+            Some((
+                Url::parse("https://cdn.example.org/footer").unwrap(),
+                "cdn".to_string()
+            )),
+            processor.resolve(&page_url, "https://CDN.example.org/footer")
+        );
+        assert_eq!(
+            None,
+            processor.resolve(&page_url, "https://other.example.org/footer")
+        );
+    }
+
+    #[test]
+    fn test_esi_response() {
+        let header = |value: &str| Header {
+            name: "Surrogate-Control".to_string(),
+            value: value.to_string(),
+        };
+
+        assert!(is_esi_response(&[header(
+            "max-age=60, content=\"ESI/1.0\""
+        )]));
+        assert!(!is_esi_response(&[header("max-age=60")]));
+        assert_eq!(
+            "max-age=60",
+            strip_esi_directive("max-age=60, content=\"ESI/1.0\"")
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+/// Decisions made while processing a request, logged as an `explain` record once the response
+/// is sent, so support teams can reconstruct why a request got its response.
+///
+/// Each stage holds the decisions made for it, in order:
+///
+/// * `excluded`: why the rules were not applied, like `bypass` or `unknown_host`;
+/// * `action_cache`: `hit`, `stale`, `miss` or `refresh` in the action cache;
+/// * `degradation`: how the worker coped with the API, like `retry` or `cached_action`;
+/// * `action_type`, `rule_ids` and `filters`: what the action did to the response;
+/// * `backend` and `cache_state`: where the backend response came from.
+///
+/// Request handling records what it decides as it goes, nothing is kept when it is not enabled.
+#[derive(Default)]
+pub struct Explain {
+    enabled: Cell<bool>,
+    stages: RefCell<Vec<(&'static str, String)>>,
+}
+
+impl Explain {
+    /// Enabled by the `explain` entry, or for debug requests, once the configuration is known.
+    pub fn enable(&self) {
+        self.enabled.set(true);
+    }
+
+    pub fn record(&self, stage: &'static str, decision: impl Into<String>) {
+        if self.enabled.get() {
+            self.stages.borrow_mut().push((stage, decision.into()));
+        }
+    }
+
+    /// Context of the log record, with the decisions of each stage, separated by commas.
+    pub fn context(&self) -> Option<HashMap<&'static str, String>> {
+        if !self.enabled.get() {
+            return None;
+        }
+
+        let mut context = HashMap::from([("record", "explain".to_string())]);
+
+        for (stage, decision) in self.stages.borrow().iter() {
+            context
+                .entry(*stage)
+                .and_modify(|decisions: &mut String| {
+                    decisions.push_str(", ");
+                    decisions.push_str(decision);
+                })
+                .or_insert_with(|| decision.clone());
+        }
+
+        Some(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context() {
+        let explain = Explain::default();
+        explain.enable();
+        explain.record("action_cache", "miss");
+        explain.record("degradation", "retry");
+        explain.record("degradation", "cached_action");
+
+        let context = explain.context().unwrap();
+
+        assert_eq!(Some(&"explain".to_string()), context.get("record"));
+        assert_eq!(Some(&"miss".to_string()), context.get("action_cache"));
+        assert_eq!(
+            Some(&"retry, cached_action".to_string()),
+            context.get("degradation")
+        );
+    }
+
+    #[test]
+    fn test_disabled() {
+        let explain = Explain::default();
+        explain.record("excluded", "bypass");
+
+        assert!(explain.context().is_none());
+        assert!(explain.stages.borrow().is_empty());
+    }
+}
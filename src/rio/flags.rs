@@ -0,0 +1,132 @@
+use super::kv_store::KvStore;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::cell::OnceCell;
+use std::collections::HashMap;
+
+const FLAGS_KEY: &str = "rio_flags";
+
+/// Feature flags read from the `rio_flags` entry of the KV Store, a JSON object, so operators can
+/// change the behavior of the worker instantly, without publishing a new service version:
+///
+/// * `maintenance`: answer every request with a `503`;
+/// * `dry_run`: match requests and log them, but forward them to the backend untouched;
+/// * `body_filtering`: apply the body filters of the rules, enabled by default;
+/// * `log_sampling`: share of the requests logged to redirection.io, from `0` to `1`.
+///
+/// The entry is read once per request, on first use.
+pub struct FeatureFlags<'a> {
+    store: &'a dyn KvStore,
+    flags: OnceCell<HashMap<String, Value>>,
+}
+
+impl<'a> FeatureFlags<'a> {
+    pub(crate) fn new(store: &'a dyn KvStore) -> FeatureFlags<'a> {
+        FeatureFlags {
+            store,
+            flags: OnceCell::new(),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&Value> {
+        self.all().get(name)
+    }
+
+    fn is_enabled(&self, name: &str, default: bool) -> bool {
+        self.get(name).and_then(Value::as_bool).unwrap_or(default)
+    }
+
+    /// All the flags, as set in the entry.
+    pub fn all(&self) -> &HashMap<String, Value> {
+        self.flags.get_or_init(|| {
+            self.store
+                .lookup(FLAGS_KEY)
+                .map(|json| parse(&json))
+                .unwrap_or_default()
+        })
+    }
+
+    pub fn maintenance(&self) -> bool {
+        self.is_enabled("maintenance", false)
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.is_enabled("dry_run", false)
+    }
+
+    pub fn body_filtering(&self) -> bool {
+        self.is_enabled("body_filtering", true)
+    }
+
+    pub fn log_sampling(&self) -> f64 {
+        self.get("log_sampling")
+            .and_then(Value::as_f64)
+            .map(|rate| rate.clamp(0.0, 1.0))
+            .unwrap_or(1.0)
+    }
+}
+
+fn parse(json: &str) -> HashMap<String, Value> {
+    serde_json::from_str(json).unwrap_or_default()
+}
+
+/// Whether a request is part of the logged sample, drawn from its unique identifier.
+pub fn is_sampled(request_id: Option<&str>, rate: f64) -> bool {
+    let request_id = match request_id {
+        Some(request_id) if rate < 1.0 => request_id,
+        _ => return true,
+    };
+
+    let hash = Sha256::digest(request_id.as_bytes());
+    let draw = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) as f64 / u32::MAX as f64;
+
+    draw < rate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rio::mock::MockKvStore;
+
+    #[test]
+    fn test_flags() {
+        let store = MockKvStore::new(&[(
+            FLAGS_KEY,
+            r#"{"maintenance": true, "body_filtering": false, "log_sampling": 0.25}"#,
+        )]);
+        let flags = FeatureFlags::new(&store);
+
+        assert!(flags.maintenance());
+        assert!(!flags.dry_run());
+        assert!(!flags.body_filtering());
+        assert_eq!(0.25, flags.log_sampling());
+    }
+
+    #[test]
+    fn test_default_flags() {
+        for store in [
+            MockKvStore::new(&[(FLAGS_KEY, "not json")]),
+            MockKvStore::default(),
+        ] {
+            let flags = FeatureFlags::new(&store);
+
+            assert!(!flags.maintenance());
+            assert!(!flags.dry_run());
+            assert!(flags.body_filtering());
+            assert_eq!(1.0, flags.log_sampling());
+        }
+    }
+
+    #[test]
+    fn test_is_sampled() {
+        assert!(is_sampled(None, 0.0));
+        assert!(is_sampled(Some("request"), 1.0));
+        assert!(!is_sampled(Some("request"), 0.0));
+
+        let sampled = (0..1000)
+            .filter(|index| is_sampled(Some(&index.to_string()), 0.25))
+            .count();
+
+        assert!((200..300).contains(&sampled));
+    }
+}
@@ -0,0 +1,148 @@
+use fastly::geo::geo_lookup;
+use fastly::http::header;
+use fastly::{Request, Response};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// What to do with the requests of a country, keyed by ISO 3166-1 alpha-2 country code in the
+/// `geo_policies` entry.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum GeoPolicy {
+    /// Answer with an error page, `451` by default
+    Block {
+        #[serde(default = "default_block_status")]
+        status: u16,
+    },
+    /// Redirect to the site of the country, `302` by default
+    Redirect {
+        location: String,
+        #[serde(default)]
+        preserve_path: bool,
+        #[serde(default = "default_redirect_status")]
+        status: u16,
+    },
+}
+
+fn default_block_status() -> u16 {
+    451
+}
+
+fn default_redirect_status() -> u16 {
+    302
+}
+
+/// Apply the policy of the country of the client, before any call to the redirection.io API.
+///
+/// Returns `None` when the country of the client is unknown or has no policy.
+pub fn handle(geo_policies: &HashMap<String, GeoPolicy>, req: &Request) -> Option<Response> {
+    if geo_policies.is_empty() {
+        return None;
+    }
+
+    let country_code = geo_lookup(req.get_client_ip_addr()?)?
+        .country_code()
+        .to_string();
+    let policy = policy_for(geo_policies, &country_code)?;
+
+    let mut response = respond(policy, req.get_url().path(), req.get_url().query());
+
+    if let GeoPolicy::Block { .. } = policy {
+        response.set_body_text_plain("This content is not available in your country.\n");
+    }
+
+    Some(response)
+}
+
+fn policy_for<'a>(
+    geo_policies: &'a HashMap<String, GeoPolicy>,
+    country_code: &str,
+) -> Option<&'a GeoPolicy> {
+    geo_policies
+        .iter()
+        .find(|(code, _)| code.eq_ignore_ascii_case(country_code))
+        .map(|(_, policy)| policy)
+}
+
+fn respond(policy: &GeoPolicy, path: &str, query: Option<&str>) -> Response {
+    let mut response = match policy {
+        GeoPolicy::Block { status } => Response::from_status(*status),
+        GeoPolicy::Redirect {
+            location,
+            preserve_path,
+            status,
+        } => {
+            let location = match (preserve_path, query) {
+                (true, Some(query)) => {
+                    format!("{}{}?{}", location.trim_end_matches('/'), path, query)
+                }
+                (true, None) => format!("{}{}", location.trim_end_matches('/'), path),
+                (false, _) => location.clone(),
+            };
+
+            let mut response = Response::from_status(*status);
+            response.set_header(header::LOCATION, location);
+            response
+        }
+    };
+
+    // The answer depends on the location of the client: it must not be shared
+    response.set_header(header::CACHE_CONTROL, "private, no-store");
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::from_str as json_decode;
+
+    fn policies() -> HashMap<String, GeoPolicy> {
+        json_decode(
+            r#"{
+                "XX": {"action": "block"},
+                "fr": {"action": "redirect", "location": "https://fr.example.com/", "preserve_path": true}
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_policies() {
+        let policies = policies();
+
+        assert_eq!(Some(&GeoPolicy::Block { status: 451 }), policies.get("XX"));
+        assert_eq!(
+            Some(&GeoPolicy::Redirect {
+                location: "https://fr.example.com/".to_string(),
+                preserve_path: true,
+                status: 302,
+            }),
+            policies.get("fr")
+        );
+    }
+
+    #[test]
+    fn test_policy_for() {
+        let policies = policies();
+
+        assert!(policy_for(&policies, "FR").is_some());
+        assert!(policy_for(&policies, "US").is_none());
+    }
+
+    #[test]
+    fn test_redirect() {
+        let policies = policies();
+        let response = respond(&policies["fr"], "/products", Some("page=2"));
+
+        assert_eq!(302, response.get_status().as_u16());
+        assert_eq!(
+            Some("https://fr.example.com/products?page=2"),
+            response.get_header_str(header::LOCATION)
+        );
+        assert_eq!(
+            Some("private, no-store"),
+            response.get_header_str(header::CACHE_CONTROL)
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use fastly::http::HeaderValue;
+use std::convert::TryFrom;
+
+/// Decode a header value as UTF-8 when it is valid, or as latin-1 (ISO-8859-1) otherwise, so
+/// headers with raw bytes still take part in matching, filtering and logging.
+pub fn decode(value: &HeaderValue) -> String {
+    match std::str::from_utf8(value.as_bytes()) {
+        Ok(value) => value.to_string(),
+        Err(_) => latin1_decode(value.as_bytes()),
+    }
+}
+
+pub fn is_latin1(value: &HeaderValue) -> bool {
+    std::str::from_utf8(value.as_bytes()).is_err()
+}
+
+pub fn latin1_decode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| *byte as char).collect()
+}
+
+/// Encode a value decoded by `latin1_decode` back to its original bytes.
+pub fn latin1_encode(value: &str) -> Option<HeaderValue> {
+    let bytes = value
+        .chars()
+        .map(|c| u8::try_from(c as u32).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    HeaderValue::from_bytes(&bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_utf8() {
+        assert_eq!("café", decode(&HeaderValue::from_str("café").unwrap()));
+        assert!(!is_latin1(&HeaderValue::from_str("café").unwrap()));
+    }
+
+    #[test]
+    fn test_decode_latin1() {
+        let value = HeaderValue::from_bytes(b"attachment; filename=caf\xe9.pdf").unwrap();
+
+        assert!(is_latin1(&value));
+        assert_eq!("attachment; filename=café.pdf", decode(&value));
+        assert_eq!(Some(value.clone()), latin1_encode(&decode(&value)));
+    }
+
+    #[test]
+    fn test_latin1_encode_rejects_other_characters() {
+        assert_eq!(None, latin1_encode("€"));
+    }
+}
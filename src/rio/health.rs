@@ -0,0 +1,60 @@
+use super::backend_health::{BackendHealthState, BackendHealthTracker};
+use super::clock::Clock;
+use super::kv_store::KvStore;
+use super::outage::{OutageState, OutageTracker};
+use fastly::http::StatusCode;
+use fastly::Response;
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Serialize)]
+struct Health {
+    status: &'static str,
+    api_outage: Option<OutageState>,
+    backends: HashMap<String, Option<BackendHealthState>>,
+}
+
+/// Answer the health endpoint, reporting the redirection.io API outage state and the health of
+/// the given backends.
+pub fn handle(
+    outage_tracker: &OutageTracker,
+    backend_health_tracker: &BackendHealthTracker,
+    backends: &[String],
+    clock: &dyn Clock,
+    store: &dyn KvStore,
+) -> Response {
+    let api_outage = outage_tracker.state(store);
+    let backends: HashMap<String, Option<BackendHealthState>> = backends
+        .iter()
+        .map(|backend| {
+            (
+                backend.clone(),
+                backend_health_tracker.state(backend, store),
+            )
+        })
+        .collect();
+    let now = clock.now_secs();
+    let has_unhealthy_backend = backends.values().any(|state| match state {
+        Some(state) => state.is_unhealthy(now),
+        None => false,
+    });
+
+    let health = Health {
+        status: if api_outage.is_some() || has_unhealthy_backend {
+            "degraded"
+        } else {
+            "ok"
+        },
+        api_outage,
+        backends,
+    };
+
+    let mut response = Response::from_status(StatusCode::OK);
+    response.set_header("Cache-Control", "no-store");
+
+    if response.set_body_json(&health).is_err() {
+        response.set_body_text_plain(health.status);
+    }
+
+    response
+}
@@ -0,0 +1,35 @@
+use fastly::{Request, Response};
+use redirectionio::action::Action;
+use redirectionio::http::Request as RedirectionioRequest;
+
+/// This trait is used to run custom logic at well defined points of the request processing.
+///
+/// The application may implement this trait to add feature flags, authentication or experiments
+/// without reimplementing `Application::proxy`. All the callbacks do nothing by default.
+pub trait WorkerHooks {
+    /// Called before asking redirection.io for an action. Returning a response answers the
+    /// client directly, without calling the API nor the backend.
+    fn before_match(
+        &self,
+        _req: &mut Request,
+        _rio_request: &mut RedirectionioRequest,
+    ) -> Option<Response> {
+        None
+    }
+
+    /// Called with the action returned by redirection.io, before it is applied.
+    fn after_match(&self, _req: &Request, _action: &mut Action) {}
+
+    /// Called before the request is sent to the backend.
+    fn before_backend(&self, _req: &mut Request) {}
+
+    /// Called with the backend response, before the action is applied to it.
+    fn after_backend(&self, _response: &mut Response) {}
+
+    /// Called with the final response, before it is sent to the client.
+    fn before_respond(&self, _response: &mut Response) {}
+}
+
+/// Default implementation, with no custom logic.
+pub struct NoHooks;
+impl WorkerHooks for NoHooks {}
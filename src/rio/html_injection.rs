@@ -0,0 +1,203 @@
+use redirectionio::http::Header;
+use serde::Deserialize;
+
+/// A snippet injected in HTML responses, like an analytics tag or a consent banner script, from
+/// the `html_injections` entry.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HtmlInjection {
+    pub position: InjectionPosition,
+    pub snippet: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InjectionPosition {
+    HeadEnd,
+    BodyEnd,
+}
+
+impl InjectionPosition {
+    fn marker(&self) -> &'static [u8] {
+        match self {
+            InjectionPosition::HeadEnd => b"</head>",
+            InjectionPosition::BodyEnd => b"</body>",
+        }
+    }
+}
+
+/// Streaming rewriter injecting snippets before the `</head>` and `</body>` tags of an HTML body.
+///
+/// Chunks may end in the middle of a tag, so the end of each chunk is kept until the next one is
+/// received. Each tag is only looked for once, in document order: a snippet whose tag is never
+/// found is not injected.
+pub struct HtmlInjector {
+    // Markers still looked for, in document order, with the snippet to inject before them
+    injections: Vec<(&'static [u8], Vec<u8>)>,
+    buffer: Vec<u8>,
+}
+
+impl HtmlInjector {
+    /// Create an injector for a response, when it is an HTML response and there is something to
+    /// inject.
+    pub fn new(injections: &[HtmlInjection], headers: &[Header]) -> Option<HtmlInjector> {
+        let is_html = headers.iter().any(|header| {
+            header.name.eq_ignore_ascii_case("Content-Type")
+                && header.value.to_lowercase().contains("text/html")
+        });
+
+        if !is_html {
+            return None;
+        }
+
+        let injections: Vec<(&'static [u8], Vec<u8>)> =
+            [InjectionPosition::HeadEnd, InjectionPosition::BodyEnd]
+                .iter()
+                .map(|position| {
+                    let snippet = injections
+                        .iter()
+                        .filter(|injection| injection.position == *position)
+                        .map(|injection| injection.snippet.as_str())
+                        .collect::<String>();
+
+                    (position.marker(), snippet.into_bytes())
+                })
+                .filter(|(_, snippet)| !snippet.is_empty())
+                .collect();
+
+        if injections.is_empty() {
+            return None;
+        }
+
+        Some(HtmlInjector {
+            injections,
+            buffer: Vec::new(),
+        })
+    }
+
+    pub fn filter(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut output = Vec::new();
+
+        loop {
+            let found = self
+                .injections
+                .iter()
+                .enumerate()
+                .filter_map(|(index, (marker, _))| {
+                    find(&self.buffer, marker).map(|position| (position, index))
+                })
+                .min();
+
+            match found {
+                Some((position, index)) => {
+                    output.extend(self.buffer.drain(..position));
+                    output.extend_from_slice(&self.injections[index].1);
+                    // A later tag was found first: the earlier ones will not be found anymore
+                    self.injections.drain(..=index);
+                }
+                None => break,
+            }
+        }
+
+        // Keep what could be the beginning of a tag split over two chunks
+        let keep = self
+            .injections
+            .iter()
+            .map(|(marker, _)| marker.len() - 1)
+            .max()
+            .unwrap_or(0)
+            .min(self.buffer.len());
+        output.extend(self.buffer.drain(..self.buffer.len() - keep));
+
+        output
+    }
+
+    pub fn end(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HTML: &str = "<html><head><title>Title</title></head><body><p>Text</p></body></html>";
+
+    fn injector() -> HtmlInjector {
+        let injections = vec![
+            HtmlInjection {
+                position: InjectionPosition::BodyEnd,
+                snippet: "<script src=\"/consent.js\"></script>".to_string(),
+            },
+            HtmlInjection {
+                position: InjectionPosition::HeadEnd,
+                snippet: "<script src=\"/analytics.js\"></script>".to_string(),
+            },
+        ];
+        let headers = vec![Header {
+            name: "Content-Type".to_string(),
+            value: "text/html; charset=UTF-8".to_string(),
+        }];
+
+        HtmlInjector::new(&injections, &headers).unwrap()
+    }
+
+    fn inject(chunks: &[&str]) -> String {
+        let mut injector = injector();
+        let mut output = Vec::new();
+
+        for chunk in chunks {
+            output.extend(injector.filter(chunk.as_bytes()));
+        }
+
+        output.extend(injector.end());
+
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_inject() {
+        assert_eq!(
+            "<html><head><title>Title</title><script src=\"/analytics.js\"></script></head><body><p>Text</p><script src=\"/consent.js\"></script></body></html>",
+            inject(&[HTML])
+        );
+    }
+
+    #[test]
+    fn test_inject_in_split_tags() {
+        let expected = inject(&[HTML]);
+
+        for split in 1..HTML.len() {
+            assert_eq!(expected, inject(&[&HTML[..split], &HTML[split..]]));
+        }
+    }
+
+    #[test]
+    fn test_inject_without_head() {
+        assert_eq!(
+            "<BODY>Text<script src=\"/consent.js\"></script></BODY>",
+            inject(&["<BODY>Text</BODY>"])
+        );
+    }
+
+    #[test]
+    fn test_no_injector_for_other_content_types() {
+        let injections = vec![HtmlInjection {
+            position: InjectionPosition::HeadEnd,
+            snippet: "<script></script>".to_string(),
+        }];
+        let headers = vec![Header {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        }];
+
+        assert!(HtmlInjector::new(&injections, &headers).is_none());
+    }
+}
@@ -0,0 +1,52 @@
+use fastly::Request;
+
+const IMAGE_OPTIMIZER_HEADER: &str = "x-fastly-imageopto-api";
+
+/// Enables the Fastly Image Optimizer on the requests matching some path prefixes.
+///
+/// The responses of such requests are images, so they are never run through the body filters.
+pub struct ImageOptimizer {
+    paths: Vec<String>,
+}
+
+impl ImageOptimizer {
+    pub(crate) fn new(paths: Vec<String>) -> ImageOptimizer {
+        ImageOptimizer { paths }
+    }
+
+    pub fn matches(&self, req: &Request) -> bool {
+        let path = req.get_path();
+
+        self.paths.iter().any(|prefix| path.starts_with(prefix))
+    }
+
+    /// Ask Fastly to optimize the response of the request.
+    pub fn enable(&self, req: &mut Request) {
+        req.set_header(IMAGE_OPTIMIZER_HEADER, "fastly");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_path_prefixes() {
+        let image_optimizer =
+            ImageOptimizer::new(vec!["/images/".to_string(), "/media/".to_string()]);
+
+        assert!(image_optimizer.matches(&Request::get("https://example.org/images/logo.png")));
+        assert!(image_optimizer.matches(&Request::get("https://example.org/media/a.jpg?w=100")));
+        assert!(!image_optimizer.matches(&Request::get("https://example.org/page/images/")));
+        assert!(!ImageOptimizer::new(vec![]).matches(&Request::get("https://example.org/")));
+    }
+
+    #[test]
+    fn test_enable() {
+        let mut req = Request::get("https://example.org/images/logo.png");
+
+        ImageOptimizer::new(vec![]).enable(&mut req);
+
+        assert_eq!(Some("fastly"), req.get_header_str(IMAGE_OPTIMIZER_HEADER));
+    }
+}
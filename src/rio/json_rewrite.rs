@@ -0,0 +1,168 @@
+use redirectionio::http::Header;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Rewrites the URLs embedded in JSON responses, from the `json_rewrites` entry: a map of JSON
+/// pointers to the prefix replacements applied to the strings found under them, like
+/// `{"/data": {"https://old.example.com/": "https://new.example.com/"}}`.
+///
+/// The body is parsed and serialized again, so the response stays valid JSON. A body which is not
+/// valid JSON, or where nothing is replaced, is kept as is.
+pub struct JsonRewriter {
+    // Replacements of each pointer, the longest prefixes first
+    rewrites: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl JsonRewriter {
+    pub(crate) fn new(rewrites: &HashMap<String, HashMap<String, String>>) -> JsonRewriter {
+        let mut rewrites: Vec<(String, Vec<(String, String)>)> = rewrites
+            .iter()
+            .map(|(pointer, replacements)| {
+                let mut replacements: Vec<(String, String)> = replacements
+                    .iter()
+                    .map(|(from, to)| (from.clone(), to.clone()))
+                    .collect();
+
+                replacements.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then(a.0.cmp(&b.0)));
+
+                (pointer.clone(), replacements)
+            })
+            .collect();
+
+        rewrites.sort();
+
+        JsonRewriter { rewrites }
+    }
+
+    pub fn rewrite(&self, body: &str) -> Option<String> {
+        let mut value: Value = serde_json::from_str(body).ok()?;
+        let mut replaced = false;
+
+        for (pointer, replacements) in &self.rewrites {
+            if let Some(target) = value.pointer_mut(pointer) {
+                replaced |= replace_strings(target, replacements);
+            }
+        }
+
+        match replaced {
+            true => serde_json::to_string(&value).ok(),
+            false => None,
+        }
+    }
+}
+
+/// Whether the response is a JSON document: `application/json`, or a `+json` media type.
+pub fn is_json_response(headers: &[Header]) -> bool {
+    headers.iter().any(|header| {
+        if !header.name.eq_ignore_ascii_case("Content-Type") {
+            return false;
+        }
+
+        let media_type = header.value.split(';').next().unwrap_or_default().trim();
+        let media_type = media_type.to_lowercase();
+
+        media_type == "application/json" || media_type.ends_with("+json")
+    })
+}
+
+fn replace_strings(value: &mut Value, replacements: &[(String, String)]) -> bool {
+    match value {
+        Value::String(string) => {
+            let replacement = replacements
+                .iter()
+                .find(|(from, _)| string.starts_with(from.as_str()));
+
+            match replacement {
+                Some((from, to)) => {
+                    *string = format!("{}{}", to, &string[from.len()..]);
+
+                    true
+                }
+                None => false,
+            }
+        }
+        Value::Array(values) => replace_all(values.iter_mut(), replacements),
+        Value::Object(values) => replace_all(values.values_mut(), replacements),
+        _ => false,
+    }
+}
+
+// Every value is visited, the replacements do not stop at the first one
+fn replace_all<'a>(
+    values: impl Iterator<Item = &'a mut Value>,
+    replacements: &[(String, String)],
+) -> bool {
+    let mut replaced = false;
+
+    for value in values {
+        replaced |= replace_strings(value, replacements);
+    }
+
+    replaced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_rewriter(pointer: &str) -> JsonRewriter {
+        JsonRewriter::new(&HashMap::from([(
+            pointer.to_string(),
+            HashMap::from([
+                (
+                    "https://old.example.com".to_string(),
+                    "https://new.example.com".to_string(),
+                ),
+                (
+                    "https://old.example.com/blog/".to_string(),
+                    "https://blog.example.com/".to_string(),
+                ),
+            ]),
+        )]))
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let body = r#"{"data": {"url": "https://old.example.com/a", "links": ["https://old.example.com/blog/b", "https://other.example.com/"]}, "self": "https://old.example.com/"}"#;
+
+        assert_eq!(
+            Some(r#"{"data":{"links":["https://blog.example.com/b","https://other.example.com/"],"url":"https://new.example.com/a"},"self":"https://old.example.com/"}"#.to_string()),
+            create_rewriter("/data").rewrite(body)
+        );
+        assert_eq!(
+            Some(r#"{"data":{"links":["https://blog.example.com/b","https://other.example.com/"],"url":"https://new.example.com/a"},"self":"https://new.example.com/"}"#.to_string()),
+            create_rewriter("").rewrite(body)
+        );
+    }
+
+    #[test]
+    fn test_rewrite_unchanged() {
+        assert_eq!(
+            None,
+            create_rewriter("").rewrite(r#"{"url": "https://other.example.com/"}"#)
+        );
+        assert_eq!(
+            None,
+            create_rewriter("/missing").rewrite(r#"{"url": "https://old.example.com/"}"#)
+        );
+        assert_eq!(
+            None,
+            create_rewriter("").rewrite(r#"{"url": "https://old.example.com/""#)
+        );
+    }
+
+    #[test]
+    fn test_is_json_response() {
+        let header = |value: &str| Header {
+            name: "content-type".to_string(),
+            value: value.to_string(),
+        };
+
+        assert!(is_json_response(&[header(
+            "application/json; charset=utf-8"
+        )]));
+        assert!(is_json_response(&[header("application/ld+json")]));
+        assert!(!is_json_response(&[header("text/html")]));
+        assert!(!is_json_response(&[]));
+    }
+}
@@ -0,0 +1,59 @@
+use fastly::KVStore;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::from_str as json_decode;
+use serde_json::to_string as json_encode;
+
+pub const KV_STORE_NAME: &str = "redirectionio";
+
+/// This trait provides access to the KV Store shared by the instances of a POP, so the states kept
+/// in it can be tested with an in-memory store.
+pub trait KvStore {
+    fn lookup(&self, key: &str) -> Option<String>;
+
+    fn insert(&self, key: &str, value: String) -> bool;
+
+    fn delete(&self, key: &str) -> bool;
+}
+
+/// Default implementation based on the `redirectionio` KV Store, which finds nothing and stores
+/// nothing when the KV Store is not linked to the service.
+pub struct FastlyKvStore;
+
+impl KvStore for FastlyKvStore {
+    fn lookup(&self, key: &str) -> Option<String> {
+        open()?.lookup_str(key).ok()?
+    }
+
+    fn insert(&self, key: &str, value: String) -> bool {
+        match open() {
+            Some(mut store) => store.insert(key, value).is_ok(),
+            None => false,
+        }
+    }
+
+    fn delete(&self, key: &str) -> bool {
+        match open() {
+            Some(store) => store.delete(key).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Open the `redirectionio` KV Store, if it is linked to the service.
+pub fn open() -> Option<KVStore> {
+    KVStore::open(KV_STORE_NAME).ok().flatten()
+}
+
+pub fn get_json<T: DeserializeOwned>(store: &dyn KvStore, key: &str) -> Option<T> {
+    json_decode(&store.lookup(key)?).ok()
+}
+
+pub fn set_json<T: Serialize>(store: &dyn KvStore, key: &str, value: &T) -> bool {
+    let value = match json_encode(value) {
+        Ok(value) => value,
+        Err(_) => return false,
+    };
+
+    store.insert(key, value)
+}
@@ -0,0 +1,133 @@
+use redirectionio::http::Header;
+use serde::Deserialize;
+
+/// A `Link` header value added to HTML responses, like `</app.css>; rel=preload; as=style`, from
+/// the `link_headers` entry.
+///
+/// When `paths` is not empty, the link is only added to responses of paths starting with one of
+/// them.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LinkHeader {
+    pub value: String,
+    #[serde(default)]
+    pub paths: Vec<String>,
+}
+
+impl LinkHeader {
+    pub fn matches(&self, path: &str) -> bool {
+        self.paths.is_empty() || self.paths.iter().any(|prefix| path.starts_with(prefix))
+    }
+}
+
+/// Add the links matching the path to the `Link` header of an HTML response.
+///
+/// Links are merged in a single header, after the ones already sent by the backend or set by the
+/// rules.
+pub fn add_link_headers(headers: &mut Vec<Header>, link_headers: &[LinkHeader], path: &str) {
+    let is_html = headers.iter().any(|header| {
+        header.name.eq_ignore_ascii_case("Content-Type")
+            && header.value.to_lowercase().contains("text/html")
+    });
+
+    if !is_html {
+        return;
+    }
+
+    let mut links: Vec<String> = headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("Link"))
+        .map(|header| header.value.clone())
+        .collect();
+    let existing = links.len();
+
+    links.extend(
+        link_headers
+            .iter()
+            .filter(|link_header| link_header.matches(path))
+            .map(|link_header| link_header.value.clone()),
+    );
+
+    if links.len() == existing {
+        return;
+    }
+
+    headers.retain(|header| !header.name.eq_ignore_ascii_case("Link"));
+    headers.push(Header {
+        name: "Link".to_string(),
+        value: links.join(", "),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, value: &str) -> Header {
+        Header {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn link_header(value: &str, paths: &[&str]) -> LinkHeader {
+        LinkHeader {
+            value: value.to_string(),
+            paths: paths.iter().map(|path| path.to_string()).collect(),
+        }
+    }
+
+    fn links(headers: &[Header]) -> Vec<&str> {
+        headers
+            .iter()
+            .filter(|header| header.name.eq_ignore_ascii_case("Link"))
+            .map(|header| header.value.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_add_link_headers() {
+        let link_headers = vec![
+            link_header("<https://cdn.example.org>; rel=preconnect", &[]),
+            link_header("</blog.css>; rel=preload; as=style", &["/blog/"]),
+        ];
+        let mut headers = vec![
+            header("Content-Type", "text/html; charset=UTF-8"),
+            header("link", "</app.js>; rel=preload; as=script"),
+        ];
+
+        add_link_headers(&mut headers, &link_headers, "/");
+
+        assert_eq!(
+            vec!["</app.js>; rel=preload; as=script, <https://cdn.example.org>; rel=preconnect"],
+            links(&headers)
+        );
+        assert_eq!(2, headers.len());
+    }
+
+    #[test]
+    fn test_link_headers_for_matched_paths() {
+        let link_headers = vec![link_header(
+            "</blog.css>; rel=preload; as=style",
+            &["/blog/"],
+        )];
+        let mut headers = vec![header("Content-Type", "text/html")];
+
+        add_link_headers(&mut headers, &link_headers, "/shop/");
+
+        assert!(links(&headers).is_empty());
+
+        add_link_headers(&mut headers, &link_headers, "/blog/post");
+
+        assert_eq!(vec!["</blog.css>; rel=preload; as=style"], links(&headers));
+    }
+
+    #[test]
+    fn test_link_headers_only_on_html() {
+        let link_headers = vec![link_header("</app.css>; rel=preload; as=style", &[])];
+        let mut headers = vec![header("Content-Type", "application/json")];
+
+        add_link_headers(&mut headers, &link_headers, "/");
+
+        assert!(links(&headers).is_empty());
+    }
+}
@@ -0,0 +1,246 @@
+use super::clock::Clock;
+use super::kv_store::{self, KvStore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+const KEY_PREFIX: &str = "log_budget";
+
+// Number of messages of a class logged per window, the others are only counted
+const MESSAGES_PER_WINDOW: u64 = 10;
+
+// Duration, in seconds, of the window messages are counted in
+const WINDOW: u64 = 60;
+
+// Counts of the instance, by message class
+static STATES: OnceLock<Mutex<HashMap<String, BudgetState>>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    /// Log the message, after a summary of the messages suppressed in the previous window, if any.
+    Log {
+        suppressed: u64,
+    },
+    Suppress,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BudgetState {
+    pub window_start: u64,
+    pub count: u64,
+}
+
+impl BudgetState {
+    /// Count a message, logged when at most `limit` messages were counted in the window.
+    pub fn record(&mut self, now: u64, limit: u64) -> Decision {
+        if now.saturating_sub(self.window_start) >= WINDOW {
+            let suppressed = self.count.saturating_sub(limit);
+
+            self.window_start = now;
+            self.count = 1;
+
+            return Decision::Log { suppressed };
+        }
+
+        self.count += 1;
+
+        if self.count <= limit {
+            Decision::Log { suppressed: 0 }
+        } else {
+            Decision::Suppress
+        }
+    }
+}
+
+/// Limits how often identical error messages are logged, so a broken backend does not flood the
+/// log endpoints.
+///
+/// Messages are grouped in classes by their template: the quoted values, URLs and digits they
+/// contain are ignored. Counts are kept in memory by the instance, which reads the window of a
+/// class from the KV Store the first time it logs it, and only writes it back when a new window
+/// starts or the limit is reached, so other instances suppress the class as well.
+pub struct LogBudget;
+
+impl LogBudget {
+    pub fn record(&self, message: &str, clock: &dyn Clock, store: &dyn KvStore) -> Decision {
+        self.record_with_limit(message, MESSAGES_PER_WINDOW, clock, store)
+    }
+
+    /// Count a message logged at most once per window, like the configuration errors which
+    /// every request hits during a misdeploy.
+    pub fn record_once(&self, message: &str, clock: &dyn Clock, store: &dyn KvStore) -> Decision {
+        self.record_with_limit(message, 1, clock, store)
+    }
+
+    fn record_with_limit(
+        &self,
+        message: &str,
+        limit: u64,
+        clock: &dyn Clock,
+        store: &dyn KvStore,
+    ) -> Decision {
+        let class = message_class(message);
+        let mut states = match STATES.get_or_init(Default::default).lock() {
+            Ok(states) => states,
+            // A panic while logging must not stop the next logs
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let state = states
+            .entry(class.clone())
+            .or_insert_with(|| kv_store::get_json(store, &key(&class)).unwrap_or_default());
+        let window_start = state.window_start;
+        let decision = state.record(clock.now_secs(), limit);
+
+        if state.window_start != window_start || state.count == limit {
+            kv_store::set_json(store, &key(&class), state);
+        }
+
+        decision
+    }
+}
+
+pub fn message_class(message: &str) -> String {
+    hex::encode(&Sha256::digest(message_template(message).as_bytes())[..8])
+}
+
+/// The message without its variable parts: quoted values, URLs and digits (ports, status codes,
+/// durations).
+pub fn message_template(message: &str) -> String {
+    let mut template = String::with_capacity(message.len());
+    let mut is_quoted = false;
+
+    for c in message.chars() {
+        match c {
+            '"' => {
+                is_quoted = !is_quoted;
+
+                if !is_quoted {
+                    template.push_str("\"\"");
+                }
+            }
+            _ if is_quoted || c.is_ascii_digit() => (),
+            _ => template.push(c),
+        }
+    }
+
+    template
+        .split_whitespace()
+        .filter(|word| !word.contains("://"))
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+fn key(class: &str) -> String {
+    format!("{}:{}", KEY_PREFIX, class)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rio::mock::{MockClock, MockKvStore};
+
+    #[test]
+    fn test_budget() {
+        let mut state = BudgetState::default();
+
+        assert_eq!(
+            Decision::Log { suppressed: 0 },
+            state.record(1000, MESSAGES_PER_WINDOW)
+        );
+
+        for _ in 1..MESSAGES_PER_WINDOW {
+            assert_eq!(
+                Decision::Log { suppressed: 0 },
+                state.record(1000, MESSAGES_PER_WINDOW)
+            );
+        }
+
+        assert_eq!(Decision::Suppress, state.record(1000, MESSAGES_PER_WINDOW));
+        assert_eq!(
+            Decision::Suppress,
+            state.record(1000 + WINDOW - 1, MESSAGES_PER_WINDOW)
+        );
+        assert_eq!(
+            Decision::Log { suppressed: 2 },
+            state.record(1000 + WINDOW, MESSAGES_PER_WINDOW)
+        );
+    }
+
+    #[test]
+    fn test_budget_once() {
+        let mut state = BudgetState::default();
+
+        assert_eq!(Decision::Log { suppressed: 0 }, state.record(1000, 1));
+        assert_eq!(Decision::Suppress, state.record(1010, 1));
+        assert_eq!(Decision::Suppress, state.record(1020, 1));
+        assert_eq!(
+            Decision::Log { suppressed: 2 },
+            state.record(1000 + WINDOW, 1)
+        );
+    }
+
+    #[test]
+    fn test_record_shares_window() {
+        let message = "Cannot send request to the shared window test backend.";
+        let store = MockKvStore::default();
+        let clock = MockClock::new(1_000_000, 0);
+
+        for _ in 0..MESSAGES_PER_WINDOW + 5 {
+            LogBudget.record(message, &clock, &store);
+        }
+
+        // Written when the window starts, then when the limit is reached
+        assert_eq!(2, store.writes.borrow().len());
+
+        let state: BudgetState = kv_store::get_json(&store, &key(&message_class(message))).unwrap();
+
+        assert_eq!(1000, state.window_start);
+        assert_eq!(MESSAGES_PER_WINDOW, state.count);
+    }
+
+    #[test]
+    fn test_record_reads_window() {
+        let message = "Cannot send request to the exhausted window test backend.";
+        let clock = MockClock::new(1_000_000, 0);
+        let store = MockKvStore::new(&[(
+            &key(&message_class(message)),
+            r#"{"window_start": 1000, "count": 10}"#,
+        )]);
+
+        assert_eq!(
+            Decision::Suppress,
+            LogBudget.record(message, &clock, &store)
+        );
+        assert!(store.writes.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_message_class_ignores_digits() {
+        assert_eq!(
+            message_class("Backend error: status 502 after 1200ms"),
+            message_class("Backend error: status 503 after 30ms")
+        );
+        assert_ne!(message_class("Backend error"), message_class("API error"));
+    }
+
+    #[test]
+    fn test_message_template() {
+        assert_eq!(
+            "backend error on \"\": cannot send request to backend: error sending request: Connection refused to backend origin",
+            message_template("backend error on \"https://example.org/page?id=42\": cannot send request to backend: error sending request: Connection refused to backend origin")
+        );
+        assert_eq!(
+            "Cannot send beacon to \"\": timeout after ms.",
+            message_template("Cannot send beacon to \"beacons\": timeout after 200ms.")
+        );
+        assert_eq!(
+            "Cannot purge: invalid url",
+            message_template("Cannot purge: invalid url https://example.org/a")
+        );
+        assert_eq!(
+            message_class("action error on \"https://example.org/a\": returned status 502"),
+            message_class("action error on \"https://example.org/b\": returned status 503")
+        );
+    }
+}
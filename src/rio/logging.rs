@@ -1,7 +1,13 @@
+use super::clock::{Clock, SystemClock};
+use super::kv_store::{FastlyKvStore, KvStore};
+use super::log_budget::{Decision, LogBudget};
+use chrono::{TimeZone, Utc};
 use fastly::Request;
 use serde::Serialize;
 use serde_json::to_string as json_encode;
+use serde_json::{json, Map, Value};
 use std::collections::HashMap;
+use std::io::Write;
 use std::str::FromStr;
 
 #[derive(Debug, Serialize)]
@@ -10,19 +16,68 @@ pub struct FastlyLog {
     context: HashMap<&'static str, String>,
 }
 
+const SERVICE_NAME: &str = "redirectionio-fastly-worker";
+
+/// Shape of the logs, so they can be ingested by an observability stack without a transform.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Default,
+    Datadog,
+    Honeycomb,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "default" => Ok(LogFormat::Default),
+            "datadog" => Ok(LogFormat::Datadog),
+            "honeycomb" => Ok(LogFormat::Honeycomb),
+            _ => Err(()),
+        }
+    }
+}
+
+/// This trait writes the logs to the log endpoint, so they can be captured in tests.
+pub trait LogSink {
+    fn write(&self, endpoint: &str, json: &str);
+}
+
+/// Default implementation writing to the Fastly log endpoint, opened by the first log, so requests
+/// logging nothing do not pay for it.
+pub struct FastlyLogSink;
+
+impl LogSink for FastlyLogSink {
+    fn write(&self, endpoint: &str, json: &str) {
+        if let Ok(mut endpoint) = fastly::log::Endpoint::try_from_name(endpoint) {
+            let _ = writeln!(endpoint, "{}", json);
+        }
+    }
+}
+
 #[readonly::make]
 pub struct FastlyLogger {
     has_logger: bool,
     log_endpoint: String,
     log_level: log::LevelFilter,
+    log_format: LogFormat,
     context: Context,
+    clock: Box<dyn Clock>,
+    kv_store: Box<dyn KvStore>,
+    sink: Box<dyn LogSink>,
+    log_budget: LogBudget,
 }
 
 impl FastlyLogger {
     pub(crate) fn new(
         log_endpoint: Option<String>,
         log_level: Option<String>,
+        log_format: Option<String>,
         context: Context,
+        clock: Box<dyn Clock>,
+        kv_store: Box<dyn KvStore>,
+        sink: Box<dyn LogSink>,
     ) -> FastlyLogger {
         let has_logger = match log_endpoint {
             Some(_) => true,
@@ -44,22 +99,106 @@ impl FastlyLogger {
             }
         };
 
-        if has_logger {
-            log_fastly::init_simple(log_endpoint.clone(), log_level);
-        }
+        let log_format = log_format.unwrap_or("default".to_string());
+        let log_format = match LogFormat::from_str(log_format.as_str()) {
+            Ok(format) => format,
+            Err(_) => {
+                println!(
+                    "The log format \"{}\" is not valid, fallback to default",
+                    log_format
+                );
+
+                LogFormat::Default
+            }
+        };
 
         return FastlyLogger {
             has_logger,
             log_endpoint,
             log_level,
+            log_format,
             context,
+            clock,
+            kv_store,
+            sink,
+            log_budget: LogBudget,
         };
     }
 
+    /// Settings of the logger, which can be moved out of the request processing, like to the
+    /// panic hook.
+    pub fn settings(&self) -> LoggerSettings {
+        LoggerSettings {
+            has_logger: self.has_logger,
+            log_endpoint: self.log_endpoint.clone(),
+            log_level: self.log_level,
+            log_format: self.log_format,
+            context: self.context.clone(),
+        }
+    }
+
     pub fn log_error(&self, message: String, context: Option<HashMap<&'static str, String>>) {
+        let decision =
+            self.log_budget
+                .record(&message, self.clock.as_ref(), self.kv_store.as_ref());
+
+        self.log_error_within_budget(message, context, decision);
+    }
+
+    /// Log an error at most once per window, for errors every request hits.
+    pub fn log_error_once(&self, message: String, context: Option<HashMap<&'static str, String>>) {
+        let decision =
+            self.log_budget
+                .record_once(&message, self.clock.as_ref(), self.kv_store.as_ref());
+
+        self.log_error_within_budget(message, context, decision);
+    }
+
+    fn log_error_within_budget(
+        &self,
+        message: String,
+        context: Option<HashMap<&'static str, String>>,
+        decision: Decision,
+    ) {
+        match decision {
+            Decision::Suppress => return,
+            Decision::Log { suppressed: 0 } => (),
+            Decision::Log { suppressed } => self.log(
+                format!(
+                    "{} messages similar to the next one were suppressed.",
+                    suppressed
+                ),
+                None,
+                log::Level::Error,
+            ),
+        }
+
         self.log(message, context, log::Level::Error);
     }
 
+    /// Log a panic to stderr, and to the log endpoint, within the budget of the errors.
+    pub fn log_panic(&self, message: String, context: HashMap<&'static str, String>) {
+        let decision =
+            self.log_budget
+                .record(&message, self.clock.as_ref(), self.kv_store.as_ref());
+
+        if decision == Decision::Suppress {
+            return;
+        }
+
+        if let Ok(json) = json_encode(&self.event(message, context, log::Level::Error)) {
+            eprintln!("{}", json);
+
+            if self.has_logger {
+                self.sink.write(&self.log_endpoint, &json);
+            }
+        }
+    }
+
+    pub fn log_warn(&self, message: String, context: Option<HashMap<&'static str, String>>) {
+        self.log(message, context, log::Level::Warn);
+    }
+
     pub fn log_info(&self, message: String, context: Option<HashMap<&'static str, String>>) {
         self.log(message, context, log::Level::Info);
     }
@@ -70,17 +209,12 @@ impl FastlyLogger {
         context: Option<HashMap<&'static str, String>>,
         level: log::Level,
     ) {
-        let mut context = match context {
-            Some(context) => context,
-            None => HashMap::new(),
-        };
-
-        context.insert("url", self.context.request.get_url_str().to_string());
-        context.insert("method", self.context.request.get_method_str().to_string());
-        context.insert("date", chrono::offset::Utc::now().to_string());
-        context.insert("level", level.to_string());
+        // Errors are always written to stdout, other levels only to the log endpoint
+        if level != log::Level::Error && (!self.has_logger || level > self.log_level) {
+            return;
+        }
 
-        let log = FastlyLog { message, context };
+        let log = self.event(message, context.unwrap_or_default(), level);
 
         match json_encode(&log) {
             Ok(json) => {
@@ -89,21 +223,316 @@ impl FastlyLogger {
                 }
 
                 if self.has_logger {
-                    log::log!(level, "{}", json)
+                    self.sink.write(&self.log_endpoint, &json);
                 }
             }
             Err(_) => return,
         };
     }
+
+    /// Write a record which was explicitly enabled, like `explain`, to the log endpoint whatever
+    /// the log level.
+    pub fn log_record(&self, message: String, context: HashMap<&'static str, String>) {
+        if !self.has_logger {
+            return;
+        }
+
+        if let Ok(json) = json_encode(&self.event(message, context, log::Level::Info)) {
+            self.sink.write(&self.log_endpoint, &json);
+        }
+    }
+
+    /// Build a log event in the configured format.
+    pub fn event(
+        &self,
+        message: String,
+        mut context: HashMap<&'static str, String>,
+        level: log::Level,
+    ) -> Value {
+        let date = format_date(self.clock.now());
+        let trace = self.context.trace.as_ref();
+
+        if self.log_format == LogFormat::Default {
+            context.insert("url", self.context.url.clone());
+            context.insert("method", self.context.method.clone());
+            context.insert("date", date);
+            context.insert("level", level.to_string());
+
+            if let Some(trace) = trace {
+                context.insert("trace_id", trace.trace_id.clone());
+                context.insert("span_id", trace.span_id.clone());
+            }
+
+            return json!(FastlyLog { message, context });
+        }
+
+        let mut event: Map<String, Value> = context
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), Value::String(value)))
+            .collect();
+
+        event.insert("message".to_string(), json!(message));
+
+        match self.log_format {
+            LogFormat::Datadog => {
+                event.insert("ddsource".to_string(), json!("fastly"));
+                event.insert("service".to_string(), json!(SERVICE_NAME));
+                event.insert("status".to_string(), json!(level.as_str().to_lowercase()));
+                event.insert("date".to_string(), json!(date));
+                event.insert("http.url".to_string(), json!(self.context.url));
+                event.insert("http.method".to_string(), json!(self.context.method));
+
+                if let Some(trace) = trace {
+                    // Datadog identifiers are the low 64 bits of the W3C ones, in decimal
+                    event.insert(
+                        "dd.trace_id".to_string(),
+                        json!(datadog_id(&trace.trace_id)),
+                    );
+                    event.insert("dd.span_id".to_string(), json!(datadog_id(&trace.span_id)));
+                }
+            }
+            _ => {
+                event.insert("service.name".to_string(), json!(SERVICE_NAME));
+                event.insert("level".to_string(), json!(level.as_str().to_lowercase()));
+                event.insert("timestamp".to_string(), json!(date));
+                event.insert("http.url".to_string(), json!(self.context.url));
+                event.insert("http.method".to_string(), json!(self.context.method));
+
+                if let Some(trace) = trace {
+                    event.insert("trace.trace_id".to_string(), json!(trace.trace_id));
+                    event.insert("trace.parent_id".to_string(), json!(trace.span_id));
+                }
+            }
+        }
+
+        Value::Object(event)
+    }
+}
+
+/// Settings of a logger, without its clock, KV Store and sink, so they can be sent to another thread.
+pub struct LoggerSettings {
+    has_logger: bool,
+    log_endpoint: String,
+    log_level: log::LevelFilter,
+    log_format: LogFormat,
+    context: Context,
 }
 
+impl LoggerSettings {
+    /// A logger writing to the endpoint already initialized by the original one.
+    pub fn logger(&self) -> FastlyLogger {
+        FastlyLogger {
+            has_logger: self.has_logger,
+            log_endpoint: self.log_endpoint.clone(),
+            log_level: self.log_level,
+            log_format: self.log_format,
+            context: self.context.clone(),
+            clock: Box::new(SystemClock::new()),
+            kv_store: Box::new(FastlyKvStore),
+            sink: Box::new(FastlyLogSink),
+            log_budget: LogBudget,
+        }
+    }
+}
+
+fn datadog_id(id: &str) -> String {
+    let low_bits = &id[id.len().saturating_sub(16)..];
+
+    u64::from_str_radix(low_bits, 16)
+        .map(|id| id.to_string())
+        .unwrap_or_default()
+}
+
+/// Trace and span identifiers of the W3C `traceparent` header of the request.
+#[derive(Debug, Clone)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+}
+
+impl TraceContext {
+    pub fn parse(traceparent: &str) -> Option<TraceContext> {
+        let parts: Vec<&str> = traceparent.trim().split('-').collect();
+        let is_hex = |value: &str| value.chars().all(|c| c.is_ascii_hexdigit());
+
+        match parts.as_slice() {
+            [_, trace_id, span_id, _]
+                if trace_id.len() == 32
+                    && span_id.len() == 16
+                    && is_hex(trace_id)
+                    && is_hex(span_id) =>
+            {
+                Some(TraceContext {
+                    trace_id: trace_id.to_lowercase(),
+                    span_id: span_id.to_lowercase(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Request information added to every log.
+///
+/// Only the url and the method are kept, so the request does not have to be cloned.
 #[readonly::make]
+#[derive(Clone)]
 pub struct Context {
-    pub request: Request,
+    pub url: String,
+    pub method: String,
+    pub trace: Option<TraceContext>,
 }
 
 impl Context {
-    pub(crate) fn new(request: Request) -> Context {
-        return Context { request };
+    pub(crate) fn new(request: &Request) -> Context {
+        return Context {
+            url: request.get_url_str().to_string(),
+            method: request.get_method_str().to_string(),
+            trace: request
+                .get_header_str("traceparent")
+                .and_then(TraceContext::parse),
+        };
+    }
+}
+
+/// Format a number of milliseconds since the Unix epoch as a UTC date.
+pub fn format_date(timestamp: u128) -> String {
+    match Utc.timestamp_millis_opt(timestamp as i64).single() {
+        Some(date) => date.to_string(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rio::mock::{MockClock, MockKvStore, MockLogSink};
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!("2015-10-21 07:28:00.250 UTC", format_date(1445412480250));
+        assert_eq!("1970-01-01 00:00:00 UTC", format_date(0));
+    }
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    fn create_logger(log_format: &str) -> FastlyLogger {
+        let request = Request::get("https://example.org/").with_header("traceparent", TRACEPARENT);
+
+        FastlyLogger::new(
+            None,
+            None,
+            Some(log_format.to_string()),
+            Context::new(&request),
+            Box::new(MockClock::new(1445412480250, 0)),
+            Box::new(MockKvStore::default()),
+            Box::new(MockLogSink::default()),
+        )
+    }
+
+    #[test]
+    fn test_endpoint_logs() {
+        let sink = MockLogSink::default();
+        let logger = FastlyLogger::new(
+            Some("logs".to_string()),
+            Some("warn".to_string()),
+            None,
+            Context::new(&Request::get("https://example.org/")),
+            Box::new(MockClock::new(1445412480250, 0)),
+            Box::new(MockKvStore::default()),
+            Box::new(sink.clone()),
+        );
+
+        logger.log_warn("warning".to_string(), None);
+        logger.log_info("information".to_string(), None);
+        logger.log_record("explain".to_string(), HashMap::new());
+
+        let lines = sink.lines.borrow();
+
+        assert_eq!(2, lines.len());
+        assert!(lines[0].starts_with("logs: {"));
+        assert!(lines[0].contains("\"message\":\"warning\""));
+        assert!(lines[1].contains("\"message\":\"explain\""));
+    }
+
+    #[test]
+    fn test_panic_logs() {
+        let sink = MockLogSink::default();
+        let logger = FastlyLogger::new(
+            Some("logs".to_string()),
+            Some("error".to_string()),
+            None,
+            Context::new(&Request::get("https://example.org/")),
+            Box::new(MockClock::new(1445412480250, 0)),
+            Box::new(MockKvStore::default()),
+            Box::new(sink.clone()),
+        );
+
+        logger.log_panic(
+            "The worker panicked: test panic log.".to_string(),
+            HashMap::from([("location", "src/main.rs:1:1".to_string())]),
+        );
+
+        let lines = sink.lines.borrow();
+
+        assert_eq!(1, lines.len());
+        assert!(lines[0].contains("\"level\":\"ERROR\""));
+        assert!(lines[0].contains("\"location\":\"src/main.rs:1:1\""));
+    }
+
+    #[test]
+    fn test_parse_traceparent() {
+        let trace = TraceContext::parse(TRACEPARENT).unwrap();
+
+        assert_eq!("4bf92f3577b34da6a3ce929d0e0e4736", trace.trace_id);
+        assert_eq!("00f067aa0ba902b7", trace.span_id);
+        assert!(TraceContext::parse("00-4bf92f35-00f067aa0ba902b7-01").is_none());
+    }
+
+    #[test]
+    fn test_default_event() {
+        let event = create_logger("default").event(
+            "message".to_string(),
+            HashMap::new(),
+            log::Level::Error,
+        );
+
+        assert_eq!("message", event["message"]);
+        assert_eq!("https://example.org/", event["context"]["url"]);
+        assert_eq!("ERROR", event["context"]["level"]);
+        assert_eq!(
+            "4bf92f3577b34da6a3ce929d0e0e4736",
+            event["context"]["trace_id"]
+        );
+    }
+
+    #[test]
+    fn test_datadog_event() {
+        let event = create_logger("datadog").event(
+            "message".to_string(),
+            HashMap::from([("error", "timeout".to_string())]),
+            log::Level::Error,
+        );
+
+        assert_eq!("fastly", event["ddsource"]);
+        assert_eq!(SERVICE_NAME, event["service"]);
+        assert_eq!("error", event["status"]);
+        assert_eq!("timeout", event["error"]);
+        assert_eq!("11803532876627986230", event["dd.trace_id"]);
+        assert_eq!("67667974448284343", event["dd.span_id"]);
+    }
+
+    #[test]
+    fn test_honeycomb_event() {
+        let event = create_logger("honeycomb").event(
+            "message".to_string(),
+            HashMap::new(),
+            log::Level::Info,
+        );
+
+        assert_eq!(SERVICE_NAME, event["service.name"]);
+        assert_eq!("info", event["level"]);
+        assert_eq!("4bf92f3577b34da6a3ce929d0e0e4736", event["trace.trace_id"]);
+        assert_eq!("00f067aa0ba902b7", event["trace.parent_id"]);
     }
 }
@@ -0,0 +1,44 @@
+use fastly::Request;
+
+/// Methods forwarded to the backend when the `allowed_methods` entry is not set: TRACE, TRACK,
+/// CONNECT and unknown methods are rejected at the edge.
+pub const DEFAULT_ALLOWED_METHODS: &str = "GET,HEAD,POST,PUT,PATCH,DELETE,OPTIONS";
+
+/// Check whether the method of the request is in the `allowed_methods` entry.
+pub fn is_allowed_request(allowed_methods: &[String], req: &Request) -> bool {
+    is_allowed_method(allowed_methods, req.get_method_str())
+}
+
+fn is_allowed_method(allowed_methods: &[String], method: &str) -> bool {
+    // Methods are case-sensitive
+    allowed_methods
+        .iter()
+        .any(|allowed_method| allowed_method == method)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed_methods() -> Vec<String> {
+        DEFAULT_ALLOWED_METHODS
+            .split(',')
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_allowed_methods() {
+        assert!(is_allowed_method(&allowed_methods(), "GET"));
+        assert!(is_allowed_method(&allowed_methods(), "OPTIONS"));
+    }
+
+    #[test]
+    fn test_rejected_methods() {
+        assert!(!is_allowed_method(&allowed_methods(), "TRACE"));
+        assert!(!is_allowed_method(&allowed_methods(), "TRACK"));
+        assert!(!is_allowed_method(&allowed_methods(), "CONNECT"));
+        assert!(!is_allowed_method(&allowed_methods(), "PROPFIND"));
+        assert!(!is_allowed_method(&allowed_methods(), "get"));
+    }
+}
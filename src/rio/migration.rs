@@ -0,0 +1,202 @@
+use super::api::ApiClient;
+use super::configuration::MigrationMode;
+use super::error::ApiError;
+use super::recording::request_signature;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// A request the secondary project returned a different action for, in shadow mode.
+#[derive(Debug, Clone)]
+pub struct ShadowDifference {
+    pub request: String,
+    pub primary_action: String,
+    pub secondary_action: String,
+}
+
+/// Differences found in shadow mode, filled once the response has been sent to the client.
+#[derive(Default, Clone)]
+pub struct ShadowDifferences {
+    differences: Rc<RefCell<Vec<ShadowDifference>>>,
+}
+
+impl ShadowDifferences {
+    pub fn push(&self, difference: ShadowDifference) {
+        self.differences.borrow_mut().push(difference);
+    }
+
+    pub fn drain(&self) -> Vec<ShadowDifference> {
+        self.differences.borrow_mut().drain(..).collect()
+    }
+}
+
+/// API client helping to migrate the rules to another redirection.io project, from the
+/// `secondary_token` entry.
+///
+/// Actions always come from the primary project. In `dual_write` mode, logs are sent to both
+/// projects. In `shadow` mode, actions are also fetched from the secondary project, once the
+/// response has been sent to the client, and the differences are reported.
+pub struct MigrationApiClient<'a> {
+    mode: MigrationMode,
+    primary: &'a dyn ApiClient,
+    secondary: &'a dyn ApiClient,
+    shadow_differences: &'a ShadowDifferences,
+}
+
+impl<'a> MigrationApiClient<'a> {
+    pub(crate) fn new(
+        mode: MigrationMode,
+        primary: &'a dyn ApiClient,
+        secondary: &'a dyn ApiClient,
+        shadow_differences: &'a ShadowDifferences,
+    ) -> MigrationApiClient<'a> {
+        MigrationApiClient {
+            mode,
+            primary,
+            secondary,
+            shadow_differences,
+        }
+    }
+}
+
+impl<'a> ApiClient for MigrationApiClient<'a> {
+    fn action(&self, rio_request_json: String) -> Result<String, ApiError> {
+        let action_json = self.primary.action(rio_request_json.clone())?;
+
+        if self.mode == MigrationMode::Shadow {
+            let request = request_signature(&rio_request_json);
+            let primary_action = action_json.clone();
+            let shadow_differences = self.shadow_differences.clone();
+
+            self.secondary.action_in_background(
+                rio_request_json,
+                Box::new(move |secondary_action| {
+                    if actions_differ(&primary_action, &secondary_action) {
+                        shadow_differences.push(ShadowDifference {
+                            request,
+                            primary_action,
+                            secondary_action,
+                        });
+                    }
+                }),
+            );
+        }
+
+        Ok(action_json)
+    }
+
+    fn action_in_background(&self, rio_request_json: String, on_action: Box<dyn FnOnce(String)>) {
+        self.primary
+            .action_in_background(rio_request_json, on_action)
+    }
+
+    fn log(&self, log_json: String) -> Result<(), ApiError> {
+        if self.mode == MigrationMode::DualWrite {
+            // The secondary project must not make the primary log fail
+            let _ = self.secondary.log(log_json.clone());
+        }
+
+        self.primary.log(log_json)
+    }
+
+    // Rule identifiers belong to the primary project, the secondary one has its own
+    fn rule_metrics(&self, metrics_json: String) -> Result<(), ApiError> {
+        self.primary.rule_metrics(metrics_json)
+    }
+}
+
+/// Compare two actions, ignoring the rule identifiers, which differ between projects.
+fn actions_differ(primary_action: &str, secondary_action: &str) -> bool {
+    let parse = |action: &str| {
+        let mut action: Value = serde_json::from_str(action).unwrap_or_default();
+        strip_rule_ids(&mut action);
+        action
+    };
+
+    parse(primary_action) != parse(secondary_action)
+}
+
+fn strip_rule_ids(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for key in ["rule_id", "rule_ids", "rules_applied", "rule_traces", "id"] {
+                object.remove(key);
+            }
+
+            object.values_mut().for_each(strip_rule_ids);
+        }
+        Value::Array(array) => array.iter_mut().for_each(strip_rule_ids),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rio::mock::MockApiClient;
+
+    const PRIMARY_ACTION: &str = r#"{
+        "status_code_update": {"status_code": 301, "rule_id": "primary-rule"},
+        "header_filters": [{
+            "filter": {"action": "override", "header": "Location", "value": "/target", "id": "a"},
+            "rule_id": "primary-rule"
+        }],
+        "rule_ids": ["primary-rule"]
+    }"#;
+
+    const SECONDARY_ACTION: &str = r#"{
+        "status_code_update": {"status_code": 301, "rule_id": "secondary-rule"},
+        "header_filters": [{
+            "filter": {"action": "override", "header": "Location", "value": "/target", "id": "b"},
+            "rule_id": "secondary-rule"
+        }],
+        "rule_ids": ["secondary-rule"]
+    }"#;
+
+    #[test]
+    fn test_actions_differ() {
+        assert!(!actions_differ(PRIMARY_ACTION, SECONDARY_ACTION));
+        assert!(actions_differ(
+            PRIMARY_ACTION,
+            &SECONDARY_ACTION.replace("/target", "/other")
+        ));
+    }
+
+    #[test]
+    fn test_shadow_mode() {
+        let primary = MockApiClient::new(vec![Ok(PRIMARY_ACTION.to_string())]);
+        let secondary = MockApiClient::new(vec![Ok(SECONDARY_ACTION.replace("301", "302"))]);
+        let shadow_differences = ShadowDifferences::default();
+        let client = MigrationApiClient::new(
+            MigrationMode::Shadow,
+            &primary,
+            &secondary,
+            &shadow_differences,
+        );
+
+        let action = client.action(r#"{"method": "GET"}"#.to_string()).unwrap();
+
+        assert_eq!(PRIMARY_ACTION, action);
+        assert_eq!(1, shadow_differences.drain().len());
+    }
+
+    #[test]
+    fn test_dual_write_mode() {
+        let primary = MockApiClient::new(vec![Ok(PRIMARY_ACTION.to_string())]);
+        let secondary = MockApiClient::new(vec![]);
+        let shadow_differences = ShadowDifferences::default();
+        let client = MigrationApiClient::new(
+            MigrationMode::DualWrite,
+            &primary,
+            &secondary,
+            &shadow_differences,
+        );
+
+        client.action("{}".to_string()).unwrap();
+        client.log("log".to_string()).unwrap();
+
+        assert!(secondary.action_requests.borrow().is_empty());
+        assert_eq!(vec!["log"], *primary.logs.borrow());
+        assert_eq!(vec!["log"], *secondary.logs.borrow());
+    }
+}
@@ -0,0 +1,61 @@
+use super::background::BackgroundTasks;
+use super::flags::is_sampled;
+use super::request_sender::RequestSender;
+use fastly::http::request::SendError;
+use fastly::{Request, Response};
+
+pub const MIRROR_HEADER: &str = "x-redirectionio-mirror";
+
+/// Request sender mirroring a sample of the requests of the main backend to the shadow backend of
+/// the `shadow_backend` entry, to load test a new origin with real traffic.
+///
+/// Mirrored requests carry their body and the `x-redirectionio-mirror` header. Their responses are
+/// ignored, and only awaited once the response has been sent to the client.
+pub struct MirroringRequestSender<'a> {
+    backend_name: String,
+    shadow_backend: Option<String>,
+    sample_rate: f64,
+    background_tasks: &'a BackgroundTasks,
+    inner: &'a dyn RequestSender,
+}
+
+impl<'a> MirroringRequestSender<'a> {
+    pub(crate) fn new(
+        backend_name: String,
+        shadow_backend: Option<String>,
+        sample_rate: f64,
+        background_tasks: &'a BackgroundTasks,
+        inner: &'a dyn RequestSender,
+    ) -> MirroringRequestSender<'a> {
+        MirroringRequestSender {
+            backend_name,
+            shadow_backend,
+            sample_rate,
+            background_tasks,
+            inner,
+        }
+    }
+}
+
+impl<'a> RequestSender for MirroringRequestSender<'a> {
+    fn send(&self, mut req: Request, backend: String) -> Result<Response, SendError> {
+        let shadow_backend = match self.shadow_backend {
+            Some(ref shadow_backend) if backend == self.backend_name => shadow_backend,
+            _ => return self.inner.send(req, backend),
+        };
+
+        if self.sample_rate > 0.0 && is_sampled(req.get_client_request_id(), self.sample_rate) {
+            let mut mirror = req.clone_with_body();
+            mirror.set_header(MIRROR_HEADER, "true");
+
+            // Mirroring is best effort: the shadow backend never affects the client
+            if let Ok(pending_request) = mirror.send_async(shadow_backend.as_str()) {
+                self.background_tasks.push(Box::new(move || {
+                    let _ = pending_request.wait();
+                }));
+            }
+        }
+
+        self.inner.send(req, backend)
+    }
+}
@@ -0,0 +1,163 @@
+//! Mock implementations of the worker boundaries, to be used in tests.
+
+use super::api::ApiClient;
+use super::clock::Clock;
+use super::error::ApiError;
+use super::kv_store::KvStore;
+use super::logging::LogSink;
+use super::request_sender::RequestSender;
+use fastly::http::request::SendError;
+use fastly::{Request, Response};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::Duration;
+
+/// API client answering with queued responses, and recording the logs it receives.
+#[derive(Default)]
+pub struct MockApiClient {
+    pub action_responses: RefCell<VecDeque<Result<String, ApiError>>>,
+    pub action_requests: RefCell<Vec<String>>,
+    pub logs: RefCell<Vec<String>>,
+    pub rule_metrics: RefCell<Vec<String>>,
+}
+
+impl MockApiClient {
+    pub fn new(action_responses: Vec<Result<String, ApiError>>) -> MockApiClient {
+        MockApiClient {
+            action_responses: RefCell::new(action_responses.into()),
+            ..Default::default()
+        }
+    }
+}
+
+impl ApiClient for MockApiClient {
+    fn action(&self, rio_request_json: String) -> Result<String, ApiError> {
+        self.action_requests.borrow_mut().push(rio_request_json);
+
+        match self.action_responses.borrow_mut().pop_front() {
+            Some(response) => response,
+            None => Err(ApiError::Send("no more mocked responses".to_string())),
+        }
+    }
+
+    fn log(&self, log_json: String) -> Result<(), ApiError> {
+        self.logs.borrow_mut().push(log_json);
+
+        Ok(())
+    }
+
+    fn rule_metrics(&self, metrics_json: String) -> Result<(), ApiError> {
+        self.rule_metrics.borrow_mut().push(metrics_json);
+
+        Ok(())
+    }
+}
+
+/// Clock starting at a given time, and moving forward by a fixed step on each call.
+pub struct MockClock {
+    now: Cell<u128>,
+    step: u128,
+}
+
+impl MockClock {
+    pub fn new(now: u128, step: u128) -> MockClock {
+        MockClock {
+            now: Cell::new(now),
+            step,
+        }
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u128 {
+        let now = self.now.get();
+        self.now.set(now + self.step);
+
+        now
+    }
+
+    fn elapsed(&self) -> Duration {
+        Duration::from_millis(self.now() as u64)
+    }
+}
+
+/// KV Store keeping its entries in memory, and recording the keys written.
+#[derive(Default)]
+pub struct MockKvStore {
+    pub entries: RefCell<HashMap<String, String>>,
+    pub writes: RefCell<Vec<String>>,
+}
+
+impl MockKvStore {
+    pub fn new(entries: &[(&str, &str)]) -> MockKvStore {
+        MockKvStore {
+            entries: RefCell::new(
+                entries
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+}
+
+impl KvStore for MockKvStore {
+    fn lookup(&self, key: &str) -> Option<String> {
+        self.entries.borrow().get(key).cloned()
+    }
+
+    fn insert(&self, key: &str, value: String) -> bool {
+        self.writes.borrow_mut().push(key.to_string());
+        self.entries.borrow_mut().insert(key.to_string(), value);
+
+        true
+    }
+
+    fn delete(&self, key: &str) -> bool {
+        self.writes.borrow_mut().push(key.to_string());
+        self.entries.borrow_mut().remove(key).is_some()
+    }
+}
+
+/// Log sink recording the lines written, as `endpoint: json`; its clones share the lines.
+#[derive(Clone, Default)]
+pub struct MockLogSink {
+    pub lines: Rc<RefCell<Vec<String>>>,
+}
+
+impl LogSink for MockLogSink {
+    fn write(&self, endpoint: &str, json: &str) {
+        self.lines
+            .borrow_mut()
+            .push(format!("{}: {}", endpoint, json));
+    }
+}
+
+/// Request sender answering every request with an empty response, and recording the urls it
+/// receives.
+#[derive(Default)]
+pub struct MockRequestSender {
+    pub status: u16,
+    pub requests: RefCell<Vec<String>>,
+}
+
+impl MockRequestSender {
+    pub fn new(status: u16) -> MockRequestSender {
+        MockRequestSender {
+            status,
+            ..Default::default()
+        }
+    }
+}
+
+impl RequestSender for MockRequestSender {
+    fn send(&self, req: Request, _backend: String) -> Result<Response, SendError> {
+        self.requests
+            .borrow_mut()
+            .push(req.get_url_str().to_string());
+
+        Ok(Response::from_status(self.status))
+    }
+}
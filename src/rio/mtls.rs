@@ -0,0 +1,117 @@
+use super::configuration::MtlsBackend;
+use super::logging::FastlyLogger;
+use super::request_sender::RequestSender;
+use super::secrets::SECRET_STORE_NAME;
+use fastly::backend::BackendCreationError;
+use fastly::http::request::SendError;
+use fastly::secret_store::{LookupError, OpenError};
+use fastly::{Backend, Request, Response, SecretStore};
+use std::collections::HashMap;
+
+/// Request sender presenting a client certificate to origins that require mutual TLS.
+///
+/// Backends listed in the `mtls_backends` configuration are registered as dynamic backends
+/// using the certificate and the key stored in the Secret Store. Requests to any other backend
+/// are handed over untouched to the wrapped sender.
+pub struct MtlsRequestSender<'a> {
+    backends: &'a HashMap<String, MtlsBackend>,
+    fastly_logger: &'a FastlyLogger,
+    inner: &'a dyn RequestSender,
+}
+
+impl<'a> MtlsRequestSender<'a> {
+    pub(crate) fn new(
+        backends: &'a HashMap<String, MtlsBackend>,
+        fastly_logger: &'a FastlyLogger,
+        inner: &'a dyn RequestSender,
+    ) -> MtlsRequestSender<'a> {
+        MtlsRequestSender {
+            backends,
+            fastly_logger,
+            inner,
+        }
+    }
+}
+
+impl<'a> RequestSender for MtlsRequestSender<'a> {
+    fn send(&self, req: Request, backend: String) -> Result<Response, SendError> {
+        let mtls_backend = match self.backends.get(&backend) {
+            Some(mtls_backend) => mtls_backend,
+            None => return self.inner.send(req, backend),
+        };
+
+        match register_backend(&backend, mtls_backend) {
+            Ok(mtls_backend_name) => self.inner.send(req, mtls_backend_name),
+            Err(error) => {
+                // Fallback to the static backend, the origin will reject the request if it really
+                // requires a client certificate
+                self.fastly_logger.log_error(
+                    format!("Cannot register mTLS backend \"{}\": {}.", backend, error),
+                    None,
+                );
+
+                self.inner.send(req, backend)
+            }
+        }
+    }
+}
+
+fn register_backend(backend_name: &str, mtls_backend: &MtlsBackend) -> Result<String, MtlsError> {
+    let mtls_backend_name = format!("{}_mtls", backend_name);
+    let secret_store = SecretStore::open(SECRET_STORE_NAME)?;
+
+    let certificate = match secret_store.try_get(&mtls_backend.certificate)? {
+        Some(certificate) => match certificate.try_plaintext() {
+            Ok(certificate) => String::from_utf8_lossy(&certificate).to_string(),
+            Err(error) => return Err(MtlsError::InvalidSecret(error.to_string())),
+        },
+        None => return Err(MtlsError::MissingSecret(mtls_backend.certificate.clone())),
+    };
+
+    let key = match secret_store.try_get(&mtls_backend.key)? {
+        Some(key) => key,
+        None => return Err(MtlsError::MissingSecret(mtls_backend.key.clone())),
+    };
+
+    let mut builder = Backend::builder(&mtls_backend_name, &mtls_backend.target).enable_ssl();
+
+    if let Some(ref host) = mtls_backend.host {
+        builder = builder
+            .override_host(host)
+            .sni_hostname(host)
+            .check_certificate(host);
+    }
+
+    match builder
+        .provide_client_certificate(certificate, key)
+        .finish()
+    {
+        Ok(backend) => Ok(backend.into_string()),
+        // The backend has already been registered during this session
+        Err(BackendCreationError::NameInUse) => Ok(mtls_backend_name),
+        Err(error) => Err(MtlsError::BackendCreation(error)),
+    }
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum MtlsError {
+        SecretStoreOpen (e: OpenError) {
+            display("cannot open secret store: {}", e)
+            from()
+        }
+        SecretStoreLookup (e: LookupError) {
+            display("cannot lookup secret: {}", e)
+            from()
+        }
+        MissingSecret (name: String) {
+            display("missing secret \"{}\"", name)
+        }
+        InvalidSecret (e: String) {
+            display("cannot decrypt secret: {}", e)
+        }
+        BackendCreation (e: BackendCreationError) {
+            display("cannot create backend: {}", e)
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use fastly::http::header;
+use fastly::Request;
+use std::collections::HashMap;
+
+pub const SERVED_BY_HEADER: &str = "x-served-by-rio-worker";
+
+/// Identifies the requests sent to the backend by the worker, so origins can tell them apart
+/// from direct traffic during a migration.
+pub struct OriginStamp {
+    served_by: bool,
+    headers: Vec<(String, String)>,
+    user_agent_suffix: Option<String>,
+}
+
+impl OriginStamp {
+    pub(crate) fn new(
+        served_by: bool,
+        headers: &HashMap<String, String>,
+        user_agent_suffix: Option<String>,
+    ) -> OriginStamp {
+        let mut headers: Vec<(String, String)> = headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+
+        // Keep a stable order, the map has none
+        headers.sort();
+
+        OriginStamp {
+            served_by,
+            headers,
+            user_agent_suffix,
+        }
+    }
+
+    pub fn apply(&self, req: &mut Request, agent_version: &str) {
+        if self.served_by {
+            req.set_header(SERVED_BY_HEADER, agent_version);
+        }
+
+        for (name, value) in &self.headers {
+            req.set_header(name.as_str(), value.as_str());
+        }
+
+        if let Some(suffix) = &self.user_agent_suffix {
+            let user_agent = match req.get_header_str(header::USER_AGENT) {
+                Some(user_agent) if !user_agent.is_empty() => format!("{} {}", user_agent, suffix),
+                _ => suffix.clone(),
+            };
+
+            req.set_header(header::USER_AGENT, user_agent);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let headers = HashMap::from([
+            ("x-origin-tag".to_string(), "migration".to_string()),
+            ("x-edge".to_string(), "fastly".to_string()),
+        ]);
+        let stamp = OriginStamp::new(true, &headers, Some("rio-worker/1.0".to_string()));
+        let mut req = Request::get("https://example.org/").with_header("User-Agent", "curl/8.0");
+
+        stamp.apply(&mut req, "2.3.0");
+
+        assert_eq!(Some("2.3.0"), req.get_header_str(SERVED_BY_HEADER));
+        assert_eq!(Some("migration"), req.get_header_str("x-origin-tag"));
+        assert_eq!(Some("fastly"), req.get_header_str("x-edge"));
+        assert_eq!(
+            Some("curl/8.0 rio-worker/1.0"),
+            req.get_header_str("User-Agent")
+        );
+    }
+
+    #[test]
+    fn test_apply_without_user_agent() {
+        let stamp = OriginStamp::new(false, &HashMap::new(), Some("rio-worker/1.0".to_string()));
+        let mut req = Request::get("https://example.org/");
+
+        stamp.apply(&mut req, "2.3.0");
+
+        assert_eq!(None, req.get_header_str(SERVED_BY_HEADER));
+        assert_eq!(Some("rio-worker/1.0"), req.get_header_str("User-Agent"));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let stamp = OriginStamp::new(false, &HashMap::new(), None);
+        let mut req = Request::get("https://example.org/").with_header("User-Agent", "curl/8.0");
+
+        stamp.apply(&mut req, "2.3.0");
+
+        assert_eq!(None, req.get_header_str(SERVED_BY_HEADER));
+        assert_eq!(Some("curl/8.0"), req.get_header_str("User-Agent"));
+    }
+}
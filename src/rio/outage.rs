@@ -0,0 +1,284 @@
+use super::clock::Clock;
+use super::kv_store::{self, KvStore};
+use serde::{Deserialize, Serialize};
+use std::sync::{Mutex, OnceLock};
+
+const OUTAGE_KEY: &str = "api_outage";
+
+// Minimum delay, in seconds, between two alerts for the same outage
+const ALERT_WINDOW: u64 = 300;
+
+// Delay, in seconds, without failure of the instance before a success can end the outage
+const RECOVERY_DELAY: u64 = 60;
+
+// Outage seen by the instance
+static INSTANCE_OUTAGE: OnceLock<Mutex<InstanceOutage>> = OnceLock::new();
+
+/// Outage marker of the KV Store, written by the instance emitting an alert.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutageState {
+    pub first_failure: u64,
+    /// Failures counted by the instance which emitted the last alert.
+    pub failures: u64,
+    pub last_alert: u64,
+}
+
+impl OutageState {
+    /// Whether the next alert of the outage is due.
+    pub fn is_alert_due(&self, now: u64) -> bool {
+        now.saturating_sub(self.last_alert) >= ALERT_WINDOW
+    }
+
+    /// Whether no instance failed since the alert window ended: a failing instance would have
+    /// emitted the next alert.
+    pub fn is_over(&self, now: u64) -> bool {
+        now.saturating_sub(self.last_alert) >= ALERT_WINDOW + RECOVERY_DELAY
+    }
+}
+
+/// Failures of the redirection.io API seen by an instance.
+///
+/// Failures are counted in memory. The KV Store marker is read on the first failure, then only
+/// once the known alert window is over, in case another instance emitted the next alert; it is
+/// written when an alert window opens, and deleted when the outage ends.
+#[derive(Debug, Default)]
+pub struct InstanceOutage {
+    // Marker as last read or written by the instance, `None` until it is read
+    marker: Option<Option<OutageState>>,
+    first_failure: u64,
+    failures: u64,
+    last_failure: u64,
+}
+
+impl InstanceOutage {
+    pub fn record_failure(
+        &mut self,
+        clock: &dyn Clock,
+        store: &dyn KvStore,
+    ) -> Option<OutageState> {
+        let now = clock.now_secs();
+
+        if self.failures == 0 {
+            self.first_failure = now;
+        }
+
+        self.failures += 1;
+        self.last_failure = now;
+
+        if let Some(Some(marker)) = &self.marker {
+            if !marker.is_alert_due(now) {
+                return None;
+            }
+        }
+
+        let marker: Option<OutageState> = kv_store::get_json(store, OUTAGE_KEY);
+
+        if let Some(marker) = marker.as_ref().filter(|marker| !marker.is_alert_due(now)) {
+            self.marker = Some(Some(marker.clone()));
+
+            return None;
+        }
+
+        let state = OutageState {
+            first_failure: marker.map_or(self.first_failure, |marker| marker.first_failure),
+            failures: self.failures,
+            last_alert: now,
+        };
+
+        kv_store::set_json(store, OUTAGE_KEY, &state);
+        self.marker = Some(Some(state.clone()));
+
+        Some(state)
+    }
+
+    pub fn record_success(
+        &mut self,
+        clock: &dyn Clock,
+        store: &dyn KvStore,
+    ) -> Option<OutageState> {
+        let now = clock.now_secs();
+
+        // The instance still fails from time to time: the outage may only be partial
+        if self.failures > 0 && now.saturating_sub(self.last_failure) < RECOVERY_DELAY {
+            return None;
+        }
+
+        let marker = match &self.marker {
+            // The KV Store is only read when the known outage looks over
+            Some(Some(marker)) if !marker.is_over(now) => return None,
+            Some(None) => None,
+            // First success of the instance, or outage looking over
+            _ => kv_store::get_json::<OutageState>(store, OUTAGE_KEY),
+        };
+
+        self.failures = 0;
+
+        match marker {
+            Some(marker) if !marker.is_over(now) => {
+                self.marker = Some(Some(marker));
+
+                None
+            }
+            Some(marker) => {
+                self.marker = Some(None);
+
+                match store.delete(OUTAGE_KEY) {
+                    true => Some(marker),
+                    false => None,
+                }
+            }
+            None => {
+                self.marker = Some(None);
+
+                None
+            }
+        }
+    }
+}
+
+/// Keeps track of redirection.io API failures, in memory and in the KV Store.
+///
+/// Instead of logging an error for every failing request, callers only log when
+/// `record_failure` says so: on the first failure of an outage, then once per alert window for
+/// all the instances of the POP.
+///
+/// A success ends the outage once the instance had no failure for a minute, and no instance
+/// emitted an alert since the last alert window ended, so a partial outage is not reported as
+/// over on every success.
+pub struct OutageTracker;
+
+impl OutageTracker {
+    /// Record a failure, and return the outage state when an alert should be emitted.
+    pub fn record_failure(&self, clock: &dyn Clock, store: &dyn KvStore) -> Option<OutageState> {
+        with_instance_outage(|outage| outage.record_failure(clock, store))
+    }
+
+    /// Record a success, and return the state of the outage it ends, if any.
+    pub fn record_success(&self, clock: &dyn Clock, store: &dyn KvStore) -> Option<OutageState> {
+        with_instance_outage(|outage| outage.record_success(clock, store))
+    }
+
+    pub fn state(&self, store: &dyn KvStore) -> Option<OutageState> {
+        kv_store::get_json(store, OUTAGE_KEY)
+    }
+}
+
+fn with_instance_outage<T>(record: impl FnOnce(&mut InstanceOutage) -> T) -> T {
+    let mut outage = match INSTANCE_OUTAGE.get_or_init(Default::default).lock() {
+        Ok(outage) => outage,
+        // A panic while recording must not stop the next records
+        Err(poisoned) => poisoned.into_inner(),
+    };
+
+    record(&mut outage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rio::mock::{MockClock, MockKvStore};
+
+    fn at(now: u64) -> MockClock {
+        MockClock::new(u128::from(now) * 1000, 0)
+    }
+
+    #[test]
+    fn test_alert_once_per_window() {
+        let store = MockKvStore::default();
+        let mut outage = InstanceOutage::default();
+
+        assert_eq!(
+            Some(OutageState {
+                first_failure: 1000,
+                failures: 1,
+                last_alert: 1000,
+            }),
+            outage.record_failure(&at(1000), &store)
+        );
+
+        for now in 1001..1000 + ALERT_WINDOW {
+            assert_eq!(None, outage.record_failure(&at(now), &store));
+        }
+
+        assert_eq!(1, store.writes.borrow().len());
+        assert_eq!(
+            Some(OutageState {
+                first_failure: 1000,
+                failures: ALERT_WINDOW + 1,
+                last_alert: 1000 + ALERT_WINDOW,
+            }),
+            outage.record_failure(&at(1000 + ALERT_WINDOW), &store)
+        );
+        assert_eq!(2, store.writes.borrow().len());
+    }
+
+    #[test]
+    fn test_alert_shared_by_instances() {
+        let store = MockKvStore::default();
+        let mut first = InstanceOutage::default();
+        let mut second = InstanceOutage::default();
+
+        assert!(first.record_failure(&at(1000), &store).is_some());
+        assert!(second.record_failure(&at(1010), &store).is_none());
+        assert!(second.record_failure(&at(1020), &store).is_none());
+        assert_eq!(1, store.writes.borrow().len());
+
+        // The next alert is emitted by one instance only
+        let alert = second
+            .record_failure(&at(1000 + ALERT_WINDOW), &store)
+            .unwrap();
+
+        assert_eq!(1000, alert.first_failure);
+        assert_eq!(3, alert.failures);
+        assert!(first
+            .record_failure(&at(1000 + ALERT_WINDOW + 1), &store)
+            .is_none());
+    }
+
+    #[test]
+    fn test_partial_outage_does_not_end() {
+        let store = MockKvStore::default();
+        let mut failing = InstanceOutage::default();
+        let mut succeeding = InstanceOutage::default();
+
+        failing.record_failure(&at(1000), &store);
+
+        assert_eq!(None, failing.record_success(&at(1010), &store));
+        assert_eq!(None, succeeding.record_success(&at(1010), &store));
+        assert_eq!(
+            None,
+            succeeding.record_success(&at(1000 + ALERT_WINDOW), &store)
+        );
+        assert!(OutageTracker.state(&store).is_some());
+    }
+
+    #[test]
+    fn test_outage_ends() {
+        let store = MockKvStore::default();
+        let mut outage = InstanceOutage::default();
+        let end = 1000 + ALERT_WINDOW + RECOVERY_DELAY;
+
+        outage.record_failure(&at(1000), &store);
+        outage.record_failure(&at(1100), &store);
+
+        assert_eq!(None, outage.record_success(&at(end - 1), &store));
+        assert_eq!(
+            Some(OutageState {
+                first_failure: 1000,
+                failures: 1,
+                last_alert: 1000,
+            }),
+            outage.record_success(&at(end), &store)
+        );
+        assert_eq!(None, OutageTracker.state(&store));
+        assert_eq!(None, outage.record_success(&at(end + 1), &store));
+
+        // A new failure starts a new outage
+        assert_eq!(
+            Some(end + 10),
+            outage
+                .record_failure(&at(end + 10), &store)
+                .map(|state| state.first_failure)
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use super::logging::LoggerSettings;
+use super::synthetic::{self, DEFAULT_SYNTHETIC_CACHE_CONTROL};
+use fastly::Response;
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Whether a response has been sent to the client, which can only happen once
+static RESPONSE_SENT: AtomicBool = AtomicBool::new(false);
+
+/// Log the panics of the worker with the context of the request, through the log endpoint and
+/// stderr.
+///
+/// On Compute, the guest aborts right after the hook: the error page is then sent from the hook,
+/// when no response has been sent yet. Elsewhere, the panic unwinds to the `catch_unwind` of the
+/// main pipeline, which sends the page.
+pub fn install(logger_settings: LoggerSettings, request_id: Option<String>) {
+    std::panic::set_hook(Box::new(move |info| {
+        let mut context = HashMap::new();
+
+        if let Some(location) = info.location() {
+            context.insert("location", location.to_string());
+        }
+
+        if let Some(ref request_id) = request_id {
+            context.insert("request_id", request_id.clone());
+        }
+
+        logger_settings.logger().log_panic(
+            format!("The worker panicked: {}.", payload_message(info.payload())),
+            context,
+        );
+
+        if cfg!(panic = "abort") && !RESPONSE_SENT.swap(true, Ordering::SeqCst) {
+            error_response(request_id.as_deref()).send_to_client();
+        }
+    }));
+}
+
+/// Record that the response has been sent, so the panic hook does not send another one.
+pub fn mark_response_sent() {
+    RESPONSE_SENT.store(true, Ordering::SeqCst);
+}
+
+/// The error page answered when the worker panics.
+pub fn error_response(request_id: Option<&str>) -> Response {
+    synthetic::response(
+        500,
+        None,
+        synthetic::error_page(500, request_id),
+        true,
+        DEFAULT_SYNTHETIC_CACHE_CONTROL,
+    )
+}
+
+fn payload_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "unknown cause".to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_message() {
+        let payload: Box<dyn Any + Send> = Box::new("index out of bounds");
+        assert_eq!("index out of bounds", payload_message(payload.as_ref()));
+
+        let payload: Box<dyn Any + Send> = Box::new(format!("invalid {}", "header"));
+        assert_eq!("invalid header", payload_message(payload.as_ref()));
+
+        let payload: Box<dyn Any + Send> = Box::new(42);
+        assert_eq!("unknown cause", payload_message(payload.as_ref()));
+    }
+}
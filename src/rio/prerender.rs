@@ -0,0 +1,145 @@
+use super::kv_store;
+use super::request_sender::RequestSender;
+use fastly::http::request::SendError;
+use fastly::http::{header, Method, StatusCode};
+use fastly::{Request, Response};
+
+const KEY_PREFIX: &str = "prerender";
+
+pub const PRERENDER_HEADER: &str = "x-redirectionio-prerendered";
+
+// Crawlers served with snapshots, by `User-Agent` token, with the autonomous systems their
+// requests come from
+const CRAWLERS: &[(&str, &[u32])] = &[
+    ("googlebot", &[15169]),
+    ("bingbot", &[8075]),
+    ("applebot", &[714]),
+    ("yandexbot", &[13238]),
+    ("baiduspider", &[55967]),
+];
+
+/// Whether the request comes from a known crawler: the `User-Agent` must name it, and the client
+/// address must belong to one of its autonomous systems, so a spoofed `User-Agent` is not enough.
+pub fn is_verified_crawler(user_agent: &str, as_number: Option<u32>) -> bool {
+    let user_agent = user_agent.to_lowercase();
+    let as_number = match as_number {
+        Some(as_number) => as_number,
+        None => return false,
+    };
+
+    CRAWLERS
+        .iter()
+        .any(|(token, as_numbers)| user_agent.contains(token) && as_numbers.contains(&as_number))
+}
+
+/// Whether the request is for an HTML page, judging by its method, path and `Accept` header.
+pub fn is_html_request(req: &Request) -> bool {
+    if req.get_method() != Method::GET {
+        return false;
+    }
+
+    let accepts_html = match req.get_header_str(header::ACCEPT) {
+        Some(accept) => accept.contains("text/html") || accept.contains("*/*"),
+        None => true,
+    };
+
+    let file_name = req.get_path().rsplit('/').next().unwrap_or_default();
+    let is_page = match file_name.rsplit_once('.') {
+        Some((_, extension)) => extension == "html" || extension == "htm",
+        None => true,
+    };
+
+    accepts_html && is_page
+}
+
+/// Request sender serving pre-rendered snapshots of HTML pages to verified crawlers.
+///
+/// Snapshots are stored in the KV Store under `prerender:<host><path and query>`. When there is no
+/// snapshot for a page, the request is sent to the backend.
+pub struct PrerenderRequestSender<'a> {
+    enabled: bool,
+    inner: &'a dyn RequestSender,
+}
+
+impl<'a> PrerenderRequestSender<'a> {
+    pub(crate) fn new(enabled: bool, inner: &'a dyn RequestSender) -> PrerenderRequestSender<'a> {
+        PrerenderRequestSender { enabled, inner }
+    }
+}
+
+impl<'a> RequestSender for PrerenderRequestSender<'a> {
+    fn send(&self, req: Request, backend: String) -> Result<Response, SendError> {
+        if !self.enabled || !is_html_request(&req) {
+            return self.inner.send(req, backend);
+        }
+
+        let snapshot =
+            kv_store::open().and_then(|store| store.lookup_str(&snapshot_key(&req)).ok().flatten());
+
+        match snapshot {
+            Some(snapshot) => Ok(Response::from_status(StatusCode::OK)
+                .with_header(header::CONTENT_TYPE, "text/html; charset=UTF-8")
+                // Snapshots are only for crawlers, they must not be served to other clients
+                .with_header(header::CACHE_CONTROL, "private")
+                .with_header(PRERENDER_HEADER, "true")
+                .with_body(snapshot)),
+            None => self.inner.send(req, backend),
+        }
+    }
+}
+
+pub fn snapshot_key(req: &Request) -> String {
+    let url = req.get_url();
+    let host = url.host_str().unwrap_or_default().to_lowercase();
+
+    match url.query() {
+        Some(query) => format!("{}:{}{}?{}", KEY_PREFIX, host, url.path(), query),
+        None => format!("{}:{}{}", KEY_PREFIX, host, url.path()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOOGLEBOT: &str =
+        "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+
+    #[test]
+    fn test_verified_crawler() {
+        assert!(is_verified_crawler(GOOGLEBOT, Some(15169)));
+        assert!(!is_verified_crawler(GOOGLEBOT, Some(8075)));
+        assert!(!is_verified_crawler(GOOGLEBOT, None));
+        assert!(!is_verified_crawler("curl/8.0", Some(15169)));
+    }
+
+    #[test]
+    fn test_html_request() {
+        assert!(is_html_request(&Request::get("https://example.org/")));
+        assert!(is_html_request(&Request::get(
+            "https://example.org/blog/post"
+        )));
+        assert!(is_html_request(&Request::get(
+            "https://example.org/index.html"
+        )));
+        assert!(!is_html_request(&Request::get(
+            "https://example.org/app.js"
+        )));
+        assert!(!is_html_request(&Request::head("https://example.org/")));
+        assert!(!is_html_request(
+            &Request::get("https://example.org/").with_header("Accept", "application/json")
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_key() {
+        assert_eq!(
+            "prerender:example.org/blog?page=2",
+            snapshot_key(&Request::get("https://Example.org/blog?page=2"))
+        );
+        assert_eq!(
+            "prerender:example.org/",
+            snapshot_key(&Request::get("https://example.org/"))
+        );
+    }
+}
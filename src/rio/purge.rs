@@ -0,0 +1,252 @@
+use super::action_cache;
+use super::logging::FastlyLogger;
+use super::secrets::{get_secret, secure_compare};
+use fastly::http::{Method, StatusCode, Url, Version};
+use fastly::{Request, Response};
+use serde::Serialize;
+
+pub const PURGE_SECRET_HEADER: &str = "x-redirectionio-purge-secret";
+const FASTLY_API_ENDPOINT: &str = "https://api.fastly.com";
+const FASTLY_API_BACKEND: &str = "fastly_api";
+
+#[derive(Debug, Serialize, Default)]
+pub struct PurgeResult {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    surrogate_keys: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+/// Handles `PURGE` requests at the edge instead of forwarding them to the backend.
+///
+/// Requests must be authenticated with the `purge_secret` Secret Store entry. When the request
+/// carries a `Surrogate-Key` header, the given keys are purged, otherwise the requested URL is
+/// purged through the Fastly API (which requires a `fastly_api_token` secret and a `fastly_api`
+/// backend), along with the cached actions.
+pub struct PurgeHandler<'a> {
+    secret: Option<String>,
+    fastly_logger: &'a FastlyLogger,
+}
+
+impl<'a> PurgeHandler<'a> {
+    /// Returns `None` when no purge secret is configured: purge requests are then forwarded
+    /// to the backend as any other request.
+    pub(crate) fn new(fastly_logger: &'a FastlyLogger) -> Option<PurgeHandler<'a>> {
+        let secret = get_secret("purge_secret")?;
+
+        Some(PurgeHandler {
+            secret: Some(secret),
+            fastly_logger,
+        })
+    }
+
+    /// Handler for the requests already authenticated by the admin endpoints.
+    pub(crate) fn authenticated(fastly_logger: &'a FastlyLogger) -> PurgeHandler<'a> {
+        PurgeHandler {
+            secret: None,
+            fastly_logger,
+        }
+    }
+
+    pub fn is_purge_request(req: &Request) -> bool {
+        req.get_method_str().eq_ignore_ascii_case("PURGE")
+    }
+
+    pub fn handle(&self, req: &Request) -> Response {
+        let authenticated = match (req.get_header_str(PURGE_SECRET_HEADER), &self.secret) {
+            (Some(value), Some(secret)) => secure_compare(value, secret),
+            _ => false,
+        };
+
+        if !authenticated {
+            return create_response(
+                StatusCode::UNAUTHORIZED,
+                PurgeResult {
+                    status: "error",
+                    message: Some("invalid purge secret".to_string()),
+                    ..Default::default()
+                },
+            );
+        }
+
+        self.purge(req, req.get_url())
+    }
+
+    /// Purge the surrogate keys of the `Surrogate-Key` header of the request, or the given URL and
+    /// the cached actions.
+    pub fn purge(&self, req: &Request, url: &Url) -> Response {
+        let soft = req.get_header_str("fastly-soft-purge") == Some("1");
+        let (surrogate_keys, is_url_purge) = purged_surrogate_keys(req);
+
+        let result = match is_url_purge {
+            true => self.purge_url(url, soft).and_then(|result| {
+                Ok(PurgeResult {
+                    surrogate_keys: self
+                        .purge_surrogate_keys(surrogate_keys, soft)?
+                        .surrogate_keys,
+                    ..result
+                })
+            }),
+            false => self.purge_surrogate_keys(surrogate_keys, soft),
+        };
+
+        match result {
+            Ok(result) => create_response(StatusCode::OK, result),
+            Err(message) => {
+                self.fastly_logger
+                    .log_error(format!("Cannot purge: {}.", message), None);
+
+                create_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    PurgeResult {
+                        status: "error",
+                        message: Some(message),
+                        ..Default::default()
+                    },
+                )
+            }
+        }
+    }
+
+    fn purge_surrogate_keys(
+        &self,
+        surrogate_keys: Vec<String>,
+        soft: bool,
+    ) -> Result<PurgeResult, String> {
+        for surrogate_key in &surrogate_keys {
+            let result = if soft {
+                fastly::http::purge::soft_purge_surrogate_key(surrogate_key)
+            } else {
+                fastly::http::purge::purge_surrogate_key(surrogate_key)
+            };
+
+            if let Err(error) = result {
+                return Err(format!(
+                    "cannot purge surrogate key \"{}\": {}",
+                    surrogate_key, error
+                ));
+            }
+        }
+
+        Ok(PurgeResult {
+            status: "ok",
+            surrogate_keys,
+            ..Default::default()
+        })
+    }
+
+    fn purge_url(&self, url: &Url, soft: bool) -> Result<PurgeResult, String> {
+        let api_token = match get_secret("fastly_api_token") {
+            Some(api_token) => api_token,
+            None => return Err("missing \"fastly_api_token\" secret to purge URLs".to_string()),
+        };
+
+        let target = match url.query() {
+            Some(query) => format!(
+                "{}{}?{}",
+                url.host_str().unwrap_or_default(),
+                url.path(),
+                query
+            ),
+            None => format!("{}{}", url.host_str().unwrap_or_default(), url.path()),
+        };
+
+        let mut purge_request = Request::new(
+            Method::POST,
+            format!("{}/purge/{}", FASTLY_API_ENDPOINT, target),
+        )
+        .with_header("Fastly-Key", api_token)
+        .with_header("Accept", "application/json")
+        .with_version(Version::HTTP_11);
+
+        if soft {
+            purge_request.set_header("Fastly-Soft-Purge", "1");
+        }
+
+        let mut response = match purge_request.send(FASTLY_API_BACKEND) {
+            Ok(response) => response,
+            Err(error) => return Err(format!("cannot send request to the Fastly API: {}", error)),
+        };
+
+        if response.get_status() != StatusCode::OK {
+            return Err(format!(
+                "Fastly API returned status {}: {}",
+                response.get_status(),
+                response.take_body_str()
+            ));
+        }
+
+        Ok(PurgeResult {
+            status: "ok",
+            url: Some(url.to_string()),
+            ..Default::default()
+        })
+    }
+}
+
+/// Surrogate keys purged by a request, and whether its URL is purged as well: the keys of its
+/// `Surrogate-Key` header, or, for a URL purge, the key of the cached actions, which would keep
+/// redirecting or filtering the URL until they expire.
+///
+/// Actions are only cached by their redirection.io request, so all of them are purged.
+pub fn purged_surrogate_keys(req: &Request) -> (Vec<String>, bool) {
+    let surrogate_keys: Vec<String> = req
+        .get_header_all_str("surrogate-key")
+        .iter()
+        .flat_map(|value| value.split_whitespace())
+        .map(|key| key.to_string())
+        .collect();
+
+    match surrogate_keys.is_empty() {
+        true => (vec![action_cache::SURROGATE_KEY.to_string()], true),
+        false => (surrogate_keys, false),
+    }
+}
+
+fn create_response(status: StatusCode, result: PurgeResult) -> Response {
+    let mut response = Response::from_status(status);
+    response.set_header("Cache-Control", "no-store");
+
+    if response.set_body_json(&result).is_err() {
+        response.set_body_text_plain(result.status);
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_purged_surrogate_keys() {
+        let req = Request::new("PURGE", "https://example.org/")
+            .with_header("Surrogate-Key", "page-1 page-2")
+            .with_header("Surrogate-Key", "layout");
+
+        assert_eq!(
+            (
+                vec![
+                    "page-1".to_string(),
+                    "page-2".to_string(),
+                    "layout".to_string()
+                ],
+                false
+            ),
+            purged_surrogate_keys(&req)
+        );
+    }
+
+    #[test]
+    fn test_url_purge_purges_cached_actions() {
+        let req = Request::new("PURGE", "https://example.org/page");
+
+        assert_eq!(
+            (vec!["redirectionio-action".to_string()], true),
+            purged_surrogate_keys(&req)
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use fastly::http::{header, StatusCode};
+use fastly::{Request, Response};
+
+/// Whether the response holds only a part of the resource, which body filters can not rewrite
+/// without breaking the byte ranges.
+pub fn is_partial_response(response: &Response) -> bool {
+    response.get_status() == StatusCode::PARTIAL_CONTENT
+        || response
+            .get_header_str(header::CONTENT_TYPE)
+            .is_some_and(|content_type| {
+                content_type
+                    .trim_start()
+                    .to_lowercase()
+                    .starts_with("multipart/byteranges")
+            })
+}
+
+/// Remove the `Range` header of requests on the paths of the `range_stripped_paths` entry, so the
+/// backend sends the whole page and the body filters of the rules can apply to it.
+pub fn strip_range(req: &mut Request, range_stripped_paths: &[String]) {
+    let path = req.get_path();
+
+    if !range_stripped_paths
+        .iter()
+        .any(|prefix| path.starts_with(prefix.as_str()))
+    {
+        return;
+    }
+
+    req.remove_header(header::RANGE);
+    req.remove_header(header::IF_RANGE);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_partial_response() {
+        assert!(is_partial_response(&Response::from_status(206)));
+        assert!(is_partial_response(
+            &Response::from_status(200).with_header(
+                "Content-Type",
+                "multipart/byteranges; boundary=3d6b6a416f9b5"
+            )
+        ));
+        assert!(!is_partial_response(
+            &Response::from_status(200).with_header("Content-Type", "text/html; charset=UTF-8")
+        ));
+    }
+
+    #[test]
+    fn test_strip_range() {
+        let paths = vec!["/blog/".to_string()];
+        let mut req =
+            Request::get("https://example.org/blog/post").with_header("Range", "bytes=0-1023");
+        strip_range(&mut req, &paths);
+        assert!(req.get_header("Range").is_none());
+
+        let mut req =
+            Request::get("https://example.org/video.mp4").with_header("Range", "bytes=0-1023");
+        strip_range(&mut req, &paths);
+        assert_eq!(Some("bytes=0-1023"), req.get_header_str("Range"));
+    }
+}
@@ -0,0 +1,131 @@
+use super::api::ApiClient;
+use super::configuration::ApiRecording;
+use super::error::ApiError;
+use super::kv_store;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+const KEY_PREFIX: &str = "action_record";
+
+/// API client recording the `action` responses of another client to the KV Store, or replaying
+/// them without calling the API at all.
+///
+/// Responses are keyed by the request signature: the method and the url of the request. Headers
+/// are not part of the signature, so a recorded action can be replayed for any client.
+pub struct RecordingApiClient<'a> {
+    mode: ApiRecording,
+    inner: &'a dyn ApiClient,
+}
+
+impl<'a> RecordingApiClient<'a> {
+    pub(crate) fn new(mode: ApiRecording, inner: &'a dyn ApiClient) -> RecordingApiClient<'a> {
+        RecordingApiClient { mode, inner }
+    }
+
+    fn record(&self, signature: &str, action_json: &str) {
+        let mut store = match kv_store::open() {
+            Some(store) => store,
+            None => return,
+        };
+
+        // Recording is best effort: the response is served even if it can not be stored
+        let _ = store.insert(&record_key(signature), action_json.to_string());
+    }
+
+    fn replay(&self, signature: &str) -> Result<String, ApiError> {
+        kv_store::open()
+            .and_then(|store| store.lookup_str(&record_key(signature)).ok().flatten())
+            .ok_or_else(|| ApiError::NotRecorded(signature.to_string()))
+    }
+}
+
+impl<'a> ApiClient for RecordingApiClient<'a> {
+    fn action(&self, rio_request_json: String) -> Result<String, ApiError> {
+        if self.mode == ApiRecording::Off {
+            return self.inner.action(rio_request_json);
+        }
+
+        let signature = request_signature(&rio_request_json);
+
+        if self.mode == ApiRecording::Replay {
+            return self.replay(&signature);
+        }
+
+        let action_json = self.inner.action(rio_request_json)?;
+        self.record(&signature, &action_json);
+
+        Ok(action_json)
+    }
+
+    fn action_in_background(&self, rio_request_json: String, on_action: Box<dyn FnOnce(String)>) {
+        if self.mode == ApiRecording::Off {
+            return self.inner.action_in_background(rio_request_json, on_action);
+        }
+
+        if let Ok(action_json) = self.action(rio_request_json) {
+            on_action(action_json);
+        }
+    }
+
+    fn log(&self, log_json: String) -> Result<(), ApiError> {
+        // Replayed traffic must never reach the production API
+        if self.mode == ApiRecording::Replay {
+            return Ok(());
+        }
+
+        self.inner.log(log_json)
+    }
+
+    fn rule_metrics(&self, metrics_json: String) -> Result<(), ApiError> {
+        if self.mode == ApiRecording::Replay {
+            return Ok(());
+        }
+
+        self.inner.rule_metrics(metrics_json)
+    }
+}
+
+/// Build the signature of a serialized redirection.io request, like `GET https://example.org/`.
+pub fn request_signature(rio_request_json: &str) -> String {
+    let rio_request: Value = serde_json::from_str(rio_request_json).unwrap_or_default();
+    let field = |name: &str| rio_request[name].as_str().unwrap_or_default().to_string();
+
+    format!(
+        "{} {}://{}{}",
+        field("method"),
+        field("scheme"),
+        field("host"),
+        field("path_and_query_v2")
+    )
+}
+
+fn record_key(signature: &str) -> String {
+    format!(
+        "{}:{}",
+        KEY_PREFIX,
+        hex::encode(Sha256::digest(signature.as_bytes()))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redirectionio::http::Request as RedirectionioRequest;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_request_signature_ignores_headers() {
+        let mut rio_request =
+            RedirectionioRequest::from_str("https://example.org/foo?bar").unwrap();
+        rio_request.method = Some("GET".to_string());
+        let signature = request_signature(&serde_json::to_string(&rio_request).unwrap());
+
+        rio_request.add_header("User-Agent".to_string(), "curl".to_string(), false);
+
+        assert_eq!("GET https://example.org/foo?bar", signature);
+        assert_eq!(
+            signature,
+            request_signature(&serde_json::to_string(&rio_request).unwrap())
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use redirectionio::http::Header;
+
+/// Headers of the backend responses stripped before they reach the clients, like `X-Powered-By`
+/// or `Server`, and headers the rules are not allowed to override, from the
+/// `stripped_response_headers` and `protected_response_headers` entries.
+#[derive(Debug, Clone, Default)]
+pub struct ResponseHeaderPolicy {
+    stripped: Vec<String>,
+    protected: Vec<String>,
+}
+
+impl ResponseHeaderPolicy {
+    pub(crate) fn new(stripped: &[String], protected: &[String]) -> ResponseHeaderPolicy {
+        ResponseHeaderPolicy {
+            stripped: stripped.to_vec(),
+            protected: protected.to_vec(),
+        }
+    }
+
+    /// Restore the protected headers of the backend response, then remove the stripped ones.
+    pub fn apply(&self, backend_headers: &[Header], headers: &mut Vec<Header>) {
+        keep_backend_headers(backend_headers, headers, &self.protected);
+
+        headers.retain(|header| !has_name(header, &self.stripped));
+    }
+}
+
+/// Send back the headers of the backend response with one of these names unchanged, whatever the
+/// rules did to them.
+pub fn keep_backend_headers<N: AsRef<str>>(
+    backend_headers: &[Header],
+    headers: &mut Vec<Header>,
+    names: &[N],
+) {
+    if names.is_empty() {
+        return;
+    }
+
+    headers.retain(|header| !has_name(header, names));
+    headers.extend(
+        backend_headers
+            .iter()
+            .filter(|header| has_name(header, names))
+            .cloned(),
+    );
+}
+
+fn has_name<N: AsRef<str>>(header: &Header, names: &[N]) -> bool {
+    names
+        .iter()
+        .any(|name| header.name.eq_ignore_ascii_case(name.as_ref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, value: &str) -> Header {
+        Header {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn pairs(headers: &[Header]) -> Vec<(&str, &str)> {
+        headers
+            .iter()
+            .map(|header| (header.name.as_str(), header.value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_keep_backend_headers() {
+        let backend_headers = vec![
+            header("Content-Length", "1024"),
+            header("Content-Type", "text/html"),
+        ];
+        // A rule replaced the length and another one added a range
+        let mut headers = vec![
+            header("Content-Type", "text/html"),
+            header("Content-Length", "12"),
+            header("Content-Range", "bytes 0-11/12"),
+        ];
+
+        keep_backend_headers(
+            &backend_headers,
+            &mut headers,
+            &["content-length", "content-range"],
+        );
+
+        assert_eq!(
+            vec![("Content-Type", "text/html"), ("Content-Length", "1024")],
+            pairs(&headers)
+        );
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = ResponseHeaderPolicy::new(
+            &["Server".to_string(), "X-Powered-By".to_string()],
+            &["Strict-Transport-Security".to_string()],
+        );
+        let backend_headers = vec![
+            header("Server", "Apache"),
+            header("x-powered-by", "PHP"),
+            header("Strict-Transport-Security", "max-age=31536000"),
+        ];
+        let mut headers = vec![
+            header("Server", "Apache"),
+            header("x-powered-by", "PHP"),
+            header("Strict-Transport-Security", "max-age=0"),
+            header("X-Rule", "1"),
+        ];
+
+        policy.apply(&backend_headers, &mut headers);
+
+        assert_eq!(
+            vec![
+                ("X-Rule", "1"),
+                ("Strict-Transport-Security", "max-age=31536000")
+            ],
+            pairs(&headers)
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use super::clock::Clock;
+use super::kv_store::{self, KvStore};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+const KEY: &str = "rule_metrics";
+
+// Duration, in seconds, of the window rules are aggregated in
+const WINDOW: u64 = 60;
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RuleStats {
+    pub matches: u64,
+    pub filtered: u64,
+    pub filter_duration_us: u64,
+    pub max_filter_duration_us: u64,
+}
+
+/// Metrics of the rules applied during a window, shared by the requests through the KV Store.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RuleMetricsWindow {
+    pub start: u64,
+    pub rules: BTreeMap<String, RuleStats>,
+}
+
+impl RuleMetricsWindow {
+    /// Count a match for each rule, and the duration of the body filter when the body was
+    /// filtered: the filter combines the rules, its whole duration is counted for each of them.
+    pub fn record(&mut self, now: u64, rule_ids: &[String], filter_duration: Option<Duration>) {
+        if self.start == 0 {
+            self.start = now;
+        }
+
+        let filter_duration_us = filter_duration.map(|duration| duration.as_micros() as u64);
+
+        for rule_id in rule_ids {
+            let stats = self.rules.entry(rule_id.clone()).or_default();
+            stats.matches += 1;
+
+            if let Some(filter_duration_us) = filter_duration_us {
+                stats.filtered += 1;
+                stats.filter_duration_us += filter_duration_us;
+                stats.max_filter_duration_us = stats.max_filter_duration_us.max(filter_duration_us);
+            }
+        }
+    }
+
+    pub fn is_due(&self, now: u64) -> bool {
+        self.start > 0 && now >= self.start + WINDOW
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RuleMetricsReport {
+    pub agent_version: String,
+    pub pop: Option<String>,
+    pub window_start: u64,
+    pub window_end: u64,
+    pub rules: BTreeMap<String, RuleStats>,
+}
+
+/// Aggregates which rules matched and how long their body filters took, so the redirection.io
+/// dashboard can show the impact of each rule at the edge.
+///
+/// Requests add their rules to the window of the KV Store, the first one seeing the window over
+/// takes its metrics to send them to the API, and starts a new window.
+pub struct RuleMetrics;
+
+impl RuleMetrics {
+    pub fn record(
+        &self,
+        rule_ids: &[String],
+        filter_duration: Option<Duration>,
+        agent_version: &str,
+        clock: &dyn Clock,
+        store: &dyn KvStore,
+    ) -> Option<RuleMetricsReport> {
+        let now = clock.now_secs();
+        let mut window: RuleMetricsWindow = kv_store::get_json(store, KEY).unwrap_or_default();
+        window.record(now, rule_ids, filter_duration);
+
+        if !window.is_due(now) {
+            kv_store::set_json(store, KEY, &window);
+
+            return None;
+        }
+
+        kv_store::set_json(store, KEY, &RuleMetricsWindow::default());
+
+        Some(RuleMetricsReport {
+            agent_version: agent_version.to_string(),
+            pop: std::env::var("FASTLY_POP").ok(),
+            window_start: window.start,
+            window_end: now,
+            rules: window.rules,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rio::mock::{MockClock, MockKvStore};
+
+    #[test]
+    fn test_window() {
+        let rule_ids = vec!["rule-1".to_string(), "rule-2".to_string()];
+        let mut window = RuleMetricsWindow::default();
+
+        assert!(!window.is_due(1000));
+
+        window.record(1000, &rule_ids, Some(Duration::from_micros(300)));
+        window.record(1010, &rule_ids[..1], Some(Duration::from_micros(500)));
+        window.record(1020, &rule_ids[..1], None);
+
+        assert_eq!(1000, window.start);
+        assert_eq!(
+            Some(&RuleStats {
+                matches: 3,
+                filtered: 2,
+                filter_duration_us: 800,
+                max_filter_duration_us: 500,
+            }),
+            window.rules.get("rule-1")
+        );
+        assert_eq!(1, window.rules["rule-2"].matches);
+        assert!(!window.is_due(1000 + WINDOW - 1));
+        assert!(window.is_due(1000 + WINDOW));
+    }
+
+    #[test]
+    fn test_record_starts_a_window() {
+        let store = MockKvStore::default();
+
+        assert!(RuleMetrics
+            .record(
+                &["rule-1".to_string()],
+                None,
+                "1.0.0",
+                &MockClock::new(1_000_000, 0),
+                &store
+            )
+            .is_none());
+
+        let window: RuleMetricsWindow = kv_store::get_json(&store, KEY).unwrap();
+
+        assert_eq!(1000, window.start);
+        assert_eq!(1, window.rules["rule-1"].matches);
+    }
+
+    #[test]
+    fn test_record_reports_a_due_window() {
+        let store = MockKvStore::new(&[(
+            KEY,
+            r#"{"start": 1000, "rules": {"rule-1": {"matches": 4, "filtered": 0, "filter_duration_us": 0, "max_filter_duration_us": 0}}}"#,
+        )]);
+        let report = RuleMetrics
+            .record(
+                &["rule-1".to_string()],
+                None,
+                "1.0.0",
+                &MockClock::new(u128::from(1000 + WINDOW) * 1000, 0),
+                &store,
+            )
+            .unwrap();
+
+        assert_eq!(1000, report.window_start);
+        assert_eq!(1000 + WINDOW, report.window_end);
+        assert_eq!(5, report.rules["rule-1"].matches);
+        assert_eq!(
+            Some(RuleMetricsWindow::default()),
+            kv_store::get_json(&store, KEY)
+        );
+    }
+}
@@ -0,0 +1,29 @@
+use fastly::SecretStore;
+
+pub const SECRET_STORE_NAME: &str = "redirectionio";
+
+/// Fetch the plaintext value of a secret from the `redirectionio` Secret Store.
+///
+/// Returns `None` when the store, or the secret, does not exist.
+pub fn get_secret(name: &str) -> Option<String> {
+    let secret_store = SecretStore::open(SECRET_STORE_NAME).ok()?;
+    let secret = secret_store.try_get(name).ok()??;
+
+    match secret.try_plaintext() {
+        Ok(plaintext) => Some(String::from_utf8_lossy(&plaintext).to_string()),
+        Err(_) => None,
+    }
+}
+
+/// Compare a user provided value with a secret in constant time.
+pub fn secure_compare(value: &str, secret: &str) -> bool {
+    if value.len() != secret.len() {
+        return false;
+    }
+
+    value
+        .bytes()
+        .zip(secret.bytes())
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
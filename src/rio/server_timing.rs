@@ -0,0 +1,106 @@
+use super::clock::Clock;
+use fastly::Response;
+use std::cell::RefCell;
+use std::time::Duration;
+
+pub const SERVER_TIMING_HEADER: &str = "Server-Timing";
+
+/// Durations of the steps of the worker, sent in the `Server-Timing` header of the response so
+/// browser devtools and RUM tools see the edge overhead: `init` for the start of the worker,
+/// `rio-match` for getting the action, `origin` for the backend response and `body-filter` for
+/// rewriting the body.
+///
+/// Only enabled by the `server_timing` entry, or for debug requests.
+pub struct ServerTiming {
+    enabled: bool,
+    metrics: RefCell<Vec<(&'static str, Duration)>>,
+}
+
+impl ServerTiming {
+    pub(crate) fn new(enabled: bool) -> ServerTiming {
+        ServerTiming {
+            enabled,
+            metrics: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Run a step of the worker, and record its duration.
+    pub fn measure<T>(&self, name: &'static str, clock: &dyn Clock, step: impl FnOnce() -> T) -> T {
+        if !self.enabled {
+            return step();
+        }
+
+        let start = clock.elapsed();
+        let result = step();
+        self.record(name, clock.elapsed().saturating_sub(start));
+
+        result
+    }
+
+    pub fn record(&self, name: &'static str, duration: Duration) {
+        if self.enabled {
+            self.metrics.borrow_mut().push((name, duration));
+        }
+    }
+
+    pub fn add_header(&self, response: &mut Response) {
+        let metrics = self.metrics.borrow();
+
+        if !self.enabled || metrics.is_empty() {
+            return;
+        }
+
+        response.append_header(SERVER_TIMING_HEADER, header_value(&metrics));
+    }
+}
+
+fn header_value(metrics: &[(&'static str, Duration)]) -> String {
+    metrics
+        .iter()
+        .map(|(name, duration)| format!("{};dur={:.1}", name, duration.as_secs_f64() * 1000.0))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rio::mock::MockClock;
+
+    #[test]
+    fn test_measure() {
+        let clock = MockClock::new(1000, 25);
+        let server_timing = ServerTiming::new(true);
+
+        server_timing.record("init", Duration::from_millis(3));
+        assert_eq!(42, server_timing.measure("rio-match", &clock, || 42));
+        server_timing.measure("origin", &clock, || ());
+
+        assert_eq!(
+            "init;dur=3.0, rio-match;dur=25.0, origin;dur=25.0",
+            header_value(&server_timing.metrics.borrow())
+        );
+    }
+
+    #[test]
+    fn test_disabled() {
+        let clock = MockClock::new(1000, 25);
+        let server_timing = ServerTiming::new(false);
+
+        server_timing.measure("rio-match", &clock, || ());
+        server_timing.record("init", Duration::from_millis(3));
+
+        assert!(server_timing.metrics.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_header_value() {
+        assert_eq!(
+            "origin;dur=80.3, body-filter;dur=1.2",
+            header_value(&[
+                ("origin", Duration::from_micros(80_260)),
+                ("body-filter", Duration::from_micros(1_180)),
+            ])
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use super::clock::Clock;
+use super::request_sender::RequestSender;
+use fastly::http::request::SendError;
+use fastly::{Request, Response};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const SHIELD_HEADER: &str = "x-redirectionio-shield";
+pub const CHAIN_HEADER: &str = "x-redirectionio-pass";
+
+// Maximum age, in seconds, of a signature to be accepted
+const SIGNATURE_TTL: u64 = 60;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Detects requests that have already been processed by this worker on a previous Fastly node.
+///
+/// When shielding is enabled, a request may go through the worker twice: once on the edge node,
+/// and once on the shield node. The edge node signs the requests it forwards with a secret shared
+/// by all nodes, so the shield node can recognize them and let them through untouched.
+///
+/// Chained services, like a platform service in front of a customer one, both running this
+/// worker, sign their requests in the same way with a secret they share, in another header.
+pub struct Shield<'a> {
+    secret: Option<String>,
+    header: &'static str,
+    clock: &'a dyn Clock,
+}
+
+impl<'a> Shield<'a> {
+    pub(crate) fn new(secret: Option<String>, clock: &'a dyn Clock) -> Shield<'a> {
+        Shield {
+            secret,
+            header: SHIELD_HEADER,
+            clock,
+        }
+    }
+
+    /// Signatures of the requests forwarded to another service running this worker.
+    pub(crate) fn chain(secret: Option<String>, clock: &'a dyn Clock) -> Shield<'a> {
+        Shield {
+            secret,
+            header: CHAIN_HEADER,
+            clock,
+        }
+    }
+
+    /// Check whether the request carries a valid signature, and remove the signature header.
+    pub fn verify(&self, req: &mut Request) -> bool {
+        let secret = match self.secret {
+            Some(ref secret) => secret,
+            None => return false,
+        };
+
+        let header = match req.remove_header_str(self.header) {
+            Some(header) => header,
+            None => return false,
+        };
+
+        let (timestamp, signature) = match header.split_once('.') {
+            Some((timestamp, signature)) => (timestamp, signature),
+            None => return false,
+        };
+
+        let timestamp: u64 = match timestamp.parse() {
+            Ok(timestamp) => timestamp,
+            Err(_) => return false,
+        };
+
+        if self.clock.now_secs().saturating_sub(timestamp) > SIGNATURE_TTL {
+            return false;
+        }
+
+        let signature = match hex::decode(signature) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+
+        match create_mac(secret, timestamp, req) {
+            Some(mac) => mac.verify_slice(&signature).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Add the signature header to a request forwarded to the backend.
+    pub fn sign(&self, req: &mut Request) {
+        let secret = match self.secret {
+            Some(ref secret) => secret,
+            None => return,
+        };
+
+        let timestamp = self.clock.now_secs();
+
+        if let Some(mac) = create_mac(secret, timestamp, req) {
+            let signature = hex::encode(mac.finalize().into_bytes());
+
+            req.set_header(self.header, format!("{}.{}", timestamp, signature));
+        }
+    }
+}
+
+/// Request sender signing every request sent to a backend, so a shield node running this worker
+/// does not evaluate rules, nor send logs, a second time.
+pub struct ShieldRequestSender<'a> {
+    shield: &'a Shield<'a>,
+    inner: &'a dyn RequestSender,
+}
+
+impl<'a> ShieldRequestSender<'a> {
+    pub(crate) fn new(
+        shield: &'a Shield<'a>,
+        inner: &'a dyn RequestSender,
+    ) -> ShieldRequestSender<'a> {
+        ShieldRequestSender { shield, inner }
+    }
+}
+
+impl<'a> RequestSender for ShieldRequestSender<'a> {
+    fn send(&self, mut req: Request, backend: String) -> Result<Response, SendError> {
+        self.shield.sign(&mut req);
+
+        self.inner.send(req, backend)
+    }
+}
+
+// The signature covers the method and host along with the path, so a signed request cannot be
+// replayed against another method or another host sharing the secret
+fn create_mac(secret: &str, timestamp: u64, req: &Request) -> Option<HmacSha256> {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(
+        format!(
+            "{}:{}:{}:{}",
+            timestamp,
+            req.get_method_str(),
+            req.get_url().host_str().unwrap_or_default(),
+            req.get_path()
+        )
+        .as_bytes(),
+    );
+
+    Some(mac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rio::mock::MockClock;
+
+    #[test]
+    fn test_chain() {
+        let clock = MockClock::new(1_000_000, 0);
+        let chain = Shield::chain(Some("secret".to_string()), &clock);
+        let mut req = Request::get("https://example.org/page");
+        chain.sign(&mut req);
+
+        assert!(req.get_header(SHIELD_HEADER).is_none());
+        assert!(
+            !Shield::new(Some("secret".to_string()), &clock).verify(&mut req.clone_without_body())
+        );
+        assert!(
+            !Shield::chain(Some("other".to_string()), &clock).verify(&mut req.clone_without_body())
+        );
+        assert!(chain.verify(&mut req));
+        assert!(req.get_header(CHAIN_HEADER).is_none());
+    }
+
+    #[test]
+    fn test_signature_covers_method_and_host() {
+        let clock = MockClock::new(1_000_000, 0);
+        let shield = Shield::new(Some("secret".to_string()), &clock);
+        let mut req = Request::get("https://example.org/page");
+        shield.sign(&mut req);
+
+        let mut other_method = req.clone_without_body();
+        other_method.set_method("POST");
+        assert!(!shield.verify(&mut other_method));
+
+        let mut other_host = req.clone_without_body();
+        other_host.set_url("https://other.example.org/page");
+        assert!(!shield.verify(&mut other_host));
+
+        assert!(shield.verify(&mut req));
+    }
+
+    #[test]
+    fn test_signature_expires() {
+        let clock = MockClock::new(1_000_000, u128::from(SIGNATURE_TTL + 1) * 1000);
+        let shield = Shield::new(Some("secret".to_string()), &clock);
+        let mut req = Request::get("https://example.org/page");
+        shield.sign(&mut req);
+
+        assert!(!shield.verify(&mut req));
+    }
+}
@@ -0,0 +1,86 @@
+use fastly::http::header;
+use fastly::Response;
+
+// Markers are searched in the beginning of the page only, where titles and headings are
+const SCAN_SIZE: usize = 64 * 1024;
+
+/// Detector of soft 404s: `200` HTML pages whose content says the page does not exist.
+///
+/// A page is a soft 404 when it contains one of the markers of the `soft_404_markers` entry, like
+/// `Page not found`, compared case-insensitively.
+pub struct Soft404Detector {
+    markers: Vec<String>,
+}
+
+impl Soft404Detector {
+    pub(crate) fn new(markers: &[String]) -> Option<Soft404Detector> {
+        if markers.is_empty() {
+            return None;
+        }
+
+        Some(Soft404Detector {
+            markers: markers.iter().map(|marker| marker.to_lowercase()).collect(),
+        })
+    }
+
+    /// Check whether the backend response is a soft 404, keeping its body unchanged.
+    pub fn detect(&self, response: &mut Response) -> bool {
+        let is_html = response
+            .get_header_str(header::CONTENT_TYPE)
+            .is_some_and(|content_type| content_type.to_lowercase().contains("text/html"));
+
+        // Compressed bodies can not be scanned
+        if response.get_status() != 200
+            || !is_html
+            || response.contains_header(header::CONTENT_ENCODING)
+        {
+            return false;
+        }
+
+        let body = response.take_body_bytes();
+        let is_soft_404 = self.matches(&body);
+        response.set_body(body);
+
+        is_soft_404
+    }
+
+    fn matches(&self, body: &[u8]) -> bool {
+        let page = String::from_utf8_lossy(&body[..body.len().min(SCAN_SIZE)]).to_lowercase();
+
+        self.markers
+            .iter()
+            .any(|marker| page.contains(marker.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let detector = Soft404Detector::new(&[
+            "Page not found".to_string(),
+            "Cette page n'existe pas".to_string(),
+        ])
+        .unwrap();
+
+        assert!(detector.matches(b"<html><title>Oops, page NOT found</title></html>"));
+        assert!(detector.matches(b"<h1>Cette page n'existe pas</h1>"));
+        assert!(!detector.matches(b"<h1>Our products</h1>"));
+    }
+
+    #[test]
+    fn test_markers_past_the_scanned_size() {
+        let detector = Soft404Detector::new(&["Page not found".to_string()]).unwrap();
+        let mut body = vec![b' '; SCAN_SIZE];
+        body.extend_from_slice(b"Page not found");
+
+        assert!(!detector.matches(&body));
+    }
+
+    #[test]
+    fn test_no_markers() {
+        assert!(Soft404Detector::new(&[]).is_none());
+    }
+}
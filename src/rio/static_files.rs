@@ -0,0 +1,99 @@
+use super::kv_store;
+use fastly::http::{header, StatusCode};
+use fastly::{Request, Response};
+
+const KEY_PREFIX: &str = "static";
+
+// How long clients and the edge may cache a file served by the worker
+const CACHE_CONTROL: &str = "public, max-age=3600";
+
+/// Whether the path is one of the files the worker can serve itself: `/robots.txt` and the
+/// `/sitemap*.xml` files.
+pub fn is_static_file(path: &str) -> bool {
+    path == "/robots.txt" || (path.starts_with("/sitemap") && path.ends_with(".xml"))
+}
+
+/// Serve `/robots.txt` and `/sitemap*.xml` from the edge, without contacting the backend.
+///
+/// Files are looked up in the KV Store, first for the host of the request (`static:<host><path>`),
+/// then for all hosts (`static:<path>`). The `robots_txt` configuration entry is used as a
+/// fallback for `/robots.txt`. Returns `None` when the file is not stored.
+pub fn handle<F>(req: &Request, get_config: F) -> Option<Response>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let path = req.get_path();
+
+    if !is_static_file(path) {
+        return None;
+    }
+
+    let host = req.get_url().host_str().unwrap_or_default().to_lowercase();
+    let content = kv_store::open()
+        .and_then(|store| {
+            lookup_keys(&host, path)
+                .iter()
+                .find_map(|key| store.lookup_str(key).ok().flatten())
+        })
+        .or_else(|| match path {
+            "/robots.txt" => get_config("robots_txt"),
+            _ => None,
+        })?;
+
+    Some(
+        Response::from_status(StatusCode::OK)
+            .with_header(header::CONTENT_TYPE, content_type(path))
+            .with_header(header::CACHE_CONTROL, CACHE_CONTROL)
+            .with_body(content),
+    )
+}
+
+pub fn lookup_keys(host: &str, path: &str) -> Vec<String> {
+    vec![
+        format!("{}:{}{}", KEY_PREFIX, host, path),
+        format!("{}:{}", KEY_PREFIX, path),
+    ]
+}
+
+fn content_type(path: &str) -> &'static str {
+    if path.ends_with(".xml") {
+        "application/xml; charset=UTF-8"
+    } else {
+        "text/plain; charset=UTF-8"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_static_file() {
+        assert!(is_static_file("/robots.txt"));
+        assert!(is_static_file("/sitemap.xml"));
+        assert!(is_static_file("/sitemap-posts-1.xml"));
+        assert!(!is_static_file("/blog/robots.txt"));
+        assert!(!is_static_file("/sitemap"));
+        assert!(!is_static_file("/feed.xml"));
+    }
+
+    #[test]
+    fn test_lookup_keys() {
+        assert_eq!(
+            vec![
+                "static:example.org/robots.txt".to_string(),
+                "static:/robots.txt".to_string()
+            ],
+            lookup_keys("example.org", "/robots.txt")
+        );
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!("text/plain; charset=UTF-8", content_type("/robots.txt"));
+        assert_eq!(
+            "application/xml; charset=UTF-8",
+            content_type("/sitemap.xml")
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use fastly::Request;
+
+/// Markers of long-polling, server-sent events or Fanout (GRIP) requests, from the
+/// `streaming_paths` and `streaming_headers` entries.
+///
+/// The responses of these requests are relayed to the client as they come: they are not buffered
+/// to apply the rules, which would hold them until the origin closes the connection.
+#[derive(Debug, Clone, Default)]
+pub struct StreamingMarkers {
+    paths: Vec<String>,
+    // Header name, and a part of its value when the marker is written `Name: value`
+    headers: Vec<(String, Option<String>)>,
+}
+
+impl StreamingMarkers {
+    pub(crate) fn new(paths: &[String], headers: &[String]) -> StreamingMarkers {
+        StreamingMarkers {
+            paths: paths.to_vec(),
+            headers: headers
+                .iter()
+                .map(|marker| match marker.split_once(':') {
+                    Some((name, value)) => {
+                        (name.trim().to_string(), Some(value.trim().to_lowercase()))
+                    }
+                    None => (marker.trim().to_string(), None),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn matches(&self, req: &Request) -> bool {
+        let path = req.get_path();
+
+        if self
+            .paths
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+        {
+            return true;
+        }
+
+        self.headers.iter().any(|(name, value)| {
+            req.get_header_all_str(name.as_str())
+                .into_iter()
+                .any(|header_value| match value {
+                    Some(value) => header_value.to_lowercase().contains(value.as_str()),
+                    None => true,
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn markers() -> StreamingMarkers {
+        StreamingMarkers::new(
+            &["/poll/".to_string()],
+            &[
+                "Accept: text/event-stream".to_string(),
+                "Grip-Sig".to_string(),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_path_marker() {
+        assert!(markers().matches(&Request::get("https://example.org/poll/updates")));
+        assert!(!markers().matches(&Request::get("https://example.org/blog/")));
+    }
+
+    #[test]
+    fn test_header_markers() {
+        assert!(markers().matches(
+            &Request::get("https://example.org/events").with_header("Accept", "text/event-stream")
+        ));
+        assert!(markers()
+            .matches(&Request::get("https://example.org/stream").with_header("Grip-Sig", "abc")));
+        assert!(!markers()
+            .matches(&Request::get("https://example.org/").with_header("Accept", "text/html")));
+    }
+}
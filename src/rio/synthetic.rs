@@ -0,0 +1,161 @@
+use fastly::http::header;
+use fastly::Response;
+
+/// Cache directive of synthetic responses when the `synthetic_cache_control` entry is not set:
+/// shields and browsers must not keep error pages.
+pub const DEFAULT_SYNTHETIC_CACHE_CONTROL: &str = "no-store";
+
+const DEFAULT_CONTENT_TYPE: &str = "text/html; charset=UTF-8";
+
+/// Default page of synthetic responses, padded to disable the friendly error pages of browsers.
+///
+/// The request id, when given, helps to find the logs of an unexpected error.
+pub fn error_page(status_code: u16, request_id: Option<&str>) -> String {
+    let request_id = match request_id {
+        Some(request_id) => format!("<center>Request ID: {}</center>\n", request_id),
+        None => String::new(),
+    };
+
+    format!(
+        "
+<html>
+<head><title>{}</title></head>
+<body bgcolor=\"white\">
+<center><h1>{}</h1></center>
+{}</body>
+</html>
+<!-- a padding to disable MSIE and Chrome friendly error page -->
+<!-- a padding to disable MSIE and Chrome friendly error page -->
+<!-- a padding to disable MSIE and Chrome friendly error page -->
+<!-- a padding to disable MSIE and Chrome friendly error page -->
+<!-- a padding to disable MSIE and Chrome friendly error page -->
+<!-- a padding to disable MSIE and Chrome friendly error page -->
+<!-- a padding to disable MSIE and Chrome friendly error page -->
+",
+        status_code, status_code, request_id
+    )
+}
+
+/// Build a response generated by the worker, like an error page or the body of a rule, with its
+/// length, charset and cache headers.
+///
+/// HEAD responses get the headers of the page, without its body.
+pub fn response(
+    status_code: u16,
+    content_type: Option<String>,
+    body: String,
+    with_body: bool,
+    cache_control: &str,
+) -> Response {
+    let mut response = Response::from_status(status_code);
+    set_headers(
+        &mut response,
+        &content_type.unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_string()),
+        body.len(),
+        cache_control,
+    );
+
+    if with_body {
+        response.set_body(body);
+    }
+
+    response
+}
+
+/// Build a plain text response generated by the worker, like a configuration error.
+pub fn text_response(message: String, status_code: u16, cache_control: &str) -> Response {
+    response(
+        status_code,
+        Some("text/plain; charset=UTF-8".to_string()),
+        message,
+        true,
+        cache_control,
+    )
+}
+
+fn set_headers(
+    response: &mut Response,
+    content_type: &str,
+    content_length: usize,
+    cache_control: &str,
+) {
+    response.set_header(header::CONTENT_TYPE, with_charset(content_type));
+
+    // The length of a compressed body is only known once it is encoded
+    if !response.contains_header(header::CONTENT_ENCODING) {
+        response.set_header(header::CONTENT_LENGTH, content_length.to_string());
+    }
+
+    if !cache_control.is_empty() {
+        response.set_header(header::CACHE_CONTROL, cache_control);
+    }
+}
+
+/// Declare the UTF-8 charset of textual content types which do not have one.
+fn with_charset(content_type: &str) -> String {
+    let lowercase = content_type.to_lowercase();
+    let is_textual = lowercase.starts_with("text/")
+        || lowercase.contains("json")
+        || lowercase.contains("xml")
+        || lowercase.contains("javascript");
+
+    match is_textual && !lowercase.contains("charset=") {
+        true => format!(
+            "{}; charset=UTF-8",
+            content_type.trim_end_matches([';', ' '])
+        ),
+        false => content_type.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_charset() {
+        assert_eq!("text/plain; charset=UTF-8", with_charset("text/plain"));
+        assert_eq!(
+            "application/json; charset=UTF-8",
+            with_charset("application/json;")
+        );
+        assert_eq!(
+            "text/html; charset=iso-8859-1",
+            with_charset("text/html; charset=iso-8859-1")
+        );
+        assert_eq!("image/png", with_charset("image/png"));
+    }
+
+    #[test]
+    fn test_set_headers() {
+        let mut response = Response::from_status(503);
+        set_headers(
+            &mut response,
+            "text/plain",
+            27,
+            DEFAULT_SYNTHETIC_CACHE_CONTROL,
+        );
+
+        assert_eq!(
+            Some("text/plain; charset=UTF-8"),
+            response.get_header_str(header::CONTENT_TYPE)
+        );
+        assert_eq!(Some("27"), response.get_header_str(header::CONTENT_LENGTH));
+        assert_eq!(
+            Some("no-store"),
+            response.get_header_str(header::CACHE_CONTROL)
+        );
+
+        let mut response = Response::from_status(503).with_header(header::CONTENT_ENCODING, "br");
+        set_headers(&mut response, "text/html", 27, "");
+
+        assert_eq!(None, response.get_header_str(header::CONTENT_LENGTH));
+        assert_eq!(None, response.get_header_str(header::CACHE_CONTROL));
+    }
+
+    #[test]
+    fn test_error_page() {
+        assert!(error_page(503, None).contains("<center><h1>503</h1></center>\n</body>"));
+        assert!(error_page(500, Some("abc123")).contains("<center>Request ID: abc123</center>"));
+    }
+}
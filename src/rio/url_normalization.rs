@@ -0,0 +1,201 @@
+// How many levels of percent-encoding are decoded, to undo double encoding
+const MAX_DECODING_PASSES: usize = 3;
+
+/// Normalization of the request path before matching, so equivalent urls match the same rules,
+/// from the `url_normalization` entry: `percent_encoding`, `case_folding`, or both.
+///
+/// Only the url used for matching is normalized: the backend gets the raw url.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UrlNormalization {
+    /// Decode percent-encoded characters, including double-encoded ones and encoded slashes, and
+    /// only encode back the characters which must be
+    pub percent_encoding: bool,
+    /// Lowercase the path, including non-ASCII characters
+    pub case_folding: bool,
+}
+
+impl UrlNormalization {
+    /// Parse a list of normalizations, like `percent_encoding,case_folding`.
+    pub fn parse(items: &[String]) -> Result<UrlNormalization, String> {
+        let mut url_normalization = UrlNormalization::default();
+
+        for item in items {
+            match item.as_str() {
+                "percent_encoding" => url_normalization.percent_encoding = true,
+                "case_folding" => url_normalization.case_folding = true,
+                _ => return Err(item.clone()),
+            }
+        }
+
+        Ok(url_normalization)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.percent_encoding || self.case_folding
+    }
+
+    pub fn normalize_path(&self, path: &str) -> String {
+        if !self.is_enabled() {
+            return path.to_string();
+        }
+
+        // Without percent-encoding normalization, the encoding of the path is kept as is
+        if !self.percent_encoding {
+            return case_fold_encoded(path);
+        }
+
+        let mut decoded = path.to_string();
+
+        for _ in 0..MAX_DECODING_PASSES {
+            match percent_decode(&decoded) {
+                Some(next) if next != decoded => decoded = next,
+                _ => break,
+            }
+        }
+
+        if self.case_folding {
+            decoded = decoded.to_lowercase();
+        }
+
+        percent_encode(&decoded)
+    }
+}
+
+/// Lowercase a path while keeping its percent-encoded sequences, decoded to be folded.
+fn case_fold_encoded(path: &str) -> String {
+    let mut output = String::with_capacity(path.len());
+    let mut rest = path;
+
+    while !rest.is_empty() {
+        // Consume a run of encoded bytes, or a single raw character
+        let encoded_len = encoded_run_len(rest);
+
+        if encoded_len > 0 {
+            match percent_decode(&rest[..encoded_len]) {
+                Some(decoded) => {
+                    for byte in decoded.to_lowercase().bytes() {
+                        output.push_str(&format!("%{:02X}", byte));
+                    }
+                }
+                None => output.push_str(&rest[..encoded_len]),
+            }
+
+            rest = &rest[encoded_len..];
+        } else {
+            let c = rest.chars().next().unwrap_or_default();
+            output.extend(c.to_lowercase());
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    output
+}
+
+fn encoded_run_len(value: &str) -> usize {
+    let bytes = value.as_bytes();
+    let mut len = 0;
+
+    while len + 2 < bytes.len()
+        && bytes[len] == b'%'
+        && bytes[len + 1].is_ascii_hexdigit()
+        && bytes[len + 2].is_ascii_hexdigit()
+    {
+        len += 3;
+    }
+
+    len
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        if bytes[index] == b'%' && index + 2 < bytes.len() {
+            if let Ok(byte) = u8::from_str_radix(&value[index + 1..index + 3], 16) {
+                decoded.push(byte);
+                index += 3;
+                continue;
+            }
+        }
+
+        decoded.push(bytes[index]);
+        index += 1;
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(byte as char),
+            b'-' | b'.' | b'_' | b'~' | b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+'
+            | b',' | b';' | b'=' | b':' | b'@' | b'/' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: UrlNormalization = UrlNormalization {
+        percent_encoding: true,
+        case_folding: true,
+    };
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Ok(ALL),
+            UrlNormalization::parse(&["percent_encoding".to_string(), "case_folding".to_string()])
+        );
+        assert_eq!(
+            Err("lowercase".to_string()),
+            UrlNormalization::parse(&["lowercase".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_percent_encoding() {
+        let normalization = UrlNormalization {
+            percent_encoding: true,
+            case_folding: false,
+        };
+
+        assert_eq!("/Foo/bar", normalization.normalize_path("/Foo%2Fbar"));
+        assert_eq!("/Foo/bar", normalization.normalize_path("/Foo%252Fbar"));
+        assert_eq!("/~user", normalization.normalize_path("/%7Euser"));
+        assert_eq!("/a%20b", normalization.normalize_path("/a b"));
+        assert_eq!("/caf%C3%A9", normalization.normalize_path("/caf%c3%a9"));
+        assert_eq!("/100%25", normalization.normalize_path("/100%"));
+    }
+
+    #[test]
+    fn test_case_folding() {
+        let normalization = UrlNormalization {
+            percent_encoding: false,
+            case_folding: true,
+        };
+
+        assert_eq!("/foo%2Fbar", normalization.normalize_path("/Foo%2FBar"));
+        assert_eq!("/caf%C3%A9", normalization.normalize_path("/CAF%C3%89"));
+    }
+
+    #[test]
+    fn test_all() {
+        assert_eq!("/foo/bar", ALL.normalize_path("/Foo%2Fbar"));
+        assert_eq!("/caf%C3%A9", ALL.normalize_path("/CAF%25C3%2589"));
+        assert_eq!(
+            "/Foo%2Fbar",
+            UrlNormalization::default().normalize_path("/Foo%2Fbar")
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use redirectionio::http::Header;
+
+// Maximum number of headers in the `Vary` header when it is not configured
+pub const DEFAULT_MAX_VARY_HEADERS: usize = 4;
+
+/// Add the request headers the rules depend on to the `Vary` header of a response, so caches
+/// do not serve a variant to clients that should get another one.
+///
+/// When the `Vary` header would list more than `max_vary_headers` headers, the response is made
+/// private instead, as such a cardinality would ruin the cache hit ratio anyway.
+pub fn add_vary_headers(
+    headers: &mut Vec<Header>,
+    vary_headers: &[String],
+    max_vary_headers: usize,
+) {
+    if vary_headers.is_empty() {
+        return;
+    }
+
+    let mut vary: Vec<String> = Vec::new();
+
+    for header in headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("vary"))
+    {
+        vary.extend(header.value.split(',').map(|name| name.trim().to_string()));
+    }
+
+    // A response varying on everything can not be cached anyway
+    if vary.iter().any(|name| name == "*") {
+        return;
+    }
+
+    for name in vary_headers {
+        if !vary
+            .iter()
+            .any(|existing| existing.eq_ignore_ascii_case(name))
+        {
+            vary.push(name.clone());
+        }
+    }
+
+    vary.retain(|name| !name.is_empty());
+
+    if vary.len() > max_vary_headers {
+        headers.retain(|header| !header.name.eq_ignore_ascii_case("cache-control"));
+        headers.push(Header {
+            name: "Cache-Control".to_string(),
+            value: "private".to_string(),
+        });
+
+        return;
+    }
+
+    headers.retain(|header| !header.name.eq_ignore_ascii_case("vary"));
+    headers.push(Header {
+        name: "Vary".to_string(),
+        value: vary.join(", "),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, value: &str) -> Header {
+        Header {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn get<'a>(headers: &'a [Header], name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.value.as_str())
+    }
+
+    #[test]
+    fn test_add_vary_headers() {
+        let mut headers = vec![header("Content-Type", "text/html")];
+
+        add_vary_headers(&mut headers, &["Accept-Language".to_string()], 4);
+
+        assert_eq!(Some("Accept-Language"), get(&headers, "Vary"));
+    }
+
+    #[test]
+    fn test_merge_with_existing_vary_header() {
+        let mut headers = vec![header("vary", "Accept-Encoding, accept-language")];
+
+        add_vary_headers(
+            &mut headers,
+            &["Accept-Language".to_string(), "Cookie".to_string()],
+            4,
+        );
+
+        assert_eq!(1, headers.len());
+        assert_eq!(
+            Some("Accept-Encoding, accept-language, Cookie"),
+            get(&headers, "Vary")
+        );
+    }
+
+    #[test]
+    fn test_vary_star_is_kept() {
+        let mut headers = vec![header("Vary", "*")];
+
+        add_vary_headers(&mut headers, &["Cookie".to_string()], 4);
+
+        assert_eq!(Some("*"), get(&headers, "Vary"));
+    }
+
+    #[test]
+    fn test_too_many_vary_headers() {
+        let mut headers = vec![
+            header("Vary", "Accept-Encoding"),
+            header("Cache-Control", "max-age=3600"),
+        ];
+
+        add_vary_headers(
+            &mut headers,
+            &["Accept-Language".to_string(), "Cookie".to_string()],
+            2,
+        );
+
+        assert_eq!(Some("Accept-Encoding"), get(&headers, "Vary"));
+        assert_eq!(Some("private"), get(&headers, "Cache-Control"));
+    }
+
+    #[test]
+    fn test_no_vary_headers() {
+        let mut headers = vec![header("Cache-Control", "max-age=3600")];
+
+        add_vary_headers(&mut headers, &[], 0);
+
+        assert_eq!(None, get(&headers, "Vary"));
+        assert_eq!(Some("max-age=3600"), get(&headers, "Cache-Control"));
+    }
+}